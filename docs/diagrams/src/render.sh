@@ -1,4 +1,5 @@
 mscgen -Tsvg -o ../open_from_scratch.svg open_from_scratch.msc
 mscgen -Tsvg -o ../simultaneous_opening.svg simultaneous_opening.msc
 mscgen -Tsvg -o ../refill_or_withdraw.svg refill_or_withdraw.msc
+cargo run -q -p guac_core --example state_machine_dot > state_machine.dot
 dot state_machine.dot -Tsvg -o ../state_machine.svg
\ No newline at end of file
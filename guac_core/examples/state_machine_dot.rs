@@ -0,0 +1,6 @@
+//! Prints the state machine diagram, see `guac_core::state_machine::to_dot`
+extern crate guac_core;
+
+fn main() {
+    print!("{}", guac_core::state_machine::to_dot());
+}
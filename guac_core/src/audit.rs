@@ -0,0 +1,344 @@
+use crate::channel::Channel;
+use crate::events::GuacEvent;
+use crate::types::{ChannelState, Counterparty, ReDrawTx};
+use crate::Guac;
+use crate::GuacError;
+use clarity::Address;
+use futures::future;
+use num256::Uint256;
+
+/// A single way in which our stored channel disagrees with the contract.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The contract has no channel with this ID
+    ChannelNotFound,
+    /// The channel on chain is between different addresses than we think it is
+    WrongAddresses {
+        address_0: Address,
+        address_1: Address,
+    },
+    /// The amount of money locked in the channel differs, for instance because we missed a reDraw
+    TotalBalanceMismatch { local: Uint256, on_chain: Uint256 },
+    /// The contract has seen a more recent state than we have stored, for instance because
+    /// the counterparty submitted a reDraw or an update that we never applied
+    SequenceNumberAhead { local: Uint256, on_chain: Uint256 },
+    /// Someone has started closing the channel
+    SettlementInProgress { settling_period_end: Uint256 },
+}
+
+/// Result of comparing one stored channel with the contract.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AuditReport {
+    pub counterparty: Address,
+    pub channel_id: [u8; 32],
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl AuditReport {
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+/// Compares a channel (and the reDraw we may be waiting on) with its state on chain.
+///
+/// Off-chain payments move money between the two balances without touching the contract, so
+/// only the total is compared. If a reDraw is pending, the contract may legitimately be in either
+/// the state before or after it.
+pub fn compare_channel(
+    channel: &Channel,
+    pending_re_draw: Option<&ReDrawTx>,
+    my_address: Address,
+    their_address: Address,
+    on_chain: &ChannelState,
+) -> Vec<Discrepancy> {
+    if on_chain.address_0 == Address::default() && on_chain.address_1 == Address::default() {
+        return vec![Discrepancy::ChannelNotFound];
+    }
+
+    let mut discrepancies = Vec::new();
+
+    let (address_0, address_1) = if channel.i_am_0 {
+        (my_address, their_address)
+    } else {
+        (their_address, my_address)
+    };
+
+    if on_chain.address_0 != address_0 || on_chain.address_1 != address_1 {
+        discrepancies.push(Discrepancy::WrongAddresses {
+            address_0: on_chain.address_0,
+            address_1: on_chain.address_1,
+        });
+    }
+
+    let local_total = channel.balance_0.clone() + channel.balance_1.clone();
+    let (re_draw_total, re_draw_seq) = match pending_re_draw {
+        Some(re_draw_tx) => (
+            Some(re_draw_tx.new_balance_0.clone() + re_draw_tx.new_balance_1.clone()),
+            Some(re_draw_tx.sequence_number.clone()),
+        ),
+        None => (None, None),
+    };
+
+    if on_chain.total_balance != local_total
+        && Some(on_chain.total_balance.clone()) != re_draw_total
+    {
+        discrepancies.push(Discrepancy::TotalBalanceMismatch {
+            local: local_total,
+            on_chain: on_chain.total_balance.clone(),
+        });
+    }
+
+    if on_chain.sequence_number > channel.sequence_number
+        && Some(on_chain.sequence_number.clone()) != re_draw_seq
+    {
+        discrepancies.push(Discrepancy::SequenceNumberAhead {
+            local: channel.sequence_number.clone(),
+            on_chain: on_chain.sequence_number.clone(),
+        });
+    }
+
+    if on_chain.settling_period_started {
+        discrepancies.push(Discrepancy::SettlementInProgress {
+            settling_period_end: on_chain.settling_period_end.clone(),
+        });
+    }
+
+    discrepancies
+}
+
+impl Guac {
+    /// Compares every stored channel with the state of the contract and reports what differs.
+    /// Counterparties which do not have a channel yet are skipped. The first time a channel is
+    /// found settling, `GuacEvent::SettlementStarted` is emitted for it.
+    pub async fn audit(&self) -> Result<Vec<AuditReport>, GuacError> {
+        let my_address = self.crypto.own_address;
+
+        let audits = self
+            .storage
+            .get_all_counterparties()
+            .into_iter()
+            .filter_map(|(their_address, counterparty)| {
+                let (channel, pending_re_draw) = match counterparty {
+                    Counterparty::Open { channel } => (channel, None),
+                    Counterparty::ReDrawing {
+                        channel,
+                        re_draw_tx,
+                    }
+                    | Counterparty::OtherReDrawing {
+                        channel,
+                        re_draw_tx,
+                    } => (channel, Some(re_draw_tx)),
+                    _ => return None,
+                };
+
+                Some(async move {
+                    let on_chain = self
+                        .blockchain_client
+                        .get_channel(channel.channel_id)
+                        .await?;
+                    let discrepancies = compare_channel(
+                        &channel,
+                        pending_re_draw.as_ref(),
+                        my_address,
+                        their_address,
+                        &on_chain,
+                    );
+
+                    let settling = discrepancies.iter().any(|discrepancy| {
+                        matches!(discrepancy, Discrepancy::SettlementInProgress { .. })
+                    });
+                    if settling && self.settling.lock().unwrap().insert(channel.channel_id) {
+                        self.events.emit(GuacEvent::SettlementStarted {
+                            counterparty: their_address,
+                            channel_id: channel.channel_id,
+                        });
+                    }
+
+                    Ok::<_, GuacError>(AuditReport {
+                        counterparty: their_address,
+                        channel_id: channel.channel_id,
+                        discrepancies,
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        future::try_join_all(audits).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{make_node, MockChain, MockNetwork, SECRET_0, SECRET_1};
+    use futures::executor::block_on;
+
+    fn address(last_byte: u8) -> Address {
+        let mut data: [u8; 20] = Default::default();
+        data[19] = last_byte;
+        data.into()
+    }
+
+    fn channel() -> Channel {
+        Channel {
+            channel_id: [1; 32],
+            sequence_number: 3u64.into(),
+            balance_0: 5u64.into(),
+            balance_1: 15u64.into(),
+            accrual: 0u64.into(),
+            hashlocks: Vec::new(),
+            signed_update: None,
+            i_am_0: true,
+        }
+    }
+
+    fn on_chain() -> ChannelState {
+        ChannelState {
+            address_0: address(1),
+            address_1: address(2),
+            total_balance: 20u64.into(),
+            balance_0: 10u64.into(),
+            balance_1: 10u64.into(),
+            sequence_number: 0u64.into(),
+            settling_period_length: 5000u64.into(),
+            settling_period_started: false,
+            settling_period_end: 0u64.into(),
+        }
+    }
+
+    #[test]
+    fn test_consistent() {
+        assert_eq!(
+            compare_channel(&channel(), None, address(1), address(2), &on_chain()),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_not_found() {
+        let state = ChannelState {
+            address_0: Address::default(),
+            address_1: Address::default(),
+            ..on_chain()
+        };
+        assert_eq!(
+            compare_channel(&channel(), None, address(1), address(2), &state),
+            vec![Discrepancy::ChannelNotFound]
+        );
+    }
+
+    #[test]
+    fn test_wrong_addresses() {
+        assert_eq!(
+            compare_channel(&channel(), None, address(2), address(1), &on_chain()),
+            vec![Discrepancy::WrongAddresses {
+                address_0: address(1),
+                address_1: address(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_missed_re_draw() {
+        let state = ChannelState {
+            total_balance: 30u64.into(),
+            sequence_number: 4u64.into(),
+            ..on_chain()
+        };
+        assert_eq!(
+            compare_channel(&channel(), None, address(1), address(2), &state),
+            vec![
+                Discrepancy::TotalBalanceMismatch {
+                    local: 20u64.into(),
+                    on_chain: 30u64.into(),
+                },
+                Discrepancy::SequenceNumberAhead {
+                    local: 3u64.into(),
+                    on_chain: 4u64.into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pending_re_draw_landed() {
+        let re_draw_tx = ReDrawTx {
+            channel_id: [1; 32],
+            sequence_number: 4u64.into(),
+            old_balance_0: 5u64.into(),
+            old_balance_1: 15u64.into(),
+            new_balance_0: 15u64.into(),
+            new_balance_1: 15u64.into(),
+            expiration: 0u64.into(),
+            signature_0: None,
+            signature_1: None,
+        };
+        let state = ChannelState {
+            total_balance: 30u64.into(),
+            sequence_number: 4u64.into(),
+            ..on_chain()
+        };
+        assert_eq!(
+            compare_channel(
+                &channel(),
+                Some(&re_draw_tx),
+                address(1),
+                address(2),
+                &state
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_settlement_in_progress() {
+        let state = ChannelState {
+            settling_period_started: true,
+            settling_period_end: 100u64.into(),
+            ..on_chain()
+        };
+        assert_eq!(
+            compare_channel(&channel(), None, address(1), address(2), &state),
+            vec![Discrepancy::SettlementInProgress {
+                settling_period_end: 100u64.into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_settlement_started_event() {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let node_0 = make_node(&network, &chain, SECRET_0, "node_0");
+        let node_1 = make_node(&network, &chain, SECRET_1, "node_1");
+        let guac = &node_0.guac;
+        let mut events = guac.subscribe();
+
+        block_on(async {
+            guac.fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            let channel_id = match guac.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { channel } => channel.channel_id,
+                state => panic!("unexpected state {:?}", state),
+            };
+
+            guac.audit().await.unwrap();
+            chain.start_settling(channel_id);
+            guac.audit().await.unwrap();
+            guac.audit().await.unwrap();
+
+            let settling: Vec<GuacEvent> = std::iter::from_fn(|| events.try_recv().ok())
+                .filter(|event| matches!(event, GuacEvent::SettlementStarted { .. }))
+                .collect();
+            assert_eq!(
+                settling,
+                vec![GuacEvent::SettlementStarted {
+                    counterparty: node_1.address,
+                    channel_id,
+                }]
+            );
+        });
+    }
+}
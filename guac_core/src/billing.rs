@@ -0,0 +1,331 @@
+//! Billing on top of what counterparties pay us.
+//!
+//! `Guac::check_accrual` resets the accrual as it reads it, so a biller which crashes before
+//! saving what it read loses those payments. Cursors are read without changing anything: the
+//! biller reads what was received since its cursor, saves it, and only then acknowledges the
+//! new cursor, which is where it starts again from after a crash.
+//!
+//! To bill by usage, the application adds up what each counterparty owes for what it used.
+//! Comparing that with what the counterparty paid tells how far behind it is when it pays
+//! afterwards, or how much credit it has left when it pays in advance.
+
+use crate::channel_manager::check_for_counterparty;
+use crate::error::{GuacError, ProtocolError};
+use crate::events::GuacEvent;
+use crate::Guac;
+use clarity::Address;
+use num::traits::ops::checked::CheckedSub;
+use num256::Uint256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// A point in the payments received from a counterparty
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccrualCursor {
+    /// Everything received from the counterparty up to this point
+    pub received: Uint256,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Account {
+    received: Uint256,
+    acked: AccrualCursor,
+    usage: Uint256,
+    debt_limit: Option<Uint256>,
+}
+
+impl Account {
+    fn debt(&self) -> Uint256 {
+        self.usage
+            .checked_sub(&self.received)
+            .unwrap_or_else(|| 0u64.into())
+    }
+
+    fn credit(&self) -> Uint256 {
+        self.received
+            .checked_sub(&self.usage)
+            .unwrap_or_else(|| 0u64.into())
+    }
+}
+
+/// What each counterparty paid us and owes us
+#[derive(Default)]
+pub struct Billing {
+    accounts: Mutex<HashMap<Address, Account>>,
+    debt_limit: Option<Uint256>,
+}
+
+impl Billing {
+    /// Never signals debts until a limit is set
+    pub fn new() -> Billing {
+        Billing::default()
+    }
+
+    /// Signals counterparties which owe more than `limit`, see `Guac::add_usage`
+    pub fn with_debt_limit(mut self, limit: Uint256) -> Self {
+        self.debt_limit = Some(limit);
+        self
+    }
+
+    /// Replaces the debt limit for one counterparty
+    pub fn set_debt_limit(&self, their_address: Address, limit: Uint256) {
+        self.accounts
+            .lock()
+            .unwrap()
+            .entry(their_address)
+            .or_default()
+            .debt_limit = Some(limit);
+    }
+
+    pub(crate) fn record_received(&self, their_address: Address, amount: Uint256) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(their_address).or_default();
+        account.received = account.received.clone() + amount;
+    }
+
+    fn account(&self, their_address: &Address) -> Account {
+        self.accounts
+            .lock()
+            .unwrap()
+            .get(their_address)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn ack(&self, their_address: Address, cursor: AccrualCursor) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(their_address).or_default();
+        if cursor.received > account.acked.received {
+            account.acked = cursor;
+        }
+    }
+
+    /// Returns the debt, and whether it is over the limit
+    fn add_usage(&self, their_address: Address, amount: Uint256) -> (Uint256, bool) {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(their_address).or_default();
+        account.usage = account.usage.clone() + amount;
+
+        let debt = account.debt();
+        let over_limit = account
+            .debt_limit
+            .as_ref()
+            .or(self.debt_limit.as_ref())
+            .is_some_and(|limit| debt > *limit);
+        (debt, over_limit)
+    }
+}
+
+impl Guac {
+    /// Returns what the counterparty paid us since `cursor`, and the cursor to read from next
+    /// time. Unlike `check_accrual`, this does not reset anything.
+    ///
+    /// The counters are only kept in memory, so a cursor read before the node restarted is
+    /// refused with `ProtocolError::CursorAhead` rather than read as nothing paid.
+    pub async fn accrual_since(
+        &self,
+        their_address: Address,
+        cursor: &AccrualCursor,
+    ) -> Result<(Uint256, AccrualCursor), GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+
+        let received = self.billing.account(&their_address).received;
+        let accrual =
+            received
+                .checked_sub(&cursor.received)
+                .ok_or_else(|| ProtocolError::CursorAhead {
+                    received: received.clone(),
+                })?;
+        Ok((accrual, AccrualCursor { received }))
+    }
+
+    /// Records that everything up to `cursor` has been billed. Cursors older than the one
+    /// acknowledged last are ignored.
+    pub async fn ack(
+        &self,
+        their_address: Address,
+        cursor: AccrualCursor,
+    ) -> Result<(), GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+        self.billing.ack(their_address, cursor);
+        Ok(())
+    }
+
+    /// The cursor acknowledged last, to read from with `accrual_since`
+    pub async fn acked_cursor(&self, their_address: Address) -> Result<AccrualCursor, GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+        Ok(self.billing.account(&their_address).acked)
+    }
+
+    /// Adds `amount` to what the counterparty owes for what it used, and returns how much of
+    /// that it has not paid for yet. A `DebtLimitExceeded` event is emitted for as long as the
+    /// debt is over the limit.
+    pub async fn add_usage(
+        &self,
+        their_address: Address,
+        amount: Uint256,
+    ) -> Result<Uint256, GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+
+        let (debt, over_limit) = self.billing.add_usage(their_address, amount);
+        if over_limit {
+            log::info!("{} owes {}, which is over its limit", their_address, debt);
+            self.events.emit(GuacEvent::DebtLimitExceeded {
+                counterparty: their_address,
+                debt: debt.clone(),
+            });
+        }
+        Ok(debt)
+    }
+
+    /// What the counterparty used and has not paid for yet
+    pub async fn debt(&self, their_address: Address) -> Result<Uint256, GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+        Ok(self.billing.account(&their_address).debt())
+    }
+
+    /// What the counterparty paid for in advance and has not used yet
+    pub async fn credit(&self, their_address: Address) -> Result<Uint256, GuacError> {
+        check_for_counterparty(&self.storage, their_address)?;
+        Ok(self.billing.account(&their_address).credit())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::make_pair;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_cursors() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+
+            let cursor = guac_1.acked_cursor(node_0.address).await.unwrap();
+            assert_eq!(cursor, AccrualCursor::default());
+            let (accrual, next) = guac_1.accrual_since(node_0.address, &cursor).await.unwrap();
+            assert_eq!(accrual, 10u64.into());
+            guac_1.ack(node_0.address, next.clone()).await.unwrap();
+
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 5u64.into())
+                .await
+                .unwrap();
+
+            // Reading again, as after a crash before the ack, gets the same payments
+            let cursor = guac_1.acked_cursor(node_0.address).await.unwrap();
+            assert_eq!(cursor, next);
+            for _ in 0..2 {
+                let (accrual, _) = guac_1.accrual_since(node_0.address, &cursor).await.unwrap();
+                assert_eq!(accrual, 5u64.into());
+            }
+
+            // An older cursor does not move the acknowledged one back
+            guac_1
+                .ack(node_0.address, AccrualCursor::default())
+                .await
+                .unwrap();
+            assert_eq!(guac_1.acked_cursor(node_0.address).await.unwrap(), next);
+
+            // Both are independent of check_accrual
+            assert_eq!(
+                guac_1.check_accrual(node_0.address).await.unwrap(),
+                15u64.into()
+            );
+            let (accrual, _) = guac_1
+                .accrual_since(node_0.address, &AccrualCursor::default())
+                .await
+                .unwrap();
+            assert_eq!(accrual, 15u64.into());
+
+            // A cursor from before a restart is ahead of the counters, which start from zero
+            let (_, cursor) = guac_1
+                .accrual_since(node_0.address, &AccrualCursor::default())
+                .await
+                .unwrap();
+            let restarted = make_pair().1;
+            restarted
+                .guac
+                .storage
+                .new_counterparty(
+                    node_0.address,
+                    guac_1.get_state(node_0.address).await.unwrap(),
+                )
+                .unwrap();
+            match restarted.guac.accrual_since(node_0.address, &cursor).await {
+                Err(GuacError::Protocol(ProtocolError::CursorAhead { received })) => {
+                    assert_eq!(received, 0u64.into())
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+        });
+    }
+
+    #[test]
+    fn test_debt() {
+        let (node_0, mut node_1) = make_pair();
+        node_1.guac.billing = Arc::new(Box::new(Billing::new().with_debt_limit(20u64.into())));
+        node_1.network.register(&node_1.url, node_1.guac.clone());
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+        let mut events_1 = guac_1.subscribe();
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            // Paying afterwards
+            assert_eq!(
+                guac_1
+                    .add_usage(node_0.address, 15u64.into())
+                    .await
+                    .unwrap(),
+                15u64.into()
+            );
+            assert_eq!(
+                guac_1
+                    .add_usage(node_0.address, 15u64.into())
+                    .await
+                    .unwrap(),
+                30u64.into()
+            );
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 25u64.into())
+                .await
+                .unwrap();
+            assert_eq!(guac_1.debt(node_0.address).await.unwrap(), 5u64.into());
+
+            // Paying in advance
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 20u64.into())
+                .await
+                .unwrap();
+            assert_eq!(guac_1.debt(node_0.address).await.unwrap(), 0u64.into());
+            assert_eq!(guac_1.credit(node_0.address).await.unwrap(), 15u64.into());
+        });
+
+        let exceeded: Vec<GuacEvent> = std::iter::from_fn(|| events_1.try_recv().ok())
+            .filter(|event| matches!(event, GuacEvent::DebtLimitExceeded { .. }))
+            .collect();
+        assert_eq!(
+            exceeded,
+            vec![GuacEvent::DebtLimitExceeded {
+                counterparty: node_0.address,
+                debt: 30u64.into(),
+            }]
+        );
+    }
+}
@@ -21,11 +21,12 @@
 // Packet loss in direction Bob -> Alice will only ever cause B(s) to be lower than A(s), since
 // Bob has decreased B(s) without Alice decreasing A(s).
 
-use failure::Error;
 use num256::Uint256;
 
-use crate::types::{GuacError, UpdateTx};
+use crate::error::ProtocolError;
+use crate::types::{Hashlock, ReDrawTx, UpdateTx};
 use num::traits::ops::checked::CheckedSub;
+use std::cmp::max;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct Channel {
@@ -35,6 +36,10 @@ pub struct Channel {
     pub balance_1: Uint256,
     pub accrual: Uint256,
     pub i_am_0: bool,
+    /// Pending conditional payments, see `Hashlock`
+    pub hashlocks: Vec<Hashlock>,
+    /// The latest update signed by both sides, see `reconcile`
+    pub signed_update: Option<UpdateTx>,
 }
 
 impl Channel {
@@ -44,7 +49,7 @@ impl Channel {
         &mut self,
         amount: Uint256,
         current_seq: Option<Uint256>,
-    ) -> Result<UpdateTx, Error> {
+    ) -> Result<UpdateTx, ProtocolError> {
         let sequence_number = if let Some(seq) = current_seq {
             seq + 1u64.into()
         } else {
@@ -57,11 +62,12 @@ impl Channel {
             (self.balance_1.clone(), self.balance_0.clone())
         };
 
-        let my_balance = my_balance
-            .checked_sub(&amount)
-            .ok_or_else(|| GuacError::NotEnough {
-                stuff: "money in channel.".to_string(),
-            })?;
+        let my_balance =
+            my_balance
+                .checked_sub(&amount)
+                .ok_or_else(|| ProtocolError::NotEnough {
+                    stuff: "money in channel.".to_string(),
+                })?;
 
         let their_balance = their_balance + amount;
 
@@ -71,20 +77,11 @@ impl Channel {
             (their_balance, my_balance)
         };
 
-        self.balance_0 = balance_0.clone();
-        self.balance_1 = balance_1.clone();
-        self.sequence_number = sequence_number.clone();
+        self.balance_0 = balance_0;
+        self.balance_1 = balance_1;
+        self.sequence_number = sequence_number;
 
-        let update_tx = UpdateTx {
-            channel_id: self.channel_id,
-            sequence_number,
-            balance_0,
-            balance_1,
-            signature_0: None,
-            signature_1: None,
-        };
-
-        Ok(update_tx)
+        Ok(self.update_tx())
     }
 
     /// This checks the validity of a payment update (note that it does not verify signatures).
@@ -93,7 +90,27 @@ impl Channel {
     /// payment. A successfully accepted payment results in a return value of Ok(None)
     /// This also adjusts `accrual` to measure how much the counterparty has paid us.
     /// Lost packets can result in an incorrect value of `accrual`.
-    pub fn receive_payment(&mut self, update_tx: &UpdateTx) -> Result<Option<Uint256>, Error> {
+    ///
+    /// The counterparty cannot take back a hashlock it pays this way, see `receive_update`.
+    pub fn receive_payment(
+        &mut self,
+        update_tx: &UpdateTx,
+    ) -> Result<Option<Uint256>, ProtocolError> {
+        self.receive_update(update_tx, None)
+    }
+
+    /// This works like `receive_payment`, and also lets the counterparty take back the
+    /// hashlocks it pays which have expired at `current_block`.
+    ///
+    /// Besides paying us, an update can add hashlocks paid by the counterparty, fulfill them,
+    /// and give back hashlocks paid by us. Any hashlock which leaves the channel has to end up
+    /// in our balance, unless it was paid by the counterparty and has expired. Money which
+    /// comes back to us from our own hashlocks does not count as a payment in `accrual`.
+    pub fn receive_update(
+        &mut self,
+        update_tx: &UpdateTx,
+        current_block: Option<&Uint256>,
+    ) -> Result<Option<Uint256>, ProtocolError> {
         if update_tx.sequence_number <= self.sequence_number {
             return Ok(Some(self.sequence_number.clone()));
         };
@@ -110,30 +127,289 @@ impl Channel {
             (update_tx.balance_1.clone(), update_tx.balance_0.clone())
         };
 
-        if (my_old_balance.clone() + their_old_balance.clone())
-            != (my_balance.clone() + their_balance.clone())
+        if (my_old_balance.clone() + their_old_balance.clone() + locked(&self.hashlocks))
+            != (my_balance.clone() + their_balance.clone() + locked(&update_tx.hashlocks))
         {
-            return Err(GuacError::Forbidden {
+            return Err(ProtocolError::Forbidden {
                 message: "Total amount in channel does not stay the same".into(),
+            });
+        }
+
+        for (i, hashlock) in update_tx.hashlocks.iter().enumerate() {
+            if hashlock.from_0 == self.i_am_0 && !self.hashlocks.contains(hashlock) {
+                return Err(ProtocolError::Forbidden {
+                    message: "This locks my money".into(),
+                });
+            }
+            if update_tx.hashlocks[..i]
+                .iter()
+                .any(|other| other.hash == hashlock.hash)
+            {
+                return Err(ProtocolError::Forbidden {
+                    message: "Hashlocks must have different hashes".into(),
+                });
             }
-            .into());
         }
 
-        if my_balance.clone() < my_old_balance.clone() {
-            return Err(GuacError::Forbidden {
-                message: "This reduces my balance".into(),
+        // What we have to end up with at least, before any payment
+        let mut returned: Uint256 = 0u64.into();
+        let mut fulfilled: Uint256 = 0u64.into();
+        for hashlock in &self.hashlocks {
+            if update_tx.hashlocks.contains(hashlock) {
+                continue;
+            }
+            if hashlock.from_0 == self.i_am_0 {
+                returned += hashlock.amount.clone();
+            } else if current_block.is_none_or(|block| *block < hashlock.expiration) {
+                fulfilled += hashlock.amount.clone();
             }
-            .into());
+        }
+
+        if my_balance < my_old_balance.clone() + returned.clone() + fulfilled {
+            return Err(ProtocolError::Forbidden {
+                message: "This reduces my balance".into(),
+            });
         }
 
         self.balance_0 = update_tx.balance_0.clone();
         self.balance_1 = update_tx.balance_1.clone();
+        self.hashlocks = update_tx.hashlocks.clone();
         self.sequence_number = update_tx.sequence_number.clone();
-        self.accrual = self.accrual.clone() + (my_balance - my_old_balance);
+        self.accrual = self.accrual.clone() + (my_balance - my_old_balance - returned);
 
         Ok(None)
     }
 
+    /// This prepares an UpdateTx which moves `amount` from our balance into a new hashlock,
+    /// although it does not sign it. The counterparty gets the money once it shows us the
+    /// preimage of `hash`, until block `expiration`.
+    pub fn add_hashlock(
+        &mut self,
+        hash: [u8; 32],
+        amount: Uint256,
+        expiration: Uint256,
+    ) -> Result<UpdateTx, ProtocolError> {
+        if self.hashlocks.iter().any(|hashlock| hashlock.hash == hash) {
+            return Err(ProtocolError::Forbidden {
+                message: "Hashlocks must have different hashes".into(),
+            });
+        }
+
+        let my_balance =
+            self.my_balance()
+                .checked_sub(&amount)
+                .ok_or_else(|| ProtocolError::NotEnough {
+                    stuff: "money in channel.".to_string(),
+                })?;
+
+        if self.i_am_0 {
+            self.balance_0 = my_balance;
+        } else {
+            self.balance_1 = my_balance;
+        }
+        self.hashlocks.push(Hashlock {
+            hash,
+            amount,
+            expiration,
+            from_0: self.i_am_0,
+        });
+        self.sequence_number = self.sequence_number.clone() + 1u64.into();
+
+        Ok(self.update_tx())
+    }
+
+    /// This prepares an UpdateTx which pays out the hashlock we pay that `preimage` unlocks,
+    /// once the counterparty has shown it to us. It returns the hashlock along with it.
+    pub fn fulfill_hashlock(
+        &mut self,
+        preimage: &[u8; 32],
+    ) -> Result<(Hashlock, UpdateTx), ProtocolError> {
+        let hashlock = self.take_hashlock(&Hashlock::hash_of(preimage), self.i_am_0)?;
+
+        self.credit(!self.i_am_0, hashlock.amount.clone());
+        self.sequence_number = self.sequence_number.clone() + 1u64.into();
+
+        Ok((hashlock, self.update_tx()))
+    }
+
+    /// This prepares an UpdateTx which gives a hashlock the counterparty pays back to them,
+    /// for instance because we cannot route the payment any further.
+    pub fn cancel_hashlock(&mut self, hash: &[u8; 32]) -> Result<UpdateTx, ProtocolError> {
+        let hashlock = self.take_hashlock(hash, !self.i_am_0)?;
+
+        self.credit(!self.i_am_0, hashlock.amount);
+        self.sequence_number = self.sequence_number.clone() + 1u64.into();
+
+        Ok(self.update_tx())
+    }
+
+    /// This prepares an UpdateTx which takes back the money of the hashlocks we pay which have
+    /// expired at `current_block`. Returns None if there are none.
+    pub fn expire_hashlocks(&mut self, current_block: &Uint256) -> Option<UpdateTx> {
+        let i_am_0 = self.i_am_0;
+        let (expired, pending): (Vec<Hashlock>, Vec<Hashlock>) =
+            self.hashlocks.drain(..).partition(|hashlock| {
+                hashlock.from_0 == i_am_0 && *current_block >= hashlock.expiration
+            });
+        self.hashlocks = pending;
+
+        if expired.is_empty() {
+            return None;
+        }
+
+        self.credit(i_am_0, locked(&expired));
+        self.sequence_number = self.sequence_number.clone() + 1u64.into();
+
+        Some(self.update_tx())
+    }
+
+    /// Removes the hashlock for `hash` which is paid by side 0 if `from_0` is set
+    fn take_hashlock(&mut self, hash: &[u8; 32], from_0: bool) -> Result<Hashlock, ProtocolError> {
+        match self
+            .hashlocks
+            .iter()
+            .position(|hashlock| hashlock.hash == *hash && hashlock.from_0 == from_0)
+        {
+            Some(i) => Ok(self.hashlocks.remove(i)),
+            None => Err(ProtocolError::Forbidden {
+                message: "There is no such hashlock".into(),
+            }),
+        }
+    }
+
+    /// Adds `amount` to balance_0 if `to_0` is set, to balance_1 otherwise
+    fn credit(&mut self, to_0: bool, amount: Uint256) {
+        let balance = if to_0 {
+            &mut self.balance_0
+        } else {
+            &mut self.balance_1
+        };
+        *balance = balance.clone() + amount;
+    }
+
+    /// The current state of the channel as an UpdateTx, without signatures
+    fn update_tx(&self) -> UpdateTx {
+        UpdateTx {
+            channel_id: self.channel_id,
+            sequence_number: self.sequence_number.clone(),
+            balance_0: self.balance_0.clone(),
+            balance_1: self.balance_1.clone(),
+            hashlocks: self.hashlocks.clone(),
+            signature_0: None,
+            signature_1: None,
+        }
+    }
+
+    /// This prepares a payment while a reDraw of the channel is pending. It works like
+    /// `make_payment`, except that the sequence number is raised above the one of the reDraw,
+    /// and that money which the reDraw takes out of our balance cannot be spent.
+    pub fn make_payment_during_re_draw(
+        &mut self,
+        amount: Uint256,
+        current_seq: Option<Uint256>,
+        re_draw_tx: &ReDrawTx,
+    ) -> Result<UpdateTx, ProtocolError> {
+        let current_seq = max(
+            current_seq.unwrap_or_else(|| self.sequence_number.clone()),
+            re_draw_tx.sequence_number.clone(),
+        );
+
+        let mut channel = self.clone();
+        let update_tx = channel.make_payment(amount, Some(current_seq))?;
+
+        channel
+            .re_drawn(re_draw_tx)
+            .map_err(|_| ProtocolError::NotEnough {
+                stuff: "money in channel after the pending redraw.".to_string(),
+            })?;
+
+        *self = channel;
+        Ok(update_tx)
+    }
+
+    /// This checks and applies a payment which was received while a reDraw of the channel is
+    /// pending. Like in `receive_payment`, a payment with a sequence number which is too low
+    /// returns the sequence number to retry from, which is never below the one of the reDraw.
+    pub fn receive_payment_during_re_draw(
+        &mut self,
+        update_tx: &UpdateTx,
+        re_draw_tx: &ReDrawTx,
+    ) -> Result<Option<Uint256>, ProtocolError> {
+        if update_tx.sequence_number <= re_draw_tx.sequence_number {
+            return Ok(Some(max(
+                self.sequence_number.clone(),
+                re_draw_tx.sequence_number.clone(),
+            )));
+        }
+
+        if !update_tx.hashlocks.is_empty() {
+            return Err(ProtocolError::Forbidden {
+                message: "No hashlocks while a redraw is pending".into(),
+            });
+        }
+
+        let mut channel = self.clone();
+        if let Some(current_seq) = channel.receive_payment(update_tx)? {
+            return Ok(Some(current_seq));
+        }
+
+        channel
+            .re_drawn(re_draw_tx)
+            .map_err(|_| ProtocolError::Forbidden {
+                message: "This spends money taken out by the pending redraw".into(),
+            })?;
+
+        *self = channel;
+        Ok(None)
+    }
+
+    /// This returns the channel as it is once a reDraw has made it to the chain. Payments
+    /// which were made while the reDraw was pending are kept on top of it: each balance moves
+    /// by as much as the reDraw moves it, and the sequence number is at least the one of the
+    /// reDraw. Fails if that leaves a balance below zero.
+    ///
+    /// Updates signed while the reDraw was pending still add up to the old total, so they have
+    /// to be signed again with `reconcile`.
+    pub fn re_drawn(&self, re_draw_tx: &ReDrawTx) -> Result<Channel, ProtocolError> {
+        let balance = |balance: &Uint256, old_balance: &Uint256, new_balance: &Uint256| {
+            (balance.clone() + new_balance.clone())
+                .checked_sub(old_balance)
+                .ok_or_else(|| ProtocolError::NotEnough {
+                    stuff: "money in channel after redraw.".to_string(),
+                })
+        };
+
+        Ok(Channel {
+            balance_0: balance(
+                &self.balance_0,
+                &re_draw_tx.old_balance_0,
+                &re_draw_tx.new_balance_0,
+            )?,
+            balance_1: balance(
+                &self.balance_1,
+                &re_draw_tx.old_balance_1,
+                &re_draw_tx.new_balance_1,
+            )?,
+            sequence_number: max(
+                self.sequence_number.clone(),
+                re_draw_tx.sequence_number.clone(),
+            ),
+            ..self.clone()
+        })
+    }
+
+    /// This prepares an UpdateTx for the channel as it is, at the next sequence number, although
+    /// it does not sign it. Once a reDraw is on the chain, this is the update both sides sign
+    /// for the payments made while it was pending, since the contract refuses those at the new
+    /// total. Returns None if no payment was made in the meantime.
+    pub fn reconcile(&mut self, re_draw_tx: &ReDrawTx) -> Option<UpdateTx> {
+        if self.sequence_number <= re_draw_tx.sequence_number {
+            return None;
+        }
+        self.sequence_number += 1u64.into();
+        Some(self.update_tx())
+    }
+
     /// This checks the accrual. Accrual is a counter of all the payments that we have received
     /// from the counterparty. Packet loss of payments from us to the counterparty can result in
     /// this returning an innacurate value. If Alice tries to pay Bob 5, but the payment gets lost,
@@ -154,6 +430,24 @@ impl Channel {
 
         accrual
     }
+
+    /// Our side of the channel
+    pub fn my_balance(&self) -> Uint256 {
+        if self.i_am_0 {
+            self.balance_0.clone()
+        } else {
+            self.balance_1.clone()
+        }
+    }
+}
+
+/// The money held by `hashlocks`
+fn locked(hashlocks: &[Hashlock]) -> Uint256 {
+    hashlocks
+        .iter()
+        .fold(0u64.into(), |sum: Uint256, hashlock| {
+            sum + hashlock.amount.clone()
+        })
 }
 
 #[cfg(test)]
@@ -167,6 +461,8 @@ mod tests {
             balance_0: 0u64.into(),
             balance_1: 0u64.into(),
             accrual: 0u64.into(),
+            hashlocks: Vec::new(),
+            signed_update: None,
             i_am_0: false,
         }
     }
@@ -341,4 +637,257 @@ mod tests {
             "check b"
         );
     }
+
+    fn re_draw(old: (u64, u64), new: (u64, u64)) -> ReDrawTx {
+        ReDrawTx {
+            channel_id: [0; 32],
+            sequence_number: 1u64.into(),
+            old_balance_0: old.0.into(),
+            old_balance_1: old.1.into(),
+            new_balance_0: new.0.into(),
+            new_balance_1: new.1.into(),
+            expiration: 0u64.into(),
+            signature_0: None,
+            signature_1: None,
+        }
+    }
+
+    /// A deposits while both sides keep paying each other
+    #[test]
+    fn test_payments_during_re_draw() {
+        let mut a = Channel {
+            i_am_0: true,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let mut b = Channel {
+            i_am_0: false,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let re_draw_tx = re_draw((100, 100), (150, 100));
+
+        let update = a
+            .make_payment_during_re_draw(10u64.into(), None, &re_draw_tx)
+            .unwrap();
+        assert_eq!(update.sequence_number, 2u64.into());
+        assert_eq!(
+            b.receive_payment_during_re_draw(&update, &re_draw_tx)
+                .unwrap(),
+            None
+        );
+
+        let update = b
+            .make_payment_during_re_draw(5u64.into(), None, &re_draw_tx)
+            .unwrap();
+        assert_eq!(
+            a.receive_payment_during_re_draw(&update, &re_draw_tx)
+                .unwrap(),
+            None
+        );
+
+        let a = a.re_drawn(&re_draw_tx).unwrap();
+        let b = b.re_drawn(&re_draw_tx).unwrap();
+        assert_eq!(
+            a,
+            Channel {
+                i_am_0: true,
+                balance_0: 145u64.into(),
+                balance_1: 105u64.into(),
+                sequence_number: 3u64.into(),
+                accrual: 5u64.into(),
+                ..default_channel()
+            },
+            "check a"
+        );
+        assert_eq!(
+            b,
+            Channel {
+                i_am_0: false,
+                accrual: 10u64.into(),
+                ..a
+            },
+            "check b"
+        );
+    }
+
+    /// B pays A as if there was no reDraw, A has B retry above the reDraw's sequence number
+    #[test]
+    fn test_payment_during_re_draw_too_old() {
+        let mut a = Channel {
+            i_am_0: true,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let mut b = Channel {
+            i_am_0: false,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let re_draw_tx = re_draw((100, 100), (150, 100));
+
+        let update = b.make_payment(5u64.into(), None).unwrap();
+        let current_seq = a
+            .receive_payment_during_re_draw(&update, &re_draw_tx)
+            .unwrap()
+            .unwrap();
+        assert_eq!(current_seq, 1u64.into());
+        assert_eq!(a.balance_0, 100u64.into());
+
+        let update = b.make_payment(0u64.into(), Some(current_seq)).unwrap();
+        assert_eq!(
+            a.receive_payment_during_re_draw(&update, &re_draw_tx)
+                .unwrap(),
+            None
+        );
+        assert_eq!(a.re_drawn(&re_draw_tx).unwrap().balance_0, 155u64.into());
+    }
+
+    /// Money that is being withdrawn cannot be paid out in the meantime
+    #[test]
+    fn test_payment_during_withdraw() {
+        let mut a = Channel {
+            i_am_0: true,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let mut b = a.clone();
+        b.i_am_0 = false;
+        let re_draw_tx = re_draw((100, 100), (20, 100));
+
+        let mut overdrawn = a.clone();
+        let update = overdrawn
+            .make_payment(30u64.into(), Some(1u64.into()))
+            .unwrap();
+        match b.receive_payment_during_re_draw(&update, &re_draw_tx) {
+            Err(ProtocolError::Forbidden { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(b.balance_1, 100u64.into());
+
+        match a.make_payment_during_re_draw(30u64.into(), None, &re_draw_tx) {
+            Err(ProtocolError::NotEnough { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(a.balance_0, 100u64.into());
+
+        let update = a
+            .make_payment_during_re_draw(20u64.into(), None, &re_draw_tx)
+            .unwrap();
+        b.receive_payment_during_re_draw(&update, &re_draw_tx)
+            .unwrap();
+        assert_eq!(a.re_drawn(&re_draw_tx).unwrap().balance_0, 0u64.into());
+        assert_eq!(b.re_drawn(&re_draw_tx).unwrap().balance_1, 120u64.into());
+    }
+
+    fn hashlock_pair() -> (Channel, Channel) {
+        let a = Channel {
+            i_am_0: true,
+            balance_0: 100u64.into(),
+            balance_1: 100u64.into(),
+            ..default_channel()
+        };
+        let b = Channel {
+            i_am_0: false,
+            ..a.clone()
+        };
+        (a, b)
+    }
+
+    #[test]
+    fn test_hashlock_fulfilled() {
+        let (mut a, mut b) = hashlock_pair();
+        let preimage = [7; 32];
+
+        let update = a
+            .add_hashlock(Hashlock::hash_of(&preimage), 30u64.into(), 10u64.into())
+            .unwrap();
+        b.receive_payment(&update).unwrap();
+        assert_eq!(b.hashlocks, a.hashlocks);
+        assert_eq!(b.balance_0, 70u64.into());
+        assert_eq!(b.check_accrual(), 0u64.into());
+
+        match a.fulfill_hashlock(&[8; 32]) {
+            Err(ProtocolError::Forbidden { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let (hashlock, update) = a.fulfill_hashlock(&preimage).unwrap();
+        assert_eq!(hashlock.amount, 30u64.into());
+        b.receive_payment(&update).unwrap();
+        assert_eq!(
+            b,
+            Channel {
+                i_am_0: false,
+                balance_0: 70u64.into(),
+                balance_1: 130u64.into(),
+                sequence_number: 2u64.into(),
+                accrual: 30u64.into(),
+                ..default_channel()
+            }
+        );
+    }
+
+    #[test]
+    fn test_hashlock_cancelled() {
+        let (mut a, mut b) = hashlock_pair();
+        let hash = Hashlock::hash_of(&[7; 32]);
+
+        let update = a.add_hashlock(hash, 30u64.into(), 10u64.into()).unwrap();
+        b.receive_payment(&update).unwrap();
+
+        // Only the payee can give it back
+        assert!(a.cancel_hashlock(&hash).is_err());
+        let update = b.cancel_hashlock(&hash).unwrap();
+        a.receive_payment(&update).unwrap();
+        assert_eq!(a.balance_0, 100u64.into());
+        assert!(a.hashlocks.is_empty());
+        assert_eq!(a.check_accrual(), 0u64.into());
+    }
+
+    #[test]
+    fn test_hashlock_expired() {
+        let (mut a, mut b) = hashlock_pair();
+
+        let update = a
+            .add_hashlock(Hashlock::hash_of(&[7; 32]), 30u64.into(), 10u64.into())
+            .unwrap();
+        b.receive_payment(&update).unwrap();
+
+        assert_eq!(a.expire_hashlocks(&9u64.into()), None);
+        let update = a.expire_hashlocks(&10u64.into()).unwrap();
+        assert_eq!(a.balance_0, 100u64.into());
+
+        // The payee has to know that the hashlock expired
+        let mut early = b.clone();
+        match early.receive_update(&update, Some(&9u64.into())) {
+            Err(ProtocolError::Forbidden { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(b.receive_payment(&update).is_err());
+        b.receive_update(&update, Some(&10u64.into())).unwrap();
+        assert_eq!(b.balance_0, 100u64.into());
+        assert!(b.hashlocks.is_empty());
+    }
+
+    #[test]
+    fn test_hashlock_cannot_lock_my_money() {
+        let (mut a, mut b) = hashlock_pair();
+
+        let mut update = a
+            .add_hashlock(Hashlock::hash_of(&[7; 32]), 30u64.into(), 10u64.into())
+            .unwrap();
+        update.hashlocks[0].from_0 = false;
+        update.balance_0 = 100u64.into();
+        update.balance_1 = 70u64.into();
+        match b.receive_payment(&update) {
+            Err(ProtocolError::Forbidden { .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
 }
@@ -1,660 +1,1705 @@
+use crate::billing::Billing;
 use crate::channel::Channel;
 use crate::crypto::Crypto;
+use crate::error::{BlockchainError, GuacError, ProtocolError, StorageError};
+use crate::events::{EventStream, Events, GuacEvent};
+use crate::ledger::{Direction, Ledger, LedgerEntry};
+use crate::metrics::Metrics;
+use crate::policy::ProposalPolicy;
+use crate::receipt::Receipt;
+use crate::refill::RefillManager;
+use crate::routing::Router;
+use crate::state_machine::{transition, Event};
 use crate::storage::Storage;
-use crate::types::{Counterparty, GuacError, NewChannelTx, ReDrawTx};
+use crate::types::{ChannelState, Counterparty, NewChannelTx, ReDrawTx, UpdateResponse, UpdateTx};
 use crate::CounterpartyApi;
-use clarity::Address;
-use failure::Error;
-use futures::{future, Future};
+use async_trait::async_trait;
+use clarity::{Address, Signature};
+use num::traits::ops::checked::CheckedSub;
 use num256::Uint256;
-use qutex::Guard;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
 
 /// Todo:
 /// - Integrate sig verification
 /// - Get to the bottom of balance discrepancies in tests
 /// - Implement expiration timer in state machine
-/// - Get rid of useless "register counterparty" step
 /// - Deal with incorrect accrual in packet loss scenario
 
-macro_rules! try_future_box {
-    ($expression:expr) => {
-        match $expression {
-            Err(err) => {
-                return Box::new(future::err(err.into())) as Box<Future<Item = _, Error = Error>>;
-            }
-            Ok(value) => value,
-        }
-    };
-}
-
 #[derive(Clone)]
 pub struct Guac {
-    pub blockchain_client: Arc<Box<BlockchainApi + Send + Sync>>,
-    pub counterparty_client: Arc<Box<CounterpartyApi + Send + Sync>>,
+    pub blockchain_client: Arc<Box<dyn BlockchainApi + Send + Sync>>,
+    pub counterparty_client: Arc<Box<dyn CounterpartyApi + Send + Sync>>,
     pub storage: Arc<Box<Storage>>,
     pub crypto: Arc<Box<Crypto>>,
+    /// Decides which proposals from counterparties we sign
+    pub proposal_policy: Arc<Box<dyn ProposalPolicy + Send + Sync>>,
+    /// Everything that happens to our channels, see `subscribe`
+    pub events: Arc<Box<Events>>,
+    /// Every payment sent and received
+    pub ledger: Arc<Box<Ledger>>,
+    /// What counterparties paid and owe us, see `accrual_since` and `add_usage`
+    pub billing: Arc<Box<Billing>>,
+    /// Pays, forwards and receives payments over routes of several channels
+    pub router: Arc<Box<Router>>,
+    /// Refills and withdraws from channels according to their thresholds
+    pub refill: Arc<Box<RefillManager>>,
+    /// Counts what the node does, see `render_metrics`
+    pub metrics: Arc<Box<Metrics>>,
+    /// Channels which `audit` found settling, so that each is reported only once
+    pub settling: Arc<Mutex<HashSet<[u8; 32]>>>,
 }
 
+#[async_trait(?Send)]
 pub trait BlockchainApi {
-    fn balance_of(&self) -> Box<Future<Item = Uint256, Error = Error>>;
+    async fn balance_of(&self) -> Result<Uint256, BlockchainError>;
 
-    fn check_for_open(
+    async fn check_for_open(
         &self,
         address_0: &Address,
         address_1: &Address,
-    ) -> Box<Future<Item = Option<[u8; 32]>, Error = Error>>;
+    ) -> Result<Option<[u8; 32]>, BlockchainError>;
 
-    fn check_for_re_draw(&self, channel_id: [u8; 32]) -> Box<Future<Item = (), Error = Error>>;
+    async fn check_for_re_draw(&self, channel_id: [u8; 32]) -> Result<(), BlockchainError>;
 
-    fn quick_deposit(&self, value: Uint256) -> Box<Future<Item = (), Error = Error>>;
+    async fn quick_deposit(&self, value: Uint256) -> Result<(), BlockchainError>;
 
-    fn get_current_block(&self) -> Box<Future<Item = Uint256, Error = Error>>;
+    async fn get_current_block(&self) -> Result<Uint256, BlockchainError>;
 
-    fn deposit_then_new_channel(
+    /// Reads the state of a channel as it is currently stored by the contract
+    async fn get_channel(&self, channel_id: [u8; 32]) -> Result<ChannelState, BlockchainError>;
+
+    async fn deposit_then_new_channel(
         &self,
         amount: Uint256,
         new_channel_tx: NewChannelTx,
-    ) -> Box<Future<Item = [u8; 32], Error = Error>>;
+    ) -> Result<[u8; 32], BlockchainError>;
 
-    fn deposit_then_re_draw(
+    async fn deposit_then_re_draw(
         &self,
         amount: Uint256,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = (), Error = Error>>;
+    ) -> Result<(), BlockchainError>;
 
-    fn re_draw_then_withdraw(
+    async fn re_draw_then_withdraw(
         &self,
         amount: Uint256,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = (), Error = Error>>;
+    ) -> Result<(), BlockchainError>;
 }
 
-/// This will create an error if a counterparty cannot be found, or return the counterparty.Meant to
-/// be used inside a futures chain.
+/// This will create an error if a counterparty cannot be found, or return the counterparty.
 pub fn check_for_counterparty(
-    counterparty: Option<Guard<Counterparty>>,
-) -> Result<Guard<Counterparty>, Error> {
-    let counterparty = counterparty.ok_or(GuacError::Error {
-        message: "Cannot find counterparty".into(),
-    })?;
-    Ok(counterparty)
+    storage: &Storage,
+    their_address: Address,
+) -> Result<Counterparty, StorageError> {
+    storage
+        .get_counterparty(their_address)
+        .ok_or(StorageError::CounterpartyNotFound(their_address))
 }
 
 /// This will create a counterparty if one cannot be found. Either way, it will return the
-/// counterparty. Meant to be used in a futures chain.
+/// counterparty.
 pub fn make_counterparty_if_none(
+    storage: &Storage,
     their_address: Address,
     my_address: Address,
-    storage: Arc<Box<Storage>>,
-) -> impl FnOnce(Option<Guard<Counterparty>>) -> Box<Future<Item = Guard<Counterparty>, Error = Error>>
-{
-    move |counterparty| match counterparty {
-        Some(counterparty) => Box::new(future::ok(counterparty)),
-        None => Box::new(
-            storage
-                .new_counterparty(
-                    their_address.clone(),
-                    Counterparty::New {
-                        i_am_0: my_address < their_address,
-                    },
-                )
-                .and_then(move |_| {
-                    storage
-                        .get_counterparty(their_address.clone())
-                        .and_then(|counterparty| {
-                            Ok(counterparty.expect("counterparty should have been created"))
-                        })
-                }),
-        ),
+) -> Counterparty {
+    storage.get_or_insert_counterparty(
+        their_address,
+        Counterparty::New {
+            i_am_0: my_address < their_address,
+        },
+    )
+}
+
+/// Like `make_counterparty_if_none`, for a counterparty who contacted us first. It is only
+/// created if the peer rules of the storage allow it.
+pub fn accept_counterparty_if_none(
+    storage: &Storage,
+    their_address: Address,
+    my_address: Address,
+) -> Result<Counterparty, StorageError> {
+    storage.new_peer(
+        their_address,
+        Counterparty::New {
+            i_am_0: my_address < their_address,
+        },
+    )
+}
+
+/// Applies `event` to the state of a counterparty, as long as it is still `current`. Resolves
+/// to the new state.
+pub fn transition_from(
+    storage: &Storage,
+    their_address: Address,
+    current: &Counterparty,
+    event: Event,
+) -> Result<Counterparty, GuacError> {
+    storage.update_counterparty(their_address, |counterparty| {
+        if *counterparty != *current {
+            return Err(StorageError::Conflict(their_address).into());
+        }
+        *counterparty = transition(counterparty.clone(), event)?;
+        Ok(counterparty.clone())
+    })
+}
+
+/// An update of the channel for `Guac::sign_update` to sign, or something which holds one
+pub(crate) trait HoldsUpdate {
+    fn update_tx_mut(&mut self) -> Option<&mut UpdateTx>;
+}
+
+impl HoldsUpdate for UpdateTx {
+    fn update_tx_mut(&mut self) -> Option<&mut UpdateTx> {
+        Some(self)
+    }
+}
+
+impl HoldsUpdate for Option<UpdateTx> {
+    fn update_tx_mut(&mut self) -> Option<&mut UpdateTx> {
+        self.as_mut()
+    }
+}
+
+impl<T> HoldsUpdate for (T, UpdateTx) {
+    fn update_tx_mut(&mut self) -> Option<&mut UpdateTx> {
+        Some(&mut self.1)
+    }
+}
+
+/// The channel with the counterparty, which has to be open to `action`
+pub(crate) fn open_channel_mut<'a>(
+    counterparty: &'a mut Counterparty,
+    action: &str,
+) -> Result<&'a mut Channel, GuacError> {
+    match counterparty {
+        Counterparty::Open { channel } => Ok(channel),
+        counterparty => Err(ProtocolError::WrongState {
+            correct_state: "Open".to_string(),
+            current_state: format!("{:?}", counterparty.clone()),
+            action: action.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// Checks that `signature` was made by `signer` and passes it through
+fn check_signature(
+    signature: Signature,
+    fingerprint: &[u8],
+    signer: Address,
+) -> Result<Signature, GuacError> {
+    match signature.recover(fingerprint) {
+        Ok(recovered_address) if recovered_address == signer => Ok(signature),
+        _ => Err(ProtocolError::BadSignature.into()),
     }
 }
 
 impl Guac {
-    pub fn check_accrual(
+    /// Returns the stream of everything that happens to our channels from now on
+    pub fn subscribe(&self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    /// Lets subscribers know that handling something sent by `their_address` failed
+    pub(crate) fn report_error<T>(
         &self,
         their_address: Address,
-    ) -> impl Future<Item = Uint256, Error = Error> {
-        let storage = self.storage.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(their_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(|mut counterparty| match &mut *counterparty {
-                    Counterparty::Open { channel, .. }
-                    | Counterparty::ReDrawing { channel, .. }
-                    | Counterparty::OtherReDrawing { channel, .. } => {
-                        let accrual = channel.check_accrual();
-                        Ok(accrual)
-                    }
-                    counterparty => {
-                        let error = GuacError::WrongState {
-                            correct_state: "Open".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "check_accrual".to_string(),
-                        };
-                        return Err(error.into());
-                    }
-                }),
-        )
+        res: Result<T, GuacError>,
+    ) -> Result<T, GuacError> {
+        if let Err(e) = &res {
+            self.events.emit(GuacEvent::Error {
+                counterparty: their_address,
+                message: e.to_string(),
+            });
+        }
+        res
+    }
+
+    pub async fn check_accrual(&self, their_address: Address) -> Result<Uint256, GuacError> {
+        self.storage
+            .update_counterparty(their_address, |counterparty| match counterparty {
+                Counterparty::Open { channel, .. }
+                | Counterparty::ReDrawing { channel, .. }
+                | Counterparty::OtherReDrawing { channel, .. } => Ok(channel.check_accrual()),
+                counterparty => Err(ProtocolError::WrongState {
+                    correct_state: "Open".to_string(),
+                    current_state: format!("{:?}", counterparty.clone()),
+                    action: "check_accrual".to_string(),
+                }
+                .into()),
+            })
+    }
+
+    pub async fn check_my_balance(&self, their_address: Address) -> Result<Uint256, GuacError> {
+        match check_for_counterparty(&self.storage, their_address)? {
+            Counterparty::Open { channel, .. }
+            | Counterparty::ReDrawing { channel, .. }
+            | Counterparty::OtherReDrawing { channel, .. } => Ok(if channel.i_am_0 {
+                channel.balance_0.clone()
+            } else {
+                channel.balance_1.clone()
+            }),
+            counterparty => {
+                let error = ProtocolError::WrongState {
+                    correct_state: "Open".to_string(),
+                    current_state: format!("{:?}", counterparty.clone()),
+                    action: "check_accrual".to_string(),
+                };
+                Err(error.into())
+            }
+        }
+    }
+
+    pub async fn get_state(&self, their_address: Address) -> Result<Counterparty, GuacError> {
+        Ok(check_for_counterparty(&self.storage, their_address)?)
     }
 
-    pub fn check_my_balance(
+    pub async fn fill_channel(
         &self,
         their_address: Address,
-    ) -> impl Future<Item = Uint256, Error = Error> {
-        let storage = self.storage.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(their_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(|mut counterparty| match &mut *counterparty {
-                    Counterparty::Open { channel, .. }
-                    | Counterparty::ReDrawing { channel, .. }
-                    | Counterparty::OtherReDrawing { channel, .. } => Ok(if channel.i_am_0 {
-                        channel.balance_0.clone()
-                    } else {
-                        channel.balance_1.clone()
-                    }),
-                    counterparty => {
-                        let error = GuacError::WrongState {
-                            correct_state: "Open".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "check_accrual".to_string(),
-                        };
-                        return Err(error.into());
-                    }
-                }),
-        )
+        their_url: String,
+        amount: Uint256,
+    ) -> Result<(), GuacError> {
+        let my_address = self.crypto.own_address;
+
+        match make_counterparty_if_none(&self.storage, their_address, my_address) {
+            Counterparty::New { i_am_0 } => {
+                self.open_channel(their_address, their_url, amount, i_am_0)
+                    .await
+            }
+            Counterparty::Open { .. } => {
+                let re_draw_tx = self
+                    .start_re_draw(their_address, &their_url, |channel| {
+                        Ok(if channel.i_am_0 {
+                            (
+                                channel.balance_0.clone() + amount.clone(),
+                                channel.balance_1.clone(),
+                            )
+                        } else {
+                            (
+                                channel.balance_0.clone(),
+                                channel.balance_1.clone() + amount.clone(),
+                            )
+                        })
+                    })
+                    .await?;
+
+                self.blockchain_client
+                    .deposit_then_re_draw(amount, re_draw_tx.clone())
+                    .await?;
+
+                self.finish_re_draw(their_address, their_url, &re_draw_tx)
+                    .await
+            }
+            _ => {
+                // Make user wait
+                Err(ProtocolError::TryAgainLater.into())
+            }
+        }
     }
 
-    pub fn get_state(
+    pub async fn withdraw(
         &self,
         their_address: Address,
-    ) -> impl Future<Item = Counterparty, Error = Error> {
-        let storage = self.storage.clone();
+        their_url: String,
+        amount: Uint256,
+    ) -> Result<(), GuacError> {
+        match check_for_counterparty(&self.storage, their_address)? {
+            Counterparty::Open { .. } => {
+                let not_enough = || ProtocolError::NotEnough {
+                    stuff: "money in channel.".to_string(),
+                };
+
+                let re_draw_tx = self
+                    .start_re_draw(their_address, &their_url, |channel| {
+                        Ok(if channel.i_am_0 {
+                            (
+                                channel
+                                    .balance_0
+                                    .checked_sub(&amount)
+                                    .ok_or_else(not_enough)?,
+                                channel.balance_1.clone(),
+                            )
+                        } else {
+                            (
+                                channel.balance_0.clone(),
+                                channel
+                                    .balance_1
+                                    .checked_sub(&amount)
+                                    .ok_or_else(not_enough)?,
+                            )
+                        })
+                    })
+                    .await?;
+
+                self.blockchain_client
+                    .re_draw_then_withdraw(amount, re_draw_tx.clone())
+                    .await?;
 
-        Box::new(
-            storage
-                .get_counterparty(their_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(|counterparty| Ok(counterparty.clone())),
-        )
+                self.finish_re_draw(their_address, their_url, &re_draw_tx)
+                    .await
+            }
+            _ => {
+                // Make user wait
+                Err(ProtocolError::TryAgainLater.into())
+            }
+        }
     }
 
-    pub fn fill_channel(
+    pub async fn make_payment(
         &self,
         their_address: Address,
         their_url: String,
         amount: Uint256,
-    ) -> impl Future<Item = (), Error = Error> {
-        let counterparty_client = self.counterparty_client.clone();
-        let blockchain_client = self.blockchain_client.clone();
-        let storage = self.storage.clone();
-        let crypto = self.crypto.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(their_address)
-                .and_then(make_counterparty_if_none(
-                    their_address,
-                    crypto.own_address,
-                    self.storage.clone(),
-                ))
-                .and_then(move |mut counterparty| {
-                    match counterparty.clone() {
-                        Counterparty::New { i_am_0 } => {
-                            let my_address = crypto.own_address;
-
-                            let (address_0, address_1) = if i_am_0 {
-                                (my_address, their_address)
-                            } else {
-                                (their_address, my_address)
-                            };
-
-                            let (balance_0, balance_1) = if i_am_0 {
-                                (amount.clone(), 0u64.into())
-                            } else {
-                                (0u64.into(), amount.clone())
-                            };
-
-                            Box::new(
-                                blockchain_client
-                                    .get_current_block()
-                                    .and_then(move |block| {
-                                        let new_channel_tx = NewChannelTx {
-                                            address_0: address_0.clone(),
-                                            address_1: address_1.clone(),
-                                            balance_0: balance_0.clone(),
-                                            balance_1: balance_1.clone(),
-                                            expiration: (block + 40u64.into()), // current block plus 10 minutes
-                                            settling_period_length: 5000u64.into(), //TODO: figure out default value
-                                            signature_0: None,
-                                            signature_1: None,
-                                        };
-
-                                        counterparty_client
-                                            .propose_channel(
-                                                my_address,
-                                                their_url.clone(),
-                                                new_channel_tx.clone(),
-                                            )
-                                            .and_then(move |their_signature| {
-                                                let fingerprint = new_channel_tx
-                                                    .clone()
-                                                    .fingerprint(crypto.contract_address);
-
-                                                let recovered_address = try_future_box!(
-                                                    their_signature.recover(&fingerprint)
-                                                );
-
-                                                if recovered_address != their_address {
-                                                    return Box::new(future::err(
-                                                        GuacError::Error {
-                                                            message: "Their signature is incorrect"
-                                                                .into(),
-                                                        }
-                                                        .into(),
-                                                    ));
-                                                }
-
-                                                let my_signature = crypto.eth_sign(
-                                                    &new_channel_tx
-                                                        .clone()
-                                                        .fingerprint(crypto.contract_address),
-                                                );
-
-                                                let (signature_0, signature_1) = if i_am_0 {
-                                                    (my_signature, their_signature)
-                                                } else {
-                                                    (their_signature, my_signature)
-                                                };
-
-                                                *counterparty = Counterparty::Creating {
-                                                    new_channel_tx: new_channel_tx.clone(),
-                                                    i_am_0,
-                                                };
-
-                                                Box::new(
-                                                    blockchain_client
-                                                        .deposit_then_new_channel(
-                                                            amount.clone(),
-                                                            NewChannelTx {
-                                                                signature_0: Some(signature_0),
-                                                                signature_1: Some(signature_1),
-                                                                ..new_channel_tx
-                                                            },
-                                                        )
-                                                        .and_then(move |channel_id| {
-                                                            counterparty_client
-                                                                .notify_channel_opened(
-                                                                    my_address,
-                                                                    their_url.clone(),
-                                                                )
-                                                                .and_then(move |()| {
-                                                                    *counterparty =
-                                                                        Counterparty::Open {
-                                                                            channel: Channel {
-                                                                                channel_id,
-                                                                                sequence_number:
-                                                                                    0u8.into(),
-                                                                                balance_0,
-                                                                                balance_1,
-                                                                                i_am_0,
-                                                                                accrual: 0u8.into(),
-                                                                            },
-                                                                        };
-                                                                    Ok(())
-                                                                })
-                                                        }),
-                                                )
-                                            })
-                                    }),
-                            ) as Box<Future<Item = (), Error = Error>>
-                        }
-                        Counterparty::Open { channel } => {
-                            let balance_0 = channel.balance_0.clone();
-                            let balance_1 = channel.balance_1.clone();
-
-                            let (new_balance_0, new_balance_1) = if channel.i_am_0 {
-                                (balance_0 + amount.clone(), balance_1)
-                            } else {
-                                (balance_0, balance_1 + amount.clone())
-                            };
-
-                            Box::new(
-                                blockchain_client
-                                    .get_current_block()
-                                    .and_then(move |block| {
-                                        let re_draw_tx = ReDrawTx {
-                                            channel_id: channel.channel_id.clone(),
-                                            sequence_number: channel.sequence_number.clone()
-                                                + 1u64.into(),
-                                            old_balance_0: channel.balance_0.clone(),
-                                            old_balance_1: channel.balance_1.clone(),
-                                            new_balance_0: new_balance_0.clone(),
-                                            new_balance_1: new_balance_1.clone(),
-                                            expiration: (block + 40u64.into()), // current block plus 10 minutes
-                                            signature_0: None,
-                                            signature_1: None,
-                                        };
-
-                                        counterparty_client
-                                            .propose_re_draw(
-                                                crypto.own_address,
-                                                their_url.clone(),
-                                                re_draw_tx.clone(),
-                                            )
-                                            .and_then(move |their_signature| {
-                                                let fingerprint = re_draw_tx
-                                                    .clone()
-                                                    .fingerprint(crypto.contract_address);
-
-                                                let recovered_address = try_future_box!(
-                                                    their_signature.recover(&fingerprint)
-                                                );
-
-                                                if recovered_address != their_address {
-                                                    return Box::new(future::err(
-                                                        GuacError::Error {
-                                                            message: "Their signature is incorrect"
-                                                                .into(),
-                                                        }
-                                                        .into(),
-                                                    ));
-                                                }
-
-                                                *counterparty = Counterparty::ReDrawing {
-                                                    channel: channel.clone(),
-                                                    re_draw_tx: re_draw_tx.clone(),
-                                                };
-
-                                                let my_signature = crypto.eth_sign(
-                                                    &re_draw_tx
-                                                        .fingerprint(crypto.contract_address),
-                                                );
-
-                                                let (signature_0, signature_1) =
-                                                    if channel.clone().i_am_0 {
-                                                        (my_signature, their_signature)
-                                                    } else {
-                                                        (their_signature, my_signature)
-                                                    };
-
-                                                Box::new(
-                                                    blockchain_client
-                                                        .deposit_then_re_draw(
-                                                            amount.clone(),
-                                                            ReDrawTx {
-                                                                signature_0: Some(signature_0),
-                                                                signature_1: Some(signature_1),
-                                                                ..re_draw_tx
-                                                            },
-                                                        )
-                                                        .and_then(move |_| {
-                                                            counterparty_client
-                                                                .notify_re_draw(
-                                                                    crypto.own_address,
-                                                                    their_url.clone(),
-                                                                )
-                                                                .and_then(move |_| {
-                                                                    // Save the new open state of the channel
-                                                                    *counterparty =
-                                                                        Counterparty::Open {
-                                                                            channel: Channel {
-                                                                                // TODO: what else changes here?
-                                                                                balance_0:
-                                                                                    new_balance_0
-                                                                                        .clone(),
-                                                                                balance_1:
-                                                                                    new_balance_1
-                                                                                        .clone(),
-                                                                                ..channel
-                                                                            },
-                                                                        };
-                                                                    Ok(())
-                                                                })
-                                                        }),
-                                                )
-                                            })
-                                    }),
-                            ) as Box<Future<Item = (), Error = Error>>
-                        }
-                        _ => {
-                            // Make user wait
-                            return Box::new(future::err(GuacError::TryAgainLater().into()))
-                                as Box<Future<Item = (), Error = Error>>;
-                        }
-                    }
-                }),
-        )
+    ) -> Result<(), GuacError> {
+        let update_tx = self
+            .sign_payment(their_address, amount.clone(), None)
+            .await?;
+        self.deliver_payment(their_address, their_url, amount, update_tx)
+            .await
     }
 
-    pub fn withdraw(
+    /// Sends the update of a payment signed with `sign_payment`, and records the payment once
+    /// the counterparty accepts it
+    pub(crate) async fn deliver_payment(
         &self,
         their_address: Address,
         their_url: String,
         amount: Uint256,
-    ) -> impl Future<Item = (), Error = Error> {
-        let storage = self.storage.clone();
-        let counterparty_client = self.counterparty_client.clone();
-        let blockchain_client = self.blockchain_client.clone();
-        let crypto = self.crypto.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(their_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| {
-                    match counterparty.clone() {
-                        Counterparty::Open { channel } => {
-                            let balance_0 = channel.balance_0.clone();
-                            let balance_1 = channel.balance_1.clone();
-
-                            let (new_balance_0, new_balance_1) = if channel.i_am_0 {
-                                (balance_0 - amount.clone(), balance_1)
-                            } else {
-                                (balance_0, balance_1 - amount.clone())
-                            };
-
-                            Box::new(
-                                blockchain_client
-                                    .get_current_block()
-                                    .and_then(move |block| {
-                                        let re_draw_tx = ReDrawTx {
-                                            channel_id: channel.channel_id.clone(),
-                                            sequence_number: channel.sequence_number.clone()
-                                                + 1u64.into(),
-                                            old_balance_0: channel.balance_0.clone(),
-                                            old_balance_1: channel.balance_1.clone(),
-                                            new_balance_0: new_balance_0.clone(),
-                                            new_balance_1: new_balance_1.clone(),
-                                            expiration: (block + 40u64.into()), // current block plus 10 minutes
-                                            signature_0: None,
-                                            signature_1: None,
-                                        };
-
-                                        counterparty_client
-                                            .propose_re_draw(
-                                                crypto.own_address,
-                                                their_url.clone(),
-                                                re_draw_tx.clone(),
-                                            )
-                                            .and_then(move |their_signature| {
-                                                let fingerprint = re_draw_tx
-                                                    .clone()
-                                                    .fingerprint(crypto.contract_address);
-
-                                                let recovered_address = try_future_box!(
-                                                    their_signature.recover(&fingerprint)
-                                                );
-
-                                                if recovered_address != their_address {
-                                                    return Box::new(future::err(
-                                                        GuacError::Error {
-                                                            message: "Their signature is incorrect"
-                                                                .into(),
-                                                        }
-                                                        .into(),
-                                                    ));
-                                                }
-
-                                                *counterparty = Counterparty::ReDrawing {
-                                                    channel: channel.clone(),
-                                                    re_draw_tx: re_draw_tx.clone(),
-                                                };
-
-                                                let my_signature = crypto.eth_sign(
-                                                    &re_draw_tx
-                                                        .fingerprint(crypto.contract_address),
-                                                );
-
-                                                let (signature_0, signature_1) =
-                                                    if channel.clone().i_am_0 {
-                                                        (my_signature, their_signature)
-                                                    } else {
-                                                        (their_signature, my_signature)
-                                                    };
-
-                                                Box::new(
-                                                    blockchain_client
-                                                        .re_draw_then_withdraw(
-                                                            amount.clone(),
-                                                            ReDrawTx {
-                                                                signature_0: Some(signature_0),
-                                                                signature_1: Some(signature_1),
-                                                                ..re_draw_tx
-                                                            },
-                                                        )
-                                                        .and_then(move |_| {
-                                                            counterparty_client
-                                                                .notify_re_draw(
-                                                                    crypto.own_address,
-                                                                    their_url.clone(),
-                                                                )
-                                                                .and_then(move |_| {
-                                                                    // Save the new open state of the channel
-                                                                    *counterparty =
-                                                                        Counterparty::Open {
-                                                                            channel: Channel {
-                                                                                balance_0:
-                                                                                    new_balance_0
-                                                                                        .clone(),
-                                                                                balance_1:
-                                                                                    new_balance_1
-                                                                                        .clone(),
-                                                                                ..channel
-                                                                            },
-                                                                        };
-                                                                    Ok(())
-                                                                })
-                                                        }),
-                                                )
-                                            })
-                                    }),
-                            ) as Box<Future<Item = (), Error = Error>>
-                        }
-                        _ => {
-                            // Make user wait
-                            return Box::new(future::err(GuacError::TryAgainLater().into()))
-                                as Box<Future<Item = (), Error = Error>>;
-                        }
+        update_tx: UpdateTx,
+    ) -> Result<(), GuacError> {
+        let (update_tx, receipt) = self
+            .send_update(their_address, their_url, &amount, update_tx)
+            .await?;
+
+        self.events.emit(GuacEvent::PaymentSent {
+            counterparty: their_address,
+            amount: amount.clone(),
+            seq: update_tx.sequence_number.clone(),
+        });
+        self.metrics.record_payment(Direction::Sent, &amount);
+        self.ledger.record(
+            LedgerEntry::new(their_address, Direction::Sent, amount, update_tx)
+                .with_receipt(receipt),
+        );
+
+        self.refill.queue(their_address);
+
+        Ok(())
+    }
+
+    /// Pays `amount` to the counterparty once it shows us a preimage of `hash`, which it has to
+    /// do before block `expiration`. Until then the money is held by a hashlock, which can pay
+    /// for something further down a route: the payee only learns the preimage by having it
+    /// revealed to them, for instance when they fulfill a hashlock of their own with the same
+    /// hash.
+    pub async fn make_conditional_payment(
+        &self,
+        their_address: Address,
+        their_url: String,
+        hash: [u8; 32],
+        amount: Uint256,
+        expiration: Uint256,
+    ) -> Result<(), GuacError> {
+        let update_tx = self
+            .sign_update(their_address, |counterparty| {
+                Ok(
+                    open_channel_mut(counterparty, "make conditional payment")?.add_hashlock(
+                        hash,
+                        amount.clone(),
+                        expiration.clone(),
+                    )?,
+                )
+            })
+            .await?;
+        self.send_update(their_address, their_url, &0u64.into(), update_tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Shows `preimage` to the counterparty, which gets it to pay out the hashlock it pays us
+    /// for the hash of the preimage.
+    pub async fn claim_conditional_payment(
+        &self,
+        their_address: Address,
+        their_url: String,
+        preimage: [u8; 32],
+    ) -> Result<(), GuacError> {
+        let update_tx = self
+            .counterparty_client
+            .fulfill_hashlock(self.crypto.own_address, their_url, preimage)
+            .await?;
+
+        // The update is checked and applied like any other update they send us
+        match self
+            .receive_payment(their_address, String::default(), update_tx)
+            .await?
+        {
+            UpdateResponse::Accepted(_) => Ok(()),
+            UpdateResponse::Retry(_) => Err(ProtocolError::SequenceNumberDisagreement.into()),
+        }
+    }
+
+    /// Gives a hashlock the counterparty pays for `hash` back to them, for instance because the
+    /// payment cannot be routed any further
+    pub async fn cancel_conditional_payment(
+        &self,
+        their_address: Address,
+        their_url: String,
+        hash: [u8; 32],
+    ) -> Result<(), GuacError> {
+        let update_tx = self
+            .sign_update(their_address, |counterparty| {
+                Ok(
+                    open_channel_mut(counterparty, "cancel conditional payment")?
+                        .cancel_hashlock(&hash)?,
+                )
+            })
+            .await?;
+        self.send_update(their_address, their_url, &0u64.into(), update_tx)
+            .await?;
+        Ok(())
+    }
+
+    /// Takes back the money of the hashlocks we pay the counterparty which have expired
+    pub async fn expire_conditional_payments(
+        &self,
+        their_address: Address,
+        their_url: String,
+    ) -> Result<(), GuacError> {
+        let block = self.blockchain_client.get_current_block().await?;
+
+        let update_tx = self
+            .sign_update(their_address, |counterparty| {
+                Ok(
+                    open_channel_mut(counterparty, "expire conditional payments")?
+                        .expire_hashlocks(&block),
+                )
+            })
+            .await?;
+        if let Some(update_tx) = update_tx {
+            self.send_update(their_address, their_url, &0u64.into(), update_tx)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends an update which pays the counterparty `amount` and returns the update they
+    /// accepted, with their receipt for it. If they have seen a higher sequence number than we
+    /// have, for instance because some of their payments got lost, the update is already applied
+    /// on our side, so we send our balances again with a sequence number they will accept.
+    async fn send_update(
+        &self,
+        their_address: Address,
+        their_url: String,
+        amount: &Uint256,
+        update_tx: UpdateTx,
+    ) -> Result<(UpdateTx, Receipt), GuacError> {
+        let my_address = self.crypto.own_address;
+
+        let res = self
+            .counterparty_client
+            .receive_payment(my_address, their_url.clone(), update_tx.clone())
+            .await?;
+
+        let (update_tx, receipt) = match res {
+            UpdateResponse::Accepted(receipt) => (update_tx, receipt),
+            UpdateResponse::Retry(current_seq) => {
+                self.metrics.record_update_too_old(Direction::Sent);
+                let update_tx = self
+                    .sign_payment(their_address, 0u64.into(), Some(current_seq))
+                    .await?;
+
+                let res = self
+                    .counterparty_client
+                    .receive_payment(my_address, their_url, update_tx.clone())
+                    .await?;
+
+                match res {
+                    UpdateResponse::Accepted(receipt) => (update_tx, receipt),
+                    UpdateResponse::Retry(_) => {
+                        self.metrics.record_update_too_old(Direction::Sent);
+                        return Err(ProtocolError::SequenceNumberDisagreement.into());
+                    }
+                }
+            }
+        };
+
+        self.check_receipt(their_address, &update_tx, amount, &receipt)?;
+        Ok((update_tx, receipt))
+    }
+
+    /// Runs `f` on the channel with the counterparty, which has to be open
+    pub(crate) fn update_open_channel<T, F>(
+        &self,
+        their_address: Address,
+        action: &str,
+        f: F,
+    ) -> Result<T, GuacError>
+    where
+        F: FnOnce(&mut Channel) -> Result<T, GuacError>,
+    {
+        self.storage
+            .update_counterparty(their_address, |counterparty| {
+                f(open_channel_mut(counterparty, action)?)
+            })
+    }
+
+    /// Runs `f` on a copy of the state of the counterparty and signs the update it returns.
+    /// The copy is only stored once the update is signed, so a signer which fails leaves the
+    /// counterparty as it was. The counterparty is not locked while the signer works: if it
+    /// changed in the meantime, `f` is run again on the new state.
+    pub(crate) async fn sign_update<T, F>(
+        &self,
+        their_address: Address,
+        mut f: F,
+    ) -> Result<T, GuacError>
+    where
+        T: HoldsUpdate,
+        F: FnMut(&mut Counterparty) -> Result<T, GuacError>,
+    {
+        loop {
+            let current = check_for_counterparty(&self.storage, their_address)?;
+            let mut updated = current.clone();
+            let mut res = f(&mut updated)?;
+            if let Some(update_tx) = res.update_tx_mut() {
+                *update_tx = self
+                    .sign_update_tx(updated.i_am_0(), update_tx.clone())
+                    .await?;
+            }
+
+            let stored = self
+                .storage
+                .update_counterparty(their_address, |counterparty| {
+                    if *counterparty != current {
+                        return Err(StorageError::Conflict(their_address));
                     }
-                }),
-        )
+                    *counterparty = updated;
+                    Ok(())
+                });
+            match stored {
+                Ok(()) => return Ok(res),
+                Err(StorageError::Conflict(_)) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Adds our signature to an update of the channel
+    pub(crate) async fn sign_update_tx(
+        &self,
+        i_am_0: bool,
+        mut update_tx: UpdateTx,
+    ) -> Result<UpdateTx, GuacError> {
+        let crypto = &self.crypto;
+        let my_signature = crypto.sign(&update_tx).await?;
+        update_tx.set_my_signature(i_am_0, &my_signature);
+        Ok(update_tx)
+    }
+
+    /// Applies a payment to the stored channel and returns the update, signed by us, which
+    /// tells the counterparty about it. Like the counterparty's balance, our balance is
+    /// updated when the payment is sent, whether or not it arrives.
+    pub(crate) async fn sign_payment(
+        &self,
+        their_address: Address,
+        amount: Uint256,
+        current_seq: Option<Uint256>,
+    ) -> Result<UpdateTx, GuacError> {
+        self.sign_update(their_address, |counterparty| match counterparty {
+            Counterparty::Open { channel } => {
+                Ok(channel.make_payment(amount.clone(), current_seq.clone())?)
+            }
+            Counterparty::ReDrawing {
+                channel,
+                re_draw_tx,
+            }
+            | Counterparty::OtherReDrawing {
+                channel,
+                re_draw_tx,
+            } => Ok(channel.make_payment_during_re_draw(
+                amount.clone(),
+                current_seq.clone(),
+                re_draw_tx,
+            )?),
+            counterparty => Err(ProtocolError::WrongState {
+                correct_state: "Open, ReDrawing or OtherReDrawing".to_string(),
+                current_state: format!("{:?}", counterparty.clone()),
+                action: "make payment".to_string(),
+            }
+            .into()),
+        })
+        .await
     }
 
-    pub fn make_payment(
+    /// Opens a new channel with the counterparty, who has to be in the `New` state.
+    ///
+    /// The counterparty is moved to `Creating` before anything is sent, so that nothing else
+    /// can start opening the same channel. Until the transaction has been sent to the chain a
+    /// failure moves it back to `New`, after that the outcome is only known from the chain.
+    ///
+    /// If the counterparty proposes a channel to us at the same time, the proposal of the side
+    /// with the lower address wins (see `propose_channel`) and the other side gets a
+    /// `SimultaneousOpen` error.
+    async fn open_channel(
         &self,
         their_address: Address,
         their_url: String,
         amount: Uint256,
-    ) -> impl Future<Item = (), Error = Error> {
-        let storage = self.storage.clone();
-        let counterparty_client = self.counterparty_client.clone();
-        let crypto = self.crypto.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(their_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| match counterparty.clone() {
-                    Counterparty::Open { mut channel } => {
-                        let mut update_tx =
-                            try_future_box!(channel.make_payment(amount.clone(), None));
-
-                        let my_signature = crypto
-                            .eth_sign(&update_tx.clone().fingerprint(crypto.contract_address));
-
-                        if channel.i_am_0 {
-                            update_tx.signature_0 = Some(my_signature);
+        i_am_0: bool,
+    ) -> Result<(), GuacError> {
+        let crypto = &self.crypto;
+        let my_address = crypto.own_address;
+
+        let (address_0, address_1) = if i_am_0 {
+            (my_address, their_address)
+        } else {
+            (their_address, my_address)
+        };
+
+        let (balance_0, balance_1) = if i_am_0 {
+            (amount.clone(), 0u64.into())
+        } else {
+            (0u64.into(), amount.clone())
+        };
+
+        let block = self.blockchain_client.get_current_block().await?;
+
+        let new_channel_tx = NewChannelTx {
+            address_0,
+            address_1,
+            balance_0,
+            balance_1,
+            expiration: (block + 40u64.into()), // current block plus 10 minutes
+            settling_period_length: 5000u64.into(), //TODO: figure out default value
+            signature_0: None,
+            signature_1: None,
+        };
+
+        // Signed first, so that a signer which fails leaves the counterparty as it was
+        let my_signature = crypto.sign(&new_channel_tx).await?;
+        let fingerprint = crypto.fingerprint(&new_channel_tx);
+
+        let creating = transition_from(
+            &self.storage,
+            their_address,
+            &Counterparty::New { i_am_0 },
+            Event::ProposeChannel {
+                new_channel_tx: new_channel_tx.clone(),
+            },
+        )?;
+
+        let their_signature = match self
+            .counterparty_client
+            .propose_channel(my_address, their_url.clone(), new_channel_tx.clone())
+            .await
+            .and_then(|signature| check_signature(signature, &fingerprint, their_address))
+        {
+            Ok(signature) => signature,
+            Err(e) => {
+                self.storage
+                    .update_counterparty(their_address, |counterparty| {
+                        if *counterparty == creating {
+                            *counterparty =
+                                transition(counterparty.clone(), Event::ChannelRefused)?;
+                            Ok(())
+                        } else if let Counterparty::OtherCreating { .. }
+                        | Counterparty::Open { .. } = counterparty
+                        {
+                            // They proposed a channel at the same time and won the tie-break,
+                            // so we signed theirs instead of waiting for our own
+                            Err(GuacError::from(ProtocolError::SimultaneousOpen))
                         } else {
-                            update_tx.signature_1 = Some(my_signature);
-                        };
-
-                        Box::new(
-                            counterparty_client
-                                .receive_payment(
-                                    crypto.own_address,
-                                    their_url.clone(),
-                                    update_tx.clone(),
-                                )
-                                .and_then(move |res: Option<Uint256>| {
-                                    if let Some(current_seq) = res {
-                                        let mut update_tx = try_future_box!(
-                                            channel.make_payment(amount, Some(current_seq))
-                                        );
-
-                                        let my_signature = crypto.eth_sign(
-                                            &update_tx.clone().fingerprint(crypto.contract_address),
-                                        );
-
-                                        if channel.i_am_0 {
-                                            update_tx.signature_0 = Some(my_signature);
-                                        } else {
-                                            update_tx.signature_1 = Some(my_signature);
-                                        };
-
-                                        Box::new(
-                                            counterparty_client
-                                                .receive_payment(
-                                                    crypto.own_address,
-                                                    their_url.clone(),
-                                                    update_tx.clone(),
-                                                )
-                                                .and_then(move |res: Option<Uint256>| {
-                                                    if let Some(_) = res {
-                                                        Err(GuacError::Error {
-                                                            message: "Sequence number disagreement"
-                                                                .to_string(),
-                                                        }
-                                                        .into())
-                                                    } else {
-                                                        Ok(())
-                                                    }
-                                                }),
-                                        )
-                                            as Box<Future<Item = (), Error = Error>>
-                                    } else {
-                                        *counterparty = Counterparty::Open { channel };
-                                        Box::new(future::ok(()))
-                                            as Box<Future<Item = (), Error = Error>>
-                                    }
-                                }),
-                        ) as Box<Future<Item = (), Error = Error>>
+                            Err(StorageError::Conflict(their_address).into())
+                        }
+                    })?;
+                return Err(e);
+            }
+        };
+
+        let (signature_0, signature_1) = if i_am_0 {
+            (my_signature, their_signature)
+        } else {
+            (their_signature, my_signature)
+        };
+
+        let new_channel_tx = NewChannelTx {
+            signature_0: Some(signature_0),
+            signature_1: Some(signature_1),
+            ..new_channel_tx
+        };
+
+        // Keep the signed transaction around while we wait for the chain
+        let signed = transition_from(
+            &self.storage,
+            their_address,
+            &creating,
+            Event::ChannelSigned {
+                new_channel_tx: new_channel_tx.clone(),
+            },
+        )?;
+
+        let channel_id = self
+            .blockchain_client
+            .deposit_then_new_channel(amount, new_channel_tx)
+            .await?;
+
+        transition_from(
+            &self.storage,
+            their_address,
+            &signed,
+            Event::ChannelOpened { channel_id },
+        )?;
+
+        self.events.emit(GuacEvent::ChannelOpened {
+            counterparty: their_address,
+            channel_id,
+        });
+
+        self.counterparty_client
+            .notify_channel_opened(my_address, their_url)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Moves an open channel to `ReDrawing` and gets the counterparty to sign a reDraw to the
+    /// balances returned by `new_balances`. Resolves to the reDraw with both signatures, which is
+    /// also what the `ReDrawing` state holds from then on. If the counterparty does not sign,
+    /// the channel is moved back to `Open`.
+    ///
+    /// Payments can still be made while the channel is `ReDrawing`; they are applied to the
+    /// channel and carried over when the reDraw is finished or abandoned.
+    async fn start_re_draw<F>(
+        &self,
+        their_address: Address,
+        their_url: &str,
+        new_balances: F,
+    ) -> Result<ReDrawTx, GuacError>
+    where
+        F: FnOnce(&Channel) -> Result<(Uint256, Uint256), GuacError>,
+    {
+        let crypto = &self.crypto;
+
+        let block = self.blockchain_client.get_current_block().await?;
+
+        // Signed before the channel is moved, so that a signer which fails leaves it open
+        let counterparty = check_for_counterparty(&self.storage, their_address)?;
+        let channel = match &counterparty {
+            // The contract does not know about hashlocks, so a reDraw would lose their money
+            Counterparty::Open { channel } if channel.hashlocks.is_empty() => channel,
+            _ => return Err(ProtocolError::TryAgainLater.into()),
+        };
+        let (new_balance_0, new_balance_1) = new_balances(channel)?;
+        let re_draw_tx = ReDrawTx {
+            channel_id: channel.channel_id,
+            sequence_number: channel.sequence_number.clone() + 1u64.into(),
+            old_balance_0: channel.balance_0.clone(),
+            old_balance_1: channel.balance_1.clone(),
+            new_balance_0,
+            new_balance_1,
+            expiration: (block + 40u64.into()), // current block plus 10 minutes
+            signature_0: None,
+            signature_1: None,
+        };
+        let i_am_0 = channel.i_am_0;
+        let my_signature = crypto.sign(&re_draw_tx).await?;
+
+        transition_from(
+            &self.storage,
+            their_address,
+            &counterparty,
+            Event::ProposeReDraw {
+                re_draw_tx: re_draw_tx.clone(),
+            },
+        )?;
+
+        let fingerprint = crypto.fingerprint(&re_draw_tx);
+
+        let their_signature = match self
+            .counterparty_client
+            .propose_re_draw(
+                crypto.own_address,
+                their_url.to_string(),
+                re_draw_tx.clone(),
+            )
+            .await
+            .and_then(|signature| check_signature(signature, &fingerprint, their_address))
+        {
+            Ok(signature) => signature,
+            Err(e) => {
+                self.update_re_draw(their_address, &re_draw_tx, Event::ReDrawRefused)?;
+                return Err(e);
+            }
+        };
+
+        let (signature_0, signature_1) = if i_am_0 {
+            (my_signature, their_signature)
+        } else {
+            (their_signature, my_signature)
+        };
+
+        let signed_re_draw_tx = ReDrawTx {
+            signature_0: Some(signature_0),
+            signature_1: Some(signature_1),
+            ..re_draw_tx.clone()
+        };
+
+        self.update_re_draw(
+            their_address,
+            &re_draw_tx,
+            Event::ReDrawSigned {
+                re_draw_tx: signed_re_draw_tx.clone(),
+            },
+        )?;
+
+        Ok(signed_re_draw_tx)
+    }
+
+    /// Opens the channel again with the balances of a reDraw which made it to the chain, plus
+    /// the payments made while waiting for it, and lets the counterparty know. If there were
+    /// any, both sides sign the channel again at the new total.
+    async fn finish_re_draw(
+        &self,
+        their_address: Address,
+        their_url: String,
+        re_draw_tx: &ReDrawTx,
+    ) -> Result<(), GuacError> {
+        let crypto = &self.crypto;
+
+        let update_tx = self
+            .sign_update(their_address, |counterparty| match counterparty {
+                Counterparty::ReDrawing {
+                    re_draw_tx: pending,
+                    ..
+                } if pending == re_draw_tx => {
+                    *counterparty = transition(counterparty.clone(), Event::ReDrawDone)?;
+                    Ok(match counterparty {
+                        Counterparty::Open { channel } => channel.reconcile(re_draw_tx),
+                        _ => None,
+                    })
+                }
+                _ => Err(StorageError::Conflict(their_address).into()),
+            })
+            .await?;
+
+        self.events.emit(GuacEvent::ReDrawCompleted {
+            counterparty: their_address,
+            re_draw_tx: re_draw_tx.clone(),
+        });
+
+        let signed_update = self
+            .counterparty_client
+            .notify_re_draw(crypto.own_address, their_url, update_tx.clone())
+            .await?;
+
+        if let (Some(update_tx), Some(signed_update)) = (update_tx, signed_update) {
+            self.update_open_channel(their_address, "reconcile redraw", |channel| {
+                let their_signature = if channel.i_am_0 {
+                    signed_update.signature_1.clone()
+                } else {
+                    signed_update.signature_0.clone()
+                };
+                let their_signature = their_signature.ok_or(ProtocolError::BadSignature)?;
+                check_signature(
+                    their_signature.clone(),
+                    &crypto.fingerprint(&update_tx),
+                    their_address,
+                )?;
+
+                let mut update_tx = update_tx;
+                update_tx.set_my_signature(!channel.i_am_0, &their_signature);
+                channel.signed_update = Some(update_tx);
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Applies `event` to our pending `re_draw_tx`. The channel it is applied to has any
+    /// payments made since the reDraw was started. Fails if the counterparty is no longer
+    /// waiting on that reDraw.
+    fn update_re_draw(
+        &self,
+        their_address: Address,
+        re_draw_tx: &ReDrawTx,
+        event: Event,
+    ) -> Result<(), GuacError> {
+        self.storage
+            .update_counterparty(their_address, |counterparty| match counterparty {
+                Counterparty::ReDrawing {
+                    re_draw_tx: pending,
+                    ..
+                } if pending == re_draw_tx => {
+                    *counterparty = transition(counterparty.clone(), event)?;
+                    Ok(())
+                }
+                _ => Err(StorageError::Conflict(their_address).into()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::LedgerQuery;
+    use crate::test_utils::{
+        make_node, make_pair, MockChain, MockNetwork, TestNode, SECRET_0, SECRET_1, SECRET_2,
+    };
+    use crate::types::Hashlock;
+    use futures::executor::block_on;
+    use futures::future;
+
+    #[test]
+    fn test_fill_pay_and_withdraw() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                90u64.into()
+            );
+            assert_eq!(
+                guac_1.check_accrual(node_0.address).await.unwrap(),
+                10u64.into()
+            );
+
+            guac_1
+                .fill_channel(node_0.address, node_0.url.clone(), 50u64.into())
+                .await
+                .unwrap();
+            guac_0
+                .withdraw(node_1.address, node_1.url.clone(), 40u64.into())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                50u64.into()
+            );
+            assert_eq!(
+                guac_1.check_my_balance(node_0.address).await.unwrap(),
+                60u64.into()
+            );
+            assert_eq!(
+                guac_0.get_state(node_1.address).await.unwrap(),
+                Counterparty::Open {
+                    channel: match guac_1.get_state(node_0.address).await.unwrap() {
+                        Counterparty::Open { channel } => Channel {
+                            i_am_0: !channel.i_am_0,
+                            accrual: 0u64.into(),
+                            ..channel
+                        },
+                        state => panic!("unexpected state {:?}", state),
+                    }
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_open_does_not_lock_counterparty_while_waiting_for_chain() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+        let release = node_0.blockchain.hold_next_transaction();
+
+        block_on(async {
+            let fill = guac_0.fill_channel(node_1.address, node_1.url.clone(), 100u64.into());
+            let meanwhile = async {
+                match guac_0.get_state(node_1.address).await.unwrap() {
+                    Counterparty::Creating { new_channel_tx, .. } => {
+                        assert!(new_channel_tx.signature_0.is_some());
+                        assert!(new_channel_tx.signature_1.is_some());
+                    }
+                    state => panic!("unexpected state {:?}", state),
+                }
+                match guac_0
+                    .fill_channel(node_1.address, node_1.url.clone(), 1u64.into())
+                    .await
+                {
+                    Err(GuacError::Protocol(ProtocolError::TryAgainLater)) => {}
+                    res => panic!("unexpected result {:?}", res),
+                }
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(fill, meanwhile).await;
+            res.unwrap();
+
+            match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { channel } => {
+                    assert_eq!(channel.balance_0 + channel.balance_1, 100u64.into())
+                }
+                state => panic!("unexpected state {:?}", state),
+            }
+        });
+    }
+
+    #[test]
+    fn test_re_draw_does_not_lock_counterparty_while_waiting_for_chain() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            let release = node_0.blockchain.hold_next_transaction();
+            let fill = guac_0.fill_channel(node_1.address, node_1.url.clone(), 50u64.into());
+            let meanwhile = async {
+                match guac_0.get_state(node_1.address).await.unwrap() {
+                    Counterparty::ReDrawing { re_draw_tx, .. } => {
+                        assert!(re_draw_tx.signature_0.is_some());
+                        assert!(re_draw_tx.signature_1.is_some());
                     }
-                    _ => {
-                        let error = GuacError::WrongState {
-                            correct_state: "Open".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "make payment".to_string(),
-                        };
-                        return Box::new(future::err(error.into()))
-                            as Box<Future<Item = (), Error = Error>>;
+                    state => panic!("unexpected state {:?}", state),
+                }
+                assert_eq!(
+                    guac_0.check_my_balance(node_1.address).await.unwrap(),
+                    100u64.into()
+                );
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(fill, meanwhile).await;
+            res.unwrap();
+
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                150u64.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_failed_proposal_releases_counterparty() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+
+        block_on(async {
+            assert!(guac_0
+                .fill_channel(node_1.address, "nowhere".to_string(), 100u64.into())
+                .await
+                .is_err());
+            match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::New { .. } => {}
+                state => panic!("unexpected state {:?}", state),
+            }
+
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            assert!(guac_0
+                .fill_channel(node_1.address, "nowhere".to_string(), 100u64.into())
+                .await
+                .is_err());
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                100u64.into()
+            );
+            match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { .. } => {}
+                state => panic!("unexpected state {:?}", state),
+            }
+        });
+    }
+
+    #[test]
+    fn test_refused_signature_leaves_channel() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            let before = guac_0.get_state(node_1.address).await.unwrap();
+
+            drop(node_0.signer.hold_next_signature());
+            match guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+            {
+                Err(GuacError::Signer(_)) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert_eq!(guac_0.get_state(node_1.address).await.unwrap(), before);
+
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                90u64.into()
+            );
+            assert_eq!(
+                guac_1.check_my_balance(node_0.address).await.unwrap(),
+                10u64.into()
+            );
+        });
+    }
+
+    /// A payment comes in while we wait on our signer for one of ours, which is then made again
+    /// on top of it
+    #[test]
+    fn test_payment_received_while_signing() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_1
+                .fill_channel(node_0.address, node_0.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            let release = node_0.signer.hold_next_signature();
+            let payment = guac_0.make_payment(node_1.address, node_1.url.clone(), 10u64.into());
+            let meanwhile = async {
+                guac_1
+                    .make_payment(node_0.address, node_0.url.clone(), 5u64.into())
+                    .await
+                    .unwrap();
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(payment, meanwhile).await;
+            res.unwrap();
+
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                95u64.into()
+            );
+            assert_eq!(
+                guac_1.check_my_balance(node_0.address).await.unwrap(),
+                105u64.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_payments_during_re_draw() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_1
+                .fill_channel(node_0.address, node_0.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            let release = node_0.blockchain.hold_next_transaction();
+            let fill = guac_0.fill_channel(node_1.address, node_1.url.clone(), 50u64.into());
+            let meanwhile = async {
+                guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                    .await
+                    .unwrap();
+                guac_1
+                    .make_payment(node_0.address, node_0.url.clone(), 5u64.into())
+                    .await
+                    .unwrap();
+                match guac_1.get_state(node_0.address).await.unwrap() {
+                    Counterparty::OtherReDrawing { .. } => {}
+                    state => panic!("unexpected state {:?}", state),
+                }
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(fill, meanwhile).await;
+            res.unwrap();
+
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                145u64.into()
+            );
+            assert_eq!(
+                guac_1.check_my_balance(node_0.address).await.unwrap(),
+                105u64.into()
+            );
+
+            // Both sides end up with the same channel, and keep paying from there
+            let channel_0 = match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { channel } => channel,
+                state => panic!("unexpected state {:?}", state),
+            };
+            let channel_1 = match guac_1.get_state(node_0.address).await.unwrap() {
+                Counterparty::Open { channel } => channel,
+                state => panic!("unexpected state {:?}", state),
+            };
+            assert_eq!(channel_0.sequence_number, channel_1.sequence_number);
+            assert_eq!(
+                (channel_0.balance_0, channel_0.balance_1),
+                (channel_1.balance_0, channel_1.balance_1)
+            );
+
+            guac_1
+                .make_payment(node_0.address, node_0.url.clone(), 5u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                150u64.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_close_after_payments_during_re_draw() {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let node_0 = make_node(&network, &chain, SECRET_0, "node_0");
+        let node_1 = make_node(&network, &chain, SECRET_1, "node_1");
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            let release = node_0.blockchain.hold_next_transaction();
+            let fill = guac_0.fill_channel(node_1.address, node_1.url.clone(), 50u64.into());
+            let meanwhile = async {
+                guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                    .await
+                    .unwrap();
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(fill, meanwhile).await;
+            res.unwrap();
+
+            // The payment was signed at the old total, which the contract no longer takes
+            let payment = guac_1.ledger.query(&LedgerQuery::new())[0]
+                .update_tx
+                .clone();
+            assert!(chain.close(&payment).is_err());
+
+            let channel_0 = match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { channel } => channel,
+                state => panic!("unexpected state {:?}", state),
+            };
+            let channel_1 = match guac_1.get_state(node_0.address).await.unwrap() {
+                Counterparty::Open { channel } => channel,
+                state => panic!("unexpected state {:?}", state),
+            };
+            let signed_update = channel_1.signed_update.unwrap();
+            assert_eq!(channel_0.signed_update, Some(signed_update.clone()));
+
+            let (address_0, address_1) = if channel_0.i_am_0 {
+                (node_0.address, node_1.address)
+            } else {
+                (node_1.address, node_0.address)
+            };
+            let fingerprint = guac_1.crypto.fingerprint(&signed_update);
+            check_signature(
+                signed_update.signature_0.clone().unwrap(),
+                &fingerprint,
+                address_0,
+            )
+            .unwrap();
+            check_signature(
+                signed_update.signature_1.clone().unwrap(),
+                &fingerprint,
+                address_1,
+            )
+            .unwrap();
+
+            chain.close(&signed_update).unwrap();
+            let state = chain.channel(channel_1.channel_id).unwrap();
+            assert_eq!(
+                (state.balance_0, state.balance_1),
+                (channel_1.balance_0, channel_1.balance_1)
+            );
+            assert_eq!(channel_0.my_balance(), 140u64.into());
+        });
+    }
+
+    #[test]
+    fn test_withdrawn_money_cannot_be_paid_during_re_draw() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            let release = node_0.blockchain.hold_next_transaction();
+            let withdraw = guac_0.withdraw(node_1.address, node_1.url.clone(), 80u64.into());
+            let meanwhile = async {
+                match guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), 30u64.into())
+                    .await
+                {
+                    Err(GuacError::Protocol(ProtocolError::NotEnough { .. })) => {}
+                    res => panic!("unexpected result {:?}", res),
+                }
+                guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), 20u64.into())
+                    .await
+                    .unwrap();
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(withdraw, meanwhile).await;
+            res.unwrap();
+
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                0u64.into()
+            );
+            assert_eq!(
+                node_1.guac.check_my_balance(node_0.address).await.unwrap(),
+                20u64.into()
+            );
+        });
+    }
+
+    fn events(stream: &mut EventStream) -> Vec<GuacEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = stream.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn test_events() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+        let mut events_0 = guac_0.subscribe();
+        let mut events_1 = guac_1.subscribe();
+
+        let channel_id = block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+
+            match guac_0.get_state(node_1.address).await.unwrap() {
+                Counterparty::Open { channel } => channel.channel_id,
+                state => panic!("unexpected state {:?}", state),
+            }
+        });
+
+        assert_eq!(
+            events(&mut events_0),
+            vec![
+                GuacEvent::ChannelOpened {
+                    counterparty: node_1.address,
+                    channel_id,
+                },
+                GuacEvent::PaymentSent {
+                    counterparty: node_1.address,
+                    amount: 10u64.into(),
+                    seq: 1u64.into(),
+                },
+            ]
+        );
+
+        let events_1 = events(&mut events_1);
+        assert_eq!(events_1.len(), 3);
+        match &events_1[0] {
+            GuacEvent::ChannelProposed {
+                counterparty,
+                new_channel_tx,
+            } => {
+                assert_eq!(*counterparty, node_0.address);
+                assert_eq!(
+                    new_channel_tx.balance_0.clone() + new_channel_tx.balance_1.clone(),
+                    100u64.into()
+                );
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(
+            events_1[1..],
+            [
+                GuacEvent::ChannelOpened {
+                    counterparty: node_0.address,
+                    channel_id,
+                },
+                GuacEvent::PaymentReceived {
+                    counterparty: node_0.address,
+                    amount: 10u64.into(),
+                    seq: 1u64.into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ledger() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            for amount in 1..=3u64 {
+                guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), amount.into())
+                    .await
+                    .unwrap();
+            }
+            // The ledger is kept when the accrual is reset
+            assert_eq!(
+                guac_1.check_accrual(node_0.address).await.unwrap(),
+                6u64.into()
+            );
+        });
+
+        let sent = guac_0.ledger.query(
+            &LedgerQuery::new()
+                .with_counterparty(node_1.address)
+                .with_direction(Direction::Sent),
+        );
+        let received = guac_1.ledger.query(&LedgerQuery::new());
+        assert_eq!(sent.len(), 3);
+        assert_eq!(received.len(), 3);
+        for (seq, (sent, received)) in (1u64..).zip(sent.iter().zip(received.iter())) {
+            assert_eq!(sent.amount, seq.into());
+            assert_eq!(sent.update_tx.sequence_number, seq.into());
+            assert_eq!(sent.update_tx, received.update_tx);
+            assert_eq!(received.counterparty, node_0.address);
+            assert_eq!(received.direction, Direction::Received);
+            assert_eq!(received.amount, seq.into());
+        }
+        assert!(guac_0
+            .ledger
+            .query(&LedgerQuery::new().with_direction(Direction::Received))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_refused_message_is_reported() {
+        let (node_0, node_1) = make_pair();
+        let mut events_1 = node_1.guac.subscribe();
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            events(&mut events_1);
+
+            let update_tx = UpdateTx {
+                channel_id: [0; 32],
+                sequence_number: 5u64.into(),
+                balance_0: 0u64.into(),
+                balance_1: 100u64.into(),
+                hashlocks: Vec::new(),
+                signature_0: None,
+                signature_1: None,
+            };
+            assert!(node_1
+                .guac
+                .receive_payment(node_0.address, node_1.url.clone(), update_tx)
+                .await
+                .is_err());
+        });
+
+        assert_eq!(
+            events(&mut events_1),
+            vec![GuacEvent::Error {
+                counterparty: node_0.address,
+                message: "Invalid request: No signature supplied".to_string(),
+            }]
+        );
+    }
+
+    async fn fill(from: &TestNode, to: &TestNode, amount: u64) {
+        from.guac
+            .fill_channel(to.address, to.url.clone(), amount.into())
+            .await
+            .unwrap();
+    }
+
+    async fn hashlocks(node: &TestNode, other: &TestNode) -> Vec<Hashlock> {
+        match node.guac.get_state(other.address).await.unwrap() {
+            Counterparty::Open { channel } => channel.hashlocks,
+            state => panic!("unexpected state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn test_conditional_payment_over_two_hops() {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let a = make_node(&network, &chain, SECRET_0, "a");
+        let b = make_node(&network, &chain, SECRET_1, "b");
+        let c = make_node(&network, &chain, SECRET_2, "c");
+        let mut events_b = b.guac.subscribe();
+
+        // Only C knows the preimage, and gives its hash to A
+        let preimage = [42; 32];
+        let hash = Hashlock::hash_of(&preimage);
+
+        block_on(async {
+            fill(&a, &b, 100).await;
+            fill(&b, &c, 100).await;
+
+            // B only pays C on the same condition as A pays B, and gives C less time to
+            // claim it so that B can still claim from A afterwards
+            a.guac
+                .make_conditional_payment(
+                    b.address,
+                    b.url.clone(),
+                    hash,
+                    10u64.into(),
+                    100u64.into(),
+                )
+                .await
+                .unwrap();
+            b.guac
+                .make_conditional_payment(
+                    c.address,
+                    c.url.clone(),
+                    hash,
+                    10u64.into(),
+                    50u64.into(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                a.guac.check_my_balance(b.address).await.unwrap(),
+                90u64.into()
+            );
+            assert_eq!(c.guac.check_accrual(b.address).await.unwrap(), 0u64.into());
+
+            // The wrong preimage gets C nothing
+            assert!(c
+                .guac
+                .claim_conditional_payment(b.address, b.url.clone(), [0; 32])
+                .await
+                .is_err());
+
+            c.guac
+                .claim_conditional_payment(b.address, b.url.clone(), preimage)
+                .await
+                .unwrap();
+            let revealed = events(&mut events_b)
+                .into_iter()
+                .find_map(|event| match event {
+                    GuacEvent::HashlockFulfilled {
+                        counterparty,
+                        hash: fulfilled,
+                        preimage,
+                    } => {
+                        assert_eq!(counterparty, c.address);
+                        assert_eq!(fulfilled, hash);
+                        Some(preimage)
                     }
-                }),
-        )
+                    _ => None,
+                })
+                .unwrap();
+            b.guac
+                .claim_conditional_payment(a.address, a.url.clone(), revealed)
+                .await
+                .unwrap();
+
+            assert_eq!(c.guac.check_accrual(b.address).await.unwrap(), 10u64.into());
+            assert_eq!(b.guac.check_accrual(a.address).await.unwrap(), 10u64.into());
+            assert_eq!(
+                b.guac.check_my_balance(c.address).await.unwrap(),
+                90u64.into()
+            );
+            assert_eq!(
+                a.guac.check_my_balance(b.address).await.unwrap(),
+                90u64.into()
+            );
+            for (node, other) in &[(&a, &b), (&b, &a), (&b, &c), (&c, &b)] {
+                assert!(hashlocks(node, other).await.is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn test_expired_conditional_payment() {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let node_0 = make_node(&network, &chain, SECRET_0, "node_0");
+        let node_1 = make_node(&network, &chain, SECRET_1, "node_1");
+        let preimage = [42; 32];
+
+        block_on(async {
+            fill(&node_0, &node_1, 100).await;
+            let expiration = node_0
+                .guac
+                .blockchain_client
+                .get_current_block()
+                .await
+                .unwrap()
+                + 1u64.into();
+            node_0
+                .guac
+                .make_conditional_payment(
+                    node_1.address,
+                    node_1.url.clone(),
+                    Hashlock::hash_of(&preimage),
+                    10u64.into(),
+                    expiration,
+                )
+                .await
+                .unwrap();
+
+            // Nothing has expired yet
+            node_0
+                .guac
+                .expire_conditional_payments(node_1.address, node_1.url.clone())
+                .await
+                .unwrap();
+            assert_eq!(hashlocks(&node_1, &node_0).await.len(), 1);
+
+            chain.mine();
+            node_0
+                .guac
+                .expire_conditional_payments(node_1.address, node_1.url.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                node_0.guac.check_my_balance(node_1.address).await.unwrap(),
+                100u64.into()
+            );
+            assert!(hashlocks(&node_1, &node_0).await.is_empty());
+
+            match node_1
+                .guac
+                .claim_conditional_payment(node_0.address, node_0.url.clone(), preimage)
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::Forbidden { .. })) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+        });
     }
 }
@@ -1,456 +1,852 @@
-use channel::Channel;
-use channel_manager::check_for_counterparty;
-use channel_manager::make_counterparty_if_none;
+use crate::channel::Channel;
+use crate::channel_manager::{
+    accept_counterparty_if_none, check_for_counterparty, open_channel_mut, transition_from,
+};
+use crate::crypto::Crypto;
+use crate::error::{GuacError, ProtocolError, StorageError};
+use crate::events::GuacEvent;
+use crate::ledger::{Direction, LedgerEntry};
+use crate::routing::{Forward, NodeInfo};
+use crate::state_machine::{transition, Event};
+use crate::types::{Counterparty, NewChannelTx, ReDrawTx};
+use crate::types::{UpdateResponse, UpdateTx};
+use crate::Guac;
+use async_trait::async_trait;
 use clarity::{Address, Signature};
-use failure::Error;
-use futures::{future, Future};
-use num256::Uint256;
-use types::UpdateTx;
-use types::{Counterparty, GuacError, NewChannelTx, ReDrawTx};
-use Guac;
 
 macro_rules! forbidden {
     ($expression:expr, $label:expr) => {
         if !($expression) {
-            return future::err(
-                GuacError::Forbidden {
-                    message: $label.to_string(),
-                }
-                .into(),
-            );
+            return Err(ProtocolError::Forbidden {
+                message: $label.to_string(),
+            }
+            .into());
         }
     };
 }
 
+#[async_trait(?Send)]
 pub trait CounterpartyApi {
-    fn propose_channel(
+    async fn propose_channel(
         &self,
         from_address: Address,
         to_url: String,
         new_channel_tx: NewChannelTx,
-    ) -> Box<Future<Item = Signature, Error = Error>>;
+    ) -> Result<Signature, GuacError>;
 
-    fn propose_re_draw(
+    async fn propose_re_draw(
         &self,
         from_address: Address,
         to_url: String,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = Signature, Error = Error>>;
+    ) -> Result<Signature, GuacError>;
 
-    fn notify_channel_opened(
+    async fn notify_channel_opened(
         &self,
         from_address: Address,
         to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>>;
+    ) -> Result<(), GuacError>;
 
-    fn notify_re_draw(
+    async fn notify_re_draw(
         &self,
         from_address: Address,
         to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>>;
+        update_tx: Option<UpdateTx>,
+    ) -> Result<Option<UpdateTx>, GuacError>;
 
-    fn receive_payment(
+    async fn receive_payment(
         &self,
         from_address: Address,
         to_url: String,
         update_tx: UpdateTx,
-    ) -> Box<Future<Item = Option<Uint256>, Error = Error>>;
+    ) -> Result<UpdateResponse, GuacError>;
+
+    /// Asks the counterparty to pay out the hashlock it pays us which `preimage` unlocks.
+    /// Returns the update which does so, signed by the counterparty.
+    async fn fulfill_hashlock(
+        &self,
+        from_address: Address,
+        to_url: String,
+        preimage: [u8; 32],
+    ) -> Result<UpdateTx, GuacError>;
+
+    /// Returns what the node tells about itself, followed by the other nodes it knows about
+    async fn get_info(&self, to_url: String) -> Result<Vec<NodeInfo>, GuacError>;
+
+    /// Tells the counterparty where the payment of the hashlock we just locked to it goes next.
+    /// Returns once the payee has claimed the payment.
+    async fn forward_payment(
+        &self,
+        from_address: Address,
+        to_url: String,
+        forward: Forward,
+    ) -> Result<(), GuacError>;
 }
 
+#[async_trait(?Send)]
 impl CounterpartyApi for Guac {
-    fn propose_channel(
+    async fn propose_channel(
         &self,
         from_address: Address,
         _to_url: String,
         new_channel_tx: NewChannelTx,
-    ) -> Box<Future<Item = Signature, Error = Error>> {
-        let storage = self.storage.clone();
-        let crypto = self.crypto.clone();
-        let my_address = crypto.own_address;
-
-        Box::new(
-            storage
-                .get_counterparty(from_address)
-                .and_then(make_counterparty_if_none(
-                    from_address,
-                    crypto.own_address,
-                    self.storage.clone(),
-                ))
-                .and_then(move |mut counterparty| {
-                    match counterparty.clone() {
-                        Counterparty::New { i_am_0 } => {
-                            Box::new(
-                                future::ok(())
-                                    .and_then({
-                                        let new_channel_tx = new_channel_tx.clone();
-                                        move |_| {
-                                            let NewChannelTx {
-                                                address_0,
-                                                address_1,
-                                                balance_0,
-                                                balance_1,
-                                                expiration: _,
-                                                settling_period_length,
-                                                signature_0: _,
-                                                signature_1: _,
-                                            } = new_channel_tx.clone();
-
-                                            if i_am_0 {
-                                                forbidden!(
-                                                    address_0 == my_address,
-                                                    format!(
-                                                    "Address 0 ({}) should equal my address ({})",
-                                                    address_0.to_string(),
-                                                    my_address.to_string()
-                                                )
-                                                );
-                                                forbidden!(
-                                                    address_1 == from_address,
-                                                    format!(
-                                                    "Address 1 ({}) should equal your address ({})",
-                                                    address_1.to_string(),
-                                                    from_address.to_string()
-                                                )
-                                                );
-                                            } else {
-                                                forbidden!(
-                                                    address_1 == my_address,
-                                                    format!(
-                                                    "Address 1 ({}) should equal my address ({})",
-                                                    address_1.to_string(),
-                                                    my_address.to_string()
-                                                )
-                                                );
-                                                forbidden!(
-                                                    address_0 == from_address,
-                                                    format!(
-                                                    "Address 0 ({}) should equal your address ({})",
-                                                    address_0.to_string(),
-                                                    from_address.to_string()
-                                                )
-                                                );
-                                            }
-
-                                            let my_balance =
-                                                if i_am_0 { balance_0 } else { balance_1 };
-
-                                            forbidden!(
-                                                my_balance == 0u64.into(),
-                                                "My balance in proposed channel must be zero."
-                                            );
-
-                                            forbidden!(
-                                                settling_period_length == 5000u64.into(),
-                                                "I only accept settling periods of 5000 blocks"
-                                            );
-
-                                            future::ok(())
-                                        }
-                                    })
-                                    .and_then({
-                                        let crypto = crypto.clone();
-                                        let new_channel_tx = new_channel_tx.clone();
-                                        move |_| {
-                                            // Save the current state of the counterparty
-                                            *counterparty = Counterparty::OtherCreating {
-                                                i_am_0,
-                                                new_channel_tx: new_channel_tx.clone(),
-                                            };
-
-                                            let my_signature = crypto.eth_sign(
-                                                &new_channel_tx
-                                                    .fingerprint(crypto.contract_address),
-                                            );
-                                            Ok(my_signature)
-                                        }
-                                    }),
-                            )
-                                as Box<Future<Item = Signature, Error = Error>>
-                        }
-                        _ => {
-                            let error = GuacError::WrongState {
-                                correct_state: "New".to_string(),
-                                current_state: format!("{:?}", counterparty.clone()),
-                                action: "propose channel".to_string(),
-                            };
-                            return Box::new(future::err(error.into()))
-                                as Box<Future<Item = Signature, Error = Error>>;
-                        }
-                    }
-                }),
-        )
+    ) -> Result<Signature, GuacError> {
+        let res: Result<Signature, GuacError> = async {
+            let crypto = &self.crypto;
+            let my_address = crypto.own_address;
+
+            accept_counterparty_if_none(&self.storage, from_address, my_address)?;
+
+            // If we are proposing a channel to them at the same time, this decides whose proposal
+            // is opened. It is checked before signing, and again when it is applied, as the
+            // counterparty is not locked while we sign.
+            let event = Event::ChannelProposed {
+                new_channel_tx: new_channel_tx.clone(),
+            };
+            transition(
+                check_for_counterparty(&self.storage, from_address)?,
+                event.clone(),
+            )?;
+
+            // The same as in `accept_counterparty_if_none`
+            let i_am_0 = my_address < from_address;
+            check_new_channel_tx(i_am_0, my_address, from_address, &new_channel_tx)?;
+            self.proposal_policy
+                .check_channel(from_address, i_am_0, &new_channel_tx)
+                .into_result()?;
+
+            let my_signature = crypto.sign(&new_channel_tx).await?;
+            self.storage
+                .update_counterparty(from_address, |counterparty| {
+                    *counterparty = transition(counterparty.clone(), event)?;
+                    Ok::<_, GuacError>(())
+                })?;
+
+            self.events.emit(GuacEvent::ChannelProposed {
+                counterparty: from_address,
+                new_channel_tx,
+            });
+
+            Ok(my_signature)
+        }
+        .await;
+
+        self.report_error(from_address, res)
     }
 
-    fn propose_re_draw(
+    async fn propose_re_draw(
         &self,
         from_address: Address,
         _to_url: String,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = Signature, Error = Error>> {
-        let storage = self.storage.clone();
-        let crypto = self.crypto.clone();
-
-        Box::new(
-            storage
-                .get_counterparty(from_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| match counterparty.clone() {
-                    Counterparty::Open { channel } => {
-                        let channel_clone_1 = channel.clone();
-                        let re_draw_tx_clone_1 = re_draw_tx.clone();
-                        Box::new(future::ok(()).and_then(move |_| {
-                            let ReDrawTx {
-                                channel_id,
-
-                                sequence_number,
-                                old_balance_0,
-                                old_balance_1,
-
-                                new_balance_0,
-                                new_balance_1,
-
-                                expiration: _,
-
-                                signature_0: _,
-                                signature_1: _,
-                            } = re_draw_tx;
-
-                            forbidden!(
-                                channel_id == channel.channel_id,
-                                format!(
-                                    "Channel ID ({:?}) should equal my saved channel ID ({:?})",
-                                    channel_id, channel.channel_id
-                                )
-                            );
-
-                            forbidden!(
-                                sequence_number > channel.sequence_number,
-                                format!(
-                                    "Sequence number ({}) should be higher than {}",
-                                    sequence_number, channel.sequence_number
-                                )
-                            );
-
-                            forbidden!(
-                                old_balance_0 == channel.balance_0,
-                                format!(
-                                    "Old balance_0 ({}) should equal {}",
-                                    old_balance_0, channel.balance_0
-                                )
-                            );
-
-                            forbidden!(
-                                old_balance_1 == channel.balance_1,
-                                format!(
-                                    "Old balance_1 ({}) should equal {}",
-                                    old_balance_1, channel.balance_1
-                                )
-                            );
-
-                            if channel.i_am_0 {
-                                forbidden!(
-                                    new_balance_0 == channel.balance_0,
-                                    format!(
-                                        "New balance_0 ({}) should equal my balance ({})",
-                                        new_balance_0, channel.balance_0
-                                    )
-                                );
-                            } else {
-                                forbidden!(
-                                    new_balance_1 == channel.balance_1,
-                                    format!(
-                                        "New balance_1 ({}) should equal my balance ({})",
-                                        new_balance_1, channel.balance_1
-                                    )
-                                );
-                            }
+    ) -> Result<Signature, GuacError> {
+        let res: Result<Signature, GuacError> = async {
+            let crypto = &self.crypto;
+
+            // Checked before signing, and again when it is applied, as the counterparty is not
+            // locked while we sign
+            let propose = |counterparty: &mut Counterparty| match counterparty.clone() {
+                Counterparty::Open { channel } => {
+                    let ReDrawTx {
+                        channel_id,
+                        sequence_number,
+                        old_balance_0,
+                        old_balance_1,
+                        ..
+                    } = re_draw_tx.clone();
+
+                    forbidden!(
+                        channel_id == channel.channel_id,
+                        format!(
+                            "Channel ID ({:?}) should equal my saved channel ID ({:?})",
+                            channel_id, channel.channel_id
+                        )
+                    );
+
+                    forbidden!(
+                        sequence_number > channel.sequence_number,
+                        format!(
+                            "Sequence number ({}) should be higher than {}",
+                            sequence_number, channel.sequence_number
+                        )
+                    );
+
+                    forbidden!(
+                        channel.hashlocks.is_empty(),
+                        "Cannot redraw while hashlocks are pending"
+                    );
+
+                    forbidden!(
+                        old_balance_0 == channel.balance_0,
+                        format!(
+                            "Old balance_0 ({}) should equal {}",
+                            old_balance_0, channel.balance_0
+                        )
+                    );
+
+                    forbidden!(
+                        old_balance_1 == channel.balance_1,
+                        format!(
+                            "Old balance_1 ({}) should equal {}",
+                            old_balance_1, channel.balance_1
+                        )
+                    );
+
+                    self.proposal_policy
+                        .check_re_draw(from_address, &channel, &re_draw_tx)
+                        .into_result()?;
+
+                    *counterparty = transition(
+                        counterparty.clone(),
+                        Event::ReDrawProposed {
+                            re_draw_tx: re_draw_tx.clone(),
+                        },
+                    )?;
+                    Ok::<_, GuacError>(())
+                }
+                _ => {
+                    let error = ProtocolError::WrongState {
+                        correct_state: "Open".to_string(),
+                        current_state: format!("{:?}", counterparty.clone()),
+                        action: "propose redraw".to_string(),
+                    };
+                    Err(error.into())
+                }
+            };
 
-                            *counterparty = Counterparty::OtherReDrawing {
-                                channel: channel_clone_1,
-                                re_draw_tx: re_draw_tx_clone_1.clone(),
-                            };
+            propose(&mut check_for_counterparty(&self.storage, from_address)?)?;
+            let my_signature = crypto.sign(&re_draw_tx).await?;
+            self.storage.update_counterparty(from_address, propose)?;
 
-                            let my_signature = crypto
-                                .eth_sign(&re_draw_tx_clone_1.fingerprint(crypto.contract_address));
+            Ok(my_signature)
+        }
+        .await;
 
-                            future::ok(my_signature)
-                        })) as Box<Future<Item = Signature, Error = Error>>
+        self.report_error(from_address, res)
+    }
+
+    async fn notify_channel_opened(
+        &self,
+        from_address: Address,
+        _to_url: String,
+    ) -> Result<(), GuacError> {
+        let res: Result<(), GuacError> = async {
+            let counterparty = check_for_counterparty(&self.storage, from_address)?;
+
+            match counterparty.clone() {
+                Counterparty::OtherCreating { i_am_0, .. } => {
+                    let (address_0, address_1) = if i_am_0 {
+                        (self.crypto.own_address, from_address)
+                    } else {
+                        (from_address, self.crypto.own_address)
+                    };
+
+                    let maybe_channel_id = self
+                        .blockchain_client
+                        .check_for_open(&address_0, &address_1)
+                        .await?;
+
+                    if let Some(channel_id) = maybe_channel_id {
+                        transition_from(
+                            &self.storage,
+                            from_address,
+                            &counterparty,
+                            Event::ChannelOpened { channel_id },
+                        )?;
+
+                        self.events.emit(GuacEvent::ChannelOpened {
+                            counterparty: from_address,
+                            channel_id,
+                        });
+
+                        Ok(())
+                    } else {
+                        Err(ProtocolError::ChannelNotOpened.into())
                     }
-                    _ => {
-                        let error = GuacError::WrongState {
-                            correct_state: "Open".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "propose redraw".to_string(),
-                        };
-                        return Box::new(future::err(error.into()))
-                            as Box<Future<Item = Signature, Error = Error>>;
+                }
+                _ => {
+                    let error = ProtocolError::WrongState {
+                        correct_state: "OtherCreating".to_string(),
+                        current_state: format!("{:?}", counterparty.clone()),
+                        action: "notify channel opened".to_string(),
+                    };
+                    Err(error.into())
+                }
+            }
+        }
+        .await;
+
+        self.report_error(from_address, res)
+    }
+
+    async fn notify_re_draw(
+        &self,
+        from_address: Address,
+        _to_url: String,
+        update_tx: Option<UpdateTx>,
+    ) -> Result<Option<UpdateTx>, GuacError> {
+        let res: Result<Option<UpdateTx>, GuacError> = async {
+            let counterparty = check_for_counterparty(&self.storage, from_address)?;
+
+            match counterparty.clone() {
+                Counterparty::OtherReDrawing {
+                    re_draw_tx,
+                    channel,
+                } => {
+                    self.blockchain_client
+                        .check_for_re_draw(channel.channel_id)
+                        .await?;
+
+                    // Payments may have been made while we were waiting for the chain, so the
+                    // reDraw is applied on top of the channel as it is now
+                    self.storage
+                        .update_counterparty(from_address, |counterparty| {
+                            match counterparty.clone() {
+                                Counterparty::OtherReDrawing {
+                                    re_draw_tx: pending,
+                                    ..
+                                } if pending == re_draw_tx => {
+                                    *counterparty =
+                                        transition(counterparty.clone(), Event::ReDrawDone)?;
+                                    Ok::<_, GuacError>(())
+                                }
+                                _ => Err(StorageError::Conflict(from_address).into()),
+                            }
+                        })?;
+
+                    self.events.emit(GuacEvent::ReDrawCompleted {
+                        counterparty: from_address,
+                        re_draw_tx: re_draw_tx.clone(),
+                    });
+
+                    // Those payments add up to the old total, so they are signed again at the
+                    // new one
+                    let crypto = &self.crypto;
+                    let update_tx = self
+                        .sign_update(from_address, |counterparty| {
+                            let channel = open_channel_mut(counterparty, "reconcile redraw")?;
+                            match (channel.reconcile(&re_draw_tx), &update_tx) {
+                                (None, None) => Ok(None),
+                                (Some(reconciled), Some(update_tx)) => {
+                                    forbidden!(
+                                        UpdateTx {
+                                            signature_0: None,
+                                            signature_1: None,
+                                            ..update_tx.clone()
+                                        } == reconciled,
+                                        "This does not match the channel after the redraw"
+                                    );
+                                    check_payment_signature(
+                                        crypto,
+                                        from_address,
+                                        channel,
+                                        update_tx,
+                                    )?;
+                                    Ok(Some(update_tx.clone()))
+                                }
+                                _ => Err(ProtocolError::SequenceNumberDisagreement.into()),
+                            }
+                        })
+                        .await?;
+
+                    if let Some(update_tx) = &update_tx {
+                        self.update_open_channel(from_address, "reconcile redraw", |channel| {
+                            channel.signed_update = Some(update_tx.clone());
+                            Ok(())
+                        })?;
                     }
-                }),
-        )
+                    Ok(update_tx)
+                }
+                _ => {
+                    let error = ProtocolError::WrongState {
+                        correct_state: "OtherReDrawing".to_string(),
+                        current_state: format!("{:?}", counterparty.clone()),
+                        action: "notify redraw".to_string(),
+                    };
+                    Err(error.into())
+                }
+            }
+        }
+        .await;
+
+        self.report_error(from_address, res)
     }
 
-    fn notify_channel_opened(
+    async fn receive_payment(
         &self,
         from_address: Address,
         _to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let storage = self.storage.clone();
-        let blockchain_client = self.blockchain_client.clone();
-        let crypto = self.crypto.clone();
-        Box::new(
-            storage
-                .get_counterparty(from_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| match counterparty.clone() {
-                    Counterparty::OtherCreating {
-                        i_am_0,
-                        new_channel_tx,
+        update_tx: UpdateTx,
+    ) -> Result<UpdateResponse, GuacError> {
+        let res: Result<UpdateResponse, GuacError> = async {
+            // The counterparty can take back the hashlocks it pays once they have expired, which
+            // takes knowing the current block
+            let current_block = match check_for_counterparty(&self.storage, from_address)? {
+                Counterparty::Open { channel }
+                    if channel
+                        .hashlocks
+                        .iter()
+                        .any(|hashlock| hashlock.from_0 != channel.i_am_0) =>
+                {
+                    Some(self.blockchain_client.get_current_block().await?)
+                }
+                _ => None,
+            };
+
+            // What we were paid is what the update adds to our accrual
+            let (current_seq, amount) = self.storage.update_counterparty(
+                from_address,
+                |counterparty| match counterparty {
+                    Counterparty::Open { channel } => {
+                        check_payment_signature(&self.crypto, from_address, channel, &update_tx)?;
+
+                        let old_accrual = channel.accrual.clone();
+                        let current_seq =
+                            channel.receive_update(&update_tx, current_block.as_ref())?;
+                        Ok((current_seq, channel.accrual.clone() - old_accrual))
+                    }
+                    Counterparty::ReDrawing {
+                        channel,
+                        re_draw_tx,
+                    }
+                    | Counterparty::OtherReDrawing {
+                        channel,
+                        re_draw_tx,
                     } => {
-                        let (address_0, address_1) = if i_am_0 {
-                            (crypto.own_address, from_address.clone())
-                        } else {
-                            (from_address.clone(), crypto.own_address)
-                        };
+                        check_payment_signature(&self.crypto, from_address, channel, &update_tx)?;
 
-                        Box::new(
-                            blockchain_client
-                                .check_for_open(&address_0, &address_1)
-                                .and_then(move |maybe_channel_id| {
-                                    if let Some(channel_id) = maybe_channel_id {
-                                        *counterparty = Counterparty::Open {
-                                            channel: Channel {
-                                                channel_id,
-                                                sequence_number: 0u64.into(),
-                                                balance_0: new_channel_tx.balance_0,
-                                                balance_1: new_channel_tx.balance_1,
-                                                i_am_0,
-                                                accrual: 0u64.into(),
-                                            },
-                                        };
-                                        Ok(())
-                                    } else {
-                                        bail!("Cannot confirm that channel was opened");
-                                    }
-                                }),
-                        ) as Box<Future<Item = (), Error = Error>>
+                        let old_accrual = channel.accrual.clone();
+                        let current_seq =
+                            channel.receive_payment_during_re_draw(&update_tx, re_draw_tx)?;
+                        Ok((current_seq, channel.accrual.clone() - old_accrual))
                     }
-                    _ => {
-                        let error = GuacError::WrongState {
-                            correct_state: "OtherCreating".to_string(),
+                    counterparty => {
+                        let error = ProtocolError::WrongState {
+                            correct_state: "Open, ReDrawing or OtherReDrawing".to_string(),
                             current_state: format!("{:?}", counterparty.clone()),
-                            action: "notify channel opened".to_string(),
+                            action: "receive payment".to_string(),
                         };
-                        return Box::new(future::err(error.into()))
-                            as Box<Future<Item = (), Error = Error>>;
+                        Err::<_, GuacError>(error.into())
                     }
-                }),
-        )
+                },
+            )?;
+
+            if let Some(current_seq) = current_seq {
+                self.metrics.record_update_too_old(Direction::Received);
+                return Ok(UpdateResponse::Retry(current_seq));
+            }
+
+            let receipt = self
+                .sign_receipt(from_address, &update_tx, amount.clone())
+                .await?;
+            if amount > 0u64.into() {
+                self.events.emit(GuacEvent::PaymentReceived {
+                    counterparty: from_address,
+                    amount: amount.clone(),
+                    seq: update_tx.sequence_number.clone(),
+                });
+                self.billing.record_received(from_address, amount.clone());
+                self.metrics.record_payment(Direction::Received, &amount);
+                self.ledger.record(
+                    LedgerEntry::new(from_address, Direction::Received, amount, update_tx)
+                        .with_receipt(receipt.clone()),
+                );
+            }
+
+            Ok(UpdateResponse::Accepted(receipt))
+        }
+        .await;
+
+        self.report_error(from_address, res)
     }
 
-    fn notify_re_draw(
+    async fn fulfill_hashlock(
         &self,
         from_address: Address,
         _to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let storage = self.storage.clone();
-        let blockchain_client = self.blockchain_client.clone();
-        Box::new(
-            storage
-                .get_counterparty(from_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| match counterparty.clone() {
-                    Counterparty::OtherReDrawing {
-                        re_draw_tx,
-                        channel,
-                    } => Box::new(
-                        blockchain_client
-                            .check_for_re_draw(channel.channel_id)
-                            .and_then(move |_| {
-                                *counterparty = Counterparty::Open {
-                                    channel: Channel {
-                                        balance_0: re_draw_tx.new_balance_0,
-                                        balance_1: re_draw_tx.new_balance_1,
-                                        sequence_number: re_draw_tx.sequence_number.clone(),
-                                        ..channel
-                                    },
-                                };
-                                Ok(())
-                            }),
-                    ) as Box<Future<Item = (), Error = Error>>,
-                    _ => {
-                        let error = GuacError::WrongState {
-                            correct_state: "OtherReDrawing".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "notify redraw".to_string(),
-                        };
-                        return Box::new(future::err(error.into()))
-                            as Box<Future<Item = (), Error = Error>>;
-                    }
-                }),
-        )
+        preimage: [u8; 32],
+    ) -> Result<UpdateTx, GuacError> {
+        let res: Result<UpdateTx, GuacError> = async {
+            let (hashlock, update_tx) = self
+                .sign_update(from_address, |counterparty| {
+                    Ok(open_channel_mut(counterparty, "fulfill hashlock")?
+                        .fulfill_hashlock(&preimage)?)
+                })
+                .await?;
+
+            self.events.emit(GuacEvent::HashlockFulfilled {
+                counterparty: from_address,
+                hash: hashlock.hash,
+                preimage,
+            });
+            self.events.emit(GuacEvent::PaymentSent {
+                counterparty: from_address,
+                amount: hashlock.amount.clone(),
+                seq: update_tx.sequence_number.clone(),
+            });
+            self.metrics
+                .record_payment(Direction::Sent, &hashlock.amount);
+            self.ledger.record(LedgerEntry::new(
+                from_address,
+                Direction::Sent,
+                hashlock.amount,
+                update_tx.clone(),
+            ));
+
+            self.claim_forwarded(&hashlock.hash, preimage).await;
+
+            Ok(update_tx)
+        }
+        .await;
+
+        self.report_error(from_address, res)
     }
 
-    fn receive_payment(
+    async fn get_info(&self, _to_url: String) -> Result<Vec<NodeInfo>, GuacError> {
+        let mut nodes = vec![self.node_info()];
+        nodes.extend(self.router.graph().nodes().cloned());
+        Ok(nodes)
+    }
+
+    async fn forward_payment(
         &self,
         from_address: Address,
         _to_url: String,
-        update_tx: UpdateTx,
-    ) -> Box<Future<Item = Option<Uint256>, Error = Error>> {
-        let storage = self.storage.clone();
-        let crypto = self.crypto.clone();
-        Box::new(
-            storage
-                .get_counterparty(from_address.clone())
-                .and_then(check_for_counterparty)
-                .and_then(move |mut counterparty| match counterparty.clone() {
-                    Counterparty::Open { mut channel } => {
-                        Box::new(future::ok(()).and_then(move |_| {
-                            let their_signature = if channel.i_am_0 {
-                                update_tx.clone().signature_1
-                            } else {
-                                update_tx.clone().signature_0
-                            };
-
-                            let their_signature = match their_signature {
-                                Some(sig) => sig,
-                                None => {
-                                    return Err(GuacError::Forbidden {
-                                        message: "No signature supplied".into(),
-                                    }
-                                    .into())
-                                }
-                            };
+        forward: Forward,
+    ) -> Result<(), GuacError> {
+        let res = self.handle_forward(from_address, forward).await;
+        self.report_error(from_address, res)
+    }
+}
 
-                            let fingerprint =
-                                update_tx.clone().fingerprint(crypto.contract_address);
+/// Checks that a proposed channel is between us and the counterparty who proposed it
+fn check_new_channel_tx(
+    i_am_0: bool,
+    my_address: Address,
+    from_address: Address,
+    new_channel_tx: &NewChannelTx,
+) -> Result<(), GuacError> {
+    let NewChannelTx {
+        address_0,
+        address_1,
+        ..
+    } = new_channel_tx.clone();
+
+    if i_am_0 {
+        forbidden!(
+            address_0 == my_address,
+            format!(
+                "Address 0 ({}) should equal my address ({})",
+                address_0.to_string(),
+                my_address.to_string()
+            )
+        );
+        forbidden!(
+            address_1 == from_address,
+            format!(
+                "Address 1 ({}) should equal your address ({})",
+                address_1.to_string(),
+                from_address.to_string()
+            )
+        );
+    } else {
+        forbidden!(
+            address_1 == my_address,
+            format!(
+                "Address 1 ({}) should equal my address ({})",
+                address_1.to_string(),
+                my_address.to_string()
+            )
+        );
+        forbidden!(
+            address_0 == from_address,
+            format!(
+                "Address 0 ({}) should equal your address ({})",
+                address_0.to_string(),
+                from_address.to_string()
+            )
+        );
+    }
 
-                            let recovered_address = their_signature.recover(&fingerprint)?;
+    Ok(())
+}
 
-                            if recovered_address != from_address {
-                                return Err(GuacError::Forbidden {
-                                    message: "Your signature is incorrect".into(),
-                                }
-                                .into());
-                            }
+/// Checks that a payment update was signed by the counterparty who sent it
+fn check_payment_signature(
+    crypto: &Crypto,
+    from_address: Address,
+    channel: &Channel,
+    update_tx: &UpdateTx,
+) -> Result<(), GuacError> {
+    let their_signature = if channel.i_am_0 {
+        update_tx.signature_1.clone()
+    } else {
+        update_tx.signature_0.clone()
+    };
 
-                            let maybe_seq = channel.receive_payment(&update_tx)?;
+    let their_signature = match their_signature {
+        Some(sig) => sig,
+        None => {
+            return Err(ProtocolError::Forbidden {
+                message: "No signature supplied".into(),
+            }
+            .into())
+        }
+    };
 
-                            *counterparty = Counterparty::Open { channel };
+    let fingerprint = crypto.fingerprint(update_tx);
 
-                            Ok(maybe_seq)
-                        }))
-                            as Box<Future<Item = Option<Uint256>, Error = Error>>
-                    }
-                    _ => {
-                        let error = GuacError::WrongState {
-                            correct_state: "Open".to_string(),
-                            current_state: format!("{:?}", counterparty.clone()),
-                            action: "receive payment".to_string(),
-                        };
-                        return Box::new(future::err(error.into()))
-                            as Box<Future<Item = Option<Uint256>, Error = Error>>;
-                    }
-                }),
-        )
+    let recovered_address =
+        their_signature
+            .recover(&fingerprint)
+            .map_err(|_| ProtocolError::Forbidden {
+                message: "Your signature is invalid".into(),
+            })?;
+
+    if recovered_address != from_address {
+        return Err(ProtocolError::Forbidden {
+            message: "Your signature is incorrect".into(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::{Decision, DefaultProposalPolicy, ProposalPolicy};
+    use crate::storage::{PeerFilter, Storage};
+    use crate::test_utils::{
+        make_node, make_node_with_storage, make_pair, MockChain, MockNetwork, TestNode, SECRET_0,
+        SECRET_1,
+    };
+    use futures::executor::block_on;
+    use futures::future;
+    use num256::Uint256;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    /// Returns the node with the lower address first, which wins when proposals cross
+    fn winner_and_loser() -> (TestNode, TestNode) {
+        let (node_0, node_1) = make_pair();
+        if node_0.address < node_1.address {
+            (node_0, node_1)
+        } else {
+            (node_1, node_0)
+        }
+    }
+
+    fn assert_opened_by(winner: &TestNode, loser: &TestNode, amount: u64) {
+        block_on(async {
+            assert_eq!(
+                winner.guac.check_my_balance(loser.address).await.unwrap(),
+                amount.into()
+            );
+            assert_eq!(
+                loser.guac.check_my_balance(winner.address).await.unwrap(),
+                0u64.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_simultaneous_open_loser_proposal_arrives_first() {
+        let (winner, loser) = winner_and_loser();
+
+        block_on(async {
+            let release = winner.network.hold_next_message();
+            let winner_fill =
+                winner
+                    .guac
+                    .fill_channel(loser.address, loser.url.clone(), 100u64.into());
+            let loser_fill = async {
+                // The winner is already creating its channel and turns this one down
+                match loser
+                    .guac
+                    .fill_channel(winner.address, winner.url.clone(), 50u64.into())
+                    .await
+                {
+                    Err(GuacError::Protocol(ProtocolError::SimultaneousOpen)) => {}
+                    res => panic!("unexpected result {:?}", res),
+                }
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(winner_fill, loser_fill).await;
+            res.unwrap();
+        });
+
+        assert_opened_by(&winner, &loser, 100);
+    }
+
+    #[test]
+    fn test_simultaneous_open_winner_proposal_arrives_first() {
+        let (winner, loser) = winner_and_loser();
+
+        block_on(async {
+            let release = loser.network.hold_next_message();
+            let loser_fill =
+                loser
+                    .guac
+                    .fill_channel(winner.address, winner.url.clone(), 50u64.into());
+            let winner_fill = async {
+                // The loser abandons its own proposal and signs this one
+                winner
+                    .guac
+                    .fill_channel(loser.address, loser.url.clone(), 100u64.into())
+                    .await
+                    .unwrap();
+                release.send(()).unwrap();
+            };
+            match future::join(loser_fill, winner_fill).await {
+                (Err(GuacError::Protocol(ProtocolError::SimultaneousOpen)), ()) => {}
+                (res, ()) => panic!("unexpected result {:?}", res),
+            }
+        });
+
+        assert_opened_by(&winner, &loser, 100);
+    }
+
+    #[test]
+    fn test_signed_proposal_is_not_abandoned() {
+        let (winner, loser) = winner_and_loser();
+
+        block_on(async {
+            // The loser's proposal was signed and is waiting on the chain, a proposal from the
+            // winner cannot replace it anymore
+            let release = loser.blockchain.hold_next_transaction();
+            let loser_fill =
+                loser
+                    .guac
+                    .fill_channel(winner.address, winner.url.clone(), 50u64.into());
+            let winner_fill = async {
+                match winner
+                    .guac
+                    .fill_channel(loser.address, loser.url.clone(), 100u64.into())
+                    .await
+                {
+                    Err(GuacError::Protocol(ProtocolError::TryAgainLater)) => {}
+                    res => panic!("unexpected result {:?}", res),
+                }
+                match loser
+                    .guac
+                    .propose_channel(
+                        winner.address,
+                        loser.url.clone(),
+                        NewChannelTx {
+                            address_0: winner.address,
+                            address_1: loser.address,
+                            balance_0: 100u64.into(),
+                            balance_1: 0u64.into(),
+                            expiration: 0u64.into(),
+                            settling_period_length: 5000u64.into(),
+                            signature_0: None,
+                            signature_1: None,
+                        },
+                    )
+                    .await
+                {
+                    Err(GuacError::Protocol(ProtocolError::WrongState { .. })) => {}
+                    res => panic!("unexpected result {:?}", res),
+                }
+                release.send(()).unwrap();
+            };
+            let (res, ()) = future::join(loser_fill, winner_fill).await;
+            res.unwrap();
+        });
+
+        assert_opened_by(&loser, &winner, 50);
+    }
+
+    #[test]
+    fn test_rejected_peer_is_not_stored() {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let stranger = make_node(&network, &chain, SECRET_0, "stranger");
+        let denied: HashSet<Address> = vec![stranger.address].into_iter().collect();
+        let node = make_node_with_storage(
+            &network,
+            &chain,
+            SECRET_1,
+            "node",
+            Storage::new().with_peer_filter(PeerFilter::Deny(denied)),
+        );
+
+        block_on(async {
+            match stranger
+                .guac
+                .fill_channel(node.address, node.url.clone(), 100u64.into())
+                .await
+            {
+                Err(GuacError::Storage(StorageError::PeerRejected(_))) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert!(node.guac.get_state(stranger.address).await.is_err());
+            match stranger.guac.get_state(node.address).await.unwrap() {
+                Counterparty::New { .. } => {}
+                state => panic!("unexpected state {:?}", state),
+            }
+        });
+    }
+
+    /// Only signs channels into which the counterparty puts at least the given amount
+    struct MinimumDeposit(u64);
+
+    impl ProposalPolicy for MinimumDeposit {
+        fn check_channel(
+            &self,
+            from_address: Address,
+            i_am_0: bool,
+            new_channel_tx: &NewChannelTx,
+        ) -> Decision {
+            let their_balance = if i_am_0 {
+                &new_channel_tx.balance_1
+            } else {
+                &new_channel_tx.balance_0
+            };
+            if *their_balance < Uint256::from(self.0) {
+                return Decision::reject(format!("Deposit at least {}", self.0));
+            }
+            DefaultProposalPolicy.check_channel(from_address, i_am_0, new_channel_tx)
+        }
+
+        fn check_re_draw(
+            &self,
+            from_address: Address,
+            channel: &Channel,
+            re_draw_tx: &ReDrawTx,
+        ) -> Decision {
+            DefaultProposalPolicy.check_re_draw(from_address, channel, re_draw_tx)
+        }
+    }
+
+    #[test]
+    fn test_custom_proposal_policy() {
+        let (node_0, node_1) = make_pair();
+        let guac = Guac {
+            proposal_policy: Arc::new(Box::new(MinimumDeposit(100))),
+            ..node_1.guac.clone()
+        };
+        node_1.network.register(&node_1.url, guac);
+
+        block_on(async {
+            match node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 50u64.into())
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::Forbidden { message })) => {
+                    assert_eq!(message, "Deposit at least 100")
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+        });
     }
 }
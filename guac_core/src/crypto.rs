@@ -1,17 +1,58 @@
-use clarity::{Address, PrivateKey, Signature};
+use crate::eip712::{FingerprintScheme, Signable};
+use crate::error::SignerError;
+use crate::signer::Signer;
+use clarity::{Address, Signature};
 use num256::uint256::Uint256;
 use sha3::{Digest, Keccak256};
+use std::fmt;
+use std::sync::Arc;
 
-#[derive(Default)]
 pub struct Crypto {
     pub contract_address: Address,
     pub own_address: Address,
-    pub secret: PrivateKey,
+    pub signer: Arc<dyn Signer + Send + Sync>,
+    pub fingerprints: FingerprintScheme,
 }
 
 impl Crypto {
-    pub fn eth_sign(&self, data: &[u8]) -> Signature {
-        self.secret.sign_hash(data)
+    /// Our address is the one of the signer's key
+    pub fn new(contract_address: Address, signer: Arc<dyn Signer + Send + Sync>) -> Crypto {
+        Crypto {
+            contract_address,
+            own_address: signer.address(),
+            signer,
+            fingerprints: FingerprintScheme::Plain,
+        }
+    }
+
+    /// Hashes messages with `fingerprints` instead of the plain fingerprints
+    pub fn with_fingerprints(mut self, fingerprints: FingerprintScheme) -> Self {
+        self.fingerprints = fingerprints;
+        self
+    }
+
+    /// What we and the counterparty sign for `message`
+    pub fn fingerprint<T: Signable>(&self, message: &T) -> [u8; 32] {
+        self.fingerprints
+            .fingerprint(self.contract_address, message)
+    }
+
+    /// Signs the fingerprint of `message`, as typed data if that is how it is hashed
+    pub async fn sign<T: Signable>(&self, message: &T) -> Result<Signature, SignerError> {
+        match self.fingerprints.typed_data(self.contract_address, message) {
+            Some(typed_data) => self.signer.sign_typed_data(&typed_data).await,
+            None => self.signer.sign_hash(&self.fingerprint(message)).await,
+        }
+    }
+}
+
+impl fmt::Debug for Crypto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Crypto")
+            .field("contract_address", &self.contract_address)
+            .field("own_address", &self.own_address)
+            .field("fingerprints", &self.fingerprints)
+            .finish()
     }
 }
 
@@ -0,0 +1,736 @@
+//! Fingerprints as EIP-712 typed data.
+//!
+//! The plain fingerprints of channel messages hash their fields after the name of the function,
+//! so a signer only ever sees 32 opaque bytes. With `FingerprintScheme::Eip712` messages are
+//! hashed as typed data instead, under a domain of the contract, the chain and the version of
+//! Guac. The signer gets them as `TypedData`, so signers which know EIP-712 can show what they
+//! sign, and a signature can not be replayed on another chain or contract.
+//!
+//! Receipts never go to the contract, so they are always hashed as typed data under this scheme.
+//! Channel openings, reDraws and updates are checked by the contract, which has to verify the
+//! same digest as the one that was signed. The contract as it is only knows the plain
+//! fingerprints, so these messages are only hashed as typed data when `channel_messages` is set,
+//! which needs a contract that checks their EIP-712 digests. Both sides of a channel have to use
+//! the same scheme.
+
+use crate::crypto;
+use crate::receipt::Receipt;
+use crate::types::{Hashlock, NewChannelTx, ReDrawTx, UpdateTx};
+use clarity::Address;
+use num256::Uint256;
+use serde_json::{json, Map, Value};
+
+/// The name of the EIP-712 domain
+pub const DOMAIN_NAME: &str = "Guac";
+
+/// The version of the EIP-712 domain, which changes whenever one of the types below does
+pub const DOMAIN_VERSION: &str = "1";
+
+/// The fields of an EIP-712 type, as names and types
+type Fields = &'static [(&'static str, &'static str)];
+
+const DOMAIN_FIELDS: Fields = &[
+    ("name", "string"),
+    ("version", "string"),
+    ("chainId", "uint256"),
+    ("verifyingContract", "address"),
+];
+
+const NEW_CHANNEL_FIELDS: Fields = &[
+    ("address0", "address"),
+    ("address1", "address"),
+    ("balance0", "uint256"),
+    ("balance1", "uint256"),
+    ("expiration", "uint256"),
+    ("settlingPeriodLength", "uint256"),
+];
+
+const RE_DRAW_FIELDS: Fields = &[
+    ("channelId", "bytes32"),
+    ("sequenceNumber", "uint256"),
+    ("oldBalance0", "uint256"),
+    ("oldBalance1", "uint256"),
+    ("newBalance0", "uint256"),
+    ("newBalance1", "uint256"),
+    ("expiration", "uint256"),
+];
+
+const UPDATE_FIELDS: Fields = &[
+    ("channelId", "bytes32"),
+    ("sequenceNumber", "uint256"),
+    ("balance0", "uint256"),
+    ("balance1", "uint256"),
+    ("hashlocks", "Hashlock[]"),
+];
+
+const HASHLOCK_FIELDS: Fields = &[
+    ("hash", "bytes32"),
+    ("amount", "uint256"),
+    ("expiration", "uint256"),
+    ("from0", "bool"),
+];
+
+const RECEIPT_FIELDS: Fields = &[
+    ("channelId", "bytes32"),
+    ("sequenceNumber", "uint256"),
+    ("amount", "uint256"),
+    ("payer", "address"),
+    ("payee", "address"),
+];
+
+/// How the messages of a channel are hashed before they are signed
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum FingerprintScheme {
+    /// The fingerprints the contract has always known
+    #[default]
+    Plain,
+    /// EIP-712 typed data, for the chain with id `chain_id`. Channel openings, reDraws and
+    /// updates keep their plain fingerprints unless `channel_messages` is set, which only works
+    /// with a contract that verifies their EIP-712 digests.
+    Eip712 {
+        chain_id: Uint256,
+        channel_messages: bool,
+    },
+}
+
+impl FingerprintScheme {
+    /// What is signed for `message` in the contract at `contract_address`
+    pub fn fingerprint<T: Signable>(&self, contract_address: Address, message: &T) -> [u8; 32] {
+        match self.typed_data(contract_address, message) {
+            Some(typed_data) => typed_data.hash(),
+            None => message.fingerprint(contract_address),
+        }
+    }
+
+    /// `message` as the typed data which is signed for it, unless it is hashed plainly
+    pub fn typed_data<T: Signable>(
+        &self,
+        contract_address: Address,
+        message: &T,
+    ) -> Option<TypedData> {
+        match self {
+            FingerprintScheme::Plain => None,
+            FingerprintScheme::Eip712 {
+                chain_id,
+                channel_messages,
+            } => {
+                if message.checked_by_contract() && !channel_messages {
+                    return None;
+                }
+                Some(TypedData {
+                    chain_id: chain_id.clone(),
+                    verifying_contract: contract_address,
+                    message: message.typed_message(),
+                })
+            }
+        }
+    }
+}
+
+/// A message which is signed by one or both sides of a channel
+pub trait Signable {
+    /// The fingerprint of `FingerprintScheme::Plain`
+    fn fingerprint(&self, contract_address: Address) -> [u8; 32];
+
+    /// The message as EIP-712 typed data, without its signatures
+    fn typed_message(&self) -> TypedMessage;
+
+    /// Whether the contract checks the signatures of the message
+    fn checked_by_contract(&self) -> bool {
+        true
+    }
+}
+
+/// The messages which are signed as typed data with `FingerprintScheme::Eip712`, without their
+/// signatures
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TypedMessage {
+    NewChannel(NewChannelTx),
+    ReDraw(ReDrawTx),
+    Update(UpdateTx),
+    Receipt(Receipt),
+}
+
+impl TypedMessage {
+    /// The EIP-712 type of the message, followed by the types its fields refer to
+    fn types(&self) -> &'static [(&'static str, Fields)] {
+        match self {
+            TypedMessage::NewChannel(_) => &[("NewChannel", NEW_CHANNEL_FIELDS)],
+            TypedMessage::ReDraw(_) => &[("ReDraw", RE_DRAW_FIELDS)],
+            TypedMessage::Update(_) => &[("Update", UPDATE_FIELDS), ("Hashlock", HASHLOCK_FIELDS)],
+            TypedMessage::Receipt(_) => &[("Receipt", RECEIPT_FIELDS)],
+        }
+    }
+
+    /// The `hashStruct` of the message
+    fn struct_hash(&self) -> [u8; 32] {
+        let types = self.types();
+        match self {
+            TypedMessage::NewChannel(new_channel_tx) => hash_struct(
+                types,
+                &[
+                    address(&new_channel_tx.address_0),
+                    address(&new_channel_tx.address_1),
+                    uint(&new_channel_tx.balance_0),
+                    uint(&new_channel_tx.balance_1),
+                    uint(&new_channel_tx.expiration),
+                    uint(&new_channel_tx.settling_period_length),
+                ],
+            ),
+            TypedMessage::ReDraw(re_draw_tx) => hash_struct(
+                types,
+                &[
+                    re_draw_tx.channel_id,
+                    uint(&re_draw_tx.sequence_number),
+                    uint(&re_draw_tx.old_balance_0),
+                    uint(&re_draw_tx.old_balance_1),
+                    uint(&re_draw_tx.new_balance_0),
+                    uint(&re_draw_tx.new_balance_1),
+                    uint(&re_draw_tx.expiration),
+                ],
+            ),
+            TypedMessage::Update(update_tx) => {
+                // An array is encoded as the hash of the `hashStruct`s of its members
+                let hashlocks: Vec<[u8; 32]> =
+                    update_tx.hashlocks.iter().map(hashlock_hash).collect();
+                let hashlocks: Vec<&[u8]> = hashlocks.iter().map(|hash| &hash[..]).collect();
+                hash_struct(
+                    types,
+                    &[
+                        update_tx.channel_id,
+                        uint(&update_tx.sequence_number),
+                        uint(&update_tx.balance_0),
+                        uint(&update_tx.balance_1),
+                        crypto::hash_bytes(&hashlocks).into(),
+                    ],
+                )
+            }
+            TypedMessage::Receipt(receipt) => hash_struct(
+                types,
+                &[
+                    receipt.channel_id,
+                    uint(&receipt.sequence_number),
+                    uint(&receipt.amount),
+                    address(&receipt.payer),
+                    address(&receipt.payee),
+                ],
+            ),
+        }
+    }
+
+    /// The fields of the message as `eth_signTypedData_v4` takes them
+    fn to_json(&self) -> Value {
+        match self {
+            TypedMessage::NewChannel(new_channel_tx) => json!({
+                "address0": new_channel_tx.address_0.to_string(),
+                "address1": new_channel_tx.address_1.to_string(),
+                "balance0": new_channel_tx.balance_0.to_string(),
+                "balance1": new_channel_tx.balance_1.to_string(),
+                "expiration": new_channel_tx.expiration.to_string(),
+                "settlingPeriodLength": new_channel_tx.settling_period_length.to_string(),
+            }),
+            TypedMessage::ReDraw(re_draw_tx) => json!({
+                "channelId": bytes32_json(&re_draw_tx.channel_id),
+                "sequenceNumber": re_draw_tx.sequence_number.to_string(),
+                "oldBalance0": re_draw_tx.old_balance_0.to_string(),
+                "oldBalance1": re_draw_tx.old_balance_1.to_string(),
+                "newBalance0": re_draw_tx.new_balance_0.to_string(),
+                "newBalance1": re_draw_tx.new_balance_1.to_string(),
+                "expiration": re_draw_tx.expiration.to_string(),
+            }),
+            TypedMessage::Update(update_tx) => json!({
+                "channelId": bytes32_json(&update_tx.channel_id),
+                "sequenceNumber": update_tx.sequence_number.to_string(),
+                "balance0": update_tx.balance_0.to_string(),
+                "balance1": update_tx.balance_1.to_string(),
+                "hashlocks": update_tx.hashlocks.iter().map(hashlock_json).collect::<Value>(),
+            }),
+            TypedMessage::Receipt(receipt) => json!({
+                "channelId": bytes32_json(&receipt.channel_id),
+                "sequenceNumber": receipt.sequence_number.to_string(),
+                "amount": receipt.amount.to_string(),
+                "payer": receipt.payer.to_string(),
+                "payee": receipt.payee.to_string(),
+            }),
+        }
+    }
+}
+
+/// A message as EIP-712 typed data, with the domain it is signed in. This is what a `Signer`
+/// gets to sign, so that it can show the message rather than only its hash.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TypedData {
+    pub chain_id: Uint256,
+    pub verifying_contract: Address,
+    pub message: TypedMessage,
+}
+
+impl TypedData {
+    /// What is signed for the typed data
+    pub fn hash(&self) -> [u8; 32] {
+        // The version byte 0x01 of EIP-191 is for typed data
+        let prefix: &[u8] = &[0x19, 0x01];
+        let domain_separator = domain_separator(&self.chain_id, self.verifying_contract);
+        crypto::hash_bytes(&[
+            prefix,
+            &domain_separator[..],
+            &self.message.struct_hash()[..],
+        ])
+        .into()
+    }
+
+    /// The typed data in the JSON which `eth_signTypedData_v4` takes, for wallets and signers
+    /// which show what they sign
+    pub fn to_json(&self) -> Value {
+        let message_types = self.message.types();
+        let mut types = Map::new();
+        types.insert("EIP712Domain".to_string(), fields_json(DOMAIN_FIELDS));
+        for (name, fields) in message_types {
+            types.insert(name.to_string(), fields_json(fields));
+        }
+
+        json!({
+            "types": types,
+            "primaryType": message_types[0].0,
+            "domain": {
+                "name": DOMAIN_NAME,
+                "version": DOMAIN_VERSION,
+                "chainId": self.chain_id.to_string(),
+                "verifyingContract": self.verifying_contract.to_string(),
+            },
+            "message": self.message.to_json(),
+        })
+    }
+}
+
+pub fn domain_separator(chain_id: &Uint256, contract_address: Address) -> [u8; 32] {
+    hash_struct(
+        &[("EIP712Domain", DOMAIN_FIELDS)],
+        &[
+            keccak(DOMAIN_NAME.as_bytes()),
+            keccak(DOMAIN_VERSION.as_bytes()),
+            uint(chain_id),
+            address(&contract_address),
+        ],
+    )
+}
+
+fn fields_json(fields: Fields) -> Value {
+    fields
+        .iter()
+        .map(|(name, type_)| json!({ "name": name, "type": type_ }))
+        .collect()
+}
+
+fn bytes32_json(bytes: &[u8; 32]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+fn hashlock_json(hashlock: &Hashlock) -> Value {
+    json!({
+        "hash": bytes32_json(&hashlock.hash),
+        "amount": hashlock.amount.to_string(),
+        "expiration": hashlock.expiration.to_string(),
+        "from0": hashlock.from_0,
+    })
+}
+
+/// The `encodeType` of a type, followed by the types its fields refer to
+fn encode_type(types: &[(&str, Fields)]) -> String {
+    types
+        .iter()
+        .map(|(name, fields)| {
+            let fields: Vec<String> = fields
+                .iter()
+                .map(|(name, type_)| format!("{} {}", type_, name))
+                .collect();
+            format!("{}({})", name, fields.join(","))
+        })
+        .collect()
+}
+
+fn keccak(bytes: &[u8]) -> [u8; 32] {
+    crypto::hash_bytes(&[bytes]).into()
+}
+
+fn uint(value: &Uint256) -> [u8; 32] {
+    value.clone().into()
+}
+
+fn address(address: &Address) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address.as_bytes());
+    word
+}
+
+fn hash_struct(types: &[(&str, Fields)], words: &[[u8; 32]]) -> [u8; 32] {
+    let type_hash = keccak(encode_type(types).as_bytes());
+    let mut data: Vec<&[u8]> = vec![&type_hash[..]];
+    data.extend(words.iter().map(|word| &word[..]));
+    crypto::hash_bytes(&data).into()
+}
+
+fn hashlock_hash(hashlock: &Hashlock) -> [u8; 32] {
+    let mut from_0 = [0u8; 32];
+    from_0[31] = hashlock.from_0 as u8;
+    hash_struct(
+        &[("Hashlock", HASHLOCK_FIELDS)],
+        &[
+            hashlock.hash,
+            uint(&hashlock.amount),
+            uint(&hashlock.expiration),
+            from_0,
+        ],
+    )
+}
+
+impl Signable for NewChannelTx {
+    fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
+        NewChannelTx::fingerprint(self, contract_address)
+    }
+
+    fn typed_message(&self) -> TypedMessage {
+        TypedMessage::NewChannel(NewChannelTx {
+            signature_0: None,
+            signature_1: None,
+            ..self.clone()
+        })
+    }
+}
+
+impl Signable for ReDrawTx {
+    fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
+        ReDrawTx::fingerprint(self, contract_address)
+    }
+
+    fn typed_message(&self) -> TypedMessage {
+        TypedMessage::ReDraw(ReDrawTx {
+            signature_0: None,
+            signature_1: None,
+            ..self.clone()
+        })
+    }
+}
+
+impl Signable for UpdateTx {
+    fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
+        UpdateTx::fingerprint(self, contract_address)
+    }
+
+    fn typed_message(&self) -> TypedMessage {
+        TypedMessage::Update(UpdateTx {
+            signature_0: None,
+            signature_1: None,
+            ..self.clone()
+        })
+    }
+}
+
+impl Signable for Receipt {
+    fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
+        Receipt::fingerprint(self, contract_address)
+    }
+
+    fn typed_message(&self) -> TypedMessage {
+        TypedMessage::Receipt(Receipt {
+            signature: None,
+            ..self.clone()
+        })
+    }
+
+    /// Receipts are only checked by the counterparty and whoever they are shown to
+    fn checked_by_contract(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+    use crate::error::{GuacError, ProtocolError};
+    use crate::ledger::LedgerQuery;
+    use crate::test_utils::{make_pair, TestNode};
+    use clarity::Signature;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    fn use_eip712(node: &mut TestNode, chain_id: u64, channel_messages: bool) {
+        let crypto = Crypto::new(Address::default(), node.guac.crypto.signer.clone())
+            .with_fingerprints(eip712(chain_id, channel_messages));
+        node.guac.crypto = Arc::new(Box::new(crypto));
+        node.network.register(&node.url, node.guac.clone());
+    }
+
+    fn eip712(chain_id: u64, channel_messages: bool) -> FingerprintScheme {
+        FingerprintScheme::Eip712 {
+            chain_id: chain_id.into(),
+            channel_messages,
+        }
+    }
+
+    fn receipt() -> Receipt {
+        Receipt {
+            channel_id: [1; 32],
+            sequence_number: 2u64.into(),
+            amount: 3u64.into(),
+            payer: "0x0202020202020202020202020202020202020202"
+                .parse()
+                .unwrap(),
+            payee: "0x0303030303030303030303030303030303030303"
+                .parse()
+                .unwrap(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn test_domain_separation() {
+        let contract_0 = Address::default();
+        let contract_1: Address = "0x0101010101010101010101010101010101010101"
+            .parse()
+            .unwrap();
+
+        let plain = FingerprintScheme::Plain.fingerprint(contract_0, &receipt());
+        assert_eq!(plain, receipt().fingerprint(contract_0));
+
+        let typed = eip712(1, false).fingerprint(contract_0, &receipt());
+        assert_ne!(typed, plain);
+        assert_ne!(typed, eip712(2, false).fingerprint(contract_0, &receipt()));
+        assert_ne!(typed, eip712(1, false).fingerprint(contract_1, &receipt()));
+        assert_eq!(typed, eip712(1, true).fingerprint(contract_0, &receipt()));
+    }
+
+    fn update_tx() -> UpdateTx {
+        UpdateTx {
+            channel_id: [1; 32],
+            sequence_number: 2u64.into(),
+            balance_0: 3u64.into(),
+            balance_1: 4u64.into(),
+            hashlocks: vec![Hashlock {
+                hash: [5; 32],
+                amount: 6u64.into(),
+                expiration: 7u64.into(),
+                from_0: true,
+            }],
+            signature_0: Some(Signature::new(27u64.into(), 1u64.into(), 1u64.into())),
+            signature_1: None,
+        }
+    }
+
+    /// What the contract checks is hashed the way the contract does, unless the channel
+    /// messages are asked for as typed data
+    #[test]
+    fn test_contract_messages_stay_plain() {
+        let contract = Address::default();
+        assert_eq!(
+            eip712(1, false).fingerprint(contract, &update_tx()),
+            update_tx().fingerprint(contract)
+        );
+        assert!(eip712(1, false)
+            .typed_data(contract, &update_tx())
+            .is_none());
+    }
+
+    #[test]
+    fn test_channel_messages() {
+        let contract = Address::default();
+        let typed_data = eip712(1, true).typed_data(contract, &update_tx()).unwrap();
+        assert_eq!(
+            typed_data.hash(),
+            eip712(1, true).fingerprint(contract, &update_tx())
+        );
+        assert_ne!(typed_data.hash(), update_tx().fingerprint(contract));
+        assert_ne!(
+            typed_data.hash(),
+            eip712(2, true).fingerprint(contract, &update_tx())
+        );
+
+        // The signatures are not part of what is signed
+        let unsigned = UpdateTx {
+            signature_0: None,
+            ..update_tx()
+        };
+        assert_eq!(typed_data.message, TypedMessage::Update(unsigned.clone()));
+
+        // Every hashlock is part of what is signed
+        let without_hashlocks = UpdateTx {
+            hashlocks: Vec::new(),
+            ..unsigned
+        };
+        assert_ne!(
+            typed_data.hash(),
+            eip712(1, true).fingerprint(contract, &without_hashlocks)
+        );
+
+        let json = typed_data.to_json();
+        assert_eq!(json["primaryType"], "Update");
+        assert_eq!(json["types"]["Hashlock"][3]["name"], "from0");
+        assert_eq!(json["message"]["hashlocks"][0]["amount"], "6");
+        assert_eq!(json["message"]["hashlocks"][0]["from0"], true);
+        assert_eq!(
+            encode_type(TypedMessage::Update(update_tx()).types()),
+            "Update(bytes32 channelId,uint256 sequenceNumber,uint256 balance0,uint256 balance1,\
+Hashlock[] hashlocks)Hashlock(bytes32 hash,uint256 amount,uint256 expiration,bool from0)"
+        );
+    }
+
+    #[test]
+    fn test_typed_data() {
+        let contract: Address = "0x0101010101010101010101010101010101010101"
+            .parse()
+            .unwrap();
+        let typed_data = eip712(5, false).typed_data(contract, &receipt()).unwrap();
+        assert_eq!(
+            typed_data.hash(),
+            eip712(5, false).fingerprint(contract, &receipt())
+        );
+
+        // The signature is not part of what is signed
+        let signed = Receipt {
+            signature: Some(Signature::new(27u64.into(), 1u64.into(), 1u64.into())),
+            ..receipt()
+        };
+        assert_eq!(
+            eip712(5, false).typed_data(contract, &signed).unwrap(),
+            typed_data
+        );
+
+        let json = typed_data.to_json();
+        assert_eq!(json["primaryType"], "Receipt");
+        assert_eq!(json["domain"]["name"], DOMAIN_NAME);
+        assert_eq!(json["domain"]["chainId"], "5");
+        assert_eq!(json["message"]["amount"], "3");
+        assert_eq!(json["types"]["Receipt"][0]["name"], "channelId");
+        assert_eq!(json["types"]["Receipt"][0]["type"], "bytes32");
+        assert_eq!(
+            encode_type(&[("Receipt", RECEIPT_FIELDS)]),
+            "Receipt(bytes32 channelId,uint256 sequenceNumber,uint256 amount,address payer,\
+address payee)"
+        );
+    }
+
+    #[test]
+    fn test_eip712_payments() {
+        let (mut node_0, mut node_1) = make_pair();
+        use_eip712(&mut node_0, 1, false);
+        use_eip712(&mut node_1, 1, false);
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            node_0
+                .guac
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                node_1.guac.check_accrual(node_0.address).await.unwrap(),
+                10u64.into()
+            );
+
+            // The receipts are checked under the same domain
+            let receipts = node_0.guac.receipts(node_1.address, crate::Direction::Sent);
+            let crypto = &node_0.guac.crypto;
+            assert!(crate::verify_receipts(
+                crypto.contract_address,
+                &crypto.fingerprints,
+                node_0.address,
+                node_1.address,
+                &receipts
+            )
+            .is_ok());
+            assert!(crate::verify_receipts(
+                crypto.contract_address,
+                &FingerprintScheme::Plain,
+                node_0.address,
+                node_1.address,
+                &receipts
+            )
+            .is_err());
+        });
+    }
+
+    #[test]
+    fn test_schemes_must_match() {
+        let (mut node_0, mut node_1) = make_pair();
+        use_eip712(&mut node_0, 1, false);
+        use_eip712(&mut node_1, 2, false);
+
+        block_on(async {
+            // The channel is opened with the fingerprints of the contract, but the receipts are
+            // signed under another domain
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            match node_0
+                .guac
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::InvalidReceipt { .. })) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+        });
+    }
+
+    /// With a contract which checks typed data, the whole channel is signed as typed data
+    #[test]
+    fn test_eip712_channel_messages() {
+        let (mut node_0, mut node_1) = make_pair();
+        use_eip712(&mut node_0, 1, true);
+        use_eip712(&mut node_1, 1, true);
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            node_0
+                .guac
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                node_1.guac.check_accrual(node_0.address).await.unwrap(),
+                10u64.into()
+            );
+
+            // The payer signed the update as typed data
+            let update_tx = node_1.guac.ledger.query(&LedgerQuery::new())[0]
+                .update_tx
+                .clone();
+            let hash = eip712(1, true)
+                .typed_data(Address::default(), &update_tx)
+                .unwrap()
+                .hash();
+            let signature = update_tx.signature_0.or(update_tx.signature_1).unwrap();
+            assert_eq!(signature.recover(&hash).unwrap(), node_0.address);
+        });
+    }
+
+    #[test]
+    fn test_channel_messages_must_match() {
+        let (mut node_0, mut node_1) = make_pair();
+        use_eip712(&mut node_0, 1, true);
+        use_eip712(&mut node_1, 1, false);
+
+        block_on(async {
+            match node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::BadSignature)) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+        });
+    }
+}
@@ -0,0 +1,211 @@
+//! Errors returned by Guac, one enum per layer.
+//!
+//! `GuacError` is what the public API returns. It wraps the error of the layer which failed, so
+//! callers can match on the cause without parsing messages, and `source()` leads down to the
+//! original error of a full node or HTTP client.
+
+use clarity::Address;
+use num256::Uint256;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GuacError {
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+
+    #[error(transparent)]
+    Blockchain(#[from] BlockchainError),
+
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+}
+
+/// Something in a message or the state of a counterparty is not allowed by the protocol
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Guac is currently waiting on another operation to complete. Try again later.")]
+    TryAgainLater,
+
+    #[error(
+        "Cannot {action} in the current state: {current_state}. State must be: {correct_state}"
+    )]
+    WrongState {
+        action: String,
+        current_state: String,
+        correct_state: String,
+    },
+
+    #[error("Invalid request: {message}")]
+    Forbidden { message: String },
+
+    #[error("Update too old. Correct sequence number: {correct_seq}")]
+    UpdateTooOld { correct_seq: Uint256 },
+
+    #[error("Not enough {stuff}")]
+    NotEnough { stuff: String },
+
+    /// The counterparty signed something else than what we asked them to sign
+    #[error("Their signature is incorrect")]
+    BadSignature,
+
+    /// The counterparty still disagrees on the sequence number after a retry
+    #[error("Sequence number disagreement")]
+    SequenceNumberDisagreement,
+
+    #[error("Cannot confirm that channel was opened")]
+    ChannelNotOpened,
+
+    /// Both sides proposed a channel at the same time. The channel proposed by the side with
+    /// the lower address is opened, the other proposal is abandoned.
+    #[error("Channel proposals crossed, the channel proposed by address 0 is opened instead")]
+    SimultaneousOpen,
+
+    /// None of the nodes we know of can carry the payment to its payee
+    #[error("No route to {to}")]
+    NoRoute { to: Address },
+
+    /// A routed payment did not make it past `hop`. Its hashlock to `hop` is given back, unless
+    /// `hop` could not be reached at all.
+    #[error("Routing failed at {hop}: {message}")]
+    RouteFailed { hop: Address, message: String },
+
+    /// The receipt at `index` of a list of receipts does not prove a payment
+    #[error("Invalid receipt {index}: {reason}")]
+    InvalidReceipt { index: usize, reason: String },
+
+    /// A billing cursor is past the `received` we counted from the counterparty, for instance
+    /// because the node restarted since it was read, and counts from zero again
+    #[error("Cursor is ahead of the {received} received from this counterparty")]
+    CursorAhead { received: Uint256 },
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Cannot find counterparty {0}")]
+    CounterpartyNotFound(Address),
+
+    #[error("Counterparty {0} already exists")]
+    CounterpartyExists(Address),
+
+    /// The counterparty was changed by someone else between reading and writing its state
+    #[error("Counterparty {0} was changed concurrently")]
+    Conflict(Address),
+
+    /// The peer filter does not let this address become a counterparty
+    #[error("Counterparty {0} is not accepted")]
+    PeerRejected(Address),
+
+    /// Too many counterparties are waiting in the `New` state to accept another one
+    #[error("Too many pending counterparties ({0})")]
+    TooManyPendingPeers(usize),
+}
+
+/// Errors of a `BlockchainApi` implementation
+#[derive(Debug, Error)]
+pub enum BlockchainError {
+    /// The full node could not be reached or rejected the request
+    #[error("Full node request failed")]
+    Node(#[source] Box<dyn StdError + Send + Sync>),
+
+    /// Data sent to or received from the contract does not have the expected shape
+    #[error("Invalid contract data: {0}")]
+    InvalidData(String),
+
+    /// A transaction is missing one of the signatures the contract checks
+    #[error("No {0} supplied")]
+    MissingSignature(&'static str),
+
+    /// The node stopped delivering logs before the event we are waiting for showed up
+    #[error("Log stream ended before the event was seen")]
+    EventNotSeen,
+
+    /// A transaction could not be signed
+    #[error(transparent)]
+    Signer(#[from] SignerError),
+}
+
+impl BlockchainError {
+    pub fn node<E: StdError + Send + Sync + 'static>(err: E) -> BlockchainError {
+        BlockchainError::Node(Box::new(err))
+    }
+}
+
+/// Errors of a `CounterpartyApi` implementation which sends messages over the network
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("Invalid counterparty URL {0}")]
+    InvalidUrl(String),
+
+    /// The counterparty could not be reached
+    #[error("Request to counterparty failed: {0}")]
+    Request(String),
+
+    /// The counterparty answered with something else than 200 OK
+    #[error("HTTP error {status}: {body}")]
+    Status { status: u16, body: String },
+
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// Errors of a `Signer`, which holds the key of the node
+#[derive(Debug, Error)]
+pub enum SignerError {
+    #[error("Invalid private key")]
+    InvalidKey,
+
+    /// The keystore file could not be read, or is not a V3 keystore we can decrypt
+    #[error("Invalid keystore: {0}")]
+    Keystore(String),
+
+    /// The MAC of the keystore does not match the key derived from the passphrase
+    #[error("Wrong keystore passphrase")]
+    WrongPassphrase,
+
+    /// Only 32 byte hashes are signed, anything else is not a fingerprint
+    #[error("Cannot sign a hash of {0} bytes")]
+    InvalidHash(usize),
+
+    /// The remote signer could not be reached, or refused to sign
+    #[error("Remote signer failed: {0}")]
+    Remote(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_source_chain() {
+        let err: GuacError = BlockchainError::node(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        ))
+        .into();
+
+        match err {
+            GuacError::Blockchain(BlockchainError::Node(_)) => {}
+            ref err => panic!("unexpected error {:?}", err),
+        }
+        assert_eq!(err.to_string(), "Full node request failed");
+        let source = err.source().expect("no source");
+        assert_eq!(source.to_string(), "connection refused");
+    }
+
+    #[test]
+    fn test_protocol_error_is_transparent() {
+        let err: GuacError = ProtocolError::NotEnough {
+            stuff: "money in channel.".to_string(),
+        }
+        .into();
+        assert_eq!(err.to_string(), "Not enough money in channel.");
+    }
+}
@@ -0,0 +1,153 @@
+//! Lets applications embedding Guac react to what happens to their channels instead of polling
+//! `get_state` and `check_accrual`.
+
+use crate::refill::RefillAction;
+use crate::types::{NewChannelTx, ReDrawTx};
+use clarity::Address;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use num256::Uint256;
+use std::sync::Mutex;
+
+/// Something that happened to the channel with `counterparty`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GuacEvent {
+    /// We signed a channel proposed by the counterparty. It is opened once they get it on the
+    /// chain.
+    ChannelProposed {
+        counterparty: Address,
+        new_channel_tx: NewChannelTx,
+    },
+    /// The channel is on the chain, whichever side opened it
+    ChannelOpened {
+        counterparty: Address,
+        channel_id: [u8; 32],
+    },
+    /// The counterparty paid us `amount` with the update numbered `seq`
+    PaymentReceived {
+        counterparty: Address,
+        amount: Uint256,
+        seq: Uint256,
+    },
+    /// The counterparty accepted our payment of `amount`, with the update numbered `seq`
+    PaymentSent {
+        counterparty: Address,
+        amount: Uint256,
+        seq: Uint256,
+    },
+    /// The counterparty showed us `preimage` to get paid the hashlock we pay it for `hash`.
+    /// If we are routing the payment, the preimage now gets us paid the hashlock for the same
+    /// hash which pays us. Payments forwarded over a route are claimed right away, others with
+    /// `Guac::claim_conditional_payment`.
+    HashlockFulfilled {
+        counterparty: Address,
+        hash: [u8; 32],
+        preimage: [u8; 32],
+    },
+    /// We locked a payment from the counterparty on to `next`, the next hop of its route, and
+    /// `next` took it on. Once the payee claims it, we keep `fee`.
+    PaymentForwarded {
+        counterparty: Address,
+        next: Address,
+        hash: [u8; 32],
+        fee: Uint256,
+    },
+    /// A reDraw made it to the chain, whichever side proposed it
+    ReDrawCompleted {
+        counterparty: Address,
+        re_draw_tx: ReDrawTx,
+    },
+    /// The counterparty owes `debt` for what it used, which is more than its limit, see
+    /// `Guac::add_usage`
+    DebtLimitExceeded {
+        counterparty: Address,
+        debt: Uint256,
+    },
+    /// The channel was refilled or withdrawn from because of its `Threshold`
+    Rebalanced {
+        counterparty: Address,
+        action: RefillAction,
+    },
+    /// Someone started closing the channel on the chain. `Guac::audit` emits this the first
+    /// time it finds the channel settling.
+    SettlementStarted {
+        counterparty: Address,
+        channel_id: [u8; 32],
+    },
+    /// Reserved for once the channel is closed on the chain. Guac cannot close channels yet, so
+    /// nothing emits this until it can.
+    Closed {
+        counterparty: Address,
+        channel_id: [u8; 32],
+    },
+    /// We refused something the counterparty sent us, or failed to handle it. Errors of calls
+    /// made by the application itself are only returned by those calls.
+    Error {
+        counterparty: Address,
+        message: String,
+    },
+}
+
+/// The events of one subscriber, in the order they happened
+pub type EventStream = UnboundedReceiver<GuacEvent>;
+
+/// Hands every event to all current subscribers. Events are queued until a subscriber reads
+/// them, so subscribers should keep reading, or drop their stream to unsubscribe.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<UnboundedSender<GuacEvent>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Returns the stream of all events from now on
+    pub fn subscribe(&self) -> EventStream {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn emit(&self, event: GuacEvent) {
+        log::trace!("Emitting {:?}", event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(amount: u64) -> GuacEvent {
+        GuacEvent::PaymentReceived {
+            counterparty: Address::default(),
+            amount: amount.into(),
+            seq: 1u64.into(),
+        }
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let events = Events::new();
+        events.emit(event(1));
+
+        let mut stream_0 = events.subscribe();
+        let stream_1 = events.subscribe();
+        events.emit(event(2));
+        drop(stream_1);
+        events.emit(event(3));
+
+        assert_eq!(stream_0.try_recv().unwrap(), event(2));
+        assert_eq!(stream_0.try_recv().unwrap(), event(3));
+        assert!(stream_0.try_recv().unwrap_err().is_empty());
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+
+        let mut stream_2 = events.subscribe();
+        drop(events);
+        assert!(stream_2.try_recv().unwrap_err().is_closed());
+    }
+}
@@ -0,0 +1,257 @@
+//! A record of every payment sent and received, kept for accounting.
+//!
+//! `Channel` only knows the current balances, and its `accrual` is reset by `check_accrual`,
+//! so this is the only place individual payments can be looked up afterwards. Entries are only
+//! ever appended.
+
+use crate::receipt::Receipt;
+use crate::types::UpdateTx;
+use clarity::{Address, Signature};
+use num256::Uint256;
+use std::fmt::Write;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One payment. `update_tx` is the update which carried it, with the signatures it was sent
+/// with: only the side which pays signs an update, so one of the two is usually empty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LedgerEntry {
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub counterparty: Address,
+    pub direction: Direction,
+    pub amount: Uint256,
+    pub update_tx: UpdateTx,
+    /// The payee's receipt for the update, if it sent one
+    pub receipt: Option<Receipt>,
+}
+
+impl LedgerEntry {
+    /// An entry for a payment made now
+    pub fn new(
+        counterparty: Address,
+        direction: Direction,
+        amount: Uint256,
+        update_tx: UpdateTx,
+    ) -> LedgerEntry {
+        LedgerEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            counterparty,
+            direction,
+            amount,
+            update_tx,
+            receipt: None,
+        }
+    }
+
+    pub fn with_receipt(mut self, receipt: Receipt) -> Self {
+        self.receipt = Some(receipt);
+        self
+    }
+}
+
+/// Selects entries of the ledger. Every condition which is set has to match.
+#[derive(Clone, Debug, Default)]
+pub struct LedgerQuery {
+    counterparty: Option<Address>,
+    direction: Option<Direction>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl LedgerQuery {
+    /// Matches every entry
+    pub fn new() -> LedgerQuery {
+        LedgerQuery::default()
+    }
+
+    pub fn with_counterparty(mut self, counterparty: Address) -> Self {
+        self.counterparty = Some(counterparty);
+        self
+    }
+
+    pub fn with_direction(mut self, direction: Direction) -> Self {
+        self.direction = Some(direction);
+        self
+    }
+
+    /// Only entries with a timestamp from `since` up to, but not including, `until`
+    pub fn with_time_range(mut self, since: u64, until: u64) -> Self {
+        self.since = Some(since);
+        self.until = Some(until);
+        self
+    }
+
+    fn matches(&self, entry: &LedgerEntry) -> bool {
+        self.counterparty.is_none_or(|c| c == entry.counterparty)
+            && self.direction.is_none_or(|d| d == entry.direction)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp < until)
+    }
+}
+
+#[derive(Default)]
+pub struct Ledger {
+    entries: RwLock<Vec<LedgerEntry>>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn record(&self, entry: LedgerEntry) {
+        self.entries.write().unwrap().push(entry);
+    }
+
+    /// Returns the matching entries in the order they were recorded
+    pub fn query(&self, query: &LedgerQuery) -> Vec<LedgerEntry> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|entry| query.matches(entry))
+            .cloned()
+            .collect()
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,counterparty,direction,amount,channel_id,sequence_number,\
+                          balance_0,balance_1,signature_0,signature_1";
+
+/// One line per entry, with a header. Byte strings are hex with a `0x` prefix, amounts are
+/// decimal and missing signatures are left empty.
+pub fn to_csv(entries: &[LedgerEntry]) -> String {
+    let signature = |signature: &Option<Signature>| match signature {
+        Some(signature) => format!("0x{}", hex::encode(&signature.to_bytes()[..])),
+        None => String::new(),
+    };
+
+    let mut csv = format!("{}\n", CSV_HEADER);
+    for entry in entries {
+        let update_tx = &entry.update_tx;
+        writeln!(
+            csv,
+            "{},{},{},{},0x{},{},{},{},{},{}",
+            entry.timestamp,
+            entry.counterparty,
+            match entry.direction {
+                Direction::Sent => "sent",
+                Direction::Received => "received",
+            },
+            entry.amount,
+            hex::encode(update_tx.channel_id),
+            update_tx.sequence_number,
+            update_tx.balance_0,
+            update_tx.balance_1,
+            signature(&update_tx.signature_0),
+            signature(&update_tx.signature_1),
+        )
+        .unwrap();
+    }
+    csv
+}
+
+pub fn to_json(entries: &[LedgerEntry]) -> Result<String, serde_json::Error> {
+    serde_json::to_string_pretty(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(n: u8) -> Address {
+        format!("0x{}", format!("{:02x}", n).repeat(20))
+            .parse()
+            .unwrap()
+    }
+
+    fn entry(timestamp: u64, counterparty: u8, direction: Direction, amount: u64) -> LedgerEntry {
+        LedgerEntry {
+            timestamp,
+            counterparty: address(counterparty),
+            direction,
+            amount: amount.into(),
+            update_tx: UpdateTx {
+                channel_id: [1; 32],
+                sequence_number: timestamp.into(),
+                balance_0: 100u64.into(),
+                balance_1: 0u64.into(),
+                hashlocks: Vec::new(),
+                signature_0: None,
+                signature_1: None,
+            },
+            receipt: None,
+        }
+    }
+
+    fn ledger() -> Ledger {
+        let ledger = Ledger::new();
+        ledger.record(entry(10, 1, Direction::Sent, 5));
+        ledger.record(entry(20, 2, Direction::Received, 6));
+        ledger.record(entry(30, 1, Direction::Received, 7));
+        ledger.record(entry(40, 1, Direction::Sent, 8));
+        ledger
+    }
+
+    fn amounts(entries: Vec<LedgerEntry>) -> Vec<Uint256> {
+        entries.into_iter().map(|entry| entry.amount).collect()
+    }
+
+    #[test]
+    fn test_query() {
+        let ledger = ledger();
+
+        assert_eq!(ledger.query(&LedgerQuery::new()).len(), 4);
+        assert_eq!(
+            amounts(ledger.query(&LedgerQuery::new().with_counterparty(address(1)))),
+            vec![5u64.into(), 7u64.into(), 8u64.into()]
+        );
+        assert_eq!(
+            amounts(
+                ledger.query(
+                    &LedgerQuery::new()
+                        .with_counterparty(address(1))
+                        .with_direction(Direction::Sent)
+                )
+            ),
+            vec![5u64.into(), 8u64.into()]
+        );
+        assert_eq!(
+            amounts(ledger.query(&LedgerQuery::new().with_time_range(20, 40))),
+            vec![6u64.into(), 7u64.into()]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let entries = ledger().query(&LedgerQuery::new().with_time_range(0, 20));
+
+        let csv = to_csv(&entries);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            format!(
+                "10,0x{},sent,5,0x{},10,100,0,,",
+                "01".repeat(20),
+                "01".repeat(32)
+            )
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&to_json(&entries).unwrap()).unwrap();
+        assert_eq!(json[0]["timestamp"], 10);
+        assert_eq!(json[0]["direction"], "sent");
+    }
+}
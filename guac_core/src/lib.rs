@@ -4,13 +4,7 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate base64;
-#[macro_use]
-extern crate failure;
-#[cfg(test)]
-extern crate actix;
-extern crate actix_web;
 extern crate clarity;
-extern crate futures_timer;
 extern crate hex;
 extern crate lazy_static;
 extern crate log;
@@ -20,24 +14,53 @@ extern crate multihash;
 extern crate num;
 extern crate num256;
 extern crate owning_ref;
-extern crate qutex;
 extern crate serde_json;
 extern crate sha3;
 extern crate tiny_keccak;
-extern crate tokio;
 extern crate uuid;
 
 #[macro_use]
 pub mod crypto;
+pub mod audit;
+pub mod billing;
 pub mod channel;
 pub mod channel_manager;
 pub mod counterparty_api;
+pub mod eip712;
+pub mod error;
+pub mod events;
+pub mod ledger;
+pub mod metrics;
+pub mod policy;
+pub mod receipt;
+pub mod refill;
+pub mod routing;
+pub mod signer;
+pub mod state_machine;
 pub mod storage;
+pub mod stream;
+#[cfg(test)]
+mod test_utils;
 pub mod types;
 
+pub use self::billing::{AccrualCursor, Billing};
 pub use self::channel_manager::BlockchainApi;
 pub use self::channel_manager::Guac;
 pub use self::counterparty_api::CounterpartyApi;
 pub use self::crypto::Crypto;
+pub use self::eip712::{FingerprintScheme, Signable, TypedData, TypedMessage};
+pub use self::error::{
+    BlockchainError, GuacError, ProtocolError, SignerError, StorageError, TransportError,
+};
+pub use self::events::{EventStream, Events, GuacEvent};
+pub use self::ledger::{Direction, Ledger, LedgerEntry, LedgerQuery};
+pub use self::metrics::{Histogram, Metrics, Role};
+pub use self::policy::{Decision, DefaultProposalPolicy, ProposalPolicy};
+pub use self::receipt::{verify_receipts, Receipt};
+pub use self::refill::{RefillAction, RefillManager, Threshold};
+pub use self::routing::{Graph, NodeInfo, Route, Router};
+#[cfg(unix)]
+pub use self::signer::{bind_signer, serve_signer, RemoteSigner};
+pub use self::signer::{sign_transaction, InMemorySigner, KeystoreSigner, Signer};
 pub use self::storage::Storage;
-pub use self::types::GuacError;
+pub use self::stream::{Clock, MockClock, PaymentStream, SystemClock};
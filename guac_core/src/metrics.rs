@@ -0,0 +1,471 @@
+//! Metrics in the Prometheus text format.
+//!
+//! `Metrics` counts what a node does as it happens: payments, updates refused for being too old,
+//! calls to full nodes and to counterparties, and transactions waiting to be mined.
+//! `Guac::render_metrics` adds what is read off the storage at the time of a scrape, which is
+//! how many counterparties are in each state and how long their locks have been held.
+
+use crate::ledger::Direction;
+use crate::types::Counterparty;
+use crate::Guac;
+use num256::Uint256;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Buckets in seconds for calls over the network
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Buckets in seconds for how long a lock is held
+pub const LOCK_BUCKETS: &[f64] = &[0.000_001, 0.000_01, 0.000_1, 0.001, 0.01, 0.1];
+
+/// The states of `Counterparty`, which are all rendered even when no counterparty is in them
+const STATES: &[&str] = &[
+    "New",
+    "Creating",
+    "OtherCreating",
+    "ReDrawing",
+    "OtherReDrawing",
+    "Open",
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+/// A distribution of durations
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    buckets: &'static [f64],
+    /// Observations per bucket, which are only added up when rendered
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            counts: vec![0; buckets.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = self.buckets.iter().position(|le| seconds <= *le) {
+            self.counts[bucket] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &Labels) {
+        let bucket = |le: String| {
+            let mut labels = labels.clone();
+            labels.push(("le", le));
+            labels
+        };
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count;
+            sample(
+                out,
+                &format!("{}_bucket", name),
+                &bucket(le.to_string()),
+                cumulative,
+            );
+        }
+        sample(
+            out,
+            &format!("{}_bucket", name),
+            &bucket("+Inf".to_string()),
+            self.count,
+        );
+        sample(out, &format!("{}_sum", name), labels, self.sum);
+        sample(out, &format!("{}_count", name), labels, self.count);
+    }
+}
+
+/// Which side of a call to a counterparty we are on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// We called the counterparty, through `CounterpartyApi`
+    Client,
+    /// The counterparty called us
+    Server,
+}
+
+/// One metric under several sets of labels
+struct Family<T>(Mutex<BTreeMap<Labels, T>>);
+
+impl<T> Family<T> {
+    fn new() -> Family<T> {
+        Family(Mutex::new(BTreeMap::new()))
+    }
+
+    fn update<N, F>(&self, labels: Labels, new: N, f: F)
+    where
+        N: FnOnce() -> T,
+        F: FnOnce(&mut T),
+    {
+        let mut family = self.0.lock().expect("Metrics poisoned");
+        f(family.entry(labels).or_insert_with(new))
+    }
+
+    fn render<F>(&self, out: &mut String, name: &str, kind: &str, help: &str, render: F)
+    where
+        F: Fn(&mut String, &Labels, &T),
+    {
+        header(out, name, kind, help);
+        for (labels, value) in self.0.lock().expect("Metrics poisoned").iter() {
+            render(out, labels, value);
+        }
+    }
+}
+
+/// Counters and histograms of a node, shared by `Guac` and the clients it uses
+pub struct Metrics {
+    payments: Family<u64>,
+    payment_amounts: Family<Uint256>,
+    updates_too_old: Family<u64>,
+    rpc_durations: Family<Histogram>,
+    rpc_errors: Family<u64>,
+    pending_transactions: AtomicI64,
+    request_durations: Family<Histogram>,
+    request_errors: Family<u64>,
+}
+
+/// Counts a transaction as pending until it is dropped
+pub struct PendingTransaction<'a>(&'a Metrics);
+
+impl<'a> Drop for PendingTransaction<'a> {
+    fn drop(&mut self) {
+        self.0.pending_transactions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            payments: Family::new(),
+            payment_amounts: Family::new(),
+            updates_too_old: Family::new(),
+            rpc_durations: Family::new(),
+            rpc_errors: Family::new(),
+            pending_transactions: AtomicI64::new(0),
+            request_durations: Family::new(),
+            request_errors: Family::new(),
+        }
+    }
+
+    pub fn record_payment(&self, direction: Direction, amount: &Uint256) {
+        let labels = vec![("direction", direction_label(direction))];
+        self.payments
+            .update(labels.clone(), || 0, |count| *count += 1);
+        self.payment_amounts.update(
+            labels,
+            || 0u64.into(),
+            |total| *total = total.clone() + amount.clone(),
+        );
+    }
+
+    /// Counts an update refused because the other side had seen a higher sequence number. It
+    /// was refused by the counterparty if we `Sent` it, and by us if we `Received` it.
+    pub fn record_update_too_old(&self, direction: Direction) {
+        let labels = vec![("direction", direction_label(direction))];
+        self.updates_too_old
+            .update(labels, || 0, |count| *count += 1);
+    }
+
+    /// Records a call of `method` to a full node which took `duration`
+    pub fn observe_rpc(&self, method: &str, duration: Duration, ok: bool) {
+        let labels = vec![("method", method.to_string())];
+        self.rpc_durations.update(
+            labels,
+            || Histogram::new(LATENCY_BUCKETS),
+            |histogram| histogram.observe(duration),
+        );
+        if !ok {
+            self.record_rpc_error(method);
+        }
+    }
+
+    /// Counts a failed call of `method`, for calls whose duration means nothing, like
+    /// subscriptions
+    pub fn record_rpc_error(&self, method: &str) {
+        let labels = vec![("method", method.to_string())];
+        self.rpc_errors.update(labels, || 0, |count| *count += 1);
+    }
+
+    /// Counts a transaction as pending until the returned guard is dropped, which should be
+    /// once it is mined or has failed
+    pub fn pending_transaction(&self) -> PendingTransaction<'_> {
+        self.pending_transactions.fetch_add(1, Ordering::SeqCst);
+        PendingTransaction(self)
+    }
+
+    /// Records a call of `endpoint` of the counterparty API which took `duration`
+    pub fn observe_request(&self, role: Role, endpoint: &str, duration: Duration, ok: bool) {
+        let role = match role {
+            Role::Client => "client",
+            Role::Server => "server",
+        };
+        let labels = vec![
+            ("endpoint", endpoint.to_string()),
+            ("role", role.to_string()),
+        ];
+        if !ok {
+            self.request_errors
+                .update(labels.clone(), || 0, |count| *count += 1);
+        }
+        self.request_durations.update(
+            labels,
+            || Histogram::new(LATENCY_BUCKETS),
+            |histogram| histogram.observe(duration),
+        );
+    }
+
+    /// Renders the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        self.payments.render(
+            &mut out,
+            "guac_payments_total",
+            "counter",
+            "Payments sent and received",
+            |out, labels, count| sample(out, "guac_payments_total", labels, count),
+        );
+        self.payment_amounts.render(
+            &mut out,
+            "guac_payment_amount_total",
+            "counter",
+            "Sum of the payments sent and received, in wei",
+            |out, labels, amount| sample(out, "guac_payment_amount_total", labels, amount),
+        );
+        self.updates_too_old.render(
+            &mut out,
+            "guac_update_too_old_total",
+            "counter",
+            "Updates refused because the other side had seen a higher sequence number",
+            |out, labels, count| sample(out, "guac_update_too_old_total", labels, count),
+        );
+        self.rpc_durations.render(
+            &mut out,
+            "guac_rpc_duration_seconds",
+            "histogram",
+            "Duration of calls to full nodes",
+            |out, labels, histogram| histogram.render(out, "guac_rpc_duration_seconds", labels),
+        );
+        self.rpc_errors.render(
+            &mut out,
+            "guac_rpc_errors_total",
+            "counter",
+            "Failed calls to full nodes",
+            |out, labels, count| sample(out, "guac_rpc_errors_total", labels, count),
+        );
+        header(
+            &mut out,
+            "guac_pending_transactions",
+            "gauge",
+            "Transactions sent and not yet mined",
+        );
+        sample(
+            &mut out,
+            "guac_pending_transactions",
+            &Vec::new(),
+            self.pending_transactions.load(Ordering::SeqCst),
+        );
+        self.request_durations.render(
+            &mut out,
+            "guac_counterparty_request_duration_seconds",
+            "histogram",
+            "Duration of calls of the counterparty API",
+            |out, labels, histogram| {
+                histogram.render(out, "guac_counterparty_request_duration_seconds", labels)
+            },
+        );
+        self.request_errors.render(
+            &mut out,
+            "guac_counterparty_request_errors_total",
+            "counter",
+            "Failed calls of the counterparty API",
+            |out, labels, count| {
+                sample(out, "guac_counterparty_request_errors_total", labels, count)
+            },
+        );
+        out
+    }
+}
+
+impl Guac {
+    /// Renders `metrics` together with the number of counterparties in each state and how long
+    /// the locks of the storage have been held
+    pub fn render_metrics(&self) -> String {
+        let mut out = self.metrics.render();
+
+        let mut states: BTreeMap<&str, u64> = STATES.iter().map(|state| (*state, 0)).collect();
+        for (_, counterparty) in self.storage.get_all_counterparties() {
+            *states.entry(state_label(&counterparty)).or_insert(0) += 1;
+        }
+        header(
+            &mut out,
+            "guac_channels",
+            "gauge",
+            "Counterparties in each state",
+        );
+        for (state, count) in states {
+            sample(
+                &mut out,
+                "guac_channels",
+                &vec![("state", state.to_string())],
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "guac_storage_lock_held_seconds",
+            "histogram",
+            "How long the lock of a counterparty was held",
+        );
+        self.storage
+            .lock_times()
+            .render(&mut out, "guac_storage_lock_held_seconds", &Vec::new());
+
+        out
+    }
+}
+
+fn direction_label(direction: Direction) -> String {
+    match direction {
+        Direction::Sent => "sent",
+        Direction::Received => "received",
+    }
+    .to_string()
+}
+
+fn state_label(counterparty: &Counterparty) -> &'static str {
+    match counterparty {
+        Counterparty::New { .. } => "New",
+        Counterparty::Creating { .. } => "Creating",
+        Counterparty::OtherCreating { .. } => "OtherCreating",
+        Counterparty::ReDrawing { .. } => "ReDrawing",
+        Counterparty::OtherReDrawing { .. } => "OtherReDrawing",
+        Counterparty::Open { .. } => "Open",
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn sample<V: Display>(out: &mut String, name: &str, labels: &Labels, value: V) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| {
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                format!("{}=\"{}\"", key, value)
+            })
+            .collect();
+        write!(out, "{{{}}}", labels.join(",")).unwrap();
+    }
+    writeln!(out, " {}", value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::make_pair;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_payment(Direction::Sent, &10u64.into());
+        metrics.record_payment(Direction::Sent, &5u64.into());
+        metrics.observe_rpc("eth_call", Duration::from_millis(20), true);
+        metrics.observe_rpc("eth_call", Duration::from_secs(20), false);
+        let pending = metrics.pending_transaction();
+
+        let out = metrics.render();
+        let lines: Vec<&str> = out.lines().collect();
+        for line in &[
+            "# TYPE guac_payments_total counter",
+            "guac_payments_total{direction=\"sent\"} 2",
+            "guac_payment_amount_total{direction=\"sent\"} 15",
+            "# TYPE guac_rpc_duration_seconds histogram",
+            "guac_rpc_duration_seconds_bucket{method=\"eth_call\",le=\"0.01\"} 0",
+            "guac_rpc_duration_seconds_bucket{method=\"eth_call\",le=\"0.025\"} 1",
+            "guac_rpc_duration_seconds_bucket{method=\"eth_call\",le=\"10\"} 1",
+            "guac_rpc_duration_seconds_bucket{method=\"eth_call\",le=\"+Inf\"} 2",
+            "guac_rpc_duration_seconds_count{method=\"eth_call\"} 2",
+            "guac_rpc_errors_total{method=\"eth_call\"} 1",
+            "guac_pending_transactions 1",
+        ] {
+            assert!(lines.contains(line), "{} is missing from\n{}", line, out);
+        }
+
+        drop(pending);
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "guac_pending_transactions 0"));
+    }
+
+    #[test]
+    fn test_guac_metrics() {
+        let (node_0, node_1) = make_pair();
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            node_0
+                .guac
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+        });
+
+        let out = node_0.guac.render_metrics();
+        let lines: Vec<&str> = out.lines().collect();
+        for line in &[
+            "guac_channels{state=\"Open\"} 1",
+            "guac_channels{state=\"New\"} 0",
+            "guac_payments_total{direction=\"sent\"} 1",
+            "guac_payment_amount_total{direction=\"sent\"} 10",
+        ] {
+            assert!(lines.contains(line), "{} is missing from\n{}", line, out);
+        }
+        assert!(node_0.guac.storage.lock_times().count() > 0);
+
+        let out = node_1.guac.render_metrics();
+        assert!(out
+            .lines()
+            .any(|line| line == "guac_payments_total{direction=\"received\"} 1"));
+    }
+}
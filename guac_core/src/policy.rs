@@ -0,0 +1,199 @@
+//! Decides which channels and reDraws proposed by counterparties we are willing to sign.
+//!
+//! Guac itself only refuses proposals which do not fit the stored channel, for instance a reDraw
+//! with the wrong old balances. Everything else is up to the `ProposalPolicy` of the node.
+
+use crate::channel::Channel;
+use crate::error::ProtocolError;
+use crate::types::{NewChannelTx, ReDrawTx};
+use clarity::Address;
+
+/// The answer of a `ProposalPolicy`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    /// The counterparty gets `reason` back
+    Reject {
+        reason: String,
+    },
+}
+
+impl Decision {
+    pub fn reject<T: ToString>(reason: T) -> Decision {
+        Decision::Reject {
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn into_result(self) -> Result<(), ProtocolError> {
+        match self {
+            Decision::Accept => Ok(()),
+            Decision::Reject { reason } => Err(ProtocolError::Forbidden { message: reason }),
+        }
+    }
+}
+
+/// Decides whether to sign a proposal from a counterparty.
+///
+/// The policy is asked while the state of the counterparty is locked, so it should answer from
+/// what it already knows rather than wait on anything. Implementations will usually want to
+/// start by asking `DefaultProposalPolicy`.
+pub trait ProposalPolicy {
+    /// `from_address` proposes to open `new_channel_tx` with us. `i_am_0` tells which side of
+    /// the channel is ours.
+    fn check_channel(
+        &self,
+        from_address: Address,
+        i_am_0: bool,
+        new_channel_tx: &NewChannelTx,
+    ) -> Decision;
+
+    /// `from_address` proposes to reDraw `channel` to the new balances of `re_draw_tx`
+    fn check_re_draw(
+        &self,
+        from_address: Address,
+        channel: &Channel,
+        re_draw_tx: &ReDrawTx,
+    ) -> Decision;
+}
+
+/// Accepts anything which does not cost us money: channels in which we have nothing, with the
+/// settling period we expect, and reDraws which leave our balance alone.
+pub struct DefaultProposalPolicy;
+
+impl ProposalPolicy for DefaultProposalPolicy {
+    fn check_channel(
+        &self,
+        _from_address: Address,
+        i_am_0: bool,
+        new_channel_tx: &NewChannelTx,
+    ) -> Decision {
+        let my_balance = if i_am_0 {
+            &new_channel_tx.balance_0
+        } else {
+            &new_channel_tx.balance_1
+        };
+
+        if *my_balance != 0u64.into() {
+            return Decision::reject("My balance in proposed channel must be zero.");
+        }
+
+        if new_channel_tx.settling_period_length != 5000u64.into() {
+            return Decision::reject("I only accept settling periods of 5000 blocks");
+        }
+
+        Decision::Accept
+    }
+
+    fn check_re_draw(
+        &self,
+        _from_address: Address,
+        channel: &Channel,
+        re_draw_tx: &ReDrawTx,
+    ) -> Decision {
+        if channel.i_am_0 {
+            if re_draw_tx.new_balance_0 != channel.balance_0 {
+                return Decision::reject(format!(
+                    "New balance_0 ({}) should equal my balance ({})",
+                    re_draw_tx.new_balance_0, channel.balance_0
+                ));
+            }
+        } else if re_draw_tx.new_balance_1 != channel.balance_1 {
+            return Decision::reject(format!(
+                "New balance_1 ({}) should equal my balance ({})",
+                re_draw_tx.new_balance_1, channel.balance_1
+            ));
+        }
+
+        Decision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_channel_tx() -> NewChannelTx {
+        NewChannelTx {
+            address_0: Address::default(),
+            address_1: Address::default(),
+            balance_0: 0u64.into(),
+            balance_1: 10u64.into(),
+            expiration: 0u64.into(),
+            settling_period_length: 5000u64.into(),
+            signature_0: None,
+            signature_1: None,
+        }
+    }
+
+    #[test]
+    fn test_default_channel_policy() {
+        let policy = DefaultProposalPolicy;
+        let from = Address::default();
+
+        assert_eq!(
+            policy.check_channel(from, true, &new_channel_tx()),
+            Decision::Accept
+        );
+        // We would have to put money in
+        assert!(policy
+            .check_channel(from, false, &new_channel_tx())
+            .into_result()
+            .is_err());
+
+        let new_channel_tx = NewChannelTx {
+            settling_period_length: 10u64.into(),
+            ..new_channel_tx()
+        };
+        assert_eq!(
+            policy.check_channel(from, true, &new_channel_tx),
+            Decision::reject("I only accept settling periods of 5000 blocks")
+        );
+    }
+
+    #[test]
+    fn test_default_re_draw_policy() {
+        let policy = DefaultProposalPolicy;
+        let from = Address::default();
+        let channel = Channel {
+            channel_id: [0; 32],
+            sequence_number: 0u64.into(),
+            balance_0: 10u64.into(),
+            balance_1: 10u64.into(),
+            accrual: 0u64.into(),
+            hashlocks: Vec::new(),
+            signed_update: None,
+            i_am_0: true,
+        };
+        let re_draw_tx = ReDrawTx {
+            channel_id: [0; 32],
+            sequence_number: 1u64.into(),
+            old_balance_0: 10u64.into(),
+            old_balance_1: 10u64.into(),
+            new_balance_0: 10u64.into(),
+            new_balance_1: 50u64.into(),
+            expiration: 0u64.into(),
+            signature_0: None,
+            signature_1: None,
+        };
+
+        assert_eq!(
+            policy.check_re_draw(from, &channel, &re_draw_tx),
+            Decision::Accept
+        );
+
+        let re_draw_tx = ReDrawTx {
+            new_balance_0: 5u64.into(),
+            ..re_draw_tx
+        };
+        match policy
+            .check_re_draw(from, &channel, &re_draw_tx)
+            .into_result()
+        {
+            Err(ProtocolError::Forbidden { message }) => {
+                assert_eq!(message, "New balance_0 (5) should equal my balance (10)")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
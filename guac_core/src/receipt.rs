@@ -0,0 +1,284 @@
+//! Receipts which prove payments without the chain.
+//!
+//! The payee signs a `Receipt` for every update it accepts, and both sides keep it in their
+//! `Ledger`. When a bill is disputed, either side can show the receipts of the channel, which
+//! anyone who knows the contract and both addresses can check with `verify_receipts`.
+
+use crate::crypto;
+use crate::eip712::FingerprintScheme;
+use crate::error::{GuacError, ProtocolError};
+use crate::ledger::{Direction, LedgerQuery};
+use crate::types::UpdateTx;
+use crate::Guac;
+use clarity::{Address, Signature};
+use num256::Uint256;
+
+/// The payee's acknowledgement of an update
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Receipt {
+    pub channel_id: [u8; 32],
+    /// The sequence number of the update
+    pub sequence_number: Uint256,
+    /// What the update paid the payee, which is zero for updates which only change hashlocks
+    pub amount: Uint256,
+    pub payer: Address,
+    pub payee: Address,
+    /// By the payee
+    pub signature: Option<Signature>,
+}
+
+impl Receipt {
+    pub fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
+        let func_name: &[u8] = "Receipt".as_bytes();
+        let contract_address: &[u8] = contract_address.as_bytes();
+        let sequence_number: [u8; 32] = self.sequence_number.clone().into();
+        let amount: [u8; 32] = self.amount.clone().into();
+        let payer: &[u8] = self.payer.as_bytes();
+        let payee: &[u8] = self.payee.as_bytes();
+
+        crypto::hash_bytes(&[
+            func_name,
+            contract_address,
+            &self.channel_id,
+            &sequence_number,
+            &amount,
+            payer,
+            payee,
+        ])
+        .into()
+    }
+}
+
+/// Checks receipts for payments from `payer` to `payee`, in one channel of the contract at
+/// `contract_address` and in the order they were made, signed with `fingerprints`. Returns the
+/// total they prove was paid.
+pub fn verify_receipts(
+    contract_address: Address,
+    fingerprints: &FingerprintScheme,
+    payer: Address,
+    payee: Address,
+    receipts: &[Receipt],
+) -> Result<Uint256, ProtocolError> {
+    let mut total: Uint256 = 0u64.into();
+    let mut previous: Option<&Receipt> = None;
+
+    for (index, receipt) in receipts.iter().enumerate() {
+        let invalid = |reason: &str| ProtocolError::InvalidReceipt {
+            index,
+            reason: reason.to_string(),
+        };
+
+        if receipt.payer != payer || receipt.payee != payee {
+            return Err(invalid("it is between other addresses"));
+        }
+        if let Some(previous) = previous {
+            if receipt.channel_id != previous.channel_id {
+                return Err(invalid("it is for another channel"));
+            }
+            if receipt.sequence_number <= previous.sequence_number {
+                return Err(invalid(
+                    "its sequence number is not higher than the one before",
+                ));
+            }
+        }
+        let signer = receipt.signature.as_ref().and_then(|signature| {
+            signature
+                .recover(&fingerprints.fingerprint(contract_address, receipt))
+                .ok()
+        });
+        if signer != Some(payee) {
+            return Err(invalid("it is not signed by the payee"));
+        }
+
+        total += receipt.amount.clone();
+        previous = Some(receipt);
+    }
+
+    Ok(total)
+}
+
+impl Guac {
+    /// The receipts of the payments in one direction between us and the counterparty, in the
+    /// order they were made. Payments of hashlocks fulfilled by the counterparty come without
+    /// a receipt for us.
+    pub fn receipts(&self, their_address: Address, direction: Direction) -> Vec<Receipt> {
+        self.ledger
+            .query(
+                &LedgerQuery::new()
+                    .with_counterparty(their_address)
+                    .with_direction(direction),
+            )
+            .into_iter()
+            .filter_map(|entry| entry.receipt)
+            .collect()
+    }
+
+    /// A receipt for an update from the counterparty which paid us `amount`
+    pub(crate) async fn sign_receipt(
+        &self,
+        their_address: Address,
+        update_tx: &UpdateTx,
+        amount: Uint256,
+    ) -> Result<Receipt, GuacError> {
+        let crypto = &self.crypto;
+        let mut receipt = Receipt {
+            channel_id: update_tx.channel_id,
+            sequence_number: update_tx.sequence_number.clone(),
+            amount,
+            payer: their_address,
+            payee: crypto.own_address,
+            signature: None,
+        };
+        receipt.signature = Some(crypto.sign(&receipt).await?);
+        Ok(receipt)
+    }
+
+    /// Checks that the counterparty's receipt is for `update_tx`, which paid it `amount`. The
+    /// receipt may be for more, when the counterparty had missed some of our updates.
+    pub(crate) fn check_receipt(
+        &self,
+        their_address: Address,
+        update_tx: &UpdateTx,
+        amount: &Uint256,
+        receipt: &Receipt,
+    ) -> Result<(), ProtocolError> {
+        verify_receipts(
+            self.crypto.contract_address,
+            &self.crypto.fingerprints,
+            self.crypto.own_address,
+            their_address,
+            std::slice::from_ref(receipt),
+        )?;
+        if receipt.channel_id != update_tx.channel_id
+            || receipt.sequence_number != update_tx.sequence_number
+        {
+            return Err(ProtocolError::InvalidReceipt {
+                index: 0,
+                reason: "it is for another update".to_string(),
+            });
+        }
+        if receipt.amount < *amount {
+            return Err(ProtocolError::InvalidReceipt {
+                index: 0,
+                reason: format!("it is for {} instead of {}", receipt.amount, amount),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::make_pair;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_receipts() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+        let contract_address = guac_0.crypto.contract_address;
+        let plain = FingerprintScheme::Plain;
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            for amount in &[10u64, 20, 5] {
+                guac_0
+                    .make_payment(node_1.address, node_1.url.clone(), (*amount).into())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        // Both sides hold the same receipts
+        let receipts = guac_0.receipts(node_1.address, Direction::Sent);
+        assert_eq!(receipts.len(), 3);
+        assert_eq!(
+            receipts,
+            guac_1.receipts(node_0.address, Direction::Received)
+        );
+        assert_eq!(
+            verify_receipts(
+                contract_address,
+                &plain,
+                node_0.address,
+                node_1.address,
+                &receipts
+            )
+            .unwrap(),
+            35u64.into()
+        );
+
+        // Receipts only prove payments from the payer to the payee
+        assert!(verify_receipts(
+            contract_address,
+            &plain,
+            node_1.address,
+            node_0.address,
+            &receipts
+        )
+        .is_err());
+
+        // A receipt cannot be changed, nor shown twice
+        let mut forged = receipts.clone();
+        forged[1].amount = 50u64.into();
+        match verify_receipts(
+            contract_address,
+            &plain,
+            node_0.address,
+            node_1.address,
+            &forged,
+        ) {
+            Err(ProtocolError::InvalidReceipt { index: 1, .. }) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        let repeated = vec![receipts[0].clone(), receipts[0].clone()];
+        assert!(verify_receipts(
+            contract_address,
+            &plain,
+            node_0.address,
+            node_1.address,
+            &repeated
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_understated_receipt() {
+        let (node_0, node_1) = make_pair();
+        let (guac_0, guac_1) = (&node_0.guac, &node_1.guac);
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 10u64.into())
+                .await
+                .unwrap();
+        });
+
+        let update_tx = guac_0.ledger.query(&LedgerQuery::new())[0]
+            .update_tx
+            .clone();
+        let paid: Uint256 = 10u64.into();
+        let receipt =
+            block_on(guac_1.sign_receipt(node_0.address, &update_tx, 10u64.into())).unwrap();
+        guac_0
+            .check_receipt(node_1.address, &update_tx, &paid, &receipt)
+            .unwrap();
+
+        let understated =
+            block_on(guac_1.sign_receipt(node_0.address, &update_tx, 4u64.into())).unwrap();
+        match guac_0.check_receipt(node_1.address, &update_tx, &paid, &understated) {
+            Err(ProtocolError::InvalidReceipt { index: 0, reason }) => {
+                assert_eq!(reason, "it is for 4 instead of 10")
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! Keeps our side of channels funded without the operator calling `fill_channel` and
+//! `withdraw` by hand.
+//!
+//! Each counterparty can be given a `Threshold`. Every payment we make to it queues its channel
+//! for `Guac::rebalance_queued`, which the application runs in the background so that payments
+//! do not wait on the chain. Every channel is checked whenever `Guac::rebalance_all` is called,
+//! for instance on a timer. Refills are paid from the wallet, down to a reserve kept for gas.
+
+use crate::error::{GuacError, ProtocolError};
+use crate::events::GuacEvent;
+use crate::Guac;
+use clarity::Address;
+use num::traits::ops::checked::CheckedSub;
+use num256::Uint256;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+/// When to refill or withdraw from a channel, in terms of our balance in it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Threshold {
+    /// Refill once our balance is below this
+    pub min: Uint256,
+    /// What refills and withdrawals bring our balance back to
+    pub target: Uint256,
+    /// Withdraw once our balance is above this. Never withdraws if `None`.
+    pub max: Option<Uint256>,
+}
+
+impl Threshold {
+    pub fn new(min: Uint256, target: Uint256) -> Threshold {
+        Threshold {
+            min,
+            target,
+            max: None,
+        }
+    }
+
+    pub fn with_max(mut self, max: Uint256) -> Self {
+        self.max = Some(max);
+        self
+    }
+}
+
+/// What a rebalance did to the channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefillAction {
+    Filled { amount: Uint256 },
+    Withdrew { amount: Uint256 },
+}
+
+/// The thresholds of our counterparties, and the rebalances queued or in progress
+#[derive(Default)]
+pub struct RefillManager {
+    thresholds: RwLock<HashMap<Address, (String, Threshold)>>,
+    wallet_reserve: Uint256,
+    queued: Mutex<HashSet<Address>>,
+    busy: Mutex<HashSet<Address>>,
+}
+
+impl RefillManager {
+    /// Does nothing until thresholds are set
+    pub fn new() -> RefillManager {
+        RefillManager::default()
+    }
+
+    /// Never refills out of the last `reserve` in the wallet, which is left to pay for gas
+    pub fn with_wallet_reserve(mut self, reserve: Uint256) -> Self {
+        self.wallet_reserve = reserve;
+        self
+    }
+
+    /// Rebalances the channel with the counterparty at `their_url` according to `threshold`,
+    /// replacing any threshold it had
+    pub fn set_threshold(&self, their_address: Address, their_url: String, threshold: Threshold) {
+        self.thresholds
+            .write()
+            .unwrap()
+            .insert(their_address, (their_url, threshold));
+    }
+
+    pub fn remove_threshold(&self, their_address: &Address) {
+        self.thresholds.write().unwrap().remove(their_address);
+    }
+
+    fn threshold(&self, their_address: &Address) -> Option<(String, Threshold)> {
+        self.thresholds.read().unwrap().get(their_address).cloned()
+    }
+
+    fn counterparties(&self) -> Vec<Address> {
+        self.thresholds.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Has the channel checked by the next `Guac::rebalance_queued`, if it has a threshold
+    pub(crate) fn queue(&self, their_address: Address) {
+        if self.thresholds.read().unwrap().contains_key(&their_address) {
+            self.queued.lock().unwrap().insert(their_address);
+        }
+    }
+
+    fn take_queued(&self) -> Vec<Address> {
+        self.queued.lock().unwrap().drain().collect()
+    }
+
+    /// Marks the channel as being rebalanced until the returned guard is dropped. Returns
+    /// `None` if it already is.
+    fn start(&self, their_address: Address) -> Option<Busy<'_>> {
+        if self.busy.lock().unwrap().insert(their_address) {
+            Some(Busy {
+                busy: &self.busy,
+                their_address,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+struct Busy<'a> {
+    busy: &'a Mutex<HashSet<Address>>,
+    their_address: Address,
+}
+
+impl<'a> Drop for Busy<'a> {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.their_address);
+    }
+}
+
+impl Guac {
+    /// Refills or withdraws from the channel with the counterparty if our balance is outside its
+    /// threshold. Returns what was done, which is nothing if the counterparty has no threshold
+    /// or its channel is already being rebalanced.
+    pub async fn rebalance(
+        &self,
+        their_address: Address,
+    ) -> Result<Option<RefillAction>, GuacError> {
+        let (their_url, threshold) = match self.refill.threshold(&their_address) {
+            Some(threshold) => threshold,
+            None => return Ok(None),
+        };
+        let _busy = match self.refill.start(their_address) {
+            Some(busy) => busy,
+            None => return Ok(None),
+        };
+
+        let balance = self.check_my_balance(their_address).await?;
+
+        let too_low = balance < threshold.min;
+        let too_high = threshold.max.is_some_and(|max| balance > max);
+
+        let action = if too_low {
+            let wallet = self.blockchain_client.balance_of().await?;
+            let available = wallet
+                .checked_sub(&self.refill.wallet_reserve)
+                .unwrap_or_else(|| 0u64.into());
+            if available == 0u64.into() {
+                return Err(ProtocolError::NotEnough {
+                    stuff: "money in wallet to refill channel".to_string(),
+                }
+                .into());
+            }
+
+            let amount = match threshold.target.checked_sub(&balance) {
+                Some(amount) => amount.min(available),
+                None => return Ok(None),
+            };
+            self.fill_channel(their_address, their_url, amount.clone())
+                .await?;
+            RefillAction::Filled { amount }
+        } else if too_high {
+            let amount = match balance.checked_sub(&threshold.target) {
+                Some(amount) => amount,
+                None => return Ok(None),
+            };
+            self.withdraw(their_address, their_url, amount.clone())
+                .await?;
+            RefillAction::Withdrew { amount }
+        } else {
+            return Ok(None);
+        };
+
+        log::info!("Rebalanced channel with {}: {:?}", their_address, action);
+        self.events.emit(GuacEvent::Rebalanced {
+            counterparty: their_address,
+            action: action.clone(),
+        });
+        Ok(Some(action))
+    }
+
+    /// Rebalances the channels of every counterparty which has a threshold
+    pub async fn rebalance_all(&self) {
+        for their_address in self.refill.counterparties() {
+            self.auto_rebalance(their_address).await;
+        }
+    }
+
+    /// Rebalances the channels queued by our payments since the last call. Errors are only
+    /// reported, through the log and events.
+    pub async fn rebalance_queued(&self) {
+        for their_address in self.refill.take_queued() {
+            self.auto_rebalance(their_address).await;
+        }
+    }
+
+    /// Rebalances on behalf of something else than the application, so that failing to do so
+    /// is only reported, through the log and events
+    pub(crate) async fn auto_rebalance(&self, their_address: Address) {
+        if let Err(e) = self.rebalance(their_address).await {
+            log::warn!("Could not rebalance channel with {}: {}", their_address, e);
+            let _ = self.report_error::<()>(their_address, Err(e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventStream;
+    use crate::test_utils::{make_pair, TestNode};
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    fn rebalances(stream: &mut EventStream) -> Vec<GuacEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = stream.try_recv() {
+            match event {
+                GuacEvent::Rebalanced { .. } | GuacEvent::Error { .. } => events.push(event),
+                _ => {}
+            }
+        }
+        events
+    }
+
+    fn set_threshold(node: &TestNode, other: &TestNode, threshold: Threshold) {
+        node.guac
+            .refill
+            .set_threshold(other.address, other.url.clone(), threshold);
+    }
+
+    #[test]
+    fn test_refill_after_payment() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+        let mut events_0 = guac_0.subscribe();
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            set_threshold(
+                &node_0,
+                &node_1,
+                Threshold::new(50u64.into(), 100u64.into()),
+            );
+            node_0.blockchain.set_wallet_balance(1000);
+
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 30u64.into())
+                .await
+                .unwrap();
+            guac_0.rebalance_queued().await;
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                70u64.into()
+            );
+
+            // The payment does not wait for the refill
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 30u64.into())
+                .await
+                .unwrap();
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                40u64.into()
+            );
+            guac_0.rebalance_queued().await;
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                100u64.into()
+            );
+        });
+
+        assert_eq!(
+            rebalances(&mut events_0),
+            vec![GuacEvent::Rebalanced {
+                counterparty: node_1.address,
+                action: RefillAction::Filled {
+                    amount: 60u64.into()
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_refill_within_wallet() {
+        let (mut node_0, node_1) = make_pair();
+        // Only what the wallet has above 10 pays for refills
+        node_0.guac.refill = Arc::new(Box::new(
+            RefillManager::new().with_wallet_reserve(10u64.into()),
+        ));
+        node_0.network.register(&node_0.url, node_0.guac.clone());
+        let guac_0 = &node_0.guac;
+        let mut events_0 = guac_0.subscribe();
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            set_threshold(
+                &node_0,
+                &node_1,
+                Threshold::new(50u64.into(), 100u64.into()),
+            );
+            node_0.blockchain.set_wallet_balance(30);
+
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 60u64.into())
+                .await
+                .unwrap();
+            guac_0.rebalance_queued().await;
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                60u64.into()
+            );
+
+            // Failing to refill does not fail the payment
+            node_0.blockchain.set_wallet_balance(10);
+            guac_0
+                .make_payment(node_1.address, node_1.url.clone(), 20u64.into())
+                .await
+                .unwrap();
+            guac_0.rebalance_queued().await;
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                40u64.into()
+            );
+        });
+
+        assert_eq!(
+            rebalances(&mut events_0),
+            vec![
+                GuacEvent::Rebalanced {
+                    counterparty: node_1.address,
+                    action: RefillAction::Filled {
+                        amount: 20u64.into()
+                    },
+                },
+                GuacEvent::Error {
+                    counterparty: node_1.address,
+                    message: "Not enough money in wallet to refill channel".to_string(),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_withdraw_above_max() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            set_threshold(
+                &node_0,
+                &node_1,
+                Threshold::new(10u64.into(), 50u64.into()).with_max(80u64.into()),
+            );
+
+            assert_eq!(
+                guac_0.rebalance(node_1.address).await.unwrap(),
+                Some(RefillAction::Withdrew {
+                    amount: 50u64.into()
+                })
+            );
+            assert_eq!(guac_0.rebalance(node_1.address).await.unwrap(), None);
+            assert_eq!(
+                guac_0.check_my_balance(node_1.address).await.unwrap(),
+                50u64.into()
+            );
+        });
+    }
+
+    #[test]
+    fn test_one_rebalance_at_a_time() {
+        let (node_0, node_1) = make_pair();
+        let guac_0 = &node_0.guac;
+
+        block_on(async {
+            guac_0
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            set_threshold(
+                &node_0,
+                &node_1,
+                Threshold::new(10u64.into(), 50u64.into()).with_max(80u64.into()),
+            );
+
+            let busy = guac_0.refill.start(node_1.address);
+            assert!(busy.is_some());
+            assert_eq!(guac_0.rebalance(node_1.address).await.unwrap(), None);
+            drop(busy);
+            assert!(guac_0.rebalance(node_1.address).await.unwrap().is_some());
+        });
+    }
+}
@@ -0,0 +1,758 @@
+//! Payments to nodes we have no channel with, through the channels of other nodes.
+//!
+//! Every node keeps a `Graph` of the nodes it knows about, learned from other nodes (see
+//! `Guac::gossip`) or added by hand. To pay someone, the payer finds the cheapest route through
+//! the graph and locks the payment to the first hop with a hashlock, along with a `Forward`
+//! telling the hop where the payment goes next. Each hop locks the payment, less its fee, to the
+//! next one, until it reaches the payee. The payee made the invoice, so it knows the preimage
+//! and claims its hashlock. That reveals the preimage to the hop before it, which claims its own
+//! hashlock in turn, and so on back to the payer.
+
+use crate::channel_manager::check_for_counterparty;
+use crate::error::{GuacError, ProtocolError};
+use crate::events::GuacEvent;
+use crate::types::{Counterparty, Hashlock};
+use crate::Guac;
+use clarity::Address;
+use num256::Uint256;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+/// How many blocks each hop has to claim its hashlock after the next hop has claimed theirs
+pub const HOP_EXPIRATION_DELTA: u64 = 40;
+
+/// A channel of a node, as advertised by the node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelInfo {
+    pub counterparty: Address,
+    /// How much the node can pay the counterparty through the channel
+    pub capacity: Uint256,
+}
+
+/// What a node tells other nodes about itself
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub address: Address,
+    pub url: String,
+    /// What the node charges for forwarding a payment
+    pub fee: Uint256,
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// One hop of a route: `amount` is locked to the node at `address` until block `expiration`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hop {
+    pub address: Address,
+    pub url: String,
+    pub amount: Uint256,
+    pub expiration: Uint256,
+}
+
+/// The hops a payment goes through. The last hop is the payee.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub hops: Vec<Hop>,
+}
+
+impl Route {
+    /// What the payer locks to the first hop
+    pub fn amount(&self) -> Uint256 {
+        self.hops[0].amount.clone()
+    }
+
+    /// What the hops charge on top of what the payee gets
+    pub fn fee(&self) -> Uint256 {
+        self.amount() - self.hops[self.hops.len() - 1].amount.clone()
+    }
+}
+
+/// Sent to a hop along with the hashlock which pays it, telling it where to forward the payment
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+    pub hash: [u8; 32],
+    /// Where the sender can be reached, to claim or give back its hashlock
+    pub from_url: String,
+    /// The rest of the route, empty if the receiver is the payee
+    pub hops: Vec<Hop>,
+}
+
+/// The nodes we know about and their channels
+#[derive(Debug, Clone, Default)]
+pub struct Graph {
+    nodes: HashMap<Address, NodeInfo>,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph::default()
+    }
+
+    /// Replaces whatever we knew about the node
+    pub fn add_node(&mut self, node: NodeInfo) {
+        self.nodes.insert(node.address, node);
+    }
+
+    pub fn remove_node(&mut self, address: &Address) {
+        self.nodes.remove(address);
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeInfo> {
+        self.nodes.values()
+    }
+
+    /// The route from `from` to `to` which costs the least in fees, through channels which can
+    /// carry the payment and the fees of the hops after them. The expirations of the hops are
+    /// left at zero.
+    pub fn find_route(&self, from: Address, to: Address, amount: Uint256) -> Option<Route> {
+        if from == to || !self.nodes.contains_key(&to) {
+            return None;
+        }
+
+        // Dijkstra's, from the payee back to the payer. For every node from which the payee can
+        // be reached: what has to reach the node, and who it pays next. The payer does not
+        // charge itself a fee.
+        let mut best: HashMap<Address, (Uint256, Option<Address>)> = HashMap::new();
+        let mut done: HashSet<Address> = HashSet::new();
+        best.insert(to, (amount, None));
+
+        loop {
+            let (node, needed) = best
+                .iter()
+                .filter(|(address, _)| !done.contains(*address))
+                .min_by_key(|(_, (needed, _))| needed.clone())
+                .map(|(address, (needed, _))| (*address, needed.clone()))?;
+            if node == from {
+                break;
+            }
+            done.insert(node);
+
+            for payer in self.nodes.values() {
+                if done.contains(&payer.address) {
+                    continue;
+                }
+                let can_pay = payer
+                    .channels
+                    .iter()
+                    .any(|channel| channel.counterparty == node && channel.capacity >= needed);
+                if !can_pay {
+                    continue;
+                }
+
+                let cost = if payer.address == from {
+                    needed.clone()
+                } else {
+                    needed.clone() + payer.fee.clone()
+                };
+                if best
+                    .get(&payer.address)
+                    .is_none_or(|(best_cost, _)| cost < *best_cost)
+                {
+                    best.insert(payer.address, (cost, Some(node)));
+                }
+            }
+        }
+
+        let mut hops = Vec::new();
+        let mut next = best[&from].1;
+        while let Some(address) = next {
+            let (amount, after) = &best[&address];
+            hops.push(Hop {
+                address,
+                url: self.nodes[&address].url.clone(),
+                amount: amount.clone(),
+                expiration: 0u64.into(),
+            });
+            next = *after;
+        }
+        Some(Route { hops })
+    }
+}
+
+/// A payment we expect, see `Router::add_invoice`
+#[derive(Debug, Clone)]
+struct Invoice {
+    preimage: [u8; 32],
+    amount: Uint256,
+}
+
+/// Who sent us a payment we forwarded, and so pays us once the payee claims it
+#[derive(Debug, Clone)]
+struct Upstream {
+    address: Address,
+    url: String,
+}
+
+/// Everything a node needs to send, forward and receive payments over routes
+pub struct Router {
+    url: String,
+    fee: Uint256,
+    graph: RwLock<Graph>,
+    invoices: Mutex<HashMap<[u8; 32], Invoice>>,
+    forwards: Mutex<HashMap<[u8; 32], Upstream>>,
+}
+
+impl Router {
+    /// `url` is where other nodes reach us. Payments are forwarded for free unless a fee is set
+    /// with `with_fee`.
+    pub fn new(url: String) -> Router {
+        Router {
+            url,
+            fee: 0u64.into(),
+            graph: RwLock::new(Graph::new()),
+            invoices: Mutex::new(HashMap::new()),
+            forwards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_fee(mut self, fee: Uint256) -> Self {
+        self.fee = fee;
+        self
+    }
+
+    pub fn add_node(&self, node: NodeInfo) {
+        self.graph.write().unwrap().add_node(node);
+    }
+
+    pub fn remove_node(&self, address: &Address) {
+        self.graph.write().unwrap().remove_node(address);
+    }
+
+    /// A copy of the nodes we know about
+    pub fn graph(&self) -> Graph {
+        self.graph.read().unwrap().clone()
+    }
+
+    /// Lets a payer pay us at least `amount` over a route. Returns the hash to give to the
+    /// payer. The preimage has to stay secret, and should not be used for anything else.
+    pub fn add_invoice(&self, preimage: [u8; 32], amount: Uint256) -> [u8; 32] {
+        let hash = Hashlock::hash_of(&preimage);
+        self.invoices
+            .lock()
+            .unwrap()
+            .insert(hash, Invoice { preimage, amount });
+        hash
+    }
+
+    fn invoice(&self, hash: &[u8; 32]) -> Option<Invoice> {
+        self.invoices.lock().unwrap().get(hash).cloned()
+    }
+
+    fn remove_invoice(&self, hash: &[u8; 32]) {
+        self.invoices.lock().unwrap().remove(hash);
+    }
+
+    fn add_forward(&self, hash: [u8; 32], upstream: Upstream) {
+        self.forwards.lock().unwrap().insert(hash, upstream);
+    }
+
+    fn take_forward(&self, hash: &[u8; 32]) -> Option<Upstream> {
+        self.forwards.lock().unwrap().remove(hash)
+    }
+}
+
+/// Blames `hop` for an error, unless a hop further down the route already got the blame
+fn route_failed(hop: Address, error: GuacError) -> GuacError {
+    match error {
+        GuacError::Protocol(ProtocolError::RouteFailed { .. }) => error,
+        error => ProtocolError::RouteFailed {
+            hop,
+            message: error.to_string(),
+        }
+        .into(),
+    }
+}
+
+fn forbidden<T: ToString>(message: T) -> GuacError {
+    ProtocolError::Forbidden {
+        message: message.to_string(),
+    }
+    .into()
+}
+
+impl Guac {
+    /// What we tell other nodes about ourselves, with what we can pay through each of our open
+    /// channels
+    pub fn node_info(&self) -> NodeInfo {
+        let channels = self
+            .storage
+            .get_all_counterparties()
+            .into_iter()
+            .filter_map(|(address, counterparty)| match counterparty {
+                Counterparty::Open { channel } => Some(ChannelInfo {
+                    counterparty: address,
+                    capacity: channel.my_balance(),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        NodeInfo {
+            address: self.crypto.own_address,
+            url: self.router.url.clone(),
+            fee: self.router.fee.clone(),
+            channels,
+        }
+    }
+
+    /// Adds the node at `url`, and every node it knows about, to our graph
+    pub async fn gossip(&self, url: String) -> Result<(), GuacError> {
+        let nodes = self.counterparty_client.get_info(url).await?;
+        for node in nodes {
+            if node.address != self.crypto.own_address {
+                self.router.add_node(node);
+            }
+        }
+        Ok(())
+    }
+
+    /// The cheapest route to pay `amount` to `to` over, with the expirations it would get now
+    pub async fn find_route(&self, to: Address, amount: Uint256) -> Result<Route, GuacError> {
+        let mut graph = self.router.graph();
+        graph.add_node(self.node_info());
+        let mut route = graph
+            .find_route(self.crypto.own_address, to, amount)
+            .ok_or(ProtocolError::NoRoute { to })?;
+
+        // Each hop gets more time than the next, to claim its hashlock once the next hop has
+        // revealed the preimage
+        let block = self.blockchain_client.get_current_block().await?;
+        let count = route.hops.len();
+        for (i, hop) in route.hops.iter_mut().enumerate() {
+            hop.expiration =
+                block.clone() + Uint256::from(HOP_EXPIRATION_DELTA * (count - i) as u64);
+        }
+        Ok(route)
+    }
+
+    /// Pays `amount` to `to` over a route, for `hash` from an invoice of the payee. Returns the
+    /// route once the payee has claimed the payment, `Route::fee` is what it cost on top.
+    ///
+    /// If the route fails, the first hop gives our hashlock back. If it cannot be reached at
+    /// all, the hashlock is only ours again once it expires, see `expire_conditional_payments`.
+    pub async fn make_routed_payment(
+        &self,
+        to: Address,
+        amount: Uint256,
+        hash: [u8; 32],
+    ) -> Result<Route, GuacError> {
+        let route = self.find_route(to, amount).await?;
+        let first = &route.hops[0];
+
+        self.make_conditional_payment(
+            first.address,
+            first.url.clone(),
+            hash,
+            first.amount.clone(),
+            first.expiration.clone(),
+        )
+        .await?;
+
+        let forward = Forward {
+            hash,
+            from_url: self.router.url.clone(),
+            hops: route.hops[1..].to_vec(),
+        };
+        self.counterparty_client
+            .forward_payment(self.crypto.own_address, first.url.clone(), forward)
+            .await
+            .map_err(|e| route_failed(first.address, e))?;
+
+        Ok(route)
+    }
+
+    /// Forwards or claims the payment of a hashlock the counterparty just locked to us. If that
+    /// fails, the payment goes no further, so the counterparty gets its hashlock back.
+    ///
+    /// Once we pay the next hop a hashlock for the payment though, the payee may still claim it
+    /// from there, so the counterparty keeps paying us until that hashlock is given back to us
+    /// or expires.
+    pub(crate) async fn handle_forward(
+        &self,
+        from_address: Address,
+        forward: Forward,
+    ) -> Result<(), GuacError> {
+        let incoming = self.incoming_hashlock(from_address, &forward.hash)?;
+
+        let res = match forward.hops.first() {
+            None => self.claim_invoice(from_address, &forward, &incoming).await,
+            Some(next) => {
+                self.forward_to(from_address, &forward, &incoming, next)
+                    .await
+            }
+        };
+
+        let outgoing = forward
+            .hops
+            .first()
+            .is_some_and(|next| self.pays_hashlock(next.address, &forward.hash));
+        if res.is_err() && !outgoing {
+            self.router.take_forward(&forward.hash);
+            if let Err(e) = self
+                .cancel_conditional_payment(from_address, forward.from_url.clone(), forward.hash)
+                .await
+            {
+                log::warn!("Could not give hashlock back to {}: {}", from_address, e);
+            }
+        }
+        res
+    }
+
+    /// If we forwarded the payment for `hash`, claims it from whoever sent it to us now that the
+    /// next hop has shown us the preimage
+    pub(crate) async fn claim_forwarded(&self, hash: &[u8; 32], preimage: [u8; 32]) {
+        if let Some(upstream) = self.router.take_forward(hash) {
+            let res = self
+                .claim_conditional_payment(upstream.address, upstream.url, preimage)
+                .await;
+            let _ = self.report_error(upstream.address, res);
+        }
+    }
+
+    /// The hashlock the counterparty pays us for `hash`
+    fn incoming_hashlock(
+        &self,
+        from_address: Address,
+        hash: &[u8; 32],
+    ) -> Result<Hashlock, GuacError> {
+        let channel = match check_for_counterparty(&self.storage, from_address)? {
+            Counterparty::Open { channel } => channel,
+            counterparty => {
+                return Err(ProtocolError::WrongState {
+                    correct_state: "Open".to_string(),
+                    current_state: format!("{:?}", counterparty),
+                    action: "forward payment".to_string(),
+                }
+                .into())
+            }
+        };
+
+        let i_am_0 = channel.i_am_0;
+        channel
+            .hashlocks
+            .into_iter()
+            .find(|hashlock| hashlock.hash == *hash && hashlock.from_0 != i_am_0)
+            .ok_or_else(|| forbidden("No hashlock for this payment"))
+    }
+
+    /// Whether we pay the counterparty a hashlock for `hash`
+    fn pays_hashlock(&self, their_address: Address, hash: &[u8; 32]) -> bool {
+        match check_for_counterparty(&self.storage, their_address) {
+            Ok(Counterparty::Open { channel }) => channel
+                .hashlocks
+                .iter()
+                .any(|hashlock| hashlock.hash == *hash && hashlock.from_0 == channel.i_am_0),
+            _ => false,
+        }
+    }
+
+    async fn claim_invoice(
+        &self,
+        from_address: Address,
+        forward: &Forward,
+        incoming: &Hashlock,
+    ) -> Result<(), GuacError> {
+        let invoice = self
+            .router
+            .invoice(&forward.hash)
+            .ok_or_else(|| forbidden("Unknown payment"))?;
+        if incoming.amount < invoice.amount {
+            return Err(ProtocolError::NotEnough {
+                stuff: "money in hashlock".to_string(),
+            }
+            .into());
+        }
+
+        self.claim_conditional_payment(from_address, forward.from_url.clone(), invoice.preimage)
+            .await?;
+        self.router.remove_invoice(&forward.hash);
+        Ok(())
+    }
+
+    async fn forward_to(
+        &self,
+        from_address: Address,
+        forward: &Forward,
+        incoming: &Hashlock,
+        next: &Hop,
+    ) -> Result<(), GuacError> {
+        let fee = self.router.fee.clone();
+        if incoming.amount < next.amount.clone() + fee.clone() {
+            return Err(forbidden(format!("My fee is {}", fee)));
+        }
+        if incoming.expiration < next.expiration.clone() + Uint256::from(HOP_EXPIRATION_DELTA) {
+            return Err(forbidden(format!(
+                "I need {} blocks to claim my hashlock after the next hop",
+                HOP_EXPIRATION_DELTA
+            )));
+        }
+
+        // The payee can claim before the next hop has even answered us
+        self.router.add_forward(
+            forward.hash,
+            Upstream {
+                address: from_address,
+                url: forward.from_url.clone(),
+            },
+        );
+
+        self.make_conditional_payment(
+            next.address,
+            next.url.clone(),
+            forward.hash,
+            next.amount.clone(),
+            next.expiration.clone(),
+        )
+        .await?;
+
+        let next_forward = Forward {
+            hash: forward.hash,
+            from_url: self.router.url.clone(),
+            hops: forward.hops[1..].to_vec(),
+        };
+        self.counterparty_client
+            .forward_payment(self.crypto.own_address, next.url.clone(), next_forward)
+            .await
+            .map_err(|e| route_failed(next.address, e))?;
+
+        self.events.emit(GuacEvent::PaymentForwarded {
+            counterparty: from_address,
+            next: next.address,
+            hash: forward.hash,
+            fee: incoming.amount.clone() - next.amount.clone(),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{
+        make_node, MockChain, MockNetwork, TestNode, SECRET_0, SECRET_1, SECRET_2, SECRET_3,
+    };
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    fn address(n: u8) -> Address {
+        format!("0x{}", format!("{:02x}", n).repeat(20))
+            .parse()
+            .unwrap()
+    }
+
+    fn node(n: u8, fee: u64, channels: &[(u8, u64)]) -> NodeInfo {
+        NodeInfo {
+            address: address(n),
+            url: n.to_string(),
+            fee: fee.into(),
+            channels: channels
+                .iter()
+                .map(|(counterparty, capacity)| ChannelInfo {
+                    counterparty: address(*counterparty),
+                    capacity: (*capacity).into(),
+                })
+                .collect(),
+        }
+    }
+
+    fn hops(route: &Route) -> Vec<(Address, Uint256)> {
+        route
+            .hops
+            .iter()
+            .map(|hop| (hop.address, hop.amount.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_route() {
+        // 1 can pay 4 through 2, or more cheaply through 3, which can only pay 4 a little
+        let mut graph = Graph::new();
+        graph.add_node(node(1, 0, &[(2, 100), (3, 100)]));
+        graph.add_node(node(2, 5, &[(4, 100)]));
+        graph.add_node(node(3, 2, &[(4, 10)]));
+        graph.add_node(node(4, 0, &[]));
+
+        let route = graph
+            .find_route(address(1), address(4), 5u64.into())
+            .unwrap();
+        assert_eq!(
+            hops(&route),
+            vec![(address(3), 7u64.into()), (address(4), 5u64.into())]
+        );
+        assert_eq!(route.fee(), 2u64.into());
+
+        let route = graph
+            .find_route(address(1), address(4), 20u64.into())
+            .unwrap();
+        assert_eq!(
+            hops(&route),
+            vec![(address(2), 25u64.into()), (address(4), 20u64.into())]
+        );
+        assert_eq!(route.fee(), 5u64.into());
+
+        // The fee of 2 does not fit in the channel of 1 with 2
+        assert_eq!(graph.find_route(address(1), address(4), 96u64.into()), None);
+        // Channels only pay one way
+        assert_eq!(graph.find_route(address(4), address(1), 1u64.into()), None);
+    }
+
+    fn with_fee(mut node: TestNode, fee: u64) -> TestNode {
+        node.guac.router = Arc::new(Box::new(Router::new(node.url.clone()).with_fee(fee.into())));
+        node.network.register(&node.url, node.guac.clone());
+        node
+    }
+
+    /// A pays D through B and C, which charge 1 and 2
+    fn make_route() -> (TestNode, TestNode, TestNode, TestNode) {
+        let network = MockNetwork::default();
+        let chain = MockChain::default();
+        let a = make_node(&network, &chain, SECRET_0, "a");
+        let b = with_fee(make_node(&network, &chain, SECRET_1, "b"), 1);
+        let c = with_fee(make_node(&network, &chain, SECRET_2, "c"), 2);
+        let d = make_node(&network, &chain, SECRET_3, "d");
+
+        block_on(async {
+            for (from, to) in &[(&a, &b), (&b, &c), (&c, &d)] {
+                from.guac
+                    .fill_channel(to.address, to.url.clone(), 100u64.into())
+                    .await
+                    .unwrap();
+            }
+
+            // The graph spreads back from D
+            c.guac.gossip(d.url.clone()).await.unwrap();
+            b.guac.gossip(c.url.clone()).await.unwrap();
+            a.guac.gossip(b.url.clone()).await.unwrap();
+        });
+
+        (a, b, c, d)
+    }
+
+    async fn balance_and_hashlocks(node: &TestNode, other: &TestNode) -> (Uint256, usize) {
+        match node.guac.get_state(other.address).await.unwrap() {
+            Counterparty::Open { channel } => (channel.my_balance(), channel.hashlocks.len()),
+            state => panic!("unexpected state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn test_routed_payment() {
+        let (a, b, c, d) = make_route();
+        let mut events_b = b.guac.subscribe();
+        let hash = d.guac.router.add_invoice([7; 32], 10u64.into());
+
+        block_on(async {
+            let route = a
+                .guac
+                .make_routed_payment(d.address, 10u64.into(), hash)
+                .await
+                .unwrap();
+            assert_eq!(
+                hops(&route),
+                vec![
+                    (b.address, 13u64.into()),
+                    (c.address, 12u64.into()),
+                    (d.address, 10u64.into())
+                ]
+            );
+            assert_eq!(route.fee(), 3u64.into());
+
+            assert_eq!(b.guac.check_accrual(a.address).await.unwrap(), 13u64.into());
+            assert_eq!(c.guac.check_accrual(b.address).await.unwrap(), 12u64.into());
+            assert_eq!(d.guac.check_accrual(c.address).await.unwrap(), 10u64.into());
+            for (node, other, balance) in &[(&a, &b, 87u64), (&b, &c, 88), (&c, &d, 90)] {
+                assert_eq!(
+                    balance_and_hashlocks(node, other).await,
+                    ((*balance).into(), 0)
+                );
+            }
+        });
+
+        let forwarded = std::iter::from_fn(|| events_b.try_recv().ok())
+            .find(|event| matches!(event, GuacEvent::PaymentForwarded { .. }));
+        assert_eq!(
+            forwarded,
+            Some(GuacEvent::PaymentForwarded {
+                counterparty: a.address,
+                next: c.address,
+                hash,
+                fee: 1u64.into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_routed_payment_failure() {
+        let (a, b, c, d) = make_route();
+
+        block_on(async {
+            match a.guac.find_route(a.address, 1u64.into()).await {
+                Err(GuacError::Protocol(ProtocolError::NoRoute { to })) => {
+                    assert_eq!(to, a.address)
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            // D has no invoice for this hash, so the route fails at D and every hashlock is
+            // given back
+            match a
+                .guac
+                .make_routed_payment(d.address, 10u64.into(), [1; 32])
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::RouteFailed { hop, message })) => {
+                    assert_eq!(hop, d.address);
+                    assert_eq!(message, "Invalid request: Unknown payment");
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            for (node, other) in &[(&a, &b), (&b, &c), (&c, &d)] {
+                assert_eq!(balance_and_hashlocks(node, other).await, (100u64.into(), 0));
+            }
+        });
+    }
+
+    #[test]
+    fn test_routed_payment_lost_after_next_hop() {
+        let (a, b, c, d) = make_route();
+        let mut events_b = b.guac.subscribe();
+        let preimage = [7; 32];
+        let hash = d.guac.router.add_invoice(preimage, 10u64.into());
+
+        // C cannot reach D, after it has paid D a hashlock it cannot take back before it expires
+        let e = make_node(&d.network, &MockChain::default(), SECRET_3, "e");
+        d.network.register(&d.url, e.guac.clone());
+
+        block_on(async {
+            match a
+                .guac
+                .make_routed_payment(d.address, 10u64.into(), hash)
+                .await
+            {
+                Err(GuacError::Protocol(ProtocolError::RouteFailed { hop, .. })) => {
+                    assert_eq!(hop, c.address)
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+
+            // D may still claim, so every hop keeps the hashlock which pays it
+            for (node, other, balance) in &[(&a, &b, 87u64), (&b, &c, 88), (&c, &d, 90)] {
+                assert_eq!(
+                    balance_and_hashlocks(node, other).await,
+                    ((*balance).into(), 1)
+                );
+            }
+
+            // Once C claims, B claims from A in turn
+            c.guac
+                .claim_conditional_payment(b.address, b.url.clone(), preimage)
+                .await
+                .unwrap();
+            assert_eq!(balance_and_hashlocks(&a, &b).await, (87u64.into(), 0));
+            assert_eq!(b.guac.check_accrual(a.address).await.unwrap(), 13u64.into());
+        });
+
+        assert!(!std::iter::from_fn(|| events_b.try_recv().ok())
+            .any(|event| matches!(event, GuacEvent::PaymentForwarded { .. })));
+    }
+}
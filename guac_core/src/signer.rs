@@ -0,0 +1,854 @@
+//! Keeps the private key of the node behind a `Signer`, which signs everything the node signs:
+//! the fingerprints of channel messages as well as transactions to the contract.
+//!
+//! `InMemorySigner` holds the key in the process, `KeystoreSigner` unlocks it from an encrypted
+//! Ethereum keystore file, and `RemoteSigner` asks another process over a local socket, so that
+//! the key never has to be in the payment process at all.
+
+use crate::crypto;
+use crate::eip712::TypedData;
+use crate::error::SignerError;
+use aes::cipher::{KeyIvInit, StreamCipher};
+use async_trait::async_trait;
+use clarity::{Address, PrivateKey, Signature, Transaction};
+use hmac::Hmac;
+use num256::Uint256;
+use sha2::Sha256;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Holds the key of the node and signs with it. Signing may have to wait, for instance on
+/// another process or on somebody confirming it, so it is asynchronous and the node never
+/// holds the lock of a counterparty while it waits.
+#[async_trait(?Send)]
+pub trait Signer {
+    /// The address of the key
+    fn address(&self) -> Address;
+
+    /// Signs a 32 byte hash, such as a fingerprint
+    async fn sign_hash(&self, hash: &[u8]) -> Result<Signature, SignerError>;
+
+    /// Signs the hash of `typed_data`. Signers which can show typed data, like wallets, get the
+    /// message itself here, so they can show it before signing; by default only the hash is
+    /// signed, like any other.
+    async fn sign_typed_data(&self, typed_data: &TypedData) -> Result<Signature, SignerError> {
+        self.sign_hash(&typed_data.hash()).await
+    }
+}
+
+/// Signs a transaction for the network `network_id` with replay protection (EIP-155), like
+/// `Transaction::sign` does with a `PrivateKey`
+pub async fn sign_transaction(
+    signer: &dyn Signer,
+    transaction: Transaction,
+    network_id: u64,
+) -> Result<Transaction, SignerError> {
+    // What is signed under EIP-155 is the transaction with the network id in place of `v`, and
+    // empty `r` and `s`
+    let unsigned = Transaction {
+        signature: Some(Signature::new(network_id.into(), 0u64.into(), 0u64.into())),
+        ..transaction.clone()
+    };
+    let hash = unsigned.hash();
+
+    let mut signature = signer.sign_hash(&hash).await?;
+    signature.v += Uint256::from(network_id * 2 + 8);
+
+    Ok(Transaction {
+        signature: Some(signature),
+        ..transaction
+    })
+}
+
+/// Fails for anything but a 32 byte hash, which is all a `Signer` signs
+fn check_hash(hash: &[u8]) -> Result<(), SignerError> {
+    if hash.len() != 32 {
+        return Err(SignerError::InvalidHash(hash.len()));
+    }
+    Ok(())
+}
+
+/// Signs with a key held in memory, which is zeroed when the signer is dropped
+pub struct InMemorySigner {
+    secret: Zeroizing<[u8; 32]>,
+    address: Address,
+}
+
+impl InMemorySigner {
+    /// Keeps a copy of `secret`, which the caller should drop as soon as it can
+    pub fn new(secret: PrivateKey) -> Result<InMemorySigner, SignerError> {
+        InMemorySigner::from_slice(&Zeroizing::new(secret.to_bytes())[..])
+    }
+
+    /// Reads a key in hex, with or without `0x`, for instance from a file or an environment
+    /// variable when the node starts
+    pub fn from_hex(secret: &str) -> Result<InMemorySigner, SignerError> {
+        let secret = Zeroizing::new(
+            hex::decode(secret.trim().trim_start_matches("0x"))
+                .map_err(|_| SignerError::InvalidKey)?,
+        );
+        InMemorySigner::from_slice(&secret)
+    }
+
+    fn from_slice(secret: &[u8]) -> Result<InMemorySigner, SignerError> {
+        if secret.len() != 32 {
+            return Err(SignerError::InvalidKey);
+        }
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        bytes.copy_from_slice(secret);
+
+        let mut signer = InMemorySigner {
+            secret: bytes,
+            address: Address::default(),
+        };
+        signer.address = signer
+            .key()?
+            .to_public_key()
+            .map_err(|_| SignerError::InvalidKey)?;
+        Ok(signer)
+    }
+
+    /// Only lives for as long as it is used
+    fn key(&self) -> Result<PrivateKey, SignerError> {
+        PrivateKey::from_slice(&self.secret[..]).map_err(|_| SignerError::InvalidKey)
+    }
+}
+
+#[async_trait(?Send)]
+impl Signer for InMemorySigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    async fn sign_hash(&self, hash: &[u8]) -> Result<Signature, SignerError> {
+        check_hash(hash)?;
+        Ok(self.key()?.sign_hash(hash))
+    }
+}
+
+impl fmt::Debug for InMemorySigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("InMemorySigner")
+            .field("address", &self.address)
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+type Aes128Ctr = ctr::Ctr128BE<aes::Aes128>;
+
+#[derive(Deserialize)]
+struct Keystore {
+    version: u32,
+    address: Option<String>,
+    #[serde(alias = "Crypto")]
+    crypto: KeystoreCrypto,
+}
+
+#[derive(Deserialize)]
+struct KeystoreCrypto {
+    cipher: String,
+    cipherparams: CipherParams,
+    ciphertext: String,
+    #[serde(flatten)]
+    kdf: Kdf,
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct CipherParams {
+    iv: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kdf", content = "kdfparams", rename_all = "lowercase")]
+enum Kdf {
+    Scrypt {
+        dklen: usize,
+        n: u64,
+        r: u32,
+        p: u32,
+        salt: String,
+    },
+    Pbkdf2 {
+        c: u32,
+        dklen: usize,
+        prf: String,
+        salt: String,
+    },
+}
+
+fn decode_hex(field: &str, value: &str) -> Result<Vec<u8>, SignerError> {
+    hex::decode(value.trim_start_matches("0x"))
+        .map_err(|_| SignerError::Keystore(format!("{} is not hex", field)))
+}
+
+impl Kdf {
+    fn derive_key(&self, passphrase: &str) -> Result<Zeroizing<Vec<u8>>, SignerError> {
+        match self {
+            Kdf::Scrypt {
+                dklen,
+                n,
+                r,
+                p,
+                salt,
+            } => {
+                if *dklen < 32 || !n.is_power_of_two() {
+                    return Err(SignerError::Keystore(
+                        "unsupported scrypt parameters".into(),
+                    ));
+                }
+                let params = scrypt::Params::new(n.trailing_zeros() as u8, *r, *p)
+                    .map_err(|_| SignerError::Keystore("invalid scrypt parameters".into()))?;
+                let mut key = Zeroizing::new(vec![0u8; *dklen]);
+                scrypt::scrypt(
+                    passphrase.as_bytes(),
+                    &decode_hex("salt", salt)?,
+                    &params,
+                    &mut key,
+                )
+                .map_err(|_| SignerError::Keystore("invalid scrypt key length".into()))?;
+                Ok(key)
+            }
+            Kdf::Pbkdf2 {
+                c,
+                dklen,
+                prf,
+                salt,
+            } => {
+                if *dklen < 32 || prf != "hmac-sha256" {
+                    return Err(SignerError::Keystore(
+                        "unsupported pbkdf2 parameters".into(),
+                    ));
+                }
+                let mut key = Zeroizing::new(vec![0u8; *dklen]);
+                pbkdf2::pbkdf2::<Hmac<Sha256>>(
+                    passphrase.as_bytes(),
+                    &decode_hex("salt", salt)?,
+                    *c,
+                    &mut key,
+                );
+                Ok(key)
+            }
+        }
+    }
+}
+
+/// Signs with a key unlocked from an Ethereum keystore file (version 3), as written by geth and
+/// most wallets
+#[derive(Debug)]
+pub struct KeystoreSigner {
+    signer: InMemorySigner,
+}
+
+impl KeystoreSigner {
+    /// Decrypts the keystore at `path` with `passphrase`
+    pub fn unlock<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<KeystoreSigner, SignerError> {
+        let json = fs::read_to_string(path).map_err(|e| SignerError::Keystore(e.to_string()))?;
+        KeystoreSigner::from_json(&json, passphrase)
+    }
+
+    /// Decrypts a keystore which was already read
+    pub fn from_json(json: &str, passphrase: &str) -> Result<KeystoreSigner, SignerError> {
+        let keystore: Keystore =
+            serde_json::from_str(json).map_err(|e| SignerError::Keystore(e.to_string()))?;
+        let encrypted = &keystore.crypto;
+        if keystore.version != 3 || encrypted.cipher != "aes-128-ctr" {
+            return Err(SignerError::Keystore(
+                "only version 3 with aes-128-ctr is supported".into(),
+            ));
+        }
+
+        let derived_key = encrypted.kdf.derive_key(passphrase)?;
+        let mut secret = Zeroizing::new(decode_hex("ciphertext", &encrypted.ciphertext)?);
+        let mac: [u8; 32] = crypto::hash_bytes(&[&derived_key[16..32], &secret[..]]).into();
+        if mac[..] != decode_hex("mac", &encrypted.mac)?[..] {
+            return Err(SignerError::WrongPassphrase);
+        }
+
+        let iv = decode_hex("iv", &encrypted.cipherparams.iv)?;
+        if iv.len() != 16 {
+            return Err(SignerError::Keystore("iv is not 16 bytes".into()));
+        }
+        Aes128Ctr::new(derived_key[..16].into(), iv[..].into()).apply_keystream(&mut secret);
+
+        let signer = InMemorySigner::from_slice(&secret)?;
+
+        if let Some(address) = &keystore.address {
+            let address: Address = format!("0x{}", address.trim_start_matches("0x"))
+                .parse()
+                .map_err(|_| SignerError::Keystore("address is invalid".into()))?;
+            if address != signer.address {
+                return Err(SignerError::Keystore(
+                    "key does not match the address".into(),
+                ));
+            }
+        }
+
+        Ok(KeystoreSigner { signer })
+    }
+}
+
+#[async_trait(?Send)]
+impl Signer for KeystoreSigner {
+    fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    async fn sign_hash(&self, hash: &[u8]) -> Result<Signature, SignerError> {
+        self.signer.sign_hash(hash).await
+    }
+}
+
+#[cfg(unix)]
+pub use self::remote::{bind_signer, serve_signer, RemoteSigner};
+
+/// Signing over a Unix socket. Each request and response is one line of JSON.
+#[cfg(unix)]
+mod remote {
+    use super::{check_hash, Signer};
+    use crate::eip712::TypedData;
+    use crate::error::SignerError;
+    use async_trait::async_trait;
+    use clarity::{Address, Signature};
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// How long to wait for the remote signer to answer, unless set with `with_timeout`
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(tag = "method", rename_all = "snake_case")]
+    enum Request {
+        Address,
+        /// `hash` in hex
+        SignHash {
+            hash: String,
+        },
+        SignTypedData {
+            typed_data: Box<TypedData>,
+        },
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Response {
+        Address(Address),
+        Signature(Signature),
+        Error(String),
+    }
+
+    fn remote_error<E: ToString>(e: E) -> SignerError {
+        SignerError::Remote(e.to_string())
+    }
+
+    /// A request for the thread of a `RemoteSigner` to send
+    struct Job {
+        request: Request,
+        timeout: Duration,
+        reply: oneshot::Sender<Result<Signature, SignerError>>,
+    }
+
+    /// Asks a signer in another process, which serves it with `serve_signer`.
+    ///
+    /// The socket is only used by a thread of the signer, so that waiting on the other process
+    /// does not hold up the executor of the node. A connection which failed or timed out is
+    /// dropped, and the next request connects again, so that an answer which comes in late is
+    /// never taken for the answer to another request.
+    #[derive(Debug)]
+    pub struct RemoteSigner {
+        jobs: mpsc::Sender<Job>,
+        timeout: Duration,
+        address: Address,
+    }
+
+    impl RemoteSigner {
+        /// Connects to the socket at `path`, and asks the signer for its address. This waits
+        /// for the answer, so it is meant for when the node starts.
+        pub fn connect<P: AsRef<Path>>(path: P) -> Result<RemoteSigner, SignerError> {
+            let path = path.as_ref().to_path_buf();
+            let mut connection = open(&path)?;
+            let address = match call(&mut connection, &Request::Address, TIMEOUT)? {
+                Response::Address(address) => address,
+                Response::Error(message) => return Err(SignerError::Remote(message)),
+                _ => return Err(SignerError::Remote("expected an address".into())),
+            };
+
+            let (jobs, queue) = mpsc::channel();
+            thread::spawn(move || work(&path, Some(connection), queue));
+            Ok(RemoteSigner {
+                jobs,
+                timeout: TIMEOUT,
+                address,
+            })
+        }
+
+        /// Waits `timeout` for each answer of the remote signer
+        pub fn with_timeout(mut self, timeout: Duration) -> Self {
+            self.timeout = timeout;
+            self
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Signer for RemoteSigner {
+        fn address(&self) -> Address {
+            self.address
+        }
+
+        async fn sign_hash(&self, hash: &[u8]) -> Result<Signature, SignerError> {
+            check_hash(hash)?;
+            self.sign(Request::SignHash {
+                hash: hex::encode(hash),
+            })
+            .await
+        }
+
+        async fn sign_typed_data(&self, typed_data: &TypedData) -> Result<Signature, SignerError> {
+            self.sign(Request::SignTypedData {
+                typed_data: Box::new(typed_data.clone()),
+            })
+            .await
+        }
+    }
+
+    impl RemoteSigner {
+        async fn sign(&self, request: Request) -> Result<Signature, SignerError> {
+            let (reply, signature) = oneshot::channel();
+            let job = Job {
+                request,
+                timeout: self.timeout,
+                reply,
+            };
+
+            let stopped = || SignerError::Remote("the signer thread has stopped".into());
+            self.jobs.send(job).map_err(|_| stopped())?;
+            signature.await.map_err(|_| stopped())?
+        }
+    }
+
+    /// Has the jobs of a `RemoteSigner` signed one at a time, until the signer is dropped
+    fn work(path: &Path, mut connection: Option<BufReader<UnixStream>>, jobs: mpsc::Receiver<Job>) {
+        for job in jobs {
+            let res = sign_remotely(path, &mut connection, &job.request, job.timeout);
+            // Nobody may be waiting for it any more
+            let _ = job.reply.send(res);
+        }
+    }
+
+    fn sign_remotely(
+        path: &Path,
+        connection: &mut Option<BufReader<UnixStream>>,
+        request: &Request,
+        timeout: Duration,
+    ) -> Result<Signature, SignerError> {
+        let mut open_connection = match connection.take() {
+            Some(open_connection) => open_connection,
+            None => open(path)?,
+        };
+        let response = call(&mut open_connection, request, timeout)?;
+        *connection = Some(open_connection);
+
+        match response {
+            Response::Signature(signature) => Ok(signature),
+            Response::Error(message) => Err(SignerError::Remote(message)),
+            _ => Err(SignerError::Remote("expected a signature".into())),
+        }
+    }
+
+    fn open(path: &Path) -> Result<BufReader<UnixStream>, SignerError> {
+        let stream = UnixStream::connect(path).map_err(remote_error)?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Sends `request` and reads the response. On an error the connection is in an unknown
+    /// state and has to be dropped.
+    fn call(
+        connection: &mut BufReader<UnixStream>,
+        request: &Request,
+        timeout: Duration,
+    ) -> Result<Response, SignerError> {
+        let stream = connection.get_ref();
+        stream
+            .set_read_timeout(Some(timeout))
+            .map_err(remote_error)?;
+        stream
+            .set_write_timeout(Some(timeout))
+            .map_err(remote_error)?;
+
+        let mut line = serde_json::to_string(request).map_err(remote_error)?;
+        line.push('\n');
+        connection
+            .get_mut()
+            .write_all(line.as_bytes())
+            .map_err(remote_error)?;
+
+        let mut line = String::new();
+        if connection.read_line(&mut line).map_err(remote_error)? == 0 {
+            return Err(SignerError::Remote("connection closed".into()));
+        }
+        serde_json::from_str(&line).map_err(remote_error)
+    }
+
+    /// Creates the socket for `serve_signer` at `path`, which only our own user can connect to.
+    ///
+    /// The socket is bound under another name and only moved to `path` once its permissions
+    /// are restricted, so that nobody else can connect in between.
+    pub fn bind_signer<P: AsRef<Path>>(path: P) -> Result<UnixListener, SignerError> {
+        let path = path.as_ref();
+        let mut binding = path.as_os_str().to_owned();
+        binding.push(format!(".{}", uuid::Uuid::new_v4()));
+
+        let listener = UnixListener::bind(&binding).map_err(remote_error)?;
+        let res = fs::set_permissions(&binding, fs::Permissions::from_mode(0o600))
+            .and_then(|()| fs::rename(&binding, path));
+        if let Err(e) = res {
+            let _ = fs::remove_file(&binding);
+            return Err(remote_error(e));
+        }
+        Ok(listener)
+    }
+
+    /// Answers the requests of `RemoteSigner`s connecting to `listener` with `signer`, one
+    /// connection at a time, on the thread it is called from. Only returns if the listener
+    /// fails.
+    ///
+    /// Whoever can connect to the socket can have anything signed, so the listener should come
+    /// from `bind_signer`.
+    pub fn serve_signer(listener: UnixListener, signer: &dyn Signer) -> Result<(), SignerError> {
+        for stream in listener.incoming() {
+            let stream = stream.map_err(remote_error)?;
+            if let Err(e) = serve_connection(stream, signer) {
+                log::warn!("Remote signer connection failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_connection(stream: UnixStream, signer: &dyn Signer) -> Result<(), SignerError> {
+        let mut writer = stream.try_clone().map_err(remote_error)?;
+        for line in BufReader::new(stream).lines() {
+            let line = line.map_err(remote_error)?;
+            let response = match serde_json::from_str(&line) {
+                Ok(Request::Address) => Response::Address(signer.address()),
+                Ok(Request::SignHash { hash }) => match hex::decode(&hash) {
+                    Ok(hash) => {
+                        let signature = block_on(async {
+                            check_hash(&hash)?;
+                            signer.sign_hash(&hash).await
+                        });
+                        match signature {
+                            Ok(signature) => Response::Signature(signature),
+                            Err(e) => Response::Error(e.to_string()),
+                        }
+                    }
+                    Err(_) => Response::Error("hash is not hex".into()),
+                },
+                Ok(Request::SignTypedData { typed_data }) => {
+                    match block_on(signer.sign_typed_data(&typed_data)) {
+                        Ok(signature) => Response::Signature(signature),
+                        Err(e) => Response::Error(e.to_string()),
+                    }
+                }
+                Err(e) => Response::Error(format!("invalid request: {}", e)),
+            };
+
+            let mut line = serde_json::to_string(&response).map_err(remote_error)?;
+            line.push('\n');
+            writer.write_all(line.as_bytes()).map_err(remote_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Crypto;
+    use crate::test_utils::SECRET_0;
+    use futures::executor::block_on;
+    use std::sync::Arc;
+
+    /// The PBKDF2 test vector of the Web3 Secret Storage Definition
+    const KEYSTORE: &str = r#"{
+        "crypto": {
+            "cipher": "aes-128-ctr",
+            "cipherparams": { "iv": "6087dab2f9fdbbfaddc31a909735c1e6" },
+            "ciphertext": "5318b4d5bcd28de64ee5559e671353e16f075ecae9f99c7a79a38af5f869aa46",
+            "kdf": "pbkdf2",
+            "kdfparams": {
+                "c": 262144,
+                "dklen": 32,
+                "prf": "hmac-sha256",
+                "salt": "ae3cd4e7013836a3df6bd7241b12db061dbe2c6785853cce422d148a624ce0bd"
+            },
+            "mac": "517ead924a9d0dc3124507e3393d175ce3ff7c1e96529c6c555ce9e51205e9b2"
+        },
+        "id": "3198bc9c-6672-5ab3-d995-4942343ae5b6",
+        "version": 3
+    }"#;
+
+    fn secret() -> PrivateKey {
+        SECRET_0.parse().unwrap()
+    }
+
+    #[test]
+    fn test_in_memory_signer() {
+        let signer = InMemorySigner::new(secret()).unwrap();
+        let hash = [7u8; 32];
+        let signature = block_on(signer.sign_hash(&hash)).unwrap();
+        assert_eq!(signature, secret().sign_hash(&hash));
+        assert_eq!(signature.recover(&hash).unwrap(), signer.address());
+    }
+
+    #[test]
+    fn test_invalid_hash() {
+        let signer = InMemorySigner::new(secret()).unwrap();
+        for hash in &[&[7u8; 31][..], &[7u8; 33][..], &[][..]] {
+            match block_on(signer.sign_hash(hash)) {
+                Err(SignerError::InvalidHash(length)) => assert_eq!(length, hash.len()),
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_transaction() {
+        let signer = InMemorySigner::new(secret()).unwrap();
+        let transaction = Transaction {
+            to: Address::default(),
+            nonce: 1u64.into(),
+            gas_price: 1u64.into(),
+            gas_limit: 21000u64.into(),
+            value: 5u64.into(),
+            data: Vec::new(),
+            signature: None,
+        };
+        assert_eq!(
+            block_on(sign_transaction(&signer, transaction.clone(), 1))
+                .unwrap()
+                .signature,
+            transaction.sign(&secret(), Some(1)).signature
+        );
+    }
+
+    #[test]
+    fn test_keystore_signer() {
+        let signer = KeystoreSigner::from_json(KEYSTORE, "testpassword").unwrap();
+        let secret: PrivateKey = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d"
+            .parse()
+            .unwrap();
+        assert_eq!(signer.address(), secret.to_public_key().unwrap());
+
+        match KeystoreSigner::from_json(KEYSTORE, "wrongpassword") {
+            Err(SignerError::WrongPassphrase) => {}
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("unlocked with the wrong passphrase"),
+        }
+    }
+
+    #[test]
+    fn test_secrets_are_not_printed() {
+        let keystore_secret = "7a28b5ba57c53603b0b07b56bba752f7784bf506fa95edc395f5cf6c7514fe9d";
+        let signer = InMemorySigner::from_hex(SECRET_0).unwrap();
+        let crypto = Crypto::new(
+            Address::default(),
+            Arc::new(InMemorySigner::new(secret()).unwrap()),
+        );
+
+        let printed = vec![
+            format!("{:?}", signer),
+            format!("{:?}", crypto),
+            format!(
+                "{:?}",
+                KeystoreSigner::from_json(KEYSTORE, "testpassword").unwrap()
+            ),
+            format!(
+                "{:?}",
+                InMemorySigner::from_hex(&SECRET_0[1..]).unwrap_err()
+            ),
+            InMemorySigner::from_hex(&SECRET_0[1..])
+                .unwrap_err()
+                .to_string(),
+        ];
+        for printed in printed {
+            let printed = printed.to_lowercase();
+            assert!(!printed.contains(&SECRET_0[1..]), "{}", printed);
+            assert!(!printed.contains(keystore_secret), "{}", printed);
+        }
+    }
+
+    #[cfg(unix)]
+    fn socket_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("guac-signer-{}.sock", uuid::Uuid::new_v4()))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_signer() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+
+        let path = socket_path();
+        let listener = bind_signer(&path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        let signer = Arc::new(InMemorySigner::new(secret()).unwrap());
+        let serving = signer.clone();
+        thread::spawn(move || serve_signer(listener, &*serving));
+
+        let remote = RemoteSigner::connect(&path).unwrap();
+        assert_eq!(remote.address(), signer.address());
+        let hash = [7u8; 32];
+        assert_eq!(
+            block_on(remote.sign_hash(&hash)).unwrap(),
+            block_on(signer.sign_hash(&hash)).unwrap()
+        );
+        match block_on(remote.sign_hash(&hash[1..])) {
+            Err(SignerError::InvalidHash(31)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // The server checks the hash as well, whoever is asking. It serves one connection at a
+        // time, so the signer lets go of its own first.
+        drop(remote);
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"{\"method\":\"sign_hash\",\"hash\":\"0707\"}\n")
+            .unwrap();
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).unwrap();
+        assert!(line.contains("Cannot sign a hash of 2 bytes"), "{}", line);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// A signature which comes in after the request timed out is not taken for the answer to the
+    /// next request
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_signer_late_answer() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let path = socket_path();
+        let listener = bind_signer(&path).unwrap();
+        let signer = Arc::new(InMemorySigner::new(secret()).unwrap());
+        let serving = signer.clone();
+        let (answer, answer_late) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+
+            lines.next().unwrap().unwrap();
+            let address = format!("{{\"address\":\"{}\"}}\n", serving.address());
+            writer.write_all(address.as_bytes()).unwrap();
+
+            // Answers the first signature request once it has timed out, with the signature of
+            // another hash
+            lines.next().unwrap().unwrap();
+            answer_late.recv().unwrap();
+            let signature = block_on(serving.sign_hash(&[1u8; 32])).unwrap();
+            let signature = format!("{}\n", serde_json::json!({ "signature": signature }));
+            let _ = writer.write_all(signature.as_bytes());
+
+            serve_signer(listener, &*serving)
+        });
+
+        let remote = RemoteSigner::connect(&path)
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        match block_on(remote.sign_hash(&[1u8; 32])) {
+            Err(SignerError::Remote(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        answer.send(()).unwrap();
+
+        let hash = [2u8; 32];
+        assert_eq!(
+            block_on(remote.sign_hash(&hash)).unwrap(),
+            block_on(signer.sign_hash(&hash)).unwrap()
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// Signs typed data, and nothing else
+    #[cfg(unix)]
+    struct TypedDataOnly(InMemorySigner);
+
+    #[cfg(unix)]
+    #[async_trait(?Send)]
+    impl Signer for TypedDataOnly {
+        fn address(&self) -> Address {
+            self.0.address()
+        }
+
+        async fn sign_hash(&self, _hash: &[u8]) -> Result<Signature, SignerError> {
+            Err(SignerError::Remote("only typed data is signed".into()))
+        }
+
+        async fn sign_typed_data(&self, typed_data: &TypedData) -> Result<Signature, SignerError> {
+            self.0.sign_hash(&typed_data.hash()).await
+        }
+    }
+
+    /// The signer in the other process gets the typed data, not only its hash
+    #[cfg(unix)]
+    #[test]
+    fn test_remote_typed_data() {
+        use crate::eip712::FingerprintScheme;
+        use crate::receipt::Receipt;
+        use std::thread;
+
+        let path = socket_path();
+        let listener = bind_signer(&path).unwrap();
+        let signer = TypedDataOnly(InMemorySigner::new(secret()).unwrap());
+        thread::spawn(move || serve_signer(listener, &signer));
+
+        let remote = RemoteSigner::connect(&path).unwrap();
+        let receipt = Receipt {
+            channel_id: [1; 32],
+            sequence_number: 2u64.into(),
+            amount: 3u64.into(),
+            payer: Address::default(),
+            payee: remote.address(),
+            signature: None,
+        };
+        let typed_data = FingerprintScheme::Eip712 {
+            chain_id: 1u64.into(),
+            channel_messages: false,
+        }
+        .typed_data(Address::default(), &receipt)
+        .unwrap();
+
+        let signature = block_on(remote.sign_typed_data(&typed_data)).unwrap();
+        assert_eq!(
+            signature.recover(&typed_data.hash()).unwrap(),
+            remote.address()
+        );
+        assert!(block_on(remote.sign_hash(&typed_data.hash())).is_err());
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,575 @@
+//! The states a counterparty goes through and the events which move it from one to the other.
+//!
+//! Every legal edge is listed in `TRANSITIONS`, and `transition` is the only place where a new
+//! state is made from an old one. The diagram in `docs/diagrams/src/state_machine.dot` is
+//! generated from the table with `to_dot`.
+//!
+//! Payments do not appear here: they change the balances of a channel, not the state of the
+//! counterparty.
+
+use crate::channel::Channel;
+use crate::error::{GuacError, ProtocolError};
+use crate::types::{Counterparty, NewChannelTx, ReDrawTx};
+
+/// The state of a counterparty without the data it holds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    New,
+    Creating,
+    OtherCreating,
+    ReDrawing,
+    OtherReDrawing,
+    Open,
+}
+
+impl State {
+    pub const ALL: [State; 6] = [
+        State::New,
+        State::Creating,
+        State::OtherCreating,
+        State::ReDrawing,
+        State::OtherReDrawing,
+        State::Open,
+    ];
+
+    pub fn of(counterparty: &Counterparty) -> State {
+        match counterparty {
+            Counterparty::New { .. } => State::New,
+            Counterparty::Creating { .. } => State::Creating,
+            Counterparty::OtherCreating { .. } => State::OtherCreating,
+            Counterparty::ReDrawing { .. } => State::ReDrawing,
+            Counterparty::OtherReDrawing { .. } => State::OtherReDrawing,
+            Counterparty::Open { .. } => State::Open,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// We propose a new channel to the counterparty
+    ProposeChannel { new_channel_tx: NewChannelTx },
+    /// The counterparty signed the channel we proposed
+    ChannelSigned { new_channel_tx: NewChannelTx },
+    /// The counterparty did not sign the channel we proposed
+    ChannelRefused,
+    /// The counterparty proposes a new channel to us
+    ChannelProposed { new_channel_tx: NewChannelTx },
+    /// The new channel is on the chain
+    ChannelOpened { channel_id: [u8; 32] },
+    /// We propose a reDraw to the counterparty
+    ProposeReDraw { re_draw_tx: ReDrawTx },
+    /// The counterparty signed the reDraw we proposed
+    ReDrawSigned { re_draw_tx: ReDrawTx },
+    /// The counterparty did not sign the reDraw we proposed
+    ReDrawRefused,
+    /// The counterparty proposes a reDraw to us
+    ReDrawProposed { re_draw_tx: ReDrawTx },
+    /// The reDraw is on the chain
+    ReDrawDone,
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ProposeChannel { .. } => "ProposeChannel",
+            Event::ChannelSigned { .. } => "ChannelSigned",
+            Event::ChannelRefused => "ChannelRefused",
+            Event::ChannelProposed { .. } => "ChannelProposed",
+            Event::ChannelOpened { .. } => "ChannelOpened",
+            Event::ProposeReDraw { .. } => "ProposeReDraw",
+            Event::ReDrawSigned { .. } => "ReDrawSigned",
+            Event::ReDrawRefused => "ReDrawRefused",
+            Event::ReDrawProposed { .. } => "ReDrawProposed",
+            Event::ReDrawDone => "ReDrawDone",
+        }
+    }
+}
+
+/// An edge of the state machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: State,
+    pub event: &'static str,
+    pub to: State,
+    /// Where the event comes from and what is sent out because of it
+    pub description: &'static str,
+}
+
+/// Every legal edge of the state machine. `transition` accepts an event in a state if and only
+/// if it is listed here.
+pub const TRANSITIONS: &[Transition] = &[
+    Transition {
+        from: State::New,
+        event: "ProposeChannel",
+        to: State::Creating,
+        description: "from user: fillChannel\nout: proposeChannel",
+    },
+    Transition {
+        from: State::Creating,
+        event: "ChannelSigned",
+        to: State::Creating,
+        description: "from counterparty: sig on newChannel\nout: submit newChannel",
+    },
+    Transition {
+        from: State::Creating,
+        event: "ChannelRefused",
+        to: State::New,
+        description: "from counterparty: no sig on newChannel",
+    },
+    Transition {
+        from: State::Creating,
+        event: "ChannelOpened",
+        to: State::Open,
+        description: "from blockchain: newChannel mined\nout: channelOpened notification",
+    },
+    Transition {
+        from: State::Creating,
+        event: "ChannelProposed",
+        to: State::OtherCreating,
+        description:
+            "from counterparty with lower address:\ncrossing proposeChannel\nout: sig on newChannel",
+    },
+    Transition {
+        from: State::New,
+        event: "ChannelProposed",
+        to: State::OtherCreating,
+        description: "from counterparty: proposeChannel\nout: sig on newChannel",
+    },
+    Transition {
+        from: State::OtherCreating,
+        event: "ChannelOpened",
+        to: State::Open,
+        description: "from counterparty: verified channelOpened notification",
+    },
+    Transition {
+        from: State::Open,
+        event: "ProposeReDraw",
+        to: State::ReDrawing,
+        description: "from user: fillChannel or withdraw\nout: proposeReDraw",
+    },
+    Transition {
+        from: State::ReDrawing,
+        event: "ReDrawSigned",
+        to: State::ReDrawing,
+        description: "from counterparty: sig on reDraw\nout: submit reDraw",
+    },
+    Transition {
+        from: State::ReDrawing,
+        event: "ReDrawRefused",
+        to: State::Open,
+        description: "from counterparty: no sig on reDraw",
+    },
+    Transition {
+        from: State::ReDrawing,
+        event: "ReDrawDone",
+        to: State::Open,
+        description: "from blockchain: reDraw mined\nout: reDraw notification",
+    },
+    Transition {
+        from: State::Open,
+        event: "ReDrawProposed",
+        to: State::OtherReDrawing,
+        description: "from counterparty: proposeReDraw\nout: sig on reDraw",
+    },
+    Transition {
+        from: State::OtherReDrawing,
+        event: "ReDrawDone",
+        to: State::Open,
+        description: "from counterparty: verified reDraw notification",
+    },
+];
+
+/// Returns the state a counterparty is in after `event`, or an error if the event is not
+/// allowed in its current state.
+///
+/// This only checks that the event fits the state. Whether the transactions carried by an event
+/// are acceptable is up to the caller.
+pub fn transition(state: Counterparty, event: Event) -> Result<Counterparty, GuacError> {
+    match (state, event) {
+        (Counterparty::New { i_am_0 }, Event::ProposeChannel { new_channel_tx }) => {
+            Ok(Counterparty::Creating {
+                new_channel_tx,
+                i_am_0,
+            })
+        }
+        (Counterparty::Creating { i_am_0, .. }, Event::ChannelSigned { new_channel_tx }) => {
+            Ok(Counterparty::Creating {
+                new_channel_tx,
+                i_am_0,
+            })
+        }
+        (Counterparty::Creating { i_am_0, .. }, Event::ChannelRefused) => {
+            Ok(Counterparty::New { i_am_0 })
+        }
+        (
+            Counterparty::Creating {
+                new_channel_tx,
+                i_am_0,
+            },
+            Event::ChannelOpened { channel_id },
+        )
+        | (
+            Counterparty::OtherCreating {
+                new_channel_tx,
+                i_am_0,
+            },
+            Event::ChannelOpened { channel_id },
+        ) => Ok(Counterparty::Open {
+            channel: Channel {
+                channel_id,
+                sequence_number: 0u64.into(),
+                balance_0: new_channel_tx.balance_0,
+                balance_1: new_channel_tx.balance_1,
+                i_am_0,
+                accrual: 0u64.into(),
+                hashlocks: Vec::new(),
+                signed_update: None,
+            },
+        }),
+        (Counterparty::New { i_am_0 }, Event::ChannelProposed { new_channel_tx }) => {
+            Ok(Counterparty::OtherCreating {
+                new_channel_tx,
+                i_am_0,
+            })
+        }
+        // Both sides proposed a channel at the same time. The side with the lower address
+        // (address 0) wins: if that is them and they have not signed our proposal yet, ours is
+        // abandoned for theirs.
+        (
+            Counterparty::Creating {
+                i_am_0: false,
+                new_channel_tx: ours,
+            },
+            Event::ChannelProposed { new_channel_tx },
+        ) if ours.signature_0.is_none() && ours.signature_1.is_none() => {
+            Ok(Counterparty::OtherCreating {
+                new_channel_tx,
+                i_am_0: false,
+            })
+        }
+        (Counterparty::Creating { i_am_0: true, .. }, Event::ChannelProposed { .. }) => {
+            Err(ProtocolError::SimultaneousOpen.into())
+        }
+        (Counterparty::Open { channel }, Event::ProposeReDraw { re_draw_tx }) => {
+            Ok(Counterparty::ReDrawing {
+                channel,
+                re_draw_tx,
+            })
+        }
+        (Counterparty::ReDrawing { channel, .. }, Event::ReDrawSigned { re_draw_tx }) => {
+            Ok(Counterparty::ReDrawing {
+                channel,
+                re_draw_tx,
+            })
+        }
+        (Counterparty::ReDrawing { channel, .. }, Event::ReDrawRefused) => {
+            Ok(Counterparty::Open { channel })
+        }
+        (Counterparty::Open { channel }, Event::ReDrawProposed { re_draw_tx }) => {
+            Ok(Counterparty::OtherReDrawing {
+                channel,
+                re_draw_tx,
+            })
+        }
+        // Payments made while the reDraw was pending are kept on top of it
+        (
+            Counterparty::ReDrawing {
+                channel,
+                re_draw_tx,
+            },
+            Event::ReDrawDone,
+        )
+        | (
+            Counterparty::OtherReDrawing {
+                channel,
+                re_draw_tx,
+            },
+            Event::ReDrawDone,
+        ) => Ok(Counterparty::Open {
+            channel: channel.re_drawn(&re_draw_tx)?,
+        }),
+        (state, event) => Err(ProtocolError::WrongState {
+            action: event.name().to_string(),
+            current_state: format!("{:?}", state),
+            correct_state: TRANSITIONS
+                .iter()
+                .filter(|t| t.event == event.name())
+                .map(|t| format!("{:?}", t.from))
+                .collect::<Vec<_>>()
+                .join(" or "),
+        }
+        .into()),
+    }
+}
+
+/// Renders `TRANSITIONS` as a Graphviz digraph, which is what
+/// `docs/diagrams/src/state_machine.dot` contains.
+pub fn to_dot() -> String {
+    let mut dot = String::new();
+    dot.push_str(
+        "// Generated by `cargo run -p guac_core --example state_machine_dot`, do not edit.\n",
+    );
+    dot.push_str("// command to render: dot state_machine.dot -Tsvg -o state_machine.svg\n");
+    dot.push_str("digraph G {\n    pad=0.2\n\n");
+    for t in TRANSITIONS {
+        dot.push_str(&format!(
+            "    {:?} -> {:?} [ label=\"{}\\n{}\" ];\n",
+            t.from,
+            t.to,
+            t.event,
+            t.description.replace('\n', "\\n")
+        ));
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::{Address, Signature};
+
+    fn new_channel_tx() -> NewChannelTx {
+        NewChannelTx {
+            address_0: Address::default(),
+            address_1: Address::default(),
+            balance_0: 10u64.into(),
+            balance_1: 0u64.into(),
+            expiration: 0u64.into(),
+            settling_period_length: 5000u64.into(),
+            signature_0: None,
+            signature_1: None,
+        }
+    }
+
+    fn re_draw_tx() -> ReDrawTx {
+        ReDrawTx {
+            channel_id: [1; 32],
+            sequence_number: 1u64.into(),
+            old_balance_0: 10u64.into(),
+            old_balance_1: 0u64.into(),
+            new_balance_0: 20u64.into(),
+            new_balance_1: 0u64.into(),
+            expiration: 0u64.into(),
+            signature_0: None,
+            signature_1: None,
+        }
+    }
+
+    fn channel() -> Channel {
+        Channel {
+            channel_id: [1; 32],
+            sequence_number: 0u64.into(),
+            balance_0: 10u64.into(),
+            balance_1: 0u64.into(),
+            accrual: 0u64.into(),
+            hashlocks: Vec::new(),
+            signed_update: None,
+            i_am_0: false,
+        }
+    }
+
+    /// One counterparty in each state
+    fn states() -> Vec<Counterparty> {
+        vec![
+            Counterparty::New { i_am_0: false },
+            Counterparty::Creating {
+                new_channel_tx: new_channel_tx(),
+                i_am_0: false,
+            },
+            Counterparty::OtherCreating {
+                new_channel_tx: new_channel_tx(),
+                i_am_0: false,
+            },
+            Counterparty::ReDrawing {
+                re_draw_tx: re_draw_tx(),
+                channel: channel(),
+            },
+            Counterparty::OtherReDrawing {
+                re_draw_tx: re_draw_tx(),
+                channel: channel(),
+            },
+            Counterparty::Open { channel: channel() },
+        ]
+    }
+
+    /// One event of each kind
+    fn events() -> Vec<Event> {
+        vec![
+            Event::ProposeChannel {
+                new_channel_tx: new_channel_tx(),
+            },
+            Event::ChannelSigned {
+                new_channel_tx: new_channel_tx(),
+            },
+            Event::ChannelRefused,
+            Event::ChannelProposed {
+                new_channel_tx: new_channel_tx(),
+            },
+            Event::ChannelOpened {
+                channel_id: [1; 32],
+            },
+            Event::ProposeReDraw {
+                re_draw_tx: re_draw_tx(),
+            },
+            Event::ReDrawSigned {
+                re_draw_tx: re_draw_tx(),
+            },
+            Event::ReDrawRefused,
+            Event::ReDrawProposed {
+                re_draw_tx: re_draw_tx(),
+            },
+            Event::ReDrawDone,
+        ]
+    }
+
+    /// Every event in every state does what the table says: it is refused if there is no edge
+    /// for it, and leads to the state at the end of the edge otherwise.
+    #[test]
+    fn test_transition_follows_table() {
+        assert_eq!(states().len(), State::ALL.len());
+
+        for state in states() {
+            for event in events() {
+                let edge = TRANSITIONS
+                    .iter()
+                    .find(|t| t.from == State::of(&state) && t.event == event.name());
+                let res = transition(state.clone(), event.clone());
+
+                match (edge, res) {
+                    (Some(edge), Ok(next)) => {
+                        assert_eq!(State::of(&next), edge.to, "{} in {:?}", event.name(), state)
+                    }
+                    (None, Err(GuacError::Protocol(ProtocolError::WrongState { .. }))) => {}
+                    (edge, res) => panic!(
+                        "{} in {:?} should follow {:?}, got {:?}",
+                        event.name(),
+                        state,
+                        edge,
+                        res
+                    ),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_table_is_consistent() {
+        for (i, t) in TRANSITIONS.iter().enumerate() {
+            assert!(
+                events().iter().any(|e| e.name() == t.event),
+                "unknown event {}",
+                t.event
+            );
+            // An event leads to a single state from each state
+            assert!(
+                !TRANSITIONS[..i]
+                    .iter()
+                    .any(|other| other.from == t.from && other.event == t.event),
+                "{:?} appears twice for {:?}",
+                t.event,
+                t.from
+            );
+        }
+
+        // Every state can be reached from New
+        let mut reached = vec![State::New];
+        while let Some(t) = TRANSITIONS
+            .iter()
+            .find(|t| reached.contains(&t.from) && !reached.contains(&t.to))
+        {
+            reached.push(t.to);
+        }
+        for state in State::ALL.iter() {
+            assert!(reached.contains(state), "{:?} cannot be reached", state);
+        }
+    }
+
+    #[test]
+    fn test_crossing_channel_proposals() {
+        let proposal = Event::ChannelProposed {
+            new_channel_tx: new_channel_tx(),
+        };
+
+        // We have the lower address, so our proposal stands
+        match transition(
+            Counterparty::Creating {
+                new_channel_tx: new_channel_tx(),
+                i_am_0: true,
+            },
+            proposal.clone(),
+        ) {
+            Err(GuacError::Protocol(ProtocolError::SimultaneousOpen)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        // Our proposal was already signed by them, it cannot be abandoned anymore
+        let signed = NewChannelTx {
+            signature_0: Some(Signature::new(27u64.into(), 1u64.into(), 2u64.into())),
+            ..new_channel_tx()
+        };
+        match transition(
+            Counterparty::Creating {
+                new_channel_tx: signed,
+                i_am_0: false,
+            },
+            proposal,
+        ) {
+            Err(GuacError::Protocol(ProtocolError::WrongState { .. })) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_re_draw_done_keeps_payments() {
+        let mut channel = channel();
+        channel.balance_0 = 5u64.into();
+        channel.balance_1 = 5u64.into();
+        channel.sequence_number = 2u64.into();
+
+        let next = transition(
+            Counterparty::OtherReDrawing {
+                re_draw_tx: re_draw_tx(),
+                channel,
+            },
+            Event::ReDrawDone,
+        )
+        .unwrap();
+
+        match next {
+            Counterparty::Open { channel } => {
+                assert_eq!(channel.balance_0, 15u64.into());
+                assert_eq!(channel.balance_1, 5u64.into());
+                assert_eq!(channel.sequence_number, 2u64.into());
+            }
+            state => panic!("unexpected state {:?}", state),
+        }
+    }
+
+    #[test]
+    fn test_diagram_is_up_to_date() {
+        assert!(
+            include_str!("../../docs/diagrams/src/state_machine.dot") == to_dot(),
+            "docs/diagrams/src/state_machine.dot is out of date, regenerate it with \
+             `cargo run -p guac_core --example state_machine_dot > docs/diagrams/src/state_machine.dot`"
+        );
+
+        // The layout is up to graphviz, but every state and label has to be in the rendering
+        let svg = include_str!("../../docs/diagrams/state_machine.svg");
+        for t in TRANSITIONS {
+            let states = [format!("{:?}", t.from), format!("{:?}", t.to)];
+            let lines = states
+                .iter()
+                .map(String::as_str)
+                .chain(Some(t.event))
+                .chain(t.description.lines());
+            for line in lines {
+                assert!(
+                    svg.contains(&format!(">{}</text>", line)),
+                    "docs/diagrams/state_machine.svg is out of date, render it again with \
+                     docs/diagrams/src/render.sh"
+                );
+            }
+        }
+    }
+}
@@ -1,58 +1,472 @@
+use crate::error::StorageError;
+use crate::metrics::{Histogram, LOCK_BUCKETS};
 use crate::types::Counterparty;
 use clarity::Address;
-use failure::Error;
+use std::collections::hash_map;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
 
-use futures;
+/// How many counterparties may be waiting in the `New` state by default
+pub const DEFAULT_MAX_PENDING_PEERS: usize = 1000;
+/// How long a counterparty may stay in the `New` state by default before it is removed
+pub const DEFAULT_PENDING_PEER_TTL: Duration = Duration::from_secs(60 * 60);
 
-use futures::Future;
+/// Decides which addresses may become counterparties by contacting us first
+pub enum PeerFilter {
+    Any,
+    /// Only the addresses in the list
+    Allow(HashSet<Address>),
+    /// Any address except the ones in the list
+    Deny(HashSet<Address>),
+    /// Any address for which the function returns true
+    Custom(Box<dyn Fn(&Address) -> bool + Send + Sync>),
+}
+
+impl PeerFilter {
+    pub fn accepts(&self, address: &Address) -> bool {
+        match self {
+            PeerFilter::Any => true,
+            PeerFilter::Allow(addresses) => addresses.contains(address),
+            PeerFilter::Deny(addresses) => !addresses.contains(address),
+            PeerFilter::Custom(accepts) => accepts(address),
+        }
+    }
+}
+
+struct Entry {
+    counterparty: Counterparty,
+    /// When the state of the counterparty last changed
+    changed: Instant,
+}
+
+impl Entry {
+    fn new(counterparty: Counterparty) -> Arc<Mutex<Entry>> {
+        Arc::new(Mutex::new(Entry {
+            counterparty,
+            changed: Instant::now(),
+        }))
+    }
+
+    fn set(&mut self, counterparty: Counterparty) {
+        self.counterparty = counterparty;
+        self.changed = Instant::now();
+    }
+}
+
+/// The lock of an entry, which records how long it was held once it is dropped
+struct Held<'a> {
+    entry: MutexGuard<'a, Entry>,
+    since: Instant,
+    lock_times: &'a Mutex<Histogram>,
+}
 
-use qutex::{Guard, QrwLock, Qutex};
-use std::collections::HashMap;
+impl<'a> Deref for Held<'a> {
+    type Target = Entry;
 
-/// Storage contains a futures aware RwLock (QrwLock) which controls access to the inner data
-/// This outer Rwlock should only be mutated very rarely, only to insert and remove counterparties
+    fn deref(&self) -> &Entry {
+        &self.entry
+    }
+}
+
+impl<'a> DerefMut for Held<'a> {
+    fn deref_mut(&mut self) -> &mut Entry {
+        &mut self.entry
+    }
+}
+
+impl<'a> Drop for Held<'a> {
+    fn drop(&mut self) {
+        self.lock_times
+            .lock()
+            .expect("Storage poisoned")
+            .observe(self.since.elapsed());
+    }
+}
+
+/// Storage keeps the state of every counterparty behind its own lock.
+///
+/// Locks are only ever held for as long as it takes to read or write a state, never across a
+/// network or chain call. Operations which have to wait on the network record what they are
+/// doing as an explicit state (e.g. `Creating` or `ReDrawing`) and then apply the outcome with
+/// `compare_and_swap`, which fails if someone else has changed the counterparty in the meantime.
+///
+/// The outer lock should only be taken for writing very rarely, to insert and remove
+/// counterparties.
+///
+/// Counterparties who contact us first are created with `new_peer`, which applies the peer
+/// rules: a `PeerFilter`, a maximum number of counterparties in the `New` state, and a time
+/// after which `remove_stale_peers` removes counterparties that are still `New`.
+///
+/// How long the lock of each counterparty is held is recorded, see `lock_times`.
 pub struct Storage {
-    inner: QrwLock<HashMap<Address, Qutex<Counterparty>>>,
+    inner: RwLock<HashMap<Address, Arc<Mutex<Entry>>>>,
+    peer_filter: PeerFilter,
+    max_pending_peers: usize,
+    pending_peer_ttl: Duration,
+    lock_times: Mutex<Histogram>,
+}
+
+impl Default for Storage {
+    fn default() -> Storage {
+        Storage::new()
+    }
 }
 
 impl Storage {
     pub fn new() -> Storage {
         Storage {
-            inner: QrwLock::new(HashMap::new()),
+            inner: RwLock::new(HashMap::new()),
+            peer_filter: PeerFilter::Any,
+            max_pending_peers: DEFAULT_MAX_PENDING_PEERS,
+            pending_peer_ttl: DEFAULT_PENDING_PEER_TTL,
+            lock_times: Mutex::new(Histogram::new(LOCK_BUCKETS)),
         }
     }
 
-    pub fn get_counterparty(
-        &self,
-        k: Address,
-    ) -> impl Future<Item = Option<Guard<Counterparty>>, Error = Error> {
+    /// Only lets addresses accepted by `filter` become counterparties by contacting us
+    pub fn with_peer_filter(mut self, filter: PeerFilter) -> Self {
+        self.peer_filter = filter;
+        self
+    }
+
+    /// Refuses new peers while `max` counterparties are in the `New` state
+    pub fn with_max_pending_peers(mut self, max: usize) -> Self {
+        self.max_pending_peers = max;
+        self
+    }
+
+    /// Lets `remove_stale_peers` remove counterparties which have been `New` for longer than
+    /// `ttl`
+    pub fn with_pending_peer_ttl(mut self, ttl: Duration) -> Self {
+        self.pending_peer_ttl = ttl;
+        self
+    }
+
+    fn lock<'a>(&'a self, entry: &'a Mutex<Entry>) -> Held<'a> {
+        Held {
+            entry: entry.lock().expect("Counterparty poisoned"),
+            since: Instant::now(),
+            lock_times: &self.lock_times,
+        }
+    }
+
+    /// How long the locks of counterparties have been held
+    pub fn lock_times(&self) -> Histogram {
+        self.lock_times.lock().expect("Storage poisoned").clone()
+    }
+
+    fn entry(&self, k: Address) -> Option<Arc<Mutex<Entry>>> {
         self.inner
-            .clone()
             .read()
-            .from_err()
-            .and_then(move |data| match data.get(&k) {
-                Some(v) => Box::new(v.clone().lock().from_err().and_then(|v| Ok(Some(v)))),
-                None => Box::new(futures::future::ok(None))
-                    as Box<Future<Item = Option<Guard<Counterparty>>, Error = Error>>,
-            })
+            .expect("Storage poisoned")
+            .get(&k)
+            .cloned()
     }
 
-    pub fn new_counterparty(
-        &self,
-        k: Address,
-        v: Counterparty,
-    ) -> impl Future<Item = (), Error = Error> {
+    /// Returns a snapshot of the state of a counterparty
+    pub fn get_counterparty(&self, k: Address) -> Option<Counterparty> {
+        self.entry(k).map(|v| {
+            let counterparty = self.lock(&v).counterparty.clone();
+            counterparty
+        })
+    }
+
+    pub fn new_counterparty(&self, k: Address, v: Counterparty) -> Result<(), StorageError> {
+        match self.inner.write().expect("Storage poisoned").entry(k) {
+            hash_map::Entry::Occupied(_) => Err(StorageError::CounterpartyExists(k)),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(Entry::new(v));
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns a snapshot of a counterparty, inserting `v` first if there is none yet
+    pub fn get_or_insert_counterparty(&self, k: Address, v: Counterparty) -> Counterparty {
+        if let Some(counterparty) = self.get_counterparty(k) {
+            return counterparty;
+        }
+        let entry = self
+            .inner
+            .write()
+            .expect("Storage poisoned")
+            .entry(k)
+            .or_insert_with(|| Entry::new(v))
+            .clone();
+        let counterparty = self.lock(&entry).counterparty.clone();
+        counterparty
+    }
+
+    /// Like `get_or_insert_counterparty`, for an address which contacted us first. `v` is only
+    /// inserted if the peer rules allow it.
+    pub fn new_peer(&self, k: Address, v: Counterparty) -> Result<Counterparty, StorageError> {
+        if let Some(counterparty) = self.get_counterparty(k) {
+            return Ok(counterparty);
+        }
+        if !self.peer_filter.accepts(&k) {
+            return Err(StorageError::PeerRejected(k));
+        }
+
+        let mut data = self.inner.write().expect("Storage poisoned");
+        if let Some(entry) = data.get(&k) {
+            let counterparty = self.lock(entry).counterparty.clone();
+            return Ok(counterparty);
+        }
+        let pending = data
+            .values()
+            .filter(|entry| matches!(self.lock(entry).counterparty, Counterparty::New { .. }))
+            .count();
+        if pending >= self.max_pending_peers {
+            return Err(StorageError::TooManyPendingPeers(pending));
+        }
+        data.insert(k, Entry::new(v.clone()));
+        Ok(v)
+    }
+
+    /// Removes the counterparties which have been `New` for longer than the pending peer TTL,
+    /// and returns their addresses
+    pub fn remove_stale_peers(&self) -> Vec<Address> {
+        let mut removed = Vec::new();
         self.inner
-            .clone()
             .write()
-            .from_err()
-            .and_then(move |mut data| {
-                if !data.contains_key(&k) {
-                    data.insert(k.clone(), Qutex::new(v.clone()));
-                } else {
-                    bail!("Counterparty already exists");
+            .expect("Storage poisoned")
+            .retain(|k, entry| {
+                let entry = self.lock(entry);
+                let stale = match entry.counterparty {
+                    Counterparty::New { .. } => entry.changed.elapsed() >= self.pending_peer_ttl,
+                    _ => false,
+                };
+                if stale {
+                    removed.push(*k);
                 }
-                Ok(())
+                !stale
+            });
+        removed
+    }
+
+    /// Replaces the state of a counterparty with `new`, as long as it is still `current`
+    pub fn compare_and_swap(
+        &self,
+        k: Address,
+        current: &Counterparty,
+        new: Counterparty,
+    ) -> Result<(), StorageError> {
+        let entry = self.entry(k).ok_or(StorageError::CounterpartyNotFound(k))?;
+        let mut entry = self.lock(&entry);
+        if entry.counterparty != *current {
+            return Err(StorageError::Conflict(k));
+        }
+        entry.set(new);
+        Ok(())
+    }
+
+    /// Runs `f` on the state of a counterparty while holding its lock. The state is only
+    /// changed if `f` succeeds. `f` cannot wait on anything, which keeps the lock short.
+    pub fn update_counterparty<T, E, F>(&self, k: Address, f: F) -> Result<T, E>
+    where
+        E: From<StorageError>,
+        F: FnOnce(&mut Counterparty) -> Result<T, E>,
+    {
+        let entry = self.entry(k).ok_or(StorageError::CounterpartyNotFound(k))?;
+        let mut entry = self.lock(&entry);
+        let mut updated = entry.counterparty.clone();
+        let res = f(&mut updated)?;
+        if updated != entry.counterparty {
+            entry.set(updated);
+        }
+        Ok(res)
+    }
+
+    /// Returns a snapshot of every counterparty. Each counterparty is locked only for as long as
+    /// it takes to clone it.
+    pub fn get_all_counterparties(&self) -> Vec<(Address, Counterparty)> {
+        let counterparties = self
+            .inner
+            .read()
+            .expect("Storage poisoned")
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect::<Vec<_>>();
+        counterparties
+            .into_iter()
+            .map(|(k, v)| {
+                let counterparty = self.lock(&v).counterparty.clone();
+                (k, counterparty)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::NewChannelTx;
+
+    fn address(last_byte: u8) -> Address {
+        let mut data: [u8; 20] = Default::default();
+        data[19] = last_byte;
+        data.into()
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let storage = Storage::new();
+        let new = Counterparty::New { i_am_0: true };
+        storage.new_counterparty(address(1), new.clone()).unwrap();
+
+        let other = Counterparty::New { i_am_0: false };
+        storage
+            .compare_and_swap(address(1), &new, other.clone())
+            .unwrap();
+        assert_eq!(storage.get_counterparty(address(1)), Some(other.clone()));
+
+        // The state is no longer `new`, so swapping again has to fail
+        match storage.compare_and_swap(address(1), &new, new.clone()) {
+            Err(StorageError::Conflict(a)) => assert_eq!(a, address(1)),
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert_eq!(storage.get_counterparty(address(1)), Some(other));
+
+        match storage.compare_and_swap(address(2), &new, new.clone()) {
+            Err(StorageError::CounterpartyNotFound(_)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_update_counterparty_is_all_or_nothing() {
+        let storage = Storage::new();
+        let new = Counterparty::New { i_am_0: true };
+        storage.new_counterparty(address(1), new.clone()).unwrap();
+
+        let res: Result<(), StorageError> = storage.update_counterparty(address(1), |c| {
+            *c = Counterparty::New { i_am_0: false };
+            Err(StorageError::Conflict(address(1)))
+        });
+        assert!(res.is_err());
+        assert_eq!(storage.get_counterparty(address(1)), Some(new));
+
+        storage
+            .update_counterparty(address(1), |c| {
+                *c = Counterparty::New { i_am_0: false };
+                Ok::<_, StorageError>(())
             })
+            .unwrap();
+        assert_eq!(
+            storage.get_counterparty(address(1)),
+            Some(Counterparty::New { i_am_0: false })
+        );
+    }
+
+    #[test]
+    fn test_get_or_insert_counterparty() {
+        let storage = Storage::new();
+        let first =
+            storage.get_or_insert_counterparty(address(1), Counterparty::New { i_am_0: true });
+        let second =
+            storage.get_or_insert_counterparty(address(1), Counterparty::New { i_am_0: false });
+        assert_eq!(first, Counterparty::New { i_am_0: true });
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn test_peer_filter() {
+        let new = Counterparty::New { i_am_0: true };
+        let listed: HashSet<Address> = vec![address(1)].into_iter().collect();
+
+        let storage = Storage::new().with_peer_filter(PeerFilter::Allow(listed.clone()));
+        storage.new_peer(address(1), new.clone()).unwrap();
+        match storage.new_peer(address(2), new.clone()) {
+            Err(StorageError::PeerRejected(a)) => assert_eq!(a, address(2)),
+            res => panic!("unexpected result {:?}", res),
+        }
+
+        let storage = Storage::new().with_peer_filter(PeerFilter::Deny(listed));
+        assert!(storage.new_peer(address(1), new.clone()).is_err());
+        storage.new_peer(address(2), new.clone()).unwrap();
+
+        let storage = Storage::new()
+            .with_peer_filter(PeerFilter::Custom(Box::new(|a: &Address| *a == address(3))));
+        assert!(storage.new_peer(address(2), new.clone()).is_err());
+        storage.new_peer(address(3), new.clone()).unwrap();
+        assert_eq!(storage.get_counterparty(address(2)), None);
+
+        // Counterparties we create ourselves are not subject to the filter
+        storage.get_or_insert_counterparty(address(2), new.clone());
+        assert_eq!(storage.get_counterparty(address(2)), Some(new));
+    }
+
+    #[test]
+    fn test_max_pending_peers() {
+        let new = Counterparty::New { i_am_0: true };
+        let storage = Storage::new().with_max_pending_peers(2);
+
+        storage.new_peer(address(1), new.clone()).unwrap();
+        storage.new_peer(address(2), new.clone()).unwrap();
+        match storage.new_peer(address(3), new.clone()) {
+            Err(StorageError::TooManyPendingPeers(2)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        // Known peers are still let through
+        storage.new_peer(address(1), new.clone()).unwrap();
+
+        // A peer which moves on from `New` makes room for another one
+        storage
+            .update_counterparty(address(2), |c| {
+                *c = Counterparty::OtherCreating {
+                    i_am_0: true,
+                    new_channel_tx: NewChannelTx {
+                        address_0: address(0),
+                        address_1: address(2),
+                        balance_0: 0u64.into(),
+                        balance_1: 10u64.into(),
+                        expiration: 0u64.into(),
+                        settling_period_length: 5000u64.into(),
+                        signature_0: None,
+                        signature_1: None,
+                    },
+                };
+                Ok::<_, StorageError>(())
+            })
+            .unwrap();
+        storage.new_peer(address(3), new).unwrap();
+    }
+
+    #[test]
+    fn test_remove_stale_peers() {
+        let new = Counterparty::New { i_am_0: true };
+
+        let storage = Storage::new();
+        storage.new_peer(address(1), new.clone()).unwrap();
+        assert!(storage.remove_stale_peers().is_empty());
+        assert!(storage.get_counterparty(address(1)).is_some());
+
+        let storage = Storage::new().with_pending_peer_ttl(Duration::from_secs(0));
+        storage.new_peer(address(1), new.clone()).unwrap();
+        storage.new_peer(address(2), new.clone()).unwrap();
+        storage
+            .update_counterparty(address(2), |c| {
+                *c = Counterparty::Creating {
+                    i_am_0: true,
+                    new_channel_tx: NewChannelTx {
+                        address_0: address(0),
+                        address_1: address(2),
+                        balance_0: 10u64.into(),
+                        balance_1: 0u64.into(),
+                        expiration: 0u64.into(),
+                        settling_period_length: 5000u64.into(),
+                        signature_0: None,
+                        signature_1: None,
+                    },
+                };
+                Ok::<_, StorageError>(())
+            })
+            .unwrap();
+
+        assert_eq!(storage.remove_stale_peers(), vec![address(1)]);
+        assert_eq!(storage.get_counterparty(address(1)), None);
+        assert!(storage.get_counterparty(address(2)).is_some());
     }
 }
@@ -0,0 +1,352 @@
+//! Paying a counterparty at a steady rate, for instance for bandwidth by the second.
+//!
+//! Sending an update for every second would be chatty, so a `PaymentStream` adds up what is due
+//! and pays it in one update once it reaches a minimum payment. It is driven by calling `tick`
+//! regularly, and measures time with a `Clock` so that tests can move time on by hand.
+
+use crate::error::GuacError;
+use crate::Guac;
+use clarity::Address;
+use num::traits::ops::checked::CheckedSub;
+use num256::Uint256;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Tells a `PaymentStream` the time
+pub trait Clock {
+    /// The time since some point in the past, which never goes back
+    fn now(&self) -> Duration;
+}
+
+/// The time since the clock was made
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock which only moves when told to. Clones share the same time.
+#[derive(Clone, Default)]
+pub struct MockClock(Arc<Mutex<Duration>>);
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        *self.0.lock().unwrap()
+    }
+}
+
+struct StreamState {
+    /// When the stream was last brought up to date
+    last: Duration,
+    /// How long the stream has been running, not counting pauses
+    active: Duration,
+    streamed: Uint256,
+    paused: bool,
+    /// Whether a payment is on its way, so that ticks do not pay the same amount twice
+    sending: bool,
+}
+
+/// Pays `rate` to a counterparty for every `interval` it runs, starting when it is made
+pub struct PaymentStream {
+    guac: Guac,
+    their_address: Address,
+    their_url: String,
+    rate: Uint256,
+    interval: Duration,
+    min_payment: Uint256,
+    clock: Arc<dyn Clock + Send + Sync>,
+    state: Mutex<StreamState>,
+}
+
+impl PaymentStream {
+    /// Pays every interval, unless the minimum payment is changed with `with_min_payment`.
+    /// Panics if `interval` is zero.
+    pub fn new(
+        guac: Guac,
+        their_address: Address,
+        their_url: String,
+        rate: Uint256,
+        interval: Duration,
+    ) -> PaymentStream {
+        assert!(
+            interval > Duration::ZERO,
+            "The interval of a stream cannot be zero"
+        );
+        PaymentStream {
+            guac,
+            their_address,
+            their_url,
+            min_payment: rate.clone(),
+            rate,
+            interval,
+            clock: Arc::new(SystemClock::new()),
+            state: Mutex::new(StreamState {
+                last: Duration::default(),
+                active: Duration::default(),
+                streamed: 0u64.into(),
+                paused: false,
+                sending: false,
+            }),
+        }
+    }
+
+    /// Measures time with `clock`, the stream starts at its current time
+    pub fn with_clock(mut self, clock: Arc<dyn Clock + Send + Sync>) -> Self {
+        self.state.get_mut().unwrap().last = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Waits until at least `min_payment` is due before paying it
+    pub fn with_min_payment(mut self, min_payment: Uint256) -> Self {
+        self.min_payment = min_payment;
+        self
+    }
+
+    /// Pays what is due if it is at least the minimum payment, and returns the amount paid.
+    ///
+    /// If the payment fails, the stream is paused until `resume` is called, and the time in
+    /// between is not paid for. A payment which was signed but did not arrive still counts as
+    /// streamed, as it is sent again with the next update.
+    pub async fn tick(&self) -> Result<Option<Uint256>, GuacError> {
+        let amount = {
+            let mut state = self.state.lock().unwrap();
+            self.bring_up_to_date(&mut state);
+            if state.paused || state.sending {
+                return Ok(None);
+            }
+            let owed = self.owed_by(&state);
+            if owed == 0u64.into() || owed < self.min_payment {
+                return Ok(None);
+            }
+            state.sending = true;
+            owed
+        };
+
+        let update_tx = match self
+            .guac
+            .sign_payment(self.their_address, amount.clone(), None)
+            .await
+        {
+            Ok(update_tx) => update_tx,
+            Err(e) => return self.pause_after(e, None),
+        };
+        let res = self
+            .guac
+            .deliver_payment(
+                self.their_address,
+                self.their_url.clone(),
+                amount.clone(),
+                update_tx,
+            )
+            .await;
+
+        match res {
+            Ok(()) => {
+                let mut state = self.state.lock().unwrap();
+                state.streamed = state.streamed.clone() + amount.clone();
+                state.sending = false;
+                Ok(Some(amount))
+            }
+            Err(e) => self.pause_after(e, Some(amount)),
+        }
+    }
+
+    /// Stops paying for time until `resume`
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.bring_up_to_date(&mut state);
+        state.paused = true;
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.bring_up_to_date(&mut state);
+        state.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Everything paid so far
+    pub fn streamed(&self) -> Uint256 {
+        self.state.lock().unwrap().streamed.clone()
+    }
+
+    /// What is due and not paid yet
+    pub fn owed(&self) -> Uint256 {
+        let mut state = self.state.lock().unwrap();
+        self.bring_up_to_date(&mut state);
+        self.owed_by(&state)
+    }
+
+    fn bring_up_to_date(&self, state: &mut StreamState) {
+        let now = self.clock.now();
+        if !state.paused {
+            state.active += now.checked_sub(state.last).unwrap_or_default();
+        }
+        state.last = now;
+    }
+
+    fn owed_by(&self, state: &StreamState) -> Uint256 {
+        let intervals = (state.active.as_nanos() / self.interval.as_nanos()) as u64;
+        let due = self.rate.clone() * Uint256::from(intervals);
+        due.checked_sub(&state.streamed)
+            .unwrap_or_else(|| 0u64.into())
+    }
+
+    /// `streamed` is what left our balance before the payment failed
+    fn pause_after(
+        &self,
+        error: GuacError,
+        streamed: Option<Uint256>,
+    ) -> Result<Option<Uint256>, GuacError> {
+        log::warn!(
+            "Pausing payment stream to {}: {}",
+            self.their_address,
+            error
+        );
+        let mut state = self.state.lock().unwrap();
+        if let Some(streamed) = streamed {
+            state.streamed = state.streamed.clone() + streamed;
+        }
+        state.sending = false;
+        state.paused = true;
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ProtocolError;
+    use crate::test_utils::make_pair;
+    use futures::executor::block_on;
+
+    fn seconds(seconds: f64) -> Duration {
+        Duration::from_secs_f64(seconds)
+    }
+
+    #[test]
+    fn test_stream_coalesces_payments() {
+        let (node_0, node_1) = make_pair();
+        let clock = MockClock::default();
+        let stream = PaymentStream::new(
+            node_0.guac.clone(),
+            node_1.address,
+            node_1.url.clone(),
+            1u64.into(),
+            seconds(1.0),
+        )
+        .with_clock(Arc::new(clock.clone()))
+        .with_min_payment(5u64.into());
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+
+            clock.advance(seconds(3.0));
+            assert_eq!(stream.tick().await.unwrap(), None);
+            assert_eq!(stream.owed(), 3u64.into());
+
+            clock.advance(seconds(2.5));
+            assert_eq!(stream.tick().await.unwrap(), Some(5u64.into()));
+
+            clock.advance(seconds(2.0));
+            assert_eq!(stream.tick().await.unwrap(), None);
+            assert_eq!(stream.owed(), 2u64.into());
+
+            assert_eq!(stream.streamed(), 5u64.into());
+            assert_eq!(
+                node_1.guac.check_accrual(node_0.address).await.unwrap(),
+                5u64.into()
+            );
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "The interval of a stream cannot be zero")]
+    fn test_stream_zero_interval() {
+        let (node_0, node_1) = make_pair();
+        PaymentStream::new(
+            node_0.guac.clone(),
+            node_1.address,
+            node_1.url.clone(),
+            1u64.into(),
+            Duration::ZERO,
+        );
+    }
+
+    #[test]
+    fn test_stream_pauses_on_error() {
+        let (node_0, node_1) = make_pair();
+        let clock = MockClock::default();
+        let stream = PaymentStream::new(
+            node_0.guac.clone(),
+            node_1.address,
+            node_1.url.clone(),
+            10u64.into(),
+            seconds(1.0),
+        )
+        .with_clock(Arc::new(clock.clone()));
+
+        block_on(async {
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 15u64.into())
+                .await
+                .unwrap();
+
+            clock.advance(seconds(1.0));
+            assert_eq!(stream.tick().await.unwrap(), Some(10u64.into()));
+
+            // Only 5 is left in the channel
+            clock.advance(seconds(1.0));
+            match stream.tick().await {
+                Err(GuacError::Protocol(ProtocolError::NotEnough { .. })) => {}
+                res => panic!("unexpected result {:?}", res),
+            }
+            assert!(stream.is_paused());
+
+            // The time paused is not paid for
+            clock.advance(seconds(60.0));
+            assert_eq!(stream.tick().await.unwrap(), None);
+            node_0
+                .guac
+                .fill_channel(node_1.address, node_1.url.clone(), 100u64.into())
+                .await
+                .unwrap();
+            stream.resume();
+            assert_eq!(stream.tick().await.unwrap(), Some(10u64.into()));
+            assert_eq!(stream.streamed(), 20u64.into());
+        });
+    }
+}
@@ -0,0 +1,442 @@
+//! In-memory stand-ins for the chain and the network, so that whole channel flows between
+//! several nodes can be tested without a full node or HTTP.
+
+use crate::channel_manager::BlockchainApi;
+use crate::crypto::Crypto;
+use crate::error::{BlockchainError, GuacError, SignerError, TransportError};
+use crate::routing::{Forward, NodeInfo};
+use crate::storage::Storage;
+use crate::types::{ChannelState, NewChannelTx, ReDrawTx, UpdateResponse, UpdateTx};
+use crate::{
+    Billing, CounterpartyApi, DefaultProposalPolicy, Events, Guac, InMemorySigner, Ledger, Metrics,
+    RefillManager, Router, Signer,
+};
+use async_trait::async_trait;
+use clarity::{Address, PrivateKey, Signature};
+use futures::channel::oneshot;
+use num256::Uint256;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+pub const SECRET_0: &str = "86de2cf259bf21a9aa2b8cf78f89ed479681001ca320c5762bb3237db65445cb";
+pub const SECRET_1: &str = "06e744bba37fd1e630dc775d10fd8cbe0b5643f4d7187072d3d08df4b4118acf";
+pub const SECRET_2: &str = "a0fe31cc8e0f906bbb30e7048f976b1854c4b0d46eae2b3f03dcf8f803c6b88b";
+pub const SECRET_3: &str = "5c1e9f0b7a3d42e8b6c0d9a1f4e7b2c5d8a3f6e9b1c4d7a0e3f6b9c2d5e8a1f4";
+
+#[derive(Default)]
+struct Chain {
+    block: u64,
+    channels: HashMap<[u8; 32], ChannelState>,
+}
+
+/// A contract shared by every node of a test
+#[derive(Clone, Default)]
+pub struct MockChain(Arc<Mutex<Chain>>);
+
+impl MockChain {
+    pub fn channel(&self, channel_id: [u8; 32]) -> Option<ChannelState> {
+        self.0.lock().unwrap().channels.get(&channel_id).cloned()
+    }
+
+    /// Moves the chain on by a block
+    pub fn mine(&self) {
+        self.0.lock().unwrap().block += 1;
+    }
+
+    /// Starts the settling period of a channel, as its counterparty would to close it
+    pub fn start_settling(&self, channel_id: [u8; 32]) {
+        let mut chain = self.0.lock().unwrap();
+        let block = chain.block;
+        let channel = chain
+            .channels
+            .get_mut(&channel_id)
+            .expect("No such channel");
+        channel.settling_period_started = true;
+        channel.settling_period_end = Uint256::from(block) + channel.settling_period_length.clone();
+    }
+
+    /// Closes a channel with an update signed by both sides, which has to be newer than what
+    /// the contract knows and hold all of its money
+    pub fn close(&self, update_tx: &UpdateTx) -> Result<(), BlockchainError> {
+        if update_tx.signature_0.is_none() || update_tx.signature_1.is_none() {
+            return Err(BlockchainError::MissingSignature("signature"));
+        }
+        let mut chain = self.0.lock().unwrap();
+        let channel = chain
+            .channels
+            .get_mut(&update_tx.channel_id)
+            .ok_or_else(|| BlockchainError::InvalidData("No such channel".to_string()))?;
+        if update_tx.sequence_number <= channel.sequence_number {
+            return Err(BlockchainError::InvalidData(
+                "Sequence number too low".to_string(),
+            ));
+        }
+        if update_tx.balance_0.clone() + update_tx.balance_1.clone() != channel.total_balance {
+            return Err(BlockchainError::InvalidData(
+                "Balances do not add up to the total".to_string(),
+            ));
+        }
+        channel.balance_0 = update_tx.balance_0.clone();
+        channel.balance_1 = update_tx.balance_1.clone();
+        channel.sequence_number = update_tx.sequence_number.clone();
+        Ok(())
+    }
+}
+
+/// One node's view of the `MockChain`. Transactions can be held back to test what happens
+/// while a node waits on the chain.
+#[derive(Clone)]
+pub struct MockBlockchain {
+    chain: MockChain,
+    hold: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+    wallet: Arc<Mutex<u64>>,
+}
+
+impl MockBlockchain {
+    /// What `balance_of` returns from now on, zero until set
+    pub fn set_wallet_balance(&self, balance: u64) {
+        *self.wallet.lock().unwrap() = balance;
+    }
+
+    /// The next transaction only makes it to the chain once the returned sender is used
+    pub fn hold_next_transaction(&self) -> oneshot::Sender<()> {
+        let (release, hold) = oneshot::channel();
+        *self.hold.lock().unwrap() = Some(hold);
+        release
+    }
+
+    async fn mine(&self) {
+        let hold = self.hold.lock().unwrap().take();
+        if let Some(hold) = hold {
+            let _ = hold.await;
+        }
+        self.chain.mine();
+    }
+
+    fn apply_re_draw(&self, re_draw_tx: &ReDrawTx) -> Result<(), BlockchainError> {
+        if re_draw_tx.signature_0.is_none() || re_draw_tx.signature_1.is_none() {
+            return Err(BlockchainError::MissingSignature("signature"));
+        }
+        let mut chain = self.chain.0.lock().unwrap();
+        let channel = chain
+            .channels
+            .get_mut(&re_draw_tx.channel_id)
+            .ok_or_else(|| BlockchainError::InvalidData("No such channel".to_string()))?;
+        if re_draw_tx.sequence_number <= channel.sequence_number {
+            return Err(BlockchainError::InvalidData(
+                "Sequence number too low".to_string(),
+            ));
+        }
+        channel.balance_0 = re_draw_tx.new_balance_0.clone();
+        channel.balance_1 = re_draw_tx.new_balance_1.clone();
+        channel.total_balance = re_draw_tx.new_balance_0.clone() + re_draw_tx.new_balance_1.clone();
+        channel.sequence_number = re_draw_tx.sequence_number.clone();
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl BlockchainApi for MockBlockchain {
+    async fn balance_of(&self) -> Result<Uint256, BlockchainError> {
+        Ok((*self.wallet.lock().unwrap()).into())
+    }
+
+    async fn check_for_open(
+        &self,
+        address_0: &Address,
+        address_1: &Address,
+    ) -> Result<Option<[u8; 32]>, BlockchainError> {
+        let chain = self.chain.0.lock().unwrap();
+        Ok(chain
+            .channels
+            .iter()
+            .find(|(_, c)| c.address_0 == *address_0 && c.address_1 == *address_1)
+            .map(|(channel_id, _)| *channel_id))
+    }
+
+    async fn check_for_re_draw(&self, _channel_id: [u8; 32]) -> Result<(), BlockchainError> {
+        Ok(())
+    }
+
+    async fn quick_deposit(&self, _value: Uint256) -> Result<(), BlockchainError> {
+        self.mine().await;
+        Ok(())
+    }
+
+    async fn get_current_block(&self) -> Result<Uint256, BlockchainError> {
+        Ok(self.chain.0.lock().unwrap().block.into())
+    }
+
+    async fn get_channel(&self, channel_id: [u8; 32]) -> Result<ChannelState, BlockchainError> {
+        self.chain
+            .channel(channel_id)
+            .ok_or_else(|| BlockchainError::InvalidData("No such channel".to_string()))
+    }
+
+    async fn deposit_then_new_channel(
+        &self,
+        _amount: Uint256,
+        new_channel_tx: NewChannelTx,
+    ) -> Result<[u8; 32], BlockchainError> {
+        self.mine().await;
+        if new_channel_tx.signature_0.is_none() || new_channel_tx.signature_1.is_none() {
+            return Err(BlockchainError::MissingSignature("signature"));
+        }
+        let mut chain = self.chain.0.lock().unwrap();
+        let mut channel_id = [0u8; 32];
+        channel_id[0] = chain.channels.len() as u8 + 1;
+        chain.channels.insert(
+            channel_id,
+            ChannelState {
+                address_0: new_channel_tx.address_0,
+                address_1: new_channel_tx.address_1,
+                total_balance: new_channel_tx.balance_0.clone() + new_channel_tx.balance_1.clone(),
+                balance_0: new_channel_tx.balance_0,
+                balance_1: new_channel_tx.balance_1,
+                sequence_number: 0u64.into(),
+                settling_period_length: new_channel_tx.settling_period_length,
+                settling_period_started: false,
+                settling_period_end: 0u64.into(),
+            },
+        );
+        Ok(channel_id)
+    }
+
+    async fn deposit_then_re_draw(
+        &self,
+        _amount: Uint256,
+        re_draw_tx: ReDrawTx,
+    ) -> Result<(), BlockchainError> {
+        self.mine().await;
+        self.apply_re_draw(&re_draw_tx)
+    }
+
+    async fn re_draw_then_withdraw(
+        &self,
+        _amount: Uint256,
+        re_draw_tx: ReDrawTx,
+    ) -> Result<(), BlockchainError> {
+        self.mine().await;
+        self.apply_re_draw(&re_draw_tx)
+    }
+}
+
+/// Delivers messages straight to the node registered under the URL they are sent to. Sending to
+/// an unknown URL fails like an unreachable node would. Like transactions, messages can be held
+/// back, which lets tests decide in which order crossing messages arrive.
+#[derive(Clone, Default)]
+pub struct MockNetwork {
+    nodes: Arc<Mutex<HashMap<String, Guac>>>,
+    hold: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+}
+
+impl MockNetwork {
+    /// The next message sent by any node is only delivered once the returned sender is used
+    pub fn hold_next_message(&self) -> oneshot::Sender<()> {
+        let (release, hold) = oneshot::channel();
+        *self.hold.lock().unwrap() = Some(hold);
+        release
+    }
+
+    /// Makes `guac` reachable at `url`, replacing whatever node was there
+    pub fn register(&self, url: &str, guac: Guac) {
+        self.nodes.lock().unwrap().insert(url.to_string(), guac);
+    }
+
+    async fn deliver(&self, url: &str) -> Result<Guac, GuacError> {
+        let hold = self.hold.lock().unwrap().take();
+        if let Some(hold) = hold {
+            let _ = hold.await;
+        }
+        self.node(url)
+    }
+
+    fn node(&self, url: &str) -> Result<Guac, GuacError> {
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(url) {
+            Some(node) => Ok(node.clone()),
+            None => Err(TransportError::Request(format!("No node at {}", url)).into()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CounterpartyApi for MockNetwork {
+    async fn propose_channel(
+        &self,
+        from_address: Address,
+        to_url: String,
+        new_channel_tx: NewChannelTx,
+    ) -> Result<Signature, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.propose_channel(from_address, to_url, new_channel_tx)
+            .await
+    }
+
+    async fn propose_re_draw(
+        &self,
+        from_address: Address,
+        to_url: String,
+        re_draw_tx: ReDrawTx,
+    ) -> Result<Signature, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.propose_re_draw(from_address, to_url, re_draw_tx).await
+    }
+
+    async fn notify_channel_opened(
+        &self,
+        from_address: Address,
+        to_url: String,
+    ) -> Result<(), GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.notify_channel_opened(from_address, to_url).await
+    }
+
+    async fn notify_re_draw(
+        &self,
+        from_address: Address,
+        to_url: String,
+        update_tx: Option<UpdateTx>,
+    ) -> Result<Option<UpdateTx>, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.notify_re_draw(from_address, to_url, update_tx).await
+    }
+
+    async fn receive_payment(
+        &self,
+        from_address: Address,
+        to_url: String,
+        update_tx: UpdateTx,
+    ) -> Result<UpdateResponse, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.receive_payment(from_address, to_url, update_tx).await
+    }
+
+    async fn fulfill_hashlock(
+        &self,
+        from_address: Address,
+        to_url: String,
+        preimage: [u8; 32],
+    ) -> Result<UpdateTx, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.fulfill_hashlock(from_address, to_url, preimage).await
+    }
+
+    async fn get_info(&self, to_url: String) -> Result<Vec<NodeInfo>, GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.get_info(to_url).await
+    }
+
+    async fn forward_payment(
+        &self,
+        from_address: Address,
+        to_url: String,
+        forward: Forward,
+    ) -> Result<(), GuacError> {
+        let node = self.deliver(&to_url).await?;
+        node.forward_payment(from_address, to_url, forward).await
+    }
+}
+
+/// A node of a test, reachable at `url` over the `MockNetwork`
+/// Signs with a key in memory, but can be made to wait or to refuse like a remote signer
+#[derive(Clone)]
+pub struct MockSigner {
+    signer: Arc<InMemorySigner>,
+    hold: Arc<Mutex<Option<oneshot::Receiver<()>>>>,
+}
+
+impl MockSigner {
+    /// The next signature is only made once the returned sender is used, and refused if it is
+    /// dropped instead
+    pub fn hold_next_signature(&self) -> oneshot::Sender<()> {
+        let (release, hold) = oneshot::channel();
+        *self.hold.lock().unwrap() = Some(hold);
+        release
+    }
+}
+
+#[async_trait(?Send)]
+impl Signer for MockSigner {
+    fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    async fn sign_hash(&self, hash: &[u8]) -> Result<Signature, SignerError> {
+        let hold = self.hold.lock().unwrap().take();
+        if let Some(hold) = hold {
+            hold.await
+                .map_err(|_| SignerError::Remote("refused to sign".into()))?;
+        }
+        self.signer.sign_hash(hash).await
+    }
+}
+
+pub struct TestNode {
+    pub guac: Guac,
+    pub blockchain: MockBlockchain,
+    pub signer: MockSigner,
+    pub network: MockNetwork,
+    pub address: Address,
+    pub url: String,
+}
+
+pub fn make_node(network: &MockNetwork, chain: &MockChain, secret: &str, url: &str) -> TestNode {
+    make_node_with_storage(network, chain, secret, url, Storage::new())
+}
+
+pub fn make_node_with_storage(
+    network: &MockNetwork,
+    chain: &MockChain,
+    secret: &str,
+    url: &str,
+    storage: Storage,
+) -> TestNode {
+    let secret: PrivateKey = secret.parse().unwrap();
+    let address = secret.to_public_key().unwrap();
+    let blockchain = MockBlockchain {
+        chain: chain.clone(),
+        hold: Arc::new(Mutex::new(None)),
+        wallet: Arc::new(Mutex::new(0)),
+    };
+    let signer = MockSigner {
+        signer: Arc::new(InMemorySigner::new(secret).unwrap()),
+        hold: Arc::new(Mutex::new(None)),
+    };
+    let guac = Guac {
+        blockchain_client: Arc::new(Box::new(blockchain.clone())),
+        counterparty_client: Arc::new(Box::new(network.clone())),
+        storage: Arc::new(Box::new(storage)),
+        crypto: Arc::new(Box::new(Crypto::new(
+            Address::default(),
+            Arc::new(signer.clone()),
+        ))),
+        proposal_policy: Arc::new(Box::new(DefaultProposalPolicy)),
+        events: Arc::new(Box::new(Events::new())),
+        ledger: Arc::new(Box::new(Ledger::new())),
+        billing: Arc::new(Box::new(Billing::new())),
+        router: Arc::new(Box::new(Router::new(url.to_string()))),
+        refill: Arc::new(Box::new(RefillManager::new())),
+        metrics: Arc::new(Box::new(Metrics::new())),
+        settling: Arc::new(Mutex::new(HashSet::new())),
+    };
+    network.register(url, guac.clone());
+    TestNode {
+        guac,
+        blockchain,
+        signer,
+        network: network.clone(),
+        address,
+        url: url.to_string(),
+    }
+}
+
+/// Two nodes with their own URLs on a fresh chain and network
+pub fn make_pair() -> (TestNode, TestNode) {
+    let network = MockNetwork::default();
+    let chain = MockChain::default();
+    (
+        make_node(&network, &chain, SECRET_0, "node_0"),
+        make_node(&network, &chain, SECRET_1, "node_1"),
+    )
+}
@@ -1,38 +1,9 @@
 use crate::channel::Channel;
 use crate::crypto;
+use crate::receipt::Receipt;
 use clarity::{Address, Signature};
 use num256::Uint256;
 
-#[derive(Debug, Fail)]
-pub enum GuacError {
-    #[fail(
-        display = "Guac is currently waiting on another operation to complete. Try again later."
-    )]
-    TryAgainLater(),
-
-    #[fail(
-        display = "Cannot {} in the current state: {}. State must be: {}",
-        action, current_state, correct_state
-    )]
-    WrongState {
-        action: String,
-        current_state: String,
-        correct_state: String,
-    },
-
-    #[fail(display = "Invalid request: {}", message)]
-    Forbidden { message: String },
-
-    #[fail(display = "Update too old. Correct sequence number: {}", correct_seq)]
-    UpdateTooOld { correct_seq: Uint256 },
-
-    #[fail(display = "Not enough {}", stuff)]
-    NotEnough { stuff: String },
-
-    #[fail(display = "Something has gone wrong: {}", message)]
-    Error { message: String },
-}
-
 #[derive(Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum Counterparty {
     New {
@@ -59,6 +30,20 @@ pub enum Counterparty {
     },
 }
 
+impl Counterparty {
+    /// Whether we are `address_0` of the channel with the counterparty
+    pub fn i_am_0(&self) -> bool {
+        match self {
+            Counterparty::New { i_am_0 }
+            | Counterparty::Creating { i_am_0, .. }
+            | Counterparty::OtherCreating { i_am_0, .. } => *i_am_0,
+            Counterparty::ReDrawing { channel, .. }
+            | Counterparty::OtherReDrawing { channel, .. }
+            | Counterparty::Open { channel } => channel.i_am_0,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct NewChannelTx {
     pub address_0: Address,
@@ -88,16 +73,14 @@ impl NewChannelTx {
         let fingerprint = crypto::hash_bytes(&[
             func_name,
             contract_address,
-            &address_0,
-            &address_1,
+            address_0,
+            address_1,
             &balance_0,
             &balance_1,
             &expiration,
             &settling_period_length,
         ]);
-        let fingerprint: [u8; 32] = fingerprint.clone().into();
-
-        return fingerprint;
+        fingerprint.into()
     }
 }
 
@@ -122,7 +105,7 @@ impl ReDrawTx {
     pub fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
         let func_name: &[u8] = "reDraw".as_bytes();
         let contract_address: &[u8] = contract_address.as_bytes();
-        let channel_id: [u8; 32] = self.channel_id.clone().into();
+        let channel_id: [u8; 32] = self.channel_id;
         let sequence_number: [u8; 32] = self.sequence_number.clone().into();
         let old_balance_0: [u8; 32] = self.old_balance_0.clone().into();
         let old_balance_1: [u8; 32] = self.old_balance_1.clone().into();
@@ -141,12 +124,26 @@ impl ReDrawTx {
             &new_balance_1,
             &expiration,
         ]);
-        let fingerprint: [u8; 32] = fingerprint.clone().into();
-
-        return fingerprint;
+        fingerprint.into()
     }
 }
 
+/// State of a channel as stored by the contract
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChannelState {
+    pub address_0: Address,
+    pub address_1: Address,
+
+    pub total_balance: Uint256,
+    pub balance_0: Uint256,
+    pub balance_1: Uint256,
+    pub sequence_number: Uint256,
+
+    pub settling_period_length: Uint256,
+    pub settling_period_started: bool,
+    pub settling_period_end: Uint256,
+}
+
 // #[derive(Clone, Debug, Serialize, PartialEq, Eq)]
 // pub struct Channel {
 //     pub channel_id: [u8; 32],
@@ -232,6 +229,25 @@ impl ReDrawTx {
 //     }
 // }
 
+/// Money on its way from one side of a channel to the other. It belongs to the payee once they
+/// show a preimage of `hash`, and goes back to the payer once block `expiration` is reached.
+/// While it is pending, the money is in neither balance of the channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hashlock {
+    pub hash: [u8; 32],
+    pub amount: Uint256,
+    pub expiration: Uint256,
+    /// Whether the money comes out of balance_0
+    pub from_0: bool,
+}
+
+impl Hashlock {
+    /// The hash which a preimage unlocks
+    pub fn hash_of(preimage: &[u8; 32]) -> [u8; 32] {
+        crypto::hash_bytes(&[&preimage[..]]).into()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct UpdateTx {
     pub channel_id: [u8; 32],
@@ -240,6 +256,10 @@ pub struct UpdateTx {
     pub balance_0: Uint256,
     pub balance_1: Uint256,
 
+    /// Pending conditional payments, in the order they were made
+    #[serde(default)]
+    pub hashlocks: Vec<Hashlock>,
+
     pub signature_0: Option<Signature>,
     pub signature_1: Option<Signature>,
 }
@@ -248,22 +268,39 @@ impl UpdateTx {
     pub fn fingerprint(&self, contract_address: Address) -> [u8; 32] {
         let func_name: &[u8] = "Update".as_bytes();
         let contract_address: &[u8] = contract_address.as_bytes();
-        let channel_id: [u8; 32] = self.channel_id.clone().into();
+        let channel_id: [u8; 32] = self.channel_id;
         let sequence_number: [u8; 32] = self.sequence_number.clone().into();
         let balance_0: [u8; 32] = self.balance_0.clone().into();
         let balance_1: [u8; 32] = self.balance_1.clone().into();
-
-        let fingerprint = crypto::hash_bytes(&[
+        // Nothing is added for an update without hashlocks, so its fingerprint is the one the
+        // contract knows
+        let hashlocks: Vec<[u8; 97]> = self
+            .hashlocks
+            .iter()
+            .map(|hashlock| {
+                let amount: [u8; 32] = hashlock.amount.clone().into();
+                let expiration: [u8; 32] = hashlock.expiration.clone().into();
+                let mut bytes = [0u8; 97];
+                bytes[..32].copy_from_slice(&hashlock.hash);
+                bytes[32..64].copy_from_slice(&amount);
+                bytes[64..96].copy_from_slice(&expiration);
+                bytes[96] = hashlock.from_0 as u8;
+                bytes
+            })
+            .collect();
+
+        let mut data: Vec<&[u8]> = vec![
             func_name,
             contract_address,
             &channel_id,
             &sequence_number,
             &balance_0,
             &balance_1,
-        ]);
-        let fingerprint: [u8; 32] = fingerprint.clone().into();
+        ];
+        data.extend(hashlocks.iter().map(|bytes| &bytes[..]));
 
-        return fingerprint;
+        let fingerprint = crypto::hash_bytes(&data);
+        fingerprint.into()
     }
 
     pub fn set_my_signature(&mut self, i_am_0: bool, signature: &Signature) {
@@ -289,3 +326,12 @@ impl UpdateTx {
         }
     }
 }
+
+/// How the counterparty answered an update
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum UpdateResponse {
+    /// The update was applied, and the counterparty signed a receipt for it
+    Accepted(Receipt),
+    /// The update was refused because the counterparty has seen this higher sequence number
+    Retry(Uint256),
+}
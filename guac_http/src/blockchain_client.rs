@@ -1,411 +1,318 @@
-use clarity::abi::derive_signature;
-use clarity::abi::{encode_call, Token};
+use crate::contract::{
+    address_to_word, BalanceOf, ChannelOpened, ChannelReDrawn, Channels, ContractCall,
+    ContractEvent, ContractView, DepositThenNewChannel, DepositThenReDraw, QuickDeposit,
+    ReDrawThenWithdraw,
+};
+use async_trait::async_trait;
 use clarity::utils::bytes_to_hex_str;
+use clarity::Address;
 use clarity::Transaction;
-use clarity::{Address, PrivateKey};
-use failure::Error;
-use futures::Future;
-use futures::IntoFuture;
-use futures::Stream;
-use guac_core::types::{NewChannelTx, ReDrawTx};
-use guac_core::BlockchainApi;
+use futures::{Future, StreamExt, TryStreamExt};
+use guac_core::types::{ChannelState, NewChannelTx, ReDrawTx};
+use guac_core::{sign_transaction, BlockchainApi, BlockchainError, Metrics, Signer};
 use num256::Uint256;
+use std::sync::Arc;
+use std::time::Instant;
 use web3::client::Web3;
 use web3::types::{Data, Log, NewFilter, TransactionRequest};
+use web3::Web3Error;
 
 fn bytes_to_data(s: &[u8]) -> String {
-    let mut foo = "0x".to_string();
-    foo.push_str(&bytes_to_hex_str(&s));
-    foo
-}
-
-pub enum Action {
-    /// Sends a "traditional" ETH transfer
-    To(Address),
-    /// Does a contract call with provided ddata
-    Call(Vec<u8>),
+    format!("0x{}", bytes_to_hex_str(s))
 }
 
 pub struct BlockchainClient {
     web3: Web3,
     contract_address: Address,
     own_address: Address,
-    secret: PrivateKey,
+    signer: Arc<dyn Signer + Send + Sync>,
+    /// The same as `Guac::metrics`
+    #[allow(clippy::redundant_allocation)]
+    metrics: Arc<Box<Metrics>>,
 }
 
 impl BlockchainClient {
+    /// Sends transactions from the address of `signer`, which signs them. Calls to the full
+    /// nodes and transactions which are waited on are recorded in `metrics`.
+    #[allow(clippy::redundant_allocation)]
     pub fn new(
         contract_address: Address,
-        own_address: Address,
-        secret: PrivateKey,
-        full_node_url: &String,
+        signer: Arc<dyn Signer + Send + Sync>,
+        full_node_urls: &[String],
+        metrics: Arc<Box<Metrics>>,
     ) -> BlockchainClient {
         BlockchainClient {
             contract_address,
-            own_address,
-            secret,
-            web3: Web3::new(full_node_url),
+            own_address: signer.address(),
+            signer,
+            metrics,
+            // With several full nodes requests fail over between them
+            web3: Web3::new(full_node_urls),
         }
     }
-    fn wait_for_event(
+
+    /// Awaits `call` of `method` on a full node, and records how long it took and whether it
+    /// failed
+    async fn rpc<T, F>(&self, method: &str, call: F) -> Result<T, BlockchainError>
+    where
+        F: Future<Output = Result<T, Web3Error>>,
+    {
+        let start = Instant::now();
+        let res = call.await;
+        self.metrics
+            .observe_rpc(method, start.elapsed(), res.is_ok());
+        res.map_err(BlockchainError::node)
+    }
+
+    async fn wait_for_event<E: ContractEvent>(
         &self,
-        event: &str,
         topic1: Option<Vec<[u8; 32]>>,
         topic2: Option<Vec<[u8; 32]>>,
-    ) -> Box<Future<Item = Log, Error = Error>> {
-        self.get_event(event, topic1, topic2, None, None)
+    ) -> Result<E, BlockchainError> {
+        let log = self
+            .get_event(E::topic(), topic1, topic2, None, None)
+            .await?;
+        E::decode(&log)
     }
 
-    fn check_for_event(
+    async fn check_for_event<E: ContractEvent>(
         &self,
-        event: &str,
         topic1: Option<Vec<[u8; 32]>>,
         topic2: Option<Vec<[u8; 32]>>,
-    ) -> Box<Future<Item = Option<Log>, Error = Error>> {
-        let web3 = self.web3.clone();
-
+    ) -> Result<Option<E>, BlockchainError> {
         // Build a filter with specified topics
-        let mut new_filter = NewFilter::default();
-        new_filter.address = vec![self.contract_address.clone()];
-        new_filter.topics = Some(vec![
-            Some(vec![Some(bytes_to_data(&derive_signature(event)))]),
-            topic1.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
-            topic2.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
-        ]);
-
-        Box::new(web3.eth_get_logs(new_filter).and_then(|logs| {
-            // Assuming the latest log is at the head of the vec
-            Ok(logs.first().map(|log| log.clone()))
-        }))
+        let new_filter = NewFilter {
+            address: vec![self.contract_address],
+            topics: Some(vec![
+                Some(vec![Some(bytes_to_data(&E::topic()))]),
+                topic1.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
+                topic2.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
+            ]),
+            ..Default::default()
+        };
+
+        let logs = self
+            .rpc("eth_getLogs", self.web3.eth_get_logs(new_filter))
+            .await?;
+        // Assuming the latest log is at the head of the vec
+        match logs.first() {
+            Some(log) => Ok(Some(E::decode(log)?)),
+            None => Ok(None),
+        }
     }
 
-    fn get_event(
+    async fn get_event(
         &self,
-        event: &str,
+        event_topic: [u8; 32],
         topic1: Option<Vec<[u8; 32]>>,
         topic2: Option<Vec<[u8; 32]>>,
         from_block: Option<String>,
         to_block: Option<String>,
-    ) -> Box<Future<Item = Log, Error = Error>> {
-        let web3 = self.web3.clone();
+    ) -> Result<Log, BlockchainError> {
         // Build a filter with specified topics
-        let mut new_filter = NewFilter::default();
-        new_filter.address = vec![self.contract_address.clone()];
-        new_filter.from_block = from_block;
-        new_filter.to_block = to_block;
-        new_filter.topics = Some(vec![
-            Some(vec![Some(bytes_to_data(&derive_signature(event)))]),
-            topic1.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
-            topic2.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
-        ]);
-
-        Box::new(
-            web3.eth_new_filter(new_filter)
-                .and_then(move |filter_id| {
-                    web3.eth_get_filter_changes(filter_id.clone())
-                        .into_future()
-                        .map(move |(head, _tail)| (filter_id, head))
-                        .map_err(|(e, _)| e)
-                        .and_then(move |(filter_id, head)| {
-                            web3.eth_uninstall_filter(filter_id).and_then(move |r| {
-                                ensure!(r, "Unable to properly uninstall filter");
-                                Ok(head)
-                            })
-                        })
-                })
-                .map(move |maybe_log| maybe_log.expect("Expected log data but None found"))
-                .from_err()
-                .into_future(),
-        )
+        let new_filter = NewFilter {
+            address: vec![self.contract_address],
+            from_block,
+            to_block,
+            topics: Some(vec![
+                Some(vec![Some(bytes_to_data(&event_topic))]),
+                topic1.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
+                topic2.map(|v| v.into_iter().map(|val| Some(bytes_to_data(&val))).collect()),
+            ]),
+        };
+
+        let mut logs = self
+            .web3
+            .eth_subscribe_logs(new_filter)
+            .try_filter(|log| futures::future::ready(log.removed != Some(true)));
+        match logs.next().await {
+            Some(Ok(log)) => Ok(log),
+            Some(Err(e)) => {
+                self.metrics.record_rpc_error("eth_subscribe");
+                Err(BlockchainError::node(e))
+            }
+            None => Err(BlockchainError::EventNotSeen),
+        }
     }
 
-    fn send_raw_transaction(
+    /// Calls a read-only function of the contract and decodes what it returns
+    async fn call_view<V: ContractView>(&self, view: V) -> Result<V::Output, BlockchainError> {
+        let payload = view.encode()?;
+
+        let (gas_price, nonce) = self
+            .rpc(
+                "eth_gasPrice_and_getTransactionCount",
+                self.web3
+                    .eth_gas_price_and_transaction_count(self.own_address),
+            )
+            .await?;
+
+        let transaction = TransactionRequest {
+            from: self.own_address,
+            to: Some(self.contract_address),
+            nonce: Some(nonce),
+            gas: None,
+            gas_price: gas_price.into(),
+            value: Some(0u64.into()),
+            data: Some(Data(payload)),
+        };
+
+        let bytes = self
+            .rpc("eth_call", self.web3.eth_call(transaction))
+            .await?;
+        V::decode_output(&bytes)
+    }
+
+    async fn send_raw_transaction(
         &self,
         to_address: Address,
         data: Vec<u8>,
         value: Uint256,
-    ) -> Box<Future<Item = Uint256, Error = Error>> {
-        let web3 = self.web3.clone();
-        let secret = self.secret.clone();
-
-        let props = web3
-            .eth_gas_price()
-            .join(web3.eth_get_transaction_count(self.own_address));
-
-        Box::new(
-            props
-                .and_then(move |(gas_price, nonce)| {
-                    let transaction = Transaction {
-                        to: to_address,
-                        nonce: nonce,
-                        gas_price: gas_price.into(),
-                        gas_limit: 6721975u32.into(),
-                        value,
-                        data,
-                        signature: None,
-                    };
-
-                    let transaction = transaction.sign(&secret, Some(1u64));
-
-                    web3.eth_send_raw_transaction(
-                        transaction
-                            .to_bytes()
-                            .expect("transaction.to_bytes() failed"),
-                    )
-                })
-                .into_future(),
+    ) -> Result<Uint256, BlockchainError> {
+        let (gas_price, nonce) = self
+            .rpc(
+                "eth_gasPrice_and_getTransactionCount",
+                self.web3
+                    .eth_gas_price_and_transaction_count(self.own_address),
+            )
+            .await?;
+
+        let transaction = Transaction {
+            to: to_address,
+            nonce,
+            gas_price,
+            gas_limit: 6721975u32.into(),
+            value,
+            data,
+            signature: None,
+        };
+
+        let transaction = sign_transaction(&*self.signer, transaction, 1).await?;
+
+        self.rpc(
+            "eth_sendRawTransaction",
+            self.web3.eth_send_raw_transaction(
+                transaction
+                    .to_bytes()
+                    .expect("transaction.to_bytes() failed"),
+            ),
         )
+        .await
     }
 }
 
+#[async_trait(?Send)]
 impl BlockchainApi for BlockchainClient {
-    fn balance_of(&self) -> Box<Future<Item = Uint256, Error = Error>> {
-        let web3 = self.web3.clone();
-        let contract_address = self.contract_address.clone();
-        let own_address = self.own_address.clone();
-
-        let props = web3
-            .eth_gas_price()
-            .join(web3.eth_get_transaction_count(own_address));
-
-        let payload = encode_call("balanceOf(address)", &[own_address.into()]);
-
-        Box::new(
-            props
-                .and_then(move |(gas_price, nonce)| {
-                    let transaction = TransactionRequest {
-                        from: own_address,
-                        to: Some(contract_address),
-                        nonce: Some(nonce),
-                        gas: None,
-                        gas_price: gas_price.into(),
-                        value: Some(0u64.into()),
-                        data: Some(Data(payload)),
-                    };
-
-                    web3.eth_call(transaction)
-                })
-                .and_then(|bytes| Ok(Uint256::from_bytes_be(&bytes))),
-        )
+    async fn balance_of(&self) -> Result<Uint256, BlockchainError> {
+        self.call_view(BalanceOf {
+            address: self.own_address,
+        })
+        .await
+    }
+
+    async fn get_channel(&self, channel_id: [u8; 32]) -> Result<ChannelState, BlockchainError> {
+        self.call_view(Channels { channel_id }).await
     }
 
-    fn deposit_then_new_channel(
+    async fn deposit_then_new_channel(
         &self,
         amount: Uint256,
         new_channel_tx: NewChannelTx,
-    ) -> Box<Future<Item = [u8; 32], Error = Error>> {
-        let contract_address = self.contract_address.clone();
-        let addr_0_bytes: [u8; 32] = {
-            let mut data: [u8; 32] = Default::default();
-            data[12..].copy_from_slice(&new_channel_tx.address_0.as_bytes());
-            data
-        };
-        let addr_1_bytes: [u8; 32] = {
-            let mut data: [u8; 32] = Default::default();
-            data[12..].copy_from_slice(&new_channel_tx.address_1.as_bytes());
-            data
-        };
+    ) -> Result<[u8; 32], BlockchainError> {
+        let payload = DepositThenNewChannel {
+            new_channel_tx: &new_channel_tx,
+        }
+        .encode()?;
 
-        let event = self.wait_for_event(
-            "ChannelOpened(address,address,bytes32)",
-            Some(vec![addr_0_bytes]),
-            Some(vec![addr_1_bytes]),
+        let event = self.wait_for_event::<ChannelOpened>(
+            Some(vec![address_to_word(&new_channel_tx.address_0)]),
+            Some(vec![address_to_word(&new_channel_tx.address_1)]),
         );
 
-        let payload = encode_call(
-            "depositThenNewChannel(address,address,uint256,uint256,uint256,uint256,bytes,bytes)",
-            &[
-                new_channel_tx.address_0.into(),
-                new_channel_tx.address_1.into(),
-                new_channel_tx.balance_0.into(),
-                new_channel_tx.balance_1.into(),
-                new_channel_tx.expiration.into(),
-                new_channel_tx.settling_period_length.into(),
-                new_channel_tx
-                    .signature_0
-                    .expect("No signature_0 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-                new_channel_tx
-                    .signature_1
-                    .expect("No signature_1 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-            ],
-        );
+        let call = self.send_raw_transaction(self.contract_address, payload, amount);
 
-        let call = self.send_raw_transaction(contract_address, payload, amount);
-
-        Box::new(
-            call.join(event)
-                .and_then(|(_tx, response)| {
-                    let mut data: [u8; 32] = Default::default();
-                    ensure!(
-                        response.data.len() == 32,
-                        "Invalid data length in ChannelOpened event"
-                    );
-                    data.copy_from_slice(&response.data);
-                    Ok(data.into())
-                })
-                .into_future(),
-        )
+        let _pending = self.metrics.pending_transaction();
+        let (_tx, event) = futures::try_join!(call, event)?;
+        Ok(event.channel_id)
     }
 
-    fn deposit_then_re_draw(
+    async fn deposit_then_re_draw(
         &self,
         amount: Uint256,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let contract_address = self.contract_address.clone();
-        let event = self.wait_for_event(
-            "ChannelReDrawn(bytes32)",
-            Some(vec![re_draw_tx.channel_id.into()]),
-            None,
-        );
+    ) -> Result<(), BlockchainError> {
+        let payload = DepositThenReDraw {
+            re_draw_tx: &re_draw_tx,
+        }
+        .encode()?;
 
-        let payload = encode_call(
-            "depositThenRedraw(bytes32,uint256,uint256,uint256,uint256,uint256,uint256,bytes,bytes)",
-            &[
-                Token::Bytes(re_draw_tx.channel_id.to_vec()),
-                re_draw_tx.sequence_number.into(),
-                re_draw_tx.old_balance_0.into(),
-                re_draw_tx.old_balance_1.into(),
-                re_draw_tx.new_balance_0.into(),
-                re_draw_tx.new_balance_1.into(),
-                re_draw_tx.expiration.into(),
-                re_draw_tx
-                    .signature_0
-                    .expect("No signature_0 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-                re_draw_tx
-                    .signature_1
-                    .expect("No signature_1 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-            ],
-        );
+        let event = self.wait_for_event::<ChannelReDrawn>(Some(vec![re_draw_tx.channel_id]), None);
 
-        let call = self.send_raw_transaction(contract_address, payload, amount);
+        let call = self.send_raw_transaction(self.contract_address, payload, amount);
 
-        Box::new(
-            call.join(event)
-                .and_then(|(_tx, _response)| Ok(()))
-                .into_future(),
-        )
+        let _pending = self.metrics.pending_transaction();
+        futures::try_join!(call, event)?;
+        Ok(())
     }
 
-    fn re_draw_then_withdraw(
+    async fn re_draw_then_withdraw(
         &self,
         amount: Uint256,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let contract_address = self.contract_address.clone();
-        let event = self.wait_for_event(
-            "ChannelReDrawn(bytes32)",
-            Some(vec![re_draw_tx.channel_id.into()]),
-            None,
+    ) -> Result<(), BlockchainError> {
+        log::debug!(
+            "reDraw then withdraw {} from balances {}/{} to {}/{}",
+            amount,
+            re_draw_tx.old_balance_0,
+            re_draw_tx.old_balance_1,
+            re_draw_tx.new_balance_0,
+            re_draw_tx.new_balance_1
         );
 
-        println!("amount: {:?}, old_balance_0: {:?}, old_balance_1: {:?}, new_balance_0: {:?}, new_balance_1: {:?}", amount.clone(), re_draw_tx.old_balance_0.clone(), re_draw_tx.old_balance_1.clone(), re_draw_tx.new_balance_0.clone(), re_draw_tx.new_balance_1.clone());
-
-        let payload = encode_call(
-            "redrawThenWithdraw(uint256,bytes32,uint256,uint256,uint256,uint256,uint256,uint256,bytes,bytes)",
-            &[
-                amount.clone().into(),
-                Token::Bytes(re_draw_tx.channel_id.to_vec()),
-                re_draw_tx.sequence_number.into(),
-                re_draw_tx.old_balance_0.into(),
-                re_draw_tx.old_balance_1.into(),
-                re_draw_tx.new_balance_0.into(),
-                re_draw_tx.new_balance_1.into(),
-                re_draw_tx.expiration.into(),
-                re_draw_tx
-                    .signature_0
-                    .expect("No signature_0 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-                re_draw_tx
-                    .signature_1
-                    .expect("No signature_1 supplied")
-                    .into_bytes()
-                    .to_vec()
-                    .into(),
-            ],
-        );
+        let payload = ReDrawThenWithdraw {
+            amount: amount.clone(),
+            re_draw_tx: &re_draw_tx,
+        }
+        .encode()?;
 
-        let call = self.send_raw_transaction(contract_address, payload, amount);
+        let event = self.wait_for_event::<ChannelReDrawn>(Some(vec![re_draw_tx.channel_id]), None);
 
-        Box::new(
-            call.join(event)
-                .and_then(|(_tx, _response)| Ok(()))
-                .into_future(),
-        )
+        let call = self.send_raw_transaction(self.contract_address, payload, amount);
+
+        let _pending = self.metrics.pending_transaction();
+        futures::try_join!(call, event)?;
+        Ok(())
     }
 
-    fn check_for_open(
+    async fn check_for_open(
         &self,
         address_0: &Address,
         address_1: &Address,
-    ) -> Box<Future<Item = Option<[u8; 32]>, Error = Error>> {
-        let addr_0_bytes: [u8; 32] = {
-            let mut data: [u8; 32] = Default::default();
-            data[12..].copy_from_slice(&address_0.as_bytes());
-            data
-        };
-        let addr_1_bytes: [u8; 32] = {
-            let mut data: [u8; 32] = Default::default();
-            data[12..].copy_from_slice(&address_1.as_bytes());
-            data
-        };
-        Box::new(
-            self.check_for_event(
-                "ChannelOpened(address,address,bytes32)",
-                Some(vec![addr_0_bytes]),
-                Some(vec![addr_1_bytes]),
+    ) -> Result<Option<[u8; 32]>, BlockchainError> {
+        let event = self
+            .check_for_event::<ChannelOpened>(
+                Some(vec![address_to_word(address_0)]),
+                Some(vec![address_to_word(address_1)]),
             )
-            .and_then(|res| {
-                if let Some(response) = res {
-                    let mut data: [u8; 32] = Default::default();
-                    ensure!(
-                        response.data.len() == 32,
-                        "Invalid data length in ChannelOpened event"
-                    );
-                    data.copy_from_slice(&response.data);
-                    Ok(Some(data.into()))
-                } else {
-                    Ok(None)
-                }
-            }),
-        )
+            .await?;
+        Ok(event.map(|event| event.channel_id))
     }
 
-    fn check_for_re_draw(&self, channel_id: [u8; 32]) -> Box<Future<Item = (), Error = Error>> {
-        Box::new(
-            self.check_for_event(
-                "ChannelReDrawn(bytes32)",
-                Some(vec![channel_id.into()]),
-                None,
-            )
-            .and_then(|_| Ok(())),
-        )
+    async fn check_for_re_draw(&self, channel_id: [u8; 32]) -> Result<(), BlockchainError> {
+        self.check_for_event::<ChannelReDrawn>(Some(vec![channel_id]), None)
+            .await?;
+        Ok(())
     }
 
-    fn quick_deposit(&self, value: Uint256) -> Box<Future<Item = (), Error = Error>> {
-        let contract_address = self.contract_address.clone();
-        let payload = encode_call("quickDeposit()", &[]);
-        let call = self
-            .send_raw_transaction(contract_address, payload, value)
-            .map(|_| ());
-        Box::new(call)
+    async fn quick_deposit(&self, value: Uint256) -> Result<(), BlockchainError> {
+        let payload = QuickDeposit.encode()?;
+        self.send_raw_transaction(self.contract_address, payload, value)
+            .await?;
+        Ok(())
     }
 
-    fn get_current_block(&self) -> Box<Future<Item = Uint256, Error = Error>> {
-        self.web3.eth_block_number()
+    async fn get_current_block(&self) -> Result<Uint256, BlockchainError> {
+        self.rpc("eth_blockNumber", self.web3.eth_block_number())
+            .await
     }
 }
@@ -1,18 +1,59 @@
-#![cfg_attr(rustfmt, rustfmt_skip)]
-#![allow(dead_code)]
+//! The settings of the integration tests, which `scripts/local-test-setup.sh` writes to
+//! `config.toml`. They are read when the tests run, so the keys are never built into a binary.
 
-use std::borrow::Cow;
+use clarity::Address;
+use guac_core::InMemorySigner;
+use serde_derive::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use zeroize::{Zeroize, Zeroizing};
 
-#[derive(Debug, Clone)]
-#[allow(non_camel_case_types)]
+/// Read unless `GUAC_CONFIG` names another file
+const CONFIG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/config.toml");
+
+#[derive(Deserialize)]
 pub struct Config {
-    pub contract_address: Cow<'static, str>,
-    pub private_key_0: Cow<'static, str>,
-    pub private_key_1: Cow<'static, str>,
+    contract_address: String,
+    private_key_0: String,
+    private_key_1: String,
 }
 
-pub const CONFIG: Config = Config {
-    contract_address: Cow::Borrowed("0xb1ebaddf5710d42e5c575aec68396cd1a4b04ce4"),
-    private_key_0: Cow::Borrowed("86de2cf259bf21a9aa2b8cf78f89ed479681001ca320c5762bb3237db65445cb"),
-    private_key_1: Cow::Borrowed("06e744bba37fd1e630dc775d10fd8cbe0b5643f4d7187072d3d08df4b4118acf"),
-};
+impl Config {
+    pub fn load() -> Config {
+        let path = env::var("GUAC_CONFIG").unwrap_or_else(|_| CONFIG_PATH.to_string());
+        let toml = Zeroizing::new(
+            fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e)),
+        );
+        toml::from_str(&toml).unwrap_or_else(|e| panic!("Invalid config {}: {}", path, e))
+    }
+
+    pub fn contract_address(&self) -> Address {
+        self.contract_address.parse().unwrap()
+    }
+
+    pub fn signer_0(&self) -> InMemorySigner {
+        InMemorySigner::from_hex(&self.private_key_0).unwrap()
+    }
+
+    pub fn signer_1(&self) -> InMemorySigner {
+        InMemorySigner::from_hex(&self.private_key_1).unwrap()
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("contract_address", &self.contract_address)
+            .field("private_key_0", &"<redacted>")
+            .field("private_key_1", &"<redacted>")
+            .finish()
+    }
+}
+
+impl Drop for Config {
+    fn drop(&mut self) {
+        self.private_key_0.zeroize();
+        self.private_key_1.zeroize();
+    }
+}
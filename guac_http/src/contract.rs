@@ -0,0 +1,542 @@
+//! Typed bindings for the guac payment channel contract.
+//!
+//! Every contract function called by the light client has a struct here which knows its own
+//! signature and how to ABI encode its arguments, and every event has a struct which can be
+//! decoded from a raw `Log`. Encoding validates that signed transactions actually carry both
+//! signatures, and decoding validates topics and data lengths instead of trusting the node.
+
+use clarity::abi::{derive_signature, encode_call, Token};
+use clarity::utils::hex_str_to_bytes;
+use clarity::{Address, Signature};
+use guac_core::types::{ChannelState, NewChannelTx, ReDrawTx};
+use guac_core::BlockchainError;
+use num256::Uint256;
+use web3::types::Log;
+
+/// Size of a single ABI encoded word
+const WORD: usize = 32;
+
+/// Returns an `InvalidData` error unless the condition holds
+macro_rules! ensure_data {
+    ($expression:expr, $($message:tt)+) => {
+        if !($expression) {
+            return Err(BlockchainError::InvalidData(format!($($message)+)));
+        }
+    };
+}
+
+/// A state changing (or view) function of the contract.
+pub trait ContractCall {
+    /// Canonical signature used to derive the method id, e.g. `quickDeposit()`
+    const SIGNATURE: &'static str;
+
+    /// Arguments of the call in the order expected by `SIGNATURE`
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError>;
+
+    /// ABI encoded payload ready to be sent as transaction data
+    fn encode(&self) -> Result<Vec<u8>, BlockchainError> {
+        Ok(encode_call(Self::SIGNATURE, &self.tokens()?))
+    }
+}
+
+/// A read-only function of the contract which returns data.
+pub trait ContractView: ContractCall {
+    type Output;
+
+    /// Decodes the raw bytes returned by `eth_call`
+    fn decode_output(data: &[u8]) -> Result<Self::Output, BlockchainError>;
+}
+
+/// An event emitted by the contract.
+pub trait ContractEvent: Sized {
+    /// Canonical signature of the event, e.g. `ChannelReDrawn(bytes32)`
+    const SIGNATURE: &'static str;
+
+    /// The first topic of every log of this event
+    fn topic() -> [u8; 32] {
+        let mut topic: [u8; 32] = Default::default();
+        topic.copy_from_slice(&derive_signature(Self::SIGNATURE));
+        topic
+    }
+
+    /// Decodes and validates a log of this event
+    fn decode(log: &Log) -> Result<Self, BlockchainError>;
+}
+
+/// Left pads an address to a full word, which is how indexed addresses appear in topics.
+pub fn address_to_word(address: &Address) -> [u8; 32] {
+    let mut data: [u8; 32] = Default::default();
+    data[12..].copy_from_slice(address.as_bytes());
+    data
+}
+
+fn word_to_address(word: &[u8]) -> Result<Address, BlockchainError> {
+    ensure_data!(word.len() == WORD, "Invalid word length {}", word.len());
+    ensure_data!(
+        word[..12].iter().all(|b| *b == 0),
+        "Address word has non-zero padding"
+    );
+    let mut data: [u8; 20] = Default::default();
+    data.copy_from_slice(&word[12..]);
+    Ok(data.into())
+}
+
+fn word_to_bool(word: &[u8]) -> Result<bool, BlockchainError> {
+    ensure_data!(word.len() == WORD, "Invalid word length {}", word.len());
+    ensure_data!(
+        word[..WORD - 1].iter().all(|b| *b == 0),
+        "Bool word has non-zero padding"
+    );
+    match word[WORD - 1] {
+        0 => Ok(false),
+        1 => Ok(true),
+        v => Err(BlockchainError::InvalidData(format!(
+            "Invalid bool value {}",
+            v
+        ))),
+    }
+}
+
+fn word_to_bytes32(word: &[u8]) -> Result<[u8; 32], BlockchainError> {
+    ensure_data!(word.len() == WORD, "Invalid word length {}", word.len());
+    let mut data: [u8; 32] = Default::default();
+    data.copy_from_slice(word);
+    Ok(data)
+}
+
+fn words(data: &[u8], count: usize) -> Result<Vec<&[u8]>, BlockchainError> {
+    ensure_data!(
+        data.len() == count * WORD,
+        "Expected {} bytes of data but got {}",
+        count * WORD,
+        data.len()
+    );
+    Ok(data.chunks(WORD).collect())
+}
+
+fn topics(log: &Log, expected: [u8; 32], count: usize) -> Result<Vec<[u8; 32]>, BlockchainError> {
+    ensure_data!(
+        log.topics.len() == count,
+        "Expected {} topics but got {}",
+        count,
+        log.topics.len()
+    );
+    let topics = log
+        .topics
+        .iter()
+        .map(|topic| {
+            let bytes =
+                hex_str_to_bytes(topic).map_err(|e| BlockchainError::InvalidData(e.to_string()))?;
+            word_to_bytes32(&bytes)
+        })
+        .collect::<Result<Vec<[u8; 32]>, BlockchainError>>()?;
+    ensure_data!(topics[0] == expected, "Log is not for the expected event");
+    Ok(topics)
+}
+
+fn signature_token(
+    signature: &Option<Signature>,
+    name: &'static str,
+) -> Result<Token, BlockchainError> {
+    match signature {
+        Some(signature) => Ok(signature.to_bytes().to_vec().into()),
+        None => Err(BlockchainError::MissingSignature(name)),
+    }
+}
+
+/// `quickDeposit()`
+pub struct QuickDeposit;
+
+impl ContractCall for QuickDeposit {
+    const SIGNATURE: &'static str = "quickDeposit()";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        Ok(vec![])
+    }
+}
+
+/// `depositThenNewChannel(...)`, opens a fully signed channel
+pub struct DepositThenNewChannel<'a> {
+    pub new_channel_tx: &'a NewChannelTx,
+}
+
+impl<'a> ContractCall for DepositThenNewChannel<'a> {
+    const SIGNATURE: &'static str =
+        "depositThenNewChannel(address,address,uint256,uint256,uint256,uint256,bytes,bytes)";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        let tx = self.new_channel_tx;
+        Ok(vec![
+            tx.address_0.into(),
+            tx.address_1.into(),
+            tx.balance_0.clone().into(),
+            tx.balance_1.clone().into(),
+            tx.expiration.clone().into(),
+            tx.settling_period_length.clone().into(),
+            signature_token(&tx.signature_0, "signature_0")?,
+            signature_token(&tx.signature_1, "signature_1")?,
+        ])
+    }
+}
+
+fn re_draw_tokens(tx: &ReDrawTx) -> Result<Vec<Token>, BlockchainError> {
+    Ok(vec![
+        Token::Bytes(tx.channel_id.to_vec()),
+        tx.sequence_number.clone().into(),
+        tx.old_balance_0.clone().into(),
+        tx.old_balance_1.clone().into(),
+        tx.new_balance_0.clone().into(),
+        tx.new_balance_1.clone().into(),
+        tx.expiration.clone().into(),
+        signature_token(&tx.signature_0, "signature_0")?,
+        signature_token(&tx.signature_1, "signature_1")?,
+    ])
+}
+
+/// `depositThenRedraw(...)`, tops up our side of a channel
+pub struct DepositThenReDraw<'a> {
+    pub re_draw_tx: &'a ReDrawTx,
+}
+
+impl<'a> ContractCall for DepositThenReDraw<'a> {
+    const SIGNATURE: &'static str =
+        "depositThenRedraw(bytes32,uint256,uint256,uint256,uint256,uint256,uint256,bytes,bytes)";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        re_draw_tokens(self.re_draw_tx)
+    }
+}
+
+/// `redrawThenWithdraw(...)`, takes money out of our side of a channel
+pub struct ReDrawThenWithdraw<'a> {
+    pub amount: Uint256,
+    pub re_draw_tx: &'a ReDrawTx,
+}
+
+impl<'a> ContractCall for ReDrawThenWithdraw<'a> {
+    const SIGNATURE: &'static str =
+        "redrawThenWithdraw(uint256,bytes32,uint256,uint256,uint256,uint256,uint256,uint256,bytes,bytes)";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        let mut tokens = vec![self.amount.clone().into()];
+        tokens.extend(re_draw_tokens(self.re_draw_tx)?);
+        Ok(tokens)
+    }
+}
+
+/// `balanceOf(address)`, the balance of an account held by the contract
+pub struct BalanceOf {
+    pub address: Address,
+}
+
+impl ContractCall for BalanceOf {
+    const SIGNATURE: &'static str = "balanceOf(address)";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        Ok(vec![self.address.into()])
+    }
+}
+
+impl ContractView for BalanceOf {
+    type Output = Uint256;
+
+    fn decode_output(data: &[u8]) -> Result<Uint256, BlockchainError> {
+        let words = words(data, 1)?;
+        Ok(Uint256::from_bytes_be(words[0]))
+    }
+}
+
+/// `channels(bytes32)`, the public getter of the contract's channel mapping
+pub struct Channels {
+    pub channel_id: [u8; 32],
+}
+
+impl ContractCall for Channels {
+    const SIGNATURE: &'static str = "channels(bytes32)";
+
+    fn tokens(&self) -> Result<Vec<Token>, BlockchainError> {
+        Ok(vec![Token::Bytes(self.channel_id.to_vec())])
+    }
+}
+
+impl ContractView for Channels {
+    type Output = ChannelState;
+
+    fn decode_output(data: &[u8]) -> Result<ChannelState, BlockchainError> {
+        let words = words(data, 9)?;
+        Ok(ChannelState {
+            address_0: word_to_address(words[0])?,
+            address_1: word_to_address(words[1])?,
+            total_balance: Uint256::from_bytes_be(words[2]),
+            balance_0: Uint256::from_bytes_be(words[3]),
+            balance_1: Uint256::from_bytes_be(words[4]),
+            sequence_number: Uint256::from_bytes_be(words[5]),
+            settling_period_length: Uint256::from_bytes_be(words[6]),
+            settling_period_started: word_to_bool(words[7])?,
+            settling_period_end: Uint256::from_bytes_be(words[8]),
+        })
+    }
+}
+
+/// `ChannelOpened(address indexed, address indexed, bytes32)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelOpened {
+    pub address_0: Address,
+    pub address_1: Address,
+    pub channel_id: [u8; 32],
+}
+
+impl ContractEvent for ChannelOpened {
+    const SIGNATURE: &'static str = "ChannelOpened(address,address,bytes32)";
+
+    fn decode(log: &Log) -> Result<ChannelOpened, BlockchainError> {
+        let topics = topics(log, Self::topic(), 3)?;
+        ensure_data!(
+            log.data.len() == WORD,
+            "Invalid data length in ChannelOpened event"
+        );
+        Ok(ChannelOpened {
+            address_0: word_to_address(&topics[1])?,
+            address_1: word_to_address(&topics[2])?,
+            channel_id: word_to_bytes32(&log.data)?,
+        })
+    }
+}
+
+/// `ChannelReDrawn(bytes32 indexed)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelReDrawn {
+    pub channel_id: [u8; 32],
+}
+
+impl ContractEvent for ChannelReDrawn {
+    const SIGNATURE: &'static str = "ChannelReDrawn(bytes32)";
+
+    fn decode(log: &Log) -> Result<ChannelReDrawn, BlockchainError> {
+        let topics = topics(log, Self::topic(), 2)?;
+        Ok(ChannelReDrawn {
+            channel_id: topics[1],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::utils::bytes_to_hex_str;
+
+    fn address(last_byte: u8) -> Address {
+        let mut data: [u8; 20] = Default::default();
+        data[19] = last_byte;
+        data.into()
+    }
+
+    fn word(value: u64) -> String {
+        format!("{:064x}", value)
+    }
+
+    fn signature() -> Signature {
+        Signature::new(27u64.into(), 1u64.into(), 2u64.into())
+    }
+
+    fn re_draw_tx() -> ReDrawTx {
+        ReDrawTx {
+            channel_id: [0xab; 32],
+            sequence_number: 1u64.into(),
+            old_balance_0: 2u64.into(),
+            old_balance_1: 3u64.into(),
+            new_balance_0: 4u64.into(),
+            new_balance_1: 5u64.into(),
+            expiration: 6u64.into(),
+            signature_0: Some(signature()),
+            signature_1: Some(signature()),
+        }
+    }
+
+    #[test]
+    fn test_event_topics() {
+        assert_eq!(
+            bytes_to_hex_str(&ChannelOpened::topic()),
+            "a79f57c989b24a51391abba00096b6d17aac193697cbc283ee2ec6570abd3111"
+        );
+        assert_eq!(
+            bytes_to_hex_str(&ChannelReDrawn::topic()),
+            "82e612a2000aabe1ff6754dc8841369c15694ada2a1fb33196a48e61fcfafab3"
+        );
+    }
+
+    #[test]
+    fn test_encode_balance_of() {
+        let payload = BalanceOf {
+            address: address(0x42),
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(
+            bytes_to_hex_str(&payload),
+            format!("70a08231{}", word(0x42))
+        );
+    }
+
+    #[test]
+    fn test_encode_quick_deposit() {
+        assert_eq!(
+            bytes_to_hex_str(&QuickDeposit.encode().unwrap()),
+            "4c0f0e12"
+        );
+    }
+
+    #[test]
+    fn test_encode_channels() {
+        let payload = Channels {
+            channel_id: [0xab; 32],
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(
+            bytes_to_hex_str(&payload),
+            format!("7a7ebd7b{}", "ab".repeat(32))
+        );
+    }
+
+    #[test]
+    fn test_encode_deposit_then_re_draw() {
+        let tx = re_draw_tx();
+        let payload = DepositThenReDraw { re_draw_tx: &tx }.encode().unwrap();
+
+        // Method id, 9 head words, then two dynamic 65 byte signatures padded to 96 bytes
+        assert_eq!(payload.len(), 4 + 9 * 32 + 2 * (32 + 96));
+
+        let hex = bytes_to_hex_str(&payload);
+        let expected_head = format!(
+            "79668526{}{}{}{}{}{}{}{}{}",
+            "ab".repeat(32),
+            word(1),
+            word(2),
+            word(3),
+            word(4),
+            word(5),
+            word(6),
+            // Offsets of the two signatures relative to the start of the arguments
+            word(9 * 32),
+            word(9 * 32 + 32 + 96),
+        );
+        assert_eq!(&hex[..expected_head.len()], &expected_head[..]);
+        // Length prefix of the first signature
+        assert_eq!(
+            &hex[expected_head.len()..expected_head.len() + 64],
+            &word(65)[..]
+        );
+    }
+
+    #[test]
+    fn test_encode_missing_signature() {
+        let tx = ReDrawTx {
+            signature_1: None,
+            ..re_draw_tx()
+        };
+        let err = ReDrawThenWithdraw {
+            amount: 1u64.into(),
+            re_draw_tx: &tx,
+        }
+        .encode()
+        .unwrap_err();
+        assert_eq!(err.to_string(), "No signature_1 supplied");
+    }
+
+    #[test]
+    fn test_decode_channel_opened() {
+        let log: Log = serde_json::from_str(r#"{
+            "logIndex":"0x0",
+            "transactionIndex":"0x0",
+            "transactionHash":"0xd6785de92c3d55e22a50ef6a37553b1abd4fc710d3662e38369656d4e747662b",
+            "blockHash":"0x5d1c0bf2d5d32754f3f9501c9d299beb12447ea2a024e0cb67628979eb6dbf36",
+            "blockNumber":"0x53","address":"0xc153bde3ab8a9721b6252dcd1ffa2cb0aa165c1a",
+            "data":"0xfd13bb0c43a8e298ee038c1c64d7a93e9653dcab2ff741005d6613ba28f31bd4",
+            "topics":["0xa79f57c989b24a51391abba00096b6d17aac193697cbc283ee2ec6570abd3111","0x000000000000000000000000b3b2b9fbf1e8cc9713dbde822eba95fbc4a9f698","0x000000000000000000000000e817f611a758ca765b09b60e2dbcceedaaa5e90c"],
+            "type":"mined"}"#).unwrap();
+
+        let event = ChannelOpened::decode(&log).unwrap();
+        assert_eq!(
+            event.address_0,
+            "0xb3b2b9fbf1e8cc9713dbde822eba95fbc4a9f698"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(
+            event.address_1,
+            "0xe817f611a758ca765b09b60e2dbcceedaaa5e90c"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(
+            bytes_to_hex_str(&event.channel_id),
+            "fd13bb0c43a8e298ee038c1c64d7a93e9653dcab2ff741005d6613ba28f31bd4"
+        );
+
+        // The same log can not be mistaken for another event
+        assert!(ChannelReDrawn::decode(&log).is_err());
+
+        let truncated = Log {
+            data: vec![0; 31],
+            ..log
+        };
+        assert!(ChannelOpened::decode(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_decode_channels() {
+        let data = format!(
+            "{}{}{}{}{}{}{}{}{}",
+            word(0x01),
+            word(0x02),
+            word(30),
+            word(10),
+            word(20),
+            word(7),
+            word(5000),
+            word(1),
+            word(123),
+        );
+        let state = Channels::decode_output(&hex_str_to_bytes(&data).unwrap()).unwrap();
+        assert_eq!(
+            state,
+            ChannelState {
+                address_0: address(0x01),
+                address_1: address(0x02),
+                total_balance: 30u64.into(),
+                balance_0: 10u64.into(),
+                balance_1: 20u64.into(),
+                sequence_number: 7u64.into(),
+                settling_period_length: 5000u64.into(),
+                settling_period_started: true,
+                settling_period_end: 123u64.into(),
+            }
+        );
+
+        // Invalid bool
+        let data = format!(
+            "{}{}{}{}{}{}{}{}{}",
+            word(0x01),
+            word(0x02),
+            word(30),
+            word(10),
+            word(20),
+            word(7),
+            word(5000),
+            word(2),
+            word(123),
+        );
+        assert!(Channels::decode_output(&hex_str_to_bytes(&data).unwrap()).is_err());
+
+        // Too short
+        assert!(Channels::decode_output(&[0u8; 32 * 8]).is_err());
+    }
+
+    #[test]
+    fn test_decode_balance_of() {
+        let data = hex_str_to_bytes(&word(1000)).unwrap();
+        assert_eq!(BalanceOf::decode_output(&data).unwrap(), 1000u64.into());
+        assert!(BalanceOf::decode_output(&[]).is_err());
+    }
+}
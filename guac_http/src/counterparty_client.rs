@@ -1,186 +1,164 @@
-use actix_web::client;
-use actix_web::client::ClientResponse;
-use actix_web::client::Connection;
-use actix_web::HttpMessage;
+use async_trait::async_trait;
 use clarity::{Address, Signature};
-use failure::Error;
-use futures::{future, Future};
-use guac_core::types::{NewChannelTx, ReDrawTx, UpdateTx};
-use guac_core::CounterpartyApi;
-use num256::Uint256;
+use guac_core::routing::{Forward, NodeInfo};
+use guac_core::types::{NewChannelTx, ReDrawTx, UpdateResponse, UpdateTx};
+use guac_core::{CounterpartyApi, GuacError, Metrics, ProtocolError, Role, TransportError};
+use serde::Serialize;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::Instant;
 
-macro_rules! try_future_box {
-    ($expression:expr) => {
-        match $expression {
-            Err(err) => {
-                return Box::new(future::err(err.into())) as Box<Future<Item = _, Error = Error>>;
-            }
-            Ok(value) => value,
-        }
-    };
+pub struct CounterpartyClient {
+    /// Where the duration and the outcome of each request is recorded, the same as
+    /// `Guac::metrics`
+    #[allow(clippy::redundant_allocation)]
+    pub metrics: Arc<Box<Metrics>>,
 }
 
-pub struct CounterpartyClient;
-
-/// Verifies if the response from server is correct by checking status code.Client
+/// Posts `body` as JSON to `path` on the counterparty at `to_url` and returns the body of the
+/// response.
 ///
 /// Implementation of this is very simplified and all responses are expected to have HTTP 200 OK
 /// response.
-fn verify_client_error(
-    response: ClientResponse,
-) -> Box<Future<Item = ClientResponse, Error = Error>> {
+async fn post<T: Serialize>(
+    to_url: String,
+    path: &str,
+    body: &T,
+) -> Result<Vec<u8>, TransportError> {
+    let to_url: SocketAddr = to_url
+        .parse()
+        .map_err(|_| TransportError::InvalidUrl(to_url.clone()))?;
+    // Prepare an endpoint for sending a proposal
+    let endpoint = format!("http://[{}]:{}{}", to_url.ip(), to_url.port(), path);
+
+    let mut response = awc::Client::default()
+        .post(&endpoint)
+        .send_json(body)
+        .await
+        .map_err(|e| TransportError::Request(e.to_string()))?;
+    let bod = response
+        .body()
+        .await
+        .map_err(|e| TransportError::Request(e.to_string()))?;
     if response.status() != 200 {
-        return Box::new(
-            response.body().from_err().and_then(move |bod| {
-                Err(format_err!("HTTP error {}: {:?}", response.status(), bod))
-            }),
-        );
+        return Err(TransportError::Status {
+            status: response.status().as_u16(),
+            body: String::from_utf8_lossy(&bod).into_owned(),
+        });
     }
-    Box::new(future::ok(response))
+    Ok(bod.to_vec())
 }
 
+impl CounterpartyClient {
+    /// Like `post`, and records how long the request took and whether it failed
+    async fn post<T: Serialize>(
+        &self,
+        to_url: String,
+        path: &str,
+        body: &T,
+    ) -> Result<Vec<u8>, TransportError> {
+        let start = Instant::now();
+        let res = post(to_url, path, body).await;
+        self.metrics
+            .observe_request(Role::Client, path, start.elapsed(), res.is_ok());
+        res
+    }
+}
+
+#[async_trait(?Send)]
 impl CounterpartyApi for CounterpartyClient {
-    fn propose_channel(
+    async fn propose_channel(
         &self,
         from_address: Address,
         to_url: String,
         new_channel_tx: NewChannelTx,
-    ) -> Box<Future<Item = Signature, Error = Error>> {
-        let to_url: Result<SocketAddr, std::net::AddrParseError> = to_url.parse();
-        let to_url: SocketAddr = try_future_box!(to_url);
-        // Prepare an endpoint for sending a proposal
-        let endpoint = format!("http://[{}]:{}/propose_channel", to_url.ip(), to_url.port());
-        // Connect to remote server
-        let stream = TcpStream::connect(&to_url);
-
-        Box::new(stream.from_err().and_then(move |stream| {
-            client::post(&endpoint)
-                .with_connection(Connection::from_stream(stream))
-                .json((from_address, new_channel_tx))
-                .expect("json parsing error")
-                .send()
-                .from_err()
-                .and_then(verify_client_error)
-                .and_then(move |response| {
-                    response
-                        .json()
-                        .from_err()
-                        .and_then(move |res: Signature| Ok(res))
-                })
-        })) as Box<Future<Item = Signature, Error = Error>>
+    ) -> Result<Signature, GuacError> {
+        let res = self
+            .post(to_url, "/propose_channel", &(from_address, new_channel_tx))
+            .await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
     }
 
-    fn propose_re_draw(
+    async fn propose_re_draw(
         &self,
         from_address: Address,
         to_url: String,
         re_draw_tx: ReDrawTx,
-    ) -> Box<Future<Item = Signature, Error = Error>> {
-        let to_url: Result<SocketAddr, std::net::AddrParseError> = to_url.parse();
-        let to_url: SocketAddr = try_future_box!(to_url);
-        // Prepare an endpoint for sending a proposal
-        let endpoint = format!("http://[{}]:{}/propose_re_draw", to_url.ip(), to_url.port());
-        // Connect to remote server
-        let stream = TcpStream::connect(&to_url);
+    ) -> Result<Signature, GuacError> {
+        let res = self
+            .post(to_url, "/propose_re_draw", &(from_address, re_draw_tx))
+            .await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
+    }
 
-        Box::new(stream.from_err().and_then(move |stream| {
-            client::post(&endpoint)
-                .with_connection(Connection::from_stream(stream))
-                .json((from_address, re_draw_tx))
-                .expect("json parsing error")
-                .send()
-                .from_err()
-                .and_then(verify_client_error)
-                .and_then(move |response| {
-                    response
-                        .json()
-                        .from_err()
-                        .and_then(move |res: Signature| Ok(res))
-                })
-        })) as Box<Future<Item = Signature, Error = Error>>
+    async fn notify_channel_opened(
+        &self,
+        from_address: Address,
+        to_url: String,
+    ) -> Result<(), GuacError> {
+        self.post(to_url, "/notify_channel_opened", &from_address)
+            .await?;
+        Ok(())
     }
 
-    fn notify_channel_opened(
+    async fn notify_re_draw(
         &self,
         from_address: Address,
         to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let to_url: Result<SocketAddr, std::net::AddrParseError> = to_url.parse();
-        let to_url: SocketAddr = try_future_box!(to_url);
-        // Prepare an endpoint for sending a proposal
-        let endpoint = format!(
-            "http://[{}]:{}/notify_channel_opened",
-            to_url.ip(),
-            to_url.port()
-        );
-        // Connect to remote server
-        let stream = TcpStream::connect(&to_url);
+        update_tx: Option<UpdateTx>,
+    ) -> Result<Option<UpdateTx>, GuacError> {
+        let res = self
+            .post(to_url, "/notify_re_draw", &(from_address, update_tx))
+            .await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
+    }
 
-        Box::new(stream.from_err().and_then(move |stream| {
-            client::post(&endpoint)
-                .with_connection(Connection::from_stream(stream))
-                .json(from_address)
-                .expect("json parsing error")
-                .send()
-                .from_err()
-                .and_then(verify_client_error)
-                .and_then(|_| Ok(()))
-        })) as Box<Future<Item = (), Error = Error>>
+    async fn receive_payment(
+        &self,
+        from_address: Address,
+        to_url: String,
+        update_tx: UpdateTx,
+    ) -> Result<UpdateResponse, GuacError> {
+        let res = self
+            .post(to_url, "/receive_payment", &(from_address, update_tx))
+            .await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
     }
 
-    fn notify_re_draw(
+    async fn fulfill_hashlock(
         &self,
         from_address: Address,
         to_url: String,
-    ) -> Box<Future<Item = (), Error = Error>> {
-        let to_url: Result<SocketAddr, std::net::AddrParseError> = to_url.parse();
-        let to_url: SocketAddr = try_future_box!(to_url);
-        // Prepare an endpoint for sending a proposal
-        let endpoint = format!("http://[{}]:{}/notify_re_draw", to_url.ip(), to_url.port());
-        // Connect to remote server
-        let stream = TcpStream::connect(&to_url);
+        preimage: [u8; 32],
+    ) -> Result<UpdateTx, GuacError> {
+        let res = self
+            .post(to_url, "/fulfill_hashlock", &(from_address, preimage))
+            .await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
+    }
 
-        Box::new(stream.from_err().and_then(move |stream| {
-            client::post(&endpoint)
-                .with_connection(Connection::from_stream(stream))
-                .json(from_address)
-                .expect("json parsing error")
-                .send()
-                .from_err()
-                .and_then(verify_client_error)
-                .and_then(|_| Ok(()))
-        })) as Box<Future<Item = (), Error = Error>>
+    async fn get_info(&self, to_url: String) -> Result<Vec<NodeInfo>, GuacError> {
+        let res = self.post(to_url, "/info", &()).await?;
+        Ok(serde_json::from_slice(&res).map_err(TransportError::from)?)
     }
 
-    fn receive_payment(
+    async fn forward_payment(
         &self,
         from_address: Address,
         to_url: String,
-        update_tx: UpdateTx,
-    ) -> Box<Future<Item = Option<Uint256>, Error = Error>> {
-        let to_url: Result<SocketAddr, std::net::AddrParseError> = to_url.parse();
-        let to_url: SocketAddr = try_future_box!(to_url);
-        // Prepare an endpoint for sending a proposal
-        let endpoint = format!("http://[{}]:{}/receive_payment", to_url.ip(), to_url.port());
-        // Connect to remote server
-        let stream = TcpStream::connect(&to_url);
-
-        Box::new(stream.from_err().and_then(move |stream| {
-            client::post(&endpoint)
-                .with_connection(Connection::from_stream(stream))
-                .json((from_address, update_tx))
-                .expect("json parsing error")
-                .send()
-                .from_err()
-                .and_then(verify_client_error)
-                .and_then(move |response| {
-                    response
-                        .json()
-                        .from_err()
-                        .and_then(move |res: Option<Uint256>| Ok(res))
-                })
-        })) as Box<Future<Item = Option<Uint256>, Error = Error>>
+        forward: Forward,
+    ) -> Result<(), GuacError> {
+        match self
+            .post(to_url, "/forward_payment", &(from_address, forward))
+            .await
+        {
+            Ok(_) => Ok(()),
+            // The route failed further down, see `convert_error` of the server
+            Err(TransportError::Status { status: 502, body }) => {
+                let (hop, message) = serde_json::from_str(&body).map_err(TransportError::from)?;
+                Err(ProtocolError::RouteFailed { hop, message }.into())
+            }
+            Err(e) => Err(e.into()),
+        }
     }
 }
@@ -1,108 +1,157 @@
-use actix_web::http::Method;
-use actix_web::*;
+use actix_web::dev::Service;
+use actix_web::{web, App, HttpResponse, HttpServer};
 
 use clarity::Address;
-use failure::Error;
+use guac_core::routing::Forward;
 use guac_core::types::{NewChannelTx, ReDrawTx, UpdateTx};
 use guac_core::CounterpartyApi;
 use guac_core::Guac;
-use guac_core::GuacError;
+use guac_core::{GuacError, ProtocolError, Role, StorageError};
+use serde::Serialize;
+use std::time::Instant;
 
-use futures::future;
-use futures::Future;
+fn convert_error(err: GuacError) -> HttpResponse {
+    match err {
+        GuacError::Protocol(ProtocolError::Forbidden { message }) => {
+            HttpResponse::Forbidden().body(message)
+        }
+        GuacError::Protocol(ProtocolError::UpdateTooOld { correct_seq }) => {
+            HttpResponse::Conflict().json(correct_seq)
+        }
+        GuacError::Protocol(ProtocolError::RouteFailed { hop, message }) => {
+            HttpResponse::BadGateway().json((hop, message))
+        }
+        GuacError::Storage(err @ StorageError::PeerRejected(_)) => {
+            HttpResponse::Forbidden().body(err.to_string())
+        }
+        GuacError::Storage(StorageError::TooManyPendingPeers(_)) => {
+            HttpResponse::ServiceUnavailable().finish()
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
 
-fn convert_error(err: Error) -> HttpResponse {
-    match err.downcast::<GuacError>() {
-        Ok(guac_err) => match guac_err {
-            GuacError::Forbidden { message } => HttpResponse::Forbidden().body(message),
-            GuacError::UpdateTooOld { correct_seq } => HttpResponse::Conflict().json(correct_seq),
-            _ => HttpResponse::InternalServerError().finish(),
-        },
-        Err(_) => HttpResponse::InternalServerError().finish(),
+fn respond<T: Serialize>(res: Result<T, GuacError>) -> HttpResponse {
+    match res {
+        Ok(res) => HttpResponse::Ok().json(res),
+        Err(err) => convert_error(err),
     }
 }
 
+async fn propose_channel(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, NewChannelTx)>,
+) -> HttpResponse {
+    let (from_address, new_channel_tx) = body.into_inner();
+    respond(
+        guac.propose_channel(from_address, String::default(), new_channel_tx)
+            .await,
+    )
+}
+
+async fn propose_re_draw(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, ReDrawTx)>,
+) -> HttpResponse {
+    let (from_address, re_draw_tx) = body.into_inner();
+    respond(
+        guac.propose_re_draw(from_address, String::default(), re_draw_tx)
+            .await,
+    )
+}
+
+async fn notify_channel_opened(guac: web::Data<Guac>, body: web::Json<Address>) -> HttpResponse {
+    respond(
+        guac.notify_channel_opened(body.into_inner(), String::default())
+            .await,
+    )
+}
+
+async fn notify_re_draw(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, Option<UpdateTx>)>,
+) -> HttpResponse {
+    let (from_address, update_tx) = body.into_inner();
+    respond(
+        guac.notify_re_draw(from_address, String::default(), update_tx)
+            .await,
+    )
+}
+
+async fn receive_payment(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, UpdateTx)>,
+) -> HttpResponse {
+    let (from_address, update_tx) = body.into_inner();
+    respond(
+        guac.receive_payment(from_address, String::default(), update_tx)
+            .await,
+    )
+}
+
+async fn fulfill_hashlock(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, [u8; 32])>,
+) -> HttpResponse {
+    let (from_address, preimage) = body.into_inner();
+    respond(
+        guac.fulfill_hashlock(from_address, String::default(), preimage)
+            .await,
+    )
+}
+
+async fn info(guac: web::Data<Guac>) -> HttpResponse {
+    respond(guac.get_info(String::default()).await)
+}
+
+async fn forward_payment(
+    guac: web::Data<Guac>,
+    body: web::Json<(Address, Forward)>,
+) -> HttpResponse {
+    let (from_address, forward) = body.into_inner();
+    respond(
+        guac.forward_payment(from_address, String::default(), forward)
+            .await,
+    )
+}
+
+/// Starts serving the counterparty API on `port`. Has to be called from within a running actix
+/// system, which the server is spawned on. The duration and status of every request is recorded
+/// in the metrics of `guac`.
 pub fn init_server(port: u16, guac: Guac) {
-    server::new(move || {
-        App::with_state(guac.clone())
-            .resource("/propose_channel", |r| {
-                r.method(Method::POST).with_async(
-                    move |(req, body): (HttpRequest<Guac>, Json<(Address, NewChannelTx)>)| {
-                        let body = body.clone();
-                        let clos = |res| match res {
-                            Ok(res) => future::ok::<HttpResponse, failure::Error>(
-                                HttpResponse::Ok().json(res),
-                            ),
-                            Err(err) => future::ok(convert_error(err)),
-                        };
-                        req.state()
-                            .propose_channel(body.0, String::default(), body.1)
-                            .then(clos)
-                    },
-                )
-            })
-            .resource("/propose_re_draw", |r| {
-                r.method(Method::POST).with_async(
-                    move |(req, body): (HttpRequest<Guac>, Json<(Address, ReDrawTx)>)| {
-                        let body = body.clone();
-                        req.state()
-                            .propose_re_draw(body.0, String::default(), body.1)
-                            .then(|res| match res {
-                                Ok(res) => future::ok::<HttpResponse, failure::Error>(
-                                    HttpResponse::Ok().json(res),
-                                ),
-                                Err(err) => future::ok(convert_error(err)),
-                            })
-                    },
-                )
-            })
-            .resource("/notify_channel_opened", |r| {
-                r.method(Method::POST).with_async(
-                    move |(req, body): (HttpRequest<Guac>, Json<Address>)| {
-                        let body = body.clone();
-                        req.state()
-                            .notify_channel_opened(body, String::default())
-                            .then(|res| match res {
-                                Ok(res) => future::ok::<HttpResponse, failure::Error>(
-                                    HttpResponse::Ok().json(res),
-                                ),
-                                Err(err) => future::ok(convert_error(err)),
-                            })
-                    },
-                )
-            })
-            .resource("/notify_re_draw", |r| {
-                r.method(Method::POST).with_async(
-                    move |(req, body): (HttpRequest<Guac>, Json<Address>)| {
-                        let body = body.clone();
-                        req.state()
-                            .notify_re_draw(body, String::default())
-                            .then(|res| match res {
-                                Ok(res) => future::ok::<HttpResponse, failure::Error>(
-                                    HttpResponse::Ok().json(res),
-                                ),
-                                Err(err) => future::ok(convert_error(err)),
-                            })
-                    },
-                )
-            })
-            .resource("/receive_payment", |r| {
-                r.method(Method::POST).with_async(
-                    move |(req, body): (HttpRequest<Guac>, Json<(Address, UpdateTx)>)| {
-                        let body = body.clone();
-                        req.state()
-                            .receive_payment(body.0, String::default(), body.1)
-                            .then(|res| match res {
-                                Ok(res) => future::ok::<HttpResponse, failure::Error>(
-                                    HttpResponse::Ok().json(res),
-                                ),
-                                Err(err) => future::ok(convert_error(err)),
-                            })
-                    },
-                )
+    let server = HttpServer::new(move || {
+        let metrics = guac.metrics.clone();
+        App::new()
+            .app_data(web::Data::new(guac.clone()))
+            .wrap_fn(move |req, srv| {
+                let start = Instant::now();
+                let endpoint = req.match_pattern().unwrap_or_else(|| "unknown".to_string());
+                let metrics = metrics.clone();
+                let res = srv.call(req);
+                async move {
+                    let res = res.await;
+                    let ok = match &res {
+                        Ok(res) => res.status().is_success(),
+                        Err(_) => false,
+                    };
+                    metrics.observe_request(Role::Server, &endpoint, start.elapsed(), ok);
+                    res
+                }
             })
+            .route("/propose_channel", web::post().to(propose_channel))
+            .route("/propose_re_draw", web::post().to(propose_re_draw))
+            .route(
+                "/notify_channel_opened",
+                web::post().to(notify_channel_opened),
+            )
+            .route("/notify_re_draw", web::post().to(notify_re_draw))
+            .route("/receive_payment", web::post().to(receive_payment))
+            .route("/fulfill_hashlock", web::post().to(fulfill_hashlock))
+            .route("/info", web::post().to(info))
+            .route("/forward_payment", web::post().to(forward_payment))
     })
     .bind(&format!("[::0]:{}", port))
     .expect("init server failed")
-    .start();
+    .run();
+    actix_rt::spawn(server);
 }
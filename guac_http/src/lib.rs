@@ -1,81 +1,126 @@
-// extern crate actix;
 extern crate actix_web;
-extern crate althea_types;
-extern crate bytes;
 extern crate clarity;
-#[macro_use]
-extern crate failure;
 extern crate futures;
 extern crate guac_core;
 
 extern crate num256;
-extern crate qutex;
 extern crate serde;
 extern crate serde_json;
-extern crate tokio;
 extern crate web3;
 
 mod blockchain_client;
+#[cfg(test)]
 mod config;
+mod contract;
 mod counterparty_client;
 mod counterparty_server;
+mod logging;
+mod metrics_server;
 
 use crate::blockchain_client::BlockchainClient;
 use crate::counterparty_client::CounterpartyClient;
-use clarity::{Address, PrivateKey};
-use guac_core::{Crypto, Guac, Storage};
-use std::sync::Arc;
-
-#[macro_export]
-macro_rules! try_future_box {
-    ($expression:expr) => {
-        match $expression {
-            Err(err) => {
-                return Box::new(future::err(err.into())) as Box<Future<Item = _, Error = Error>>;
-            }
-            Ok(value) => value,
-        }
-    };
-}
-
+pub use crate::logging::init_logger;
+pub use crate::metrics_server::init_metrics_server;
+use clarity::Address;
+use guac_core::{
+    Billing, Crypto, DefaultProposalPolicy, Events, FingerprintScheme, Guac, Ledger, Metrics,
+    RefillManager, Router, Signer, Storage,
+};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often counterparties which never got past `New` are looked for and removed
+const PEER_GC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often channels with a threshold are checked, on top of after each payment we make
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the channels we paid since the last time are checked against their threshold
+const QUEUED_REBALANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sets up a Guac node and starts serving its counterparty API on `port`, where other nodes reach
+/// it at `url`. The node's address is the one of `signer`, which signs its messages and
+/// transactions. Messages are hashed with `fingerprints` before they are signed, which has to
+/// be the same on both sides of a channel. Has to be called from within a running actix system,
+/// which also periodically removes counterparties that contacted us but never opened a channel,
+/// and rebalances channels which have a threshold set on `Guac::refill`. Metrics are only served
+/// once `init_metrics_server` is called with the node.
 pub fn init_guac(
     port: u16,
+    url: String,
     contract_address: Address,
-    own_address: Address,
-    secret: PrivateKey,
-    full_node_url: String,
+    signer: Arc<dyn Signer + Send + Sync>,
+    fingerprints: FingerprintScheme,
+    full_node_urls: Vec<String>,
 ) -> Guac {
+    let metrics = Arc::new(Box::new(Metrics::new()));
     let guac = Guac {
         blockchain_client: Arc::new(Box::new(BlockchainClient::new(
             contract_address,
-            own_address,
-            secret,
-            &full_node_url,
+            signer.clone(),
+            &full_node_urls,
+            metrics.clone(),
         ))),
-        counterparty_client: Arc::new(Box::new(CounterpartyClient {})),
-        storage: Arc::new(Box::new(Storage::new())),
-        crypto: Arc::new(Box::new(Crypto {
-            contract_address,
-            own_address,
-            secret,
+        counterparty_client: Arc::new(Box::new(CounterpartyClient {
+            metrics: metrics.clone(),
         })),
+        storage: Arc::new(Box::new(Storage::new())),
+        crypto: Arc::new(Box::new(
+            Crypto::new(contract_address, signer).with_fingerprints(fingerprints),
+        )),
+        proposal_policy: Arc::new(Box::new(DefaultProposalPolicy)),
+        events: Arc::new(Box::new(Events::new())),
+        ledger: Arc::new(Box::new(Ledger::new())),
+        billing: Arc::new(Box::new(Billing::new())),
+        router: Arc::new(Box::new(Router::new(url))),
+        refill: Arc::new(Box::new(RefillManager::new())),
+        metrics,
+        settling: Arc::new(Mutex::new(HashSet::new())),
     };
 
     counterparty_server::init_server(port, guac.clone());
 
+    let storage = guac.storage.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(PEER_GC_INTERVAL);
+        loop {
+            interval.tick().await;
+            let removed = storage.remove_stale_peers();
+            if !removed.is_empty() {
+                log::info!("Removed {} stale counterparties", removed.len());
+            }
+        }
+    });
+
+    let rebalancing = guac.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(REBALANCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            rebalancing.rebalance_all().await;
+        }
+    });
+
+    let rebalancing = guac.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(QUEUED_REBALANCE_INTERVAL);
+        loop {
+            interval.tick().await;
+            rebalancing.rebalance_queued().await;
+        }
+    });
+
     guac
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::config::CONFIG;
-    use actix::System;
-    use failure::Error;
-    use futures::{future, Future};
+    use crate::config::Config;
+    use guac_core::{BlockchainError, GuacError};
     use num256::Uint256;
-    use std::cell::RefCell;
-    use std::rc::Rc;
+    use std::future::Future;
     use web3::client::Web3;
 
     fn eth_to_wei(eth: u64) -> Uint256 {
@@ -85,323 +130,179 @@ mod tests {
     }
 
     fn make_nodes() -> (Guac, Guac) {
-        let contract_addr: Address = CONFIG.contract_address.parse().unwrap();
-
-        let pk_1: PrivateKey = CONFIG.private_key_0.parse().unwrap();
-        let addr_1 = pk_1.to_public_key().unwrap();
-
-        let pk_2: PrivateKey = CONFIG.private_key_1.parse().unwrap();
-        let addr_2 = pk_2.to_public_key().unwrap();
+        init_logger("info");
+        let config = Config::load();
+        let contract_addr = config.contract_address();
 
         let guac_1 = init_guac(
             8881,
+            "[::1]:8881".to_string(),
             contract_addr,
-            addr_1,
-            pk_1,
-            "http://127.0.0.1:8545".to_string(),
+            Arc::new(config.signer_0()),
+            FingerprintScheme::Plain,
+            vec!["http://127.0.0.1:8545".to_string()],
         );
         let guac_2 = init_guac(
             8882,
+            "[::1]:8882".to_string(),
             contract_addr,
-            addr_2,
-            pk_2,
-            "http://127.0.0.1:8545".to_string(),
+            Arc::new(config.signer_1()),
+            FingerprintScheme::Plain,
+            vec!["http://127.0.0.1:8545".to_string()],
         );
 
         (guac_1, guac_2)
     }
 
-    #[test]
-    fn test_quick_deposit() {
-        let system = actix::System::new("test");
-
-        let (guac_1, guac_2) = make_nodes();
-
-        let _storage_1 = guac_1.storage.clone();
+    /// Runs `test` against a snapshot of the chain which is reverted afterwards, whatever the
+    /// outcome of the test
+    async fn with_snapshot<F: Future<Output = Result<(), GuacError>>>(test: F) {
         let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
-
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1, guac_2)
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
-
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
-
-        system.run();
+        let snapshot_id = web3.evm_snapshot().await.unwrap();
+        let res = test.await;
+        let _ = web3.evm_revert(snapshot_id).await;
+        res.unwrap();
     }
 
-    #[test]
-    fn test_fill_channel() {
-        let system = actix::System::new("test");
-
+    #[actix_rt::test]
+    async fn test_quick_deposit() {
         let (guac_1, guac_2) = make_nodes();
 
-        let _storage_1 = guac_1.storage.clone();
-        let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
+        with_snapshot(make_and_fill_channel(&guac_1, &guac_2)).await;
+    }
 
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1, guac_2)
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
+    #[actix_rt::test]
+    async fn test_fill_channel() {
+        let (guac_1, guac_2) = make_nodes();
 
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
+        with_snapshot(make_and_fill_channel(&guac_1, &guac_2)).await;
+    }
 
-        system.run();
+    async fn make_and_fill_channel(guac_1: &Guac, guac_2: &Guac) -> Result<(), GuacError> {
+        guac_1
+            .fill_channel(
+                guac_2.crypto.own_address,
+                "[::1]:8882".to_string(),
+                eth_to_wei(50),
+            )
+            .await?;
+        guac_2
+            .fill_channel(
+                guac_1.crypto.own_address,
+                "[::1]:8881".to_string(),
+                eth_to_wei(50),
+            )
+            .await
     }
 
-    fn make_and_fill_channel(guac_1: Guac, guac_2: Guac) -> Box<Future<Item = (), Error = Error>> {
-        Box::new(
+    #[actix_rt::test]
+    async fn test_make_payment_simple() {
+        let (guac_1, guac_2) = make_nodes();
+
+        with_snapshot(async {
+            make_and_fill_channel(&guac_1, &guac_2).await?;
             guac_1
-                .fill_channel(
+                .make_payment(
                     guac_2.crypto.own_address,
                     "[::1]:8882".to_string(),
-                    eth_to_wei(50),
+                    eth_to_wei(1),
                 )
-                .and_then(move |_| {
-                    guac_2.fill_channel(
-                        guac_1.crypto.own_address,
-                        "[::1]:8881".to_string(),
-                        eth_to_wei(50),
-                    )
-                }),
-        )
-    }
-
-    #[test]
-    fn test_make_payment_simple() {
-        let system = actix::System::new("test");
-
-        let (guac_1, guac_2) = make_nodes();
-
-        let _storage_1 = guac_1.storage.clone();
-        let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
-
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1.clone(), guac_2.clone()).and_then(move |_| {
-                        guac_1.make_payment(
-                            guac_2.crypto.own_address,
-                            "[::1]:8882".to_string(),
-                            eth_to_wei(1),
-                        )
-                    })
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
-
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
-
-        system.run();
+                .await
+        })
+        .await;
     }
 
-    #[test]
-    fn test_make_payment_packet_loss() {
-        let system = actix::System::new("test");
-
+    #[actix_rt::test]
+    async fn test_make_payment_packet_loss() {
         let (guac_1, guac_2) = make_nodes();
 
-        let _storage_1 = guac_1.storage.clone();
-        let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
-
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1.clone(), guac_2.clone()).and_then(move |_| {
-                        guac_2
-                            .make_payment(
-                                guac_1.crypto.own_address,
-                                // intentionally wrong address
-                                "[::1]:8883".to_string(),
-                                1u64.into(),
-                            )
-                            .then(move |_| {
-                                guac_1.make_payment(
-                                    guac_2.crypto.own_address,
-                                    "[::1]:8882".to_string(),
-                                    1u64.into(),
-                                )
-                            })
-                    })
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
-
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
-
-        system.run();
+        with_snapshot(async {
+            make_and_fill_channel(&guac_1, &guac_2).await?;
+            let _ = guac_2
+                .make_payment(
+                    guac_1.crypto.own_address,
+                    // intentionally wrong address
+                    "[::1]:8883".to_string(),
+                    1u64.into(),
+                )
+                .await;
+            guac_1
+                .make_payment(
+                    guac_2.crypto.own_address,
+                    "[::1]:8882".to_string(),
+                    1u64.into(),
+                )
+                .await
+        })
+        .await;
     }
 
-    #[test]
-    fn test_refill_channel() {
-        let system = actix::System::new("test");
-
+    #[actix_rt::test]
+    async fn test_refill_channel() {
         let (guac_1, guac_2) = make_nodes();
 
-        let _storage_1 = guac_1.storage.clone();
-        let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
-
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1.clone(), guac_2.clone()).and_then(move |_| {
-                        guac_1
-                            .make_payment(
-                                guac_2.crypto.own_address,
-                                "[::1]:8882".to_string(),
-                                1u64.into(),
-                            )
-                            .and_then(move |_| {
-                                guac_1.fill_channel(
-                                    guac_2.crypto.own_address,
-                                    "[::1]:8882".to_string(),
-                                    1u64.into(),
-                                )
-                            })
-                    })
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
-
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
-
-        system.run();
+        with_snapshot(async {
+            make_and_fill_channel(&guac_1, &guac_2).await?;
+            guac_1
+                .make_payment(
+                    guac_2.crypto.own_address,
+                    "[::1]:8882".to_string(),
+                    1u64.into(),
+                )
+                .await?;
+            guac_1
+                .fill_channel(
+                    guac_2.crypto.own_address,
+                    "[::1]:8882".to_string(),
+                    1u64.into(),
+                )
+                .await
+        })
+        .await;
     }
 
-    #[test]
-    fn test_withdraw_channel() {
-        let system = actix::System::new("test");
-
+    #[actix_rt::test]
+    async fn test_withdraw_channel() {
         let (guac_1, guac_2) = make_nodes();
-
-        let _storage_1 = guac_1.storage.clone();
         let web3 = Web3::new(&"http://127.0.0.1:8545".to_string());
-        let web4 = Web3::new(&"http://127.0.0.1:8545".to_string());
-
-        let snapshot_id: Rc<RefCell<Uint256>> = Rc::new(RefCell::new(0u64.into()));
-        let snapshot_id_2 = snapshot_id.clone();
-
-        actix::spawn(
-            web3.evm_snapshot()
-                .and_then(move |s| {
-                    *snapshot_id.borrow_mut() = s;
-                    make_and_fill_channel(guac_1.clone(), guac_2.clone()).and_then(move |_| {
-                        guac_1
-                            .make_payment(
-                                guac_2.crypto.own_address,
-                                "[::1]:8882".to_string(),
-                                eth_to_wei(10),
-                            )
-                            .and_then(move |_| {
-                                guac_1
-                                    .withdraw(
-                                        guac_2.crypto.own_address,
-                                        "[::1]:8882".to_string(),
-                                        eth_to_wei(40),
-                                    )
-                                    .and_then(move |_| {
-                                        web3.eth_get_balance(guac_1.crypto.own_address).and_then(
-                                            move |balance| {
-                                                println!("guac_1 balance: {:?}", balance);
-                                                // assert_eq!(balance, eth_to_wei(9));
-                                                guac_2
-                                                    .withdraw(
-                                                        guac_1.crypto.own_address,
-                                                        "[::1]:8881".to_string(),
-                                                        eth_to_wei(60),
-                                                    )
-                                                    .and_then(move |_| {
-                                                        web3.eth_get_balance(
-                                                            guac_2.crypto.own_address,
-                                                        )
-                                                        .and_then(move |balance| {
-                                                            println!(
-                                                                "guac_2 balance: {:?}",
-                                                                balance
-                                                            );
-                                                            // assert_eq!(balance, eth_to_wei(11));
-                                                            Ok(())
-                                                        })
-                                                    })
-                                            },
-                                        )
-                                    })
-                            })
-                    })
-                })
-                .then(move |res| {
-                    let snapshot_id_2 = snapshot_id_2.borrow().clone();
-                    web4.evm_revert(snapshot_id_2).then(|_| {
-                        res.unwrap();
-
-                        System::current().stop();
-                        Box::new(future::ok(()))
-                    })
-                }),
-        );
 
-        system.run();
+        with_snapshot(async {
+            make_and_fill_channel(&guac_1, &guac_2).await?;
+            guac_1
+                .make_payment(
+                    guac_2.crypto.own_address,
+                    "[::1]:8882".to_string(),
+                    eth_to_wei(10),
+                )
+                .await?;
+            guac_1
+                .withdraw(
+                    guac_2.crypto.own_address,
+                    "[::1]:8882".to_string(),
+                    eth_to_wei(40),
+                )
+                .await?;
+            let balance = web3
+                .eth_get_balance(guac_1.crypto.own_address)
+                .await
+                .map_err(BlockchainError::node)?;
+            log::info!("guac_1 balance: {}", balance);
+            // assert_eq!(balance, eth_to_wei(9));
+            guac_2
+                .withdraw(
+                    guac_1.crypto.own_address,
+                    "[::1]:8881".to_string(),
+                    eth_to_wei(60),
+                )
+                .await?;
+            let balance = web3
+                .eth_get_balance(guac_2.crypto.own_address)
+                .await
+                .map_err(BlockchainError::node)?;
+            log::info!("guac_2 balance: {}", balance);
+            // assert_eq!(balance, eth_to_wei(11));
+            Ok::<(), GuacError>(())
+        })
+        .await;
     }
-
 }
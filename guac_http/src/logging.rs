@@ -0,0 +1,27 @@
+//! Logging for applications which run a Guac node. Every line is a list of `key=value` pairs,
+//! which log collectors can parse without a pattern of their own.
+
+use std::env;
+use std::io::Write;
+
+/// Logs what passes `default_filters`, which are written like `RUST_LOG`, for instance
+/// `"info,guac_core::routing=debug"` for a level per module. Filters in `RUST_LOG` are applied
+/// on top of them. Does nothing if a logger is already set.
+pub fn init_logger(default_filters: &str) {
+    let mut builder = env_logger::Builder::new();
+    builder.parse(default_filters);
+    if let Ok(filters) = env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.format(|buf, record| {
+        writeln!(
+            buf,
+            "ts={} level={} target={} msg={:?}",
+            buf.timestamp(),
+            record.level(),
+            record.target(),
+            record.args().to_string()
+        )
+    });
+    let _ = builder.try_init();
+}
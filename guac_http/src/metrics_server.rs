@@ -0,0 +1,23 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use guac_core::Guac;
+
+async fn metrics(guac: web::Data<Guac>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(guac.render_metrics())
+}
+
+/// Starts serving the metrics of `guac` in the Prometheus text format on `/metrics`, at `port`
+/// of localhost only. Has to be called from within a running actix system, which the server is
+/// spawned on.
+pub fn init_metrics_server(port: u16, guac: Guac) {
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(guac.clone()))
+            .route("/metrics", web::get().to(metrics))
+    })
+    .bind(("127.0.0.1", port))
+    .expect("init metrics server failed")
+    .run();
+    actix_rt::spawn(server);
+}
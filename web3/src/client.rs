@@ -4,152 +4,619 @@
 //! work on big endian. We can do better than that just crafting our own
 //! JSONRPC requests.
 //!
-use crate::jsonrpc::client::{Client, HTTPClient};
-use crate::types::{Log, NewFilter, TransactionRequest, TransactionResponse};
+use crate::error::Web3Error;
+use crate::jsonrpc::client::{Client, ClientExt, Transport};
+use crate::types::Data;
+use crate::types::{BlockHeader, Log, NewFilter, TransactionRequest, TransactionResponse};
+use actix_rt::time::{interval, interval_at, Instant, Interval};
+use async_stream::stream;
 use clarity::utils::bytes_to_hex_str;
 use clarity::Address;
-use failure::Error;
-use futures::stream;
-use futures::IntoFuture;
-use futures::{Future, Stream};
-use futures_timer::Interval;
+use futures::future::{self, Future};
+use futures::stream::{self, LocalBoxStream, Stream, StreamExt, TryStreamExt};
 use num256::Uint256;
-use std::sync::Arc;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use types::Data;
+
+/// How long to wait before subscribing again after a failed attempt
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+/// How many attempts to subscribe may fail in a row before a stream gives up
+const MAX_SUBSCRIBE_ATTEMPTS: u32 = 5;
+/// How often logs are polled for on transports without subscriptions
+const LOG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type SubscriptionStream<T> = LocalBoxStream<'static, Result<T, Web3Error>>;
+
+/// Keeps track of how far a stream of logs got, so that logs mined while there was no
+/// subscription can be fetched with `eth_getLogs`, and logs seen twice are handed out once.
+struct LogCursor {
+    /// Block to fetch logs from before the first subscription, as given in the filter
+    from_block: Option<String>,
+    /// First block which may still contain logs that were not handed out
+    next_block: Option<Uint256>,
+    /// Block number and log index of the last log handed out
+    last_seen: Option<(Uint256, Uint256)>,
+}
+
+impl LogCursor {
+    fn new(from_block: Option<String>) -> LogCursor {
+        LogCursor {
+            from_block,
+            next_block: None,
+            last_seen: None,
+        }
+    }
+
+    /// Called once a subscription is in place and `head` is the latest block, returns the block
+    /// to fetch missed logs from. Logs mined after `head` are delivered by the subscription.
+    fn backfill_from(&mut self, head: &Uint256) -> Option<String> {
+        let after_head = head.clone() + Uint256::from(1u64);
+        match self.next_block.take() {
+            Some(next_block) => {
+                let from_block = if next_block <= *head {
+                    Some(format!("{:#x}", next_block))
+                } else {
+                    None
+                };
+                self.next_block = Some(if next_block > after_head {
+                    next_block
+                } else {
+                    after_head
+                });
+                from_block
+            }
+            None => {
+                self.next_block = Some(after_head);
+                self.from_block.take()
+            }
+        }
+    }
+
+    /// Whether `log` should be handed out, moves the cursor past it if so
+    fn advance(&mut self, log: &Log) -> bool {
+        if log.removed == Some(true) {
+            return true;
+        }
+        let position = match (&log.block_number, &log.log_index) {
+            (Some(block_number), Some(log_index)) => (block_number.clone(), log_index.clone()),
+            // Pending logs have no position yet
+            _ => return true,
+        };
+        if let Some(ref last_seen) = self.last_seen {
+            if position <= *last_seen {
+                return false;
+            }
+        }
+        let behind = match self.next_block {
+            Some(ref next_block) => position.0 > *next_block,
+            None => true,
+        };
+        if behind {
+            self.next_block = Some(position.0.clone());
+        }
+        self.last_seen = Some(position);
+        true
+    }
+}
+
+/// Turns a function which subscribes once into a stream which subscribes again whenever the
+/// subscription is lost. Gives up after `MAX_SUBSCRIBE_ATTEMPTS` failed attempts in a row.
+fn resubscribing<T, F, Fut>(mut subscribe: F) -> SubscriptionStream<T>
+where
+    T: 'static,
+    F: FnMut() -> Fut + 'static,
+    Fut: Future<Output = Result<SubscriptionStream<T>, Web3Error>> + 'static,
+{
+    Box::pin(stream! {
+        let mut failures = 0u32;
+        loop {
+            match subscribe().await {
+                Ok(mut notifications) => {
+                    failures = 0;
+                    while let Some(item) = notifications.next().await {
+                        yield item;
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    if failures >= MAX_SUBSCRIBE_ATTEMPTS {
+                        yield Err(e);
+                        break;
+                    }
+                    warn!("Unable to subscribe, trying again: {}", e);
+                    actix_rt::time::sleep(RESUBSCRIBE_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+/// Ticks every time `interval` does
+fn ticks(interval: Interval) -> impl Stream<Item = ()> {
+    stream::unfold(interval, |mut interval| async move {
+        interval.tick().await;
+        Some(((), interval))
+    })
+}
+
+fn decode<T: DeserializeOwned>(value: Result<Value, Web3Error>) -> Result<T, Web3Error> {
+    Ok(serde_json::from_value(value?)?)
+}
 
 /// An instance of Web3Client.
 #[derive(Clone)]
 pub struct Web3 {
-    jsonrpc_client: Arc<Box<HTTPClient>>,
+    jsonrpc_client: Arc<Box<dyn Client + Send + Sync>>,
 }
 
 impl Web3 {
-    pub fn new(url: &str) -> Self {
+    /// Creates a client for the given transport. A plain URL picks HTTP or WebSocket based on
+    /// its scheme.
+    pub fn new<T: Into<Transport>>(transport: T) -> Self {
         Self {
-            jsonrpc_client: Arc::new(Box::new(HTTPClient::new(url))),
+            jsonrpc_client: Arc::new(transport.into().into_client()),
         }
     }
 
-    pub fn eth_accounts(&self) -> Box<Future<Item = Vec<Address>, Error = Error>> {
+    pub async fn eth_accounts(&self) -> Result<Vec<Address>, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_accounts", Vec::<String>::new())
+            .await
     }
-    pub fn net_version(&self) -> Box<Future<Item = String, Error = Error>> {
+    pub async fn net_version(&self) -> Result<String, Web3Error> {
         self.jsonrpc_client
             .request_method("net_version", Vec::<String>::new())
+            .await
     }
-    pub fn eth_new_filter(
-        &self,
-        new_filter: NewFilter,
-    ) -> Box<Future<Item = Uint256, Error = Error>> {
+    pub async fn eth_new_filter(&self, new_filter: NewFilter) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_newFilter", vec![new_filter])
+            .await
     }
-    pub fn eth_uninstall_filter(&self, filter: Uint256) -> Box<Future<Item = bool, Error = Error>> {
-        self.jsonrpc_client.request_method(
-            "eth_uninstallFilter",
-            vec![format!("{:#x}", filter.clone())],
-        )
+    pub async fn eth_uninstall_filter(&self, filter: Uint256) -> Result<bool, Web3Error> {
+        self.jsonrpc_client
+            .request_method("eth_uninstallFilter", vec![format!("{:#x}", filter)])
+            .await
     }
-    pub fn eth_get_filter_changes(
-        &self,
-        filter: Uint256,
-    ) -> Box<Stream<Item = Log, Error = Error>> {
+    pub fn eth_get_filter_changes(&self, filter: Uint256) -> SubscriptionStream<Log> {
         let jsonrpc_client = self.jsonrpc_client.clone();
-        Box::new(
-            // Every 1 second
-            Interval::new(Duration::from_secs(1))
-                .map(move |()| {
-                    jsonrpc_client
-                        .clone()
-                        // Call eth_getFilterChanges every second
-                        .request_method(
-                            "eth_getFilterChanges",
-                            vec![format!("{:#x}", filter.clone())],
-                        )
-                        // Convert list of logs into a Future of Stream
-                        .map(move |logs: Vec<Log>| stream::iter_ok(logs.into_iter()))
-                        .into_future()
-                        // Flatten future of stream into a stream therefore extracting nested stream of logs
-                        .flatten_stream()
-                })
-                // Flatten stream of streams into a single stream
-                .flatten(),
-        )
-    }
-
-    pub fn eth_get_logs(
+        let period = Duration::from_secs(1);
+        // Every 1 second
+        ticks(interval_at(Instant::now() + period, period))
+            .then(move |()| {
+                let jsonrpc_client = jsonrpc_client.clone();
+                let filter = filter.clone();
+                async move {
+                    // Call eth_getFilterChanges every second
+                    let logs: Vec<Log> = jsonrpc_client
+                        .request_method("eth_getFilterChanges", vec![format!("{:#x}", filter)])
+                        .await?;
+                    Ok::<_, Web3Error>(stream::iter(logs.into_iter().map(Ok::<_, Web3Error>)))
+                }
+            })
+            // Flatten stream of streams into a single stream
+            .try_flatten()
+            .boxed_local()
+    }
+
+    /// Streams logs matching `filter` as they are mined, starting with the logs from
+    /// `filter.from_block` on if it is set.
+    ///
+    /// Transports which support it use `eth_subscribe`. Whenever the subscription is lost a new
+    /// one is made and the logs mined in the meantime are fetched with `eth_getLogs`, so none
+    /// are missed or repeated. Other transports poll `eth_getLogs`, which unlike
+    /// `eth_get_filter_changes` does not depend on a filter that the node may expire.
+    pub fn eth_subscribe_logs(&self, filter: NewFilter) -> SubscriptionStream<Log> {
+        let cursor = Arc::new(Mutex::new(LogCursor::new(filter.from_block.clone())));
+        if !self.jsonrpc_client.supports_subscriptions() {
+            return self.poll_logs(filter, cursor, LOG_POLL_INTERVAL);
+        }
+
+        let web3 = self.clone();
+        let seen = cursor.clone();
+        resubscribing(move || {
+            let web3 = web3.clone();
+            let filter = filter.clone();
+            let cursor = cursor.clone();
+            async move { web3.subscribe_logs_once(filter, cursor).await }
+        })
+        .try_filter(move |log| {
+            future::ready(seen.lock().expect("Log cursor poisoned").advance(log))
+        })
+        .boxed_local()
+    }
+
+    /// Subscribes to logs, then fetches the logs mined since the last subscription
+    async fn subscribe_logs_once(
+        &self,
+        filter: NewFilter,
+        cursor: Arc<Mutex<LogCursor>>,
+    ) -> Result<SubscriptionStream<Log>, Web3Error> {
+        // The subscription always starts at the head of the chain
+        let subscription_filter = NewFilter {
+            from_block: None,
+            to_block: None,
+            ..filter.clone()
+        };
+        let notifications = self
+            .jsonrpc_client
+            .subscribe(json!(["logs", subscription_filter]))
+            .await?;
+        let head = self.eth_block_number().await?;
+        let from_block = cursor
+            .lock()
+            .expect("Log cursor poisoned")
+            .backfill_from(&head);
+        Ok(self
+            .get_logs_between(filter, from_block, head)
+            .chain(notifications.map(decode))
+            .boxed_local())
+    }
+
+    /// Polls `eth_getLogs` for the logs mined since the last poll
+    fn poll_logs(
+        &self,
+        filter: NewFilter,
+        cursor: Arc<Mutex<LogCursor>>,
+        period: Duration,
+    ) -> SubscriptionStream<Log> {
+        let web3 = self.clone();
+        let seen = cursor.clone();
+        // Poll right away to find out where the chain is before anything gets mined
+        ticks(interval(period))
+            .then(move |()| {
+                let web3 = web3.clone();
+                let filter = filter.clone();
+                let cursor = cursor.clone();
+                async move {
+                    let head = web3.eth_block_number().await?;
+                    let from_block = cursor
+                        .lock()
+                        .expect("Log cursor poisoned")
+                        .backfill_from(&head);
+                    Ok::<_, Web3Error>(web3.get_logs_between(filter, from_block, head))
+                }
+            })
+            .try_flatten()
+            .try_filter(move |log| {
+                future::ready(seen.lock().expect("Log cursor poisoned").advance(log))
+            })
+            .boxed_local()
+    }
+
+    /// Fetches the logs from `from_block` up to and including `to_block`, or none if there is
+    /// no `from_block`
+    fn get_logs_between(
         &self,
-        new_filter: NewFilter,
-    ) -> Box<Future<Item = Vec<Log>, Error = Error>> {
+        filter: NewFilter,
+        from_block: Option<String>,
+        to_block: Uint256,
+    ) -> SubscriptionStream<Log> {
+        match from_block {
+            Some(from_block) => {
+                let web3 = self.clone();
+                let filter = NewFilter {
+                    from_block: Some(from_block),
+                    to_block: Some(format!("{:#x}", to_block)),
+                    ..filter
+                };
+                stream::once(async move { web3.eth_get_logs(filter).await })
+                    .map_ok(|logs| stream::iter(logs.into_iter().map(Ok::<_, Web3Error>)))
+                    .try_flatten()
+                    .boxed_local()
+            }
+            None => stream::empty().boxed_local(),
+        }
+    }
+
+    /// Streams the headers of new blocks, subscribing again if the subscription is lost.
+    /// Blocks mined while there was no subscription are skipped. Requires a transport which
+    /// supports subscriptions.
+    pub fn eth_subscribe_new_heads(&self) -> SubscriptionStream<BlockHeader> {
+        let jsonrpc_client = self.jsonrpc_client.clone();
+        resubscribing(move || {
+            let jsonrpc_client = jsonrpc_client.clone();
+            async move {
+                let notifications = jsonrpc_client.subscribe(json!(["newHeads"])).await?;
+                Ok(notifications.map(decode).boxed_local())
+            }
+        })
+    }
+
+    pub async fn eth_get_logs(&self, new_filter: NewFilter) -> Result<Vec<Log>, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_getLogs", vec![new_filter])
+            .await
     }
 
-    pub fn eth_get_transaction_count(
-        &self,
-        address: Address,
-    ) -> Box<Future<Item = Uint256, Error = Error>> {
-        self.jsonrpc_client.request_method(
-            "eth_getTransactionCount",
-            vec![address.to_string(), "latest".to_string()],
-        )
+    pub async fn eth_get_transaction_count(&self, address: Address) -> Result<Uint256, Web3Error> {
+        self.jsonrpc_client
+            .request_method(
+                "eth_getTransactionCount",
+                vec![address.to_string(), "latest".to_string()],
+            )
+            .await
     }
-    pub fn eth_gas_price(&self) -> Box<Future<Item = Uint256, Error = Error>> {
+    pub async fn eth_gas_price(&self) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_gasPrice", Vec::<String>::new())
+            .await
     }
-    pub fn eth_get_balance(&self, address: Address) -> Box<Future<Item = Uint256, Error = Error>> {
-        self.jsonrpc_client.request_method(
-            "eth_getBalance",
-            vec![address.to_string(), "latest".to_string()],
-        )
+    /// Fetches the gas price and the nonce for `address` in a single round trip
+    pub async fn eth_gas_price_and_transaction_count(
+        &self,
+        address: Address,
+    ) -> Result<(Uint256, Uint256), Web3Error> {
+        let mut results = self
+            .jsonrpc_client
+            .request_batch(vec![
+                ("eth_gasPrice", json!([])),
+                (
+                    "eth_getTransactionCount",
+                    json!([address.to_string(), "latest"]),
+                ),
+            ])
+            .await?;
+        let transaction_count = results.pop().expect("Batch response missing")?;
+        let gas_price = results.pop().expect("Batch response missing")?;
+        Ok((
+            serde_json::from_value(gas_price)?,
+            serde_json::from_value(transaction_count)?,
+        ))
     }
-    pub fn eth_send_transaction(
+    pub async fn eth_get_balance(&self, address: Address) -> Result<Uint256, Web3Error> {
+        self.jsonrpc_client
+            .request_method(
+                "eth_getBalance",
+                vec![address.to_string(), "latest".to_string()],
+            )
+            .await
+    }
+    pub async fn eth_send_transaction(
         &self,
         transactions: Vec<TransactionRequest>,
-    ) -> Box<Future<Item = Uint256, Error = Error>> {
+    ) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_sendTransaction", transactions)
+            .await
     }
-    pub fn eth_call(
-        &self,
-        transaction: TransactionRequest,
-    ) -> Box<Future<Item = Data, Error = Error>> {
+    pub async fn eth_call(&self, transaction: TransactionRequest) -> Result<Data, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_call", vec![transaction])
+            .await
     }
-    pub fn eth_block_number(&self) -> Box<Future<Item = Uint256, Error = Error>> {
+    pub async fn eth_block_number(&self) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("eth_blockNumber", Vec::<String>::new())
+            .await
     }
-    pub fn eth_send_raw_transaction(
-        &self,
-        data: Vec<u8>,
-    ) -> Box<Future<Item = Uint256, Error = Error>> {
-        self.jsonrpc_client.request_method(
-            "eth_sendRawTransaction",
-            vec![format!("0x{}", bytes_to_hex_str(&data))],
-        )
-    }
-    pub fn eth_get_transaction_by_hash(
+    pub async fn eth_send_raw_transaction(&self, data: Vec<u8>) -> Result<Uint256, Web3Error> {
+        self.jsonrpc_client
+            .request_method(
+                "eth_sendRawTransaction",
+                vec![format!("0x{}", bytes_to_hex_str(&data))],
+            )
+            .await
+    }
+    pub async fn eth_get_transaction_by_hash(
         &self,
         hash: Uint256,
-    ) -> Box<Future<Item = Option<TransactionResponse>, Error = Error>> {
-        self.jsonrpc_client.request_method(
-            "eth_getTransactionByHash",
-            /// XXX: Technically it doesn't need to be Uint256, but since send_raw_transaction is
-            /// returning it we'll keep it consistent.
-            vec![format!("{:#066x}", hash)],
-        )
-    }
-    pub fn evm_snapshot(&self) -> Box<Future<Item = Uint256, Error = Error>> {
+    ) -> Result<Option<TransactionResponse>, Web3Error> {
+        self.jsonrpc_client
+            .request_method(
+                "eth_getTransactionByHash",
+                // XXX: Technically it doesn't need to be Uint256, but since send_raw_transaction is
+                // returning it we'll keep it consistent.
+                vec![format!("{:#066x}", hash)],
+            )
+            .await
+    }
+    pub async fn evm_snapshot(&self) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("evm_snapshot", Vec::<String>::new())
+            .await
     }
-    pub fn evm_revert(&self, snapshot_id: Uint256) -> Box<Future<Item = Uint256, Error = Error>> {
+    pub async fn evm_revert(&self, snapshot_id: Uint256) -> Result<Uint256, Web3Error> {
         self.jsonrpc_client
             .request_method("evm_revert", vec![format!("{:#066x}", snapshot_id)])
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::mock::MockClient;
+
+    #[actix_rt::test]
+    async fn test_mock_transport() {
+        let mock = MockClient::new();
+        mock.expect("eth_blockNumber", json!("0x10"));
+        let web3 = Web3::new(mock.clone());
+
+        assert_eq!(web3.eth_block_number().await.unwrap(), 16u64.into());
+        assert_eq!(
+            mock.requests(),
+            vec![("eth_blockNumber".to_string(), json!([]))]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_mock_transport_error() {
+        let mock = MockClient::new();
+        mock.expect_error("eth_gasPrice", -32000, "Node is syncing");
+        let web3 = Web3::new(mock.clone());
+
+        let err = web3.eth_gas_price().await.unwrap_err();
+        assert_eq!(err.to_string(), "JSONRPC Error -32000: Node is syncing");
+    }
+
+    #[actix_rt::test]
+    async fn test_gas_price_and_transaction_count_batched() {
+        let mock = MockClient::new();
+        mock.expect("eth_gasPrice", json!("0x3b9aca00"))
+            .expect("eth_getTransactionCount", json!("0x7"));
+        let web3 = Web3::new(mock.clone());
+
+        let (gas_price, nonce) = web3
+            .eth_gas_price_and_transaction_count(Address::default())
+            .await
+            .unwrap();
+        assert_eq!(gas_price, 1_000_000_000u64.into());
+        assert_eq!(nonce, 7u64.into());
+        assert_eq!(mock.round_trips(), 1);
+    }
+
+    fn log(block_number: u64, log_index: u64) -> Value {
+        json!({
+            "logIndex": format!("{:#x}", log_index),
+            "blockNumber": format!("{:#x}", block_number),
+            "address": "0xc153bde3ab8a9721b6252dcd1ffa2cb0aa165c1a",
+            "data": "0x",
+            "topics": [],
+        })
+    }
+
+    fn positions(logs: Vec<Log>) -> Vec<(Uint256, Uint256)> {
+        logs.into_iter()
+            .map(|log| (log.block_number.unwrap(), log.log_index.unwrap()))
+            .collect()
+    }
+
+    #[actix_rt::test]
+    async fn test_subscribe_logs_resubscribes_and_backfills() {
+        let mock = MockClient::new();
+        // The first subscription drops after a single log
+        mock.expect_subscription(vec![log(7, 0)])
+            .expect("eth_blockNumber", json!("0x6"))
+            // Blocks 8 and 9 are mined while resubscribing, 9 also comes in over the new
+            // subscription
+            .expect_subscription(vec![log(9, 0), log(10, 0)])
+            .expect("eth_blockNumber", json!("0x9"))
+            .expect("eth_getLogs", json!([log(7, 0), log(8, 0), log(9, 0)]));
+        let web3 = Web3::new(mock.clone());
+
+        let logs = web3
+            .eth_subscribe_logs(NewFilter::default())
+            .take(4)
+            .try_collect::<Vec<Log>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            positions(logs),
+            vec![
+                (7u64.into(), 0u64.into()),
+                (8u64.into(), 0u64.into()),
+                (9u64.into(), 0u64.into()),
+                (10u64.into(), 0u64.into()),
+            ]
+        );
+
+        let requests = mock.requests();
+        let backfill = requests
+            .iter()
+            .find(|(method, _)| method == "eth_getLogs")
+            .unwrap();
+        assert_eq!(backfill.1[0]["fromBlock"], "0x7");
+        assert_eq!(backfill.1[0]["toBlock"], "0x9");
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|(method, _)| method == "eth_subscribe")
+                .count(),
+            2
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_subscribe_logs_from_block() {
+        let mock = MockClient::new();
+        mock.expect_subscription(vec![log(5, 1)])
+            .expect("eth_blockNumber", json!("0x5"))
+            .expect("eth_getLogs", json!([log(3, 0), log(5, 0)]));
+        let web3 = Web3::new(mock.clone());
+
+        let filter = NewFilter {
+            from_block: Some("0x1".to_string()),
+            ..Default::default()
+        };
+        let logs = web3
+            .eth_subscribe_logs(filter)
+            .take(3)
+            .try_collect::<Vec<Log>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            positions(logs),
+            vec![
+                (3u64.into(), 0u64.into()),
+                (5u64.into(), 0u64.into()),
+                (5u64.into(), 1u64.into()),
+            ]
+        );
+        // The subscription itself must not carry the block range
+        assert_eq!(mock.requests()[0].1[1].get("fromBlock"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_poll_logs() {
+        let mock = MockClient::new();
+        // The first poll only finds out where the chain is
+        mock.expect("eth_blockNumber", json!("0x5"))
+            .expect("eth_blockNumber", json!("0x6"))
+            .expect("eth_getLogs", json!([log(6, 0)]))
+            .expect("eth_blockNumber", json!("0x6"))
+            .expect("eth_blockNumber", json!("0x7"))
+            .expect("eth_getLogs", json!([log(6, 0), log(7, 0)]));
+        let web3 = Web3::new(mock.clone());
+
+        let cursor = Arc::new(Mutex::new(LogCursor::new(None)));
+        let logs = web3
+            .poll_logs(NewFilter::default(), cursor, Duration::from_millis(1))
+            .take(2)
+            .try_collect::<Vec<Log>>()
+            .await
+            .unwrap();
+        assert_eq!(
+            positions(logs),
+            vec![(6u64.into(), 0u64.into()), (7u64.into(), 0u64.into())]
+        );
+        let from_blocks = mock
+            .requests()
+            .into_iter()
+            .filter(|(method, _)| method == "eth_getLogs")
+            .map(|(_, params)| params[0]["fromBlock"].clone())
+            .collect::<Vec<Value>>();
+        assert_eq!(from_blocks, vec![json!("0x6"), json!("0x7")]);
+    }
+
+    #[actix_rt::test]
+    async fn test_subscribe_new_heads() {
+        let mock = MockClient::new();
+        mock.expect_subscription(vec![json!({
+            "number": "0x1b4",
+            "parentHash": "0x00",
+            "timestamp": "0x56ffeff8",
+        })]);
+        let web3 = Web3::new(mock.clone());
+
+        let header = web3.eth_subscribe_new_heads().next().await.unwrap();
+        assert_eq!(header.unwrap().number, Some(0x1b4u64.into()));
+        assert_eq!(mock.requests()[0].1, json!(["newHeads"]));
+    }
+
+    #[actix_rt::test]
+    async fn test_batch_partial_failure() {
+        let mock = MockClient::new();
+        mock.expect("eth_gasPrice", json!("0x1"));
+        let web3 = Web3::new(mock.clone());
+
+        assert!(web3
+            .eth_gas_price_and_transaction_count(Address::default())
+            .await
+            .is_err());
+        assert_eq!(mock.round_trips(), 1);
     }
 }
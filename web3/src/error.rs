@@ -0,0 +1,37 @@
+use std::time::Duration;
+use thiserror::Error;
+
+/// Everything that can go wrong while talking to a full node.
+///
+/// A `JsonRpc` error is an answer from a working node, for instance a reverted call. All other
+/// variants mean that no usable answer was received.
+#[derive(Debug, Error)]
+pub enum Web3Error {
+    /// The node could not be reached or the connection to it broke
+    #[error("Transport error: {0}")]
+    Transport(String),
+
+    /// The node did not answer in time
+    #[error("No answer within {0:?}")]
+    Timeout(Duration),
+
+    /// A request could not be serialized, or the answer was not what we expected
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The node answered with a JSONRPC error
+    #[error("JSONRPC Error {code}: {message}")]
+    JsonRpc { code: i64, message: String },
+
+    /// The responses to a batch do not match the requests that were sent
+    #[error("Invalid batch response: {0}")]
+    InvalidBatch(String),
+
+    /// A failover transport was set up without any endpoint
+    #[error("No RPC endpoints configured")]
+    NoEndpoints,
+
+    /// `eth_subscribe` was used with a transport which cannot deliver notifications
+    #[error("This transport does not support subscriptions")]
+    SubscriptionsUnsupported,
+}
@@ -1,81 +1,317 @@
+use crate::error::Web3Error;
+use crate::jsonrpc::failover::FailoverClient;
+use crate::jsonrpc::mock::MockClient;
 use crate::jsonrpc::request::Request;
 use crate::jsonrpc::response::Response;
-use actix_web::client;
-use actix_web::HttpMessage;
-use failure::Error;
-use futures::future::Future;
+use crate::jsonrpc::websocket::WebSocketClient;
+use async_trait::async_trait;
+use futures::stream::LocalBoxStream;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
-use std::str;
-use std::sync::{Arc, Mutex};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
 
+/// Notifications of a subscription, see `Client::subscribe`
+pub type Notifications = LocalBoxStream<'static, Result<Value, Web3Error>>;
+
+/// A JSONRPC transport.
+///
+/// Requests and responses are passed around as untyped JSON values so that transports can be
+/// used as trait objects and picked at runtime. `ClientExt` builds the typed API on top of it.
+#[async_trait(?Send)]
 pub trait Client {
-    fn request_method<T: Serialize, R: 'static>(
+    /// Sends a single request and resolves to its response
+    async fn send_request(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error>;
+
+    /// Sends all requests in a single JSONRPC batch. Responses are returned in the same order
+    /// as the requests, regardless of the order the server answered in.
+    async fn send_batch(
         &self,
-        method: &str,
-        params: T,
-    ) -> Box<Future<Item = R, Error = Error>>
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error>;
+
+    /// Allocates an id for a new request
+    fn next_id(&self) -> u64;
+
+    /// Sends `eth_subscribe` with the given params and resolves to the stream of notifications
+    /// once the node accepted the subscription. The stream ends when the subscription is lost,
+    /// for instance because the connection dropped.
+    async fn subscribe(&self, _params: Value) -> Result<Notifications, Web3Error> {
+        Err(Web3Error::SubscriptionsUnsupported)
+    }
+
+    /// Whether `subscribe` can be used with this transport
+    fn supports_subscriptions(&self) -> bool {
+        false
+    }
+}
+
+/// Typed helpers available on every `Client`
+#[async_trait(?Send)]
+pub trait ClientExt: Client {
+    async fn request_method<T, R>(&self, method: &str, params: T) -> Result<R, Web3Error>
     where
+        T: Serialize + std::fmt::Debug,
         for<'de> R: Deserialize<'de>,
-        T: std::fmt::Debug,
         R: std::fmt::Debug;
+
+    /// Sends several calls in one round trip. Each call has its own result since some of them
+    /// may fail while others succeed.
+    async fn request_batch(
+        &self,
+        calls: Vec<(&str, Value)>,
+    ) -> Result<Vec<Result<Value, Web3Error>>, Web3Error>;
+}
+
+#[async_trait(?Send)]
+impl<C: Client + ?Sized> ClientExt for C {
+    async fn request_method<T, R>(&self, method: &str, params: T) -> Result<R, Web3Error>
+    where
+        T: Serialize + std::fmt::Debug,
+        for<'de> R: Deserialize<'de>,
+        R: std::fmt::Debug,
+    {
+        trace!("web3 request {} {:?}", method, params);
+        let payload = Request::new(self.next_id(), method, serde_json::to_value(params)?);
+        let res = self.send_request(payload).await?;
+        trace!("got web3 response {:#?}", res);
+        let data = into_result(res)?;
+        Ok(serde_json::from_value(data)?)
+    }
+
+    async fn request_batch(
+        &self,
+        calls: Vec<(&str, Value)>,
+    ) -> Result<Vec<Result<Value, Web3Error>>, Web3Error> {
+        let payload = calls
+            .into_iter()
+            .map(|(method, params)| Request::new(self.next_id(), method, params))
+            .collect::<Vec<_>>();
+        trace!("web3 batch request {:?}", payload);
+        let responses = self.send_batch(payload).await?;
+        Ok(responses.into_iter().map(into_result).collect())
+    }
+}
+
+/// Converts a JSONRPC response into its result, or an error if the server returned one
+pub fn into_result(response: Response<Value>) -> Result<Value, Web3Error> {
+    response.data.into_result().map_err(|e| Web3Error::JsonRpc {
+        code: e.code,
+        message: e.message,
+    })
+}
+
+/// Puts batch responses back in the order the requests were sent in, making sure that every
+/// request got exactly one response.
+pub fn order_batch_responses(
+    ids: &[u64],
+    responses: Vec<Response<Value>>,
+) -> Result<Vec<Response<Value>>, Web3Error> {
+    if ids.len() != responses.len() {
+        return Err(Web3Error::InvalidBatch(format!(
+            "Expected {} responses but got {}",
+            ids.len(),
+            responses.len()
+        )));
+    }
+    let mut by_id: HashMap<u64, Response<Value>> = HashMap::new();
+    for response in responses {
+        let id = response
+            .id
+            .as_u64()
+            .ok_or_else(|| Web3Error::InvalidBatch(format!("Invalid id {:?}", response.id)))?;
+        by_id.insert(id, response);
+    }
+    ids.iter()
+        .map(|id| {
+            by_id
+                .remove(id)
+                .ok_or_else(|| Web3Error::InvalidBatch(format!("No response for request {}", id)))
+        })
+        .collect()
+}
+
+/// Selects which transport `Web3` talks to a node with
+pub enum Transport {
+    Http(String),
+    WebSocket(String),
+    /// Several endpoints, in order of preference, see `FailoverClient`
+    Failover(Vec<Transport>),
+    Custom(Box<dyn Client + Send + Sync>),
+}
+
+impl Transport {
+    pub fn into_client(self) -> Box<dyn Client + Send + Sync> {
+        match self {
+            Transport::Http(url) => Box::new(HTTPClient::new(&url)),
+            Transport::WebSocket(url) => Box::new(WebSocketClient::new(&url)),
+            Transport::Failover(transports) => Box::new(FailoverClient::new(transports)),
+            Transport::Custom(client) => client,
+        }
+    }
+
+    /// Describes the transport for logging
+    pub(crate) fn name(&self) -> String {
+        match self {
+            Transport::Http(url) | Transport::WebSocket(url) => url.clone(),
+            Transport::Failover(transports) => transports
+                .iter()
+                .map(Transport::name)
+                .collect::<Vec<String>>()
+                .join(", "),
+            Transport::Custom(_) => "custom transport".to_string(),
+        }
+    }
+}
+
+/// URLs starting with `ws://` or `wss://` use a WebSocket, everything else goes over HTTP
+impl<'a> From<&'a str> for Transport {
+    fn from(url: &'a str) -> Transport {
+        if url.starts_with("ws://") || url.starts_with("wss://") {
+            Transport::WebSocket(url.to_string())
+        } else {
+            Transport::Http(url.to_string())
+        }
+    }
+}
+
+impl<'a> From<&'a String> for Transport {
+    fn from(url: &'a String) -> Transport {
+        Transport::from(url.as_str())
+    }
+}
+
+/// A single URL picks a transport like `From<&str>`, several URLs fail over between each other
+impl<'a> From<&'a [String]> for Transport {
+    fn from(urls: &'a [String]) -> Transport {
+        if urls.len() == 1 {
+            Transport::from(&urls[0])
+        } else {
+            Transport::Failover(urls.iter().map(Transport::from).collect())
+        }
+    }
+}
+
+impl From<FailoverClient> for Transport {
+    fn from(client: FailoverClient) -> Transport {
+        Transport::Custom(Box::new(client))
+    }
+}
+
+impl From<MockClient> for Transport {
+    fn from(client: MockClient) -> Transport {
+        Transport::Custom(Box::new(client))
+    }
 }
 
 pub struct HTTPClient {
-    id_counter: Arc<Mutex<RefCell<u64>>>,
+    id_counter: AtomicUsize,
     url: String,
 }
 
 impl HTTPClient {
     pub fn new(url: &str) -> Self {
         Self {
-            id_counter: Arc::new(Mutex::new(RefCell::new(0u64))),
+            id_counter: AtomicUsize::new(0),
             url: url.to_string(),
         }
     }
 
-    fn next_id(&self) -> u64 {
-        let counter = self.id_counter.clone();
-        let counter = counter.lock().expect("id error");
-        let mut value = counter.borrow_mut();
-        *value += 1;
-        *value
+    /// Posts any JSON payload and parses the response
+    async fn post<P: Serialize, R>(&self, payload: P) -> Result<R, Web3Error>
+    where
+        for<'de> R: Deserialize<'de>,
+    {
+        let client = awc::Client::builder()
+            .timeout(Duration::from_millis(1000))
+            .finish();
+        let mut response = client
+            .post(&self.url)
+            .send_json(&payload)
+            .await
+            .map_err(|e| Web3Error::Transport(e.to_string()))?;
+        let body = response
+            .body()
+            .await
+            .map_err(|e| Web3Error::Transport(e.to_string()))?;
+        Ok(serde_json::from_slice(&body)?)
     }
 }
 
+#[async_trait(?Send)]
 impl Client for HTTPClient {
-    fn request_method<T: Serialize, R: 'static>(
+    async fn send_request(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error> {
+        self.post(request).await
+    }
+
+    async fn send_batch(
         &self,
-        method: &str,
-        params: T,
-    ) -> Box<Future<Item = R, Error = Error>>
-    where
-        for<'de> R: Deserialize<'de>,
-        T: std::fmt::Debug,
-        R: std::fmt::Debug,
-    {
-        let payload = Request::new(self.next_id(), method, params);
-        trace!("web3 request {:?}", payload);
-        Box::new(
-            client::post(&self.url)
-                .json(payload)
-                .expect("json error")
-                .send()
-                .timeout(Duration::from_millis(1000))
-                .from_err()
-                .and_then(|response| {
-                    response
-                        .json()
-                        .from_err()
-                        .and_then(move |res: Response<R>| {
-                            trace!("got web3 response {:#?}", res);
-                            let data = res.data.into_result();
-                            data.map_err(move |e| {
-                                format_err!("JSONRPC Error {}: {}", e.code, e.message)
-                            })
-                        })
-                }),
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error> {
+        let ids = requests.iter().map(|r| r.id()).collect::<Vec<u64>>();
+        let responses = self.post(requests).await?;
+        order_batch_responses(&ids, responses)
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::SeqCst) as u64 + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(id: u64, result: u64) -> Response<Value> {
+        serde_json::from_str(&format!(
+            r#"{{"jsonrpc": "2.0", "result": {}, "id": {}}}"#,
+            result, id
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_order_batch_responses() {
+        let ordered = order_batch_responses(
+            &[1, 2, 3],
+            vec![response(3, 30), response(1, 10), response(2, 20)],
         )
+        .unwrap();
+        let results = ordered
+            .into_iter()
+            .map(|r| into_result(r).unwrap().as_u64().unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(results, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn test_order_batch_responses_missing() {
+        assert!(order_batch_responses(&[1, 2], vec![response(1, 10)]).is_err());
+        assert!(order_batch_responses(&[1, 2], vec![response(1, 10), response(1, 10)]).is_err());
+    }
+
+    #[test]
+    fn test_transport_from_url() {
+        match Transport::from("http://127.0.0.1:8545") {
+            Transport::Http(url) => assert_eq!(url, "http://127.0.0.1:8545"),
+            _ => panic!("expected HTTP transport"),
+        }
+        match Transport::from("ws://127.0.0.1:8546") {
+            Transport::WebSocket(url) => assert_eq!(url, "ws://127.0.0.1:8546"),
+            _ => panic!("expected WebSocket transport"),
+        }
+        let urls = [
+            "http://127.0.0.1:8545".to_string(),
+            "ws://127.0.0.1:8546".to_string(),
+        ];
+        match Transport::from(&urls[..1]) {
+            Transport::Http(_) => {}
+            _ => panic!("expected HTTP transport"),
+        }
+        match Transport::from(&urls[..]) {
+            Transport::Failover(transports) => assert_eq!(transports.len(), 2),
+            _ => panic!("expected failover transport"),
+        }
     }
 }
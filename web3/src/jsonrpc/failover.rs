@@ -0,0 +1,656 @@
+//! Spreads requests over several nodes so that one bad node does not stall everything.
+//!
+//! Endpoints are tried in the order they were given, healthy ones first. An endpoint which does
+//! not answer in time, cannot be reached or answers garbage is marked unhealthy and only used
+//! as a last resort until it answers again or passes a health check. A JSONRPC error is an
+//! answer from a working node and is returned as it is.
+//!
+//! Calls which only read state are retried on the next endpoint. Raw transactions are sent to
+//! every healthy endpoint at once so that they make it into the mempool even if some nodes are
+//! down. Anything else which changes state, or depends on state kept by a single node such as
+//! filters, is sent to one endpoint only.
+
+use crate::error::Web3Error;
+use crate::jsonrpc::client::{into_result, Client, Notifications, Transport};
+use crate::jsonrpc::request::Request;
+use crate::jsonrpc::response::{Response, ResponseData};
+use async_trait::async_trait;
+use futures::future::{self, Future};
+use num256::Uint256;
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long to wait for an answer before giving up on an endpoint
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often every endpoint is checked
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How many blocks an endpoint may be behind the others and still be healthy
+const DEFAULT_MAX_BLOCK_LAG: u64 = 5;
+
+/// How a call may be spread over the endpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Strategy {
+    /// Only reads state, so a failure can be retried on the next endpoint
+    Retry,
+    /// Sent to every healthy endpoint at once
+    Broadcast,
+    /// Sent to a single endpoint
+    Once,
+}
+
+fn strategy(method: &str) -> Strategy {
+    match method {
+        "eth_sendRawTransaction" => Strategy::Broadcast,
+        "eth_sendTransaction"
+        | "eth_newFilter"
+        | "eth_newBlockFilter"
+        | "eth_newPendingTransactionFilter"
+        | "eth_getFilterChanges"
+        | "eth_getFilterLogs"
+        | "eth_uninstallFilter"
+        | "eth_subscribe"
+        | "eth_unsubscribe" => Strategy::Once,
+        method if method.starts_with("evm_") || method.starts_with("personal_") => Strategy::Once,
+        _ => Strategy::Retry,
+    }
+}
+
+struct Endpoint {
+    name: String,
+    client: Box<dyn Client + Send + Sync>,
+    /// Why the endpoint is unhealthy, `None` while it is healthy
+    failure: Mutex<Option<String>>,
+}
+
+impl Endpoint {
+    fn is_healthy(&self) -> bool {
+        self.failure.lock().expect("Endpoint poisoned").is_none()
+    }
+
+    fn mark_healthy(&self) {
+        if self
+            .failure
+            .lock()
+            .expect("Endpoint poisoned")
+            .take()
+            .is_some()
+        {
+            info!("RPC endpoint {} is healthy again", self.name);
+        }
+    }
+
+    fn mark_unhealthy(&self, reason: String) {
+        warn!("RPC endpoint {} is unhealthy: {}", self.name, reason);
+        *self.failure.lock().expect("Endpoint poisoned") = Some(reason);
+    }
+}
+
+async fn with_timeout<T>(
+    future: impl Future<Output = Result<T, Web3Error>>,
+    timeout: Duration,
+) -> Result<T, Web3Error> {
+    match actix_rt::time::timeout(timeout, future).await {
+        Ok(res) => res,
+        Err(_) => Err(Web3Error::Timeout(timeout)),
+    }
+}
+
+/// Sends something to one endpoint and keeps track of whether it answered
+async fn attempt<T>(
+    endpoint: &Endpoint,
+    timeout: Duration,
+    future: impl Future<Output = Result<T, Web3Error>>,
+) -> Result<T, Web3Error> {
+    let res = with_timeout(future, timeout).await;
+    match res {
+        Ok(_) => endpoint.mark_healthy(),
+        Err(ref e) => endpoint.mark_unhealthy(e.to_string()),
+    }
+    res
+}
+
+/// Picks the outcome of a broadcast: the first endpoint which accepted the request, otherwise
+/// the first JSONRPC error, otherwise the first failure.
+fn first_accepted(
+    results: Vec<Result<Response<Value>, Web3Error>>,
+) -> Result<Response<Value>, Web3Error> {
+    let mut rejection = None;
+    let mut failure = None;
+    for result in results {
+        match result {
+            Ok(response) => match response.data {
+                ResponseData::Success { .. } => return Ok(response),
+                ResponseData::Error { .. } => {
+                    rejection = rejection.or(Some(response));
+                }
+            },
+            Err(e) => failure = failure.or(Some(e)),
+        }
+    }
+    match (rejection, failure) {
+        (Some(response), _) => Ok(response),
+        (None, Some(e)) => Err(e),
+        (None, None) => Err(Web3Error::NoEndpoints),
+    }
+}
+
+/// A transport which spreads requests over several endpoints, see the module documentation.
+#[derive(Clone)]
+pub struct FailoverClient {
+    endpoints: Vec<Arc<Endpoint>>,
+    id_counter: Arc<AtomicUsize>,
+    timeout: Duration,
+    health_check_interval: Duration,
+    max_block_lag: u64,
+    last_health_check: Arc<Mutex<Instant>>,
+}
+
+impl FailoverClient {
+    /// Creates a client for the given endpoints, in order of preference. Every endpoint is
+    /// considered healthy until it fails.
+    pub fn new(transports: Vec<Transport>) -> Self {
+        Self {
+            endpoints: transports
+                .into_iter()
+                .map(|transport| {
+                    Arc::new(Endpoint {
+                        name: transport.name(),
+                        client: transport.into_client(),
+                        failure: Mutex::new(None),
+                    })
+                })
+                .collect(),
+            id_counter: Arc::new(AtomicUsize::new(0)),
+            timeout: DEFAULT_TIMEOUT,
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            max_block_lag: DEFAULT_MAX_BLOCK_LAG,
+            last_health_check: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// How long to wait for an endpoint to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How often `check_health` runs. The check runs before the first request made after the
+    /// interval has passed.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// How many blocks an endpoint may be behind the most advanced one and still be healthy
+    pub fn with_max_block_lag(mut self, blocks: u64) -> Self {
+        self.max_block_lag = blocks;
+        self
+    }
+
+    /// Names of the endpoints which are currently healthy
+    pub fn healthy_endpoints(&self) -> Vec<String> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_healthy())
+            .map(|endpoint| endpoint.name.clone())
+            .collect()
+    }
+
+    /// Asks every endpoint for its latest block. Endpoints which do not answer in time, or
+    /// which are more than `max_block_lag` blocks behind the most advanced one, are marked
+    /// unhealthy and all others healthy. Resolves to the number of healthy endpoints.
+    pub async fn check_health(&self) -> Result<usize, Web3Error> {
+        *self
+            .last_health_check
+            .lock()
+            .expect("Health check poisoned") = Instant::now();
+
+        let checks = self.endpoints.iter().map(|endpoint| {
+            let request = Request::new(self.next_id(), "eth_blockNumber", json!([]));
+            async move {
+                let response =
+                    with_timeout(endpoint.client.send_request(request), self.timeout).await?;
+                Ok::<_, Web3Error>(serde_json::from_value::<Uint256>(into_result(response)?)?)
+            }
+        });
+        let block_numbers = future::join_all(checks).await;
+
+        let max_block_lag = Uint256::from(self.max_block_lag);
+        let highest = block_numbers
+            .iter()
+            .filter_map(|block_number| block_number.as_ref().ok())
+            .max()
+            .cloned()
+            .unwrap_or_else(|| Uint256::from(0u64));
+        let mut healthy = 0;
+        for (endpoint, block_number) in self.endpoints.iter().zip(block_numbers) {
+            match block_number {
+                Ok(ref block_number) if block_number.clone() + max_block_lag.clone() < highest => {
+                    endpoint.mark_unhealthy(format!(
+                        "At block {:#x} while others are at {:#x}",
+                        block_number, highest
+                    ))
+                }
+                Ok(_) => {
+                    endpoint.mark_healthy();
+                    healthy += 1;
+                }
+                Err(e) => endpoint.mark_unhealthy(e.to_string()),
+            }
+        }
+        Ok(healthy)
+    }
+
+    async fn check_health_if_due(&self) -> Result<(), Web3Error> {
+        let due = self
+            .last_health_check
+            .lock()
+            .expect("Health check poisoned")
+            .elapsed()
+            >= self.health_check_interval;
+        if due {
+            self.check_health().await?;
+        }
+        Ok(())
+    }
+
+    /// Endpoints in the order they should be tried: healthy ones first, then the others as a
+    /// last resort
+    fn candidates(&self) -> Vec<Arc<Endpoint>> {
+        let (mut healthy, unhealthy): (Vec<_>, Vec<_>) = self
+            .endpoints
+            .iter()
+            .cloned()
+            .partition(|endpoint| endpoint.is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+
+    /// Healthy endpoints, or all of them if none is healthy
+    fn healthy(&self) -> Vec<Arc<Endpoint>> {
+        let healthy = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_healthy())
+            .cloned()
+            .collect::<Vec<_>>();
+        if healthy.is_empty() {
+            self.endpoints.clone()
+        } else {
+            healthy
+        }
+    }
+
+    fn first_candidate(&self) -> Result<Arc<Endpoint>, Web3Error> {
+        self.candidates()
+            .into_iter()
+            .next()
+            .ok_or(Web3Error::NoEndpoints)
+    }
+
+    /// Tries the endpoints one after the other until one answers
+    async fn try_in_turn<T, F, Fut>(&self, send: F) -> Result<T, Web3Error>
+    where
+        F: Fn(Arc<Endpoint>) -> Fut,
+        Fut: Future<Output = Result<T, Web3Error>>,
+    {
+        let mut last_error = Web3Error::NoEndpoints;
+        for endpoint in self.candidates() {
+            match attempt(&endpoint, self.timeout, send(endpoint.clone())).await {
+                Ok(res) => return Ok(res),
+                Err(e) => {
+                    trace!("Trying the next RPC endpoint after {}", e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn dispatch(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error> {
+        match strategy(request.method()) {
+            Strategy::Retry => {
+                self.try_in_turn(|endpoint| {
+                    let request = request.clone();
+                    async move { endpoint.client.send_request(request).await }
+                })
+                .await
+            }
+            Strategy::Broadcast => {
+                let endpoints = self.healthy();
+                let sends = endpoints.iter().map(|endpoint| {
+                    attempt(
+                        endpoint,
+                        self.timeout,
+                        endpoint.client.send_request(request.clone()),
+                    )
+                });
+                first_accepted(future::join_all(sends).await)
+            }
+            Strategy::Once => {
+                let endpoint = self.first_candidate()?;
+                attempt(
+                    &endpoint,
+                    self.timeout,
+                    endpoint.client.send_request(request),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn dispatch_batch(
+        &self,
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error> {
+        let retry = requests
+            .iter()
+            .all(|request| strategy(request.method()) == Strategy::Retry);
+        if retry {
+            self.try_in_turn(|endpoint| {
+                let requests = requests.clone();
+                async move { endpoint.client.send_batch(requests).await }
+            })
+            .await
+        } else {
+            // A batch which changes state is never split up or repeated
+            let endpoint = self.first_candidate()?;
+            attempt(
+                &endpoint,
+                self.timeout,
+                endpoint.client.send_batch(requests),
+            )
+            .await
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Client for FailoverClient {
+    async fn send_request(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error> {
+        self.check_health_if_due().await?;
+        self.dispatch(request).await
+    }
+
+    async fn send_batch(
+        &self,
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error> {
+        self.check_health_if_due().await?;
+        self.dispatch_batch(requests).await
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::SeqCst) as u64 + 1
+    }
+
+    /// Subscribes on the first healthy endpoint which supports subscriptions
+    async fn subscribe(&self, params: Value) -> Result<Notifications, Web3Error> {
+        let endpoint = self
+            .candidates()
+            .into_iter()
+            .find(|endpoint| endpoint.client.supports_subscriptions())
+            .ok_or(Web3Error::SubscriptionsUnsupported)?;
+        attempt(&endpoint, self.timeout, endpoint.client.subscribe(params)).await
+    }
+
+    fn supports_subscriptions(&self) -> bool {
+        self.endpoints
+            .iter()
+            .any(|endpoint| endpoint.client.supports_subscriptions())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jsonrpc::client::ClientExt;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use std::collections::VecDeque;
+
+    /// What a mock node does with a request
+    #[derive(Clone)]
+    enum Reply {
+        Result(Value),
+        RpcError(&'static str),
+        /// Answers with an HTTP error instead of JSONRPC
+        ServerError,
+        /// Never answers in time
+        Hang,
+    }
+
+    struct MockNode {
+        replies: Mutex<VecDeque<Reply>>,
+        fallback: Reply,
+        methods: Mutex<Vec<String>>,
+    }
+
+    impl MockNode {
+        fn methods(&self) -> Vec<String> {
+            self.methods.lock().unwrap().clone()
+        }
+    }
+
+    async fn reply(node: web::Data<MockNode>, body: web::Json<Value>) -> HttpResponse {
+        node.methods
+            .lock()
+            .unwrap()
+            .push(body["method"].as_str().unwrap().to_string());
+        let id = body["id"].clone();
+        let reply = node
+            .replies
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| node.fallback.clone());
+        match reply {
+            Reply::Result(result) => HttpResponse::Ok().json(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result,
+            })),
+            Reply::RpcError(message) => HttpResponse::Ok().json(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": -32000, "message": message},
+            })),
+            Reply::ServerError => HttpResponse::InternalServerError().finish(),
+            Reply::Hang => {
+                actix_rt::time::sleep(Duration::from_secs(10)).await;
+                HttpResponse::Ok().finish()
+            }
+        }
+    }
+
+    /// Starts a node which answers with `replies` in order, then with `fallback`
+    fn start_node(replies: Vec<Reply>, fallback: Reply) -> (Transport, Arc<MockNode>) {
+        let node = Arc::new(MockNode {
+            replies: Mutex::new(replies.into_iter().collect()),
+            fallback,
+            methods: Mutex::new(Vec::new()),
+        });
+        let state = web::Data::from(node.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .route("/", web::post().to(reply))
+        })
+        .workers(1)
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let url = format!("http://{}", server.addrs()[0]);
+        actix_rt::spawn(server.shutdown_timeout(0).run());
+        (Transport::Http(url), node)
+    }
+
+    async fn block_number(client: &FailoverClient) -> Result<Uint256, Web3Error> {
+        client
+            .request_method("eth_blockNumber", Vec::<String>::new())
+            .await
+    }
+
+    #[actix_rt::test]
+    async fn test_retries_read_on_next_endpoint() {
+        let (down, down_node) = start_node(vec![], Reply::ServerError);
+        let (up, up_node) = start_node(vec![], Reply::Result(json!("0x10")));
+        let client = FailoverClient::new(vec![down, up]);
+
+        let first = block_number(&client).await.unwrap();
+        let second = block_number(&client).await.unwrap();
+
+        assert_eq!((first, second), (16u64.into(), 16u64.into()));
+        // The failed endpoint is skipped once it is known to be unhealthy
+        assert_eq!(down_node.methods(), vec!["eth_blockNumber"]);
+        assert_eq!(up_node.methods().len(), 2);
+        assert_eq!(client.healthy_endpoints().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_times_out_hanging_endpoint() {
+        let (hanging, _) = start_node(vec![], Reply::Hang);
+        let (up, _) = start_node(vec![], Reply::Result(json!("0x3b9aca00")));
+        let client =
+            FailoverClient::new(vec![hanging, up]).with_timeout(Duration::from_millis(200));
+
+        let started = Instant::now();
+        let gas_price: Uint256 = client
+            .request_method("eth_gasPrice", Vec::<String>::new())
+            .await
+            .unwrap();
+
+        assert_eq!(gas_price, 1_000_000_000u64.into());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[actix_rt::test]
+    async fn test_does_not_retry_rpc_errors() {
+        let (reverting, _) = start_node(vec![], Reply::RpcError("execution reverted"));
+        let (other, other_node) = start_node(vec![], Reply::Result(json!("0x")));
+        let client = FailoverClient::new(vec![reverting, other]);
+
+        let res: Result<Value, Web3Error> =
+            client.request_method("eth_call", vec![json!({})]).await;
+
+        assert!(res.unwrap_err().to_string().contains("execution reverted"));
+        assert!(other_node.methods().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_does_not_retry_state_changes() {
+        let (down, _) = start_node(vec![], Reply::ServerError);
+        let (up, up_node) = start_node(vec![], Reply::Result(json!("0x1")));
+        let client = FailoverClient::new(vec![down, up]);
+
+        let res: Result<Value, Web3Error> = client
+            .request_method("eth_sendTransaction", vec![json!({})])
+            .await;
+
+        assert!(res.is_err());
+        assert!(up_node.methods().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_broadcasts_raw_transactions() {
+        let (known, known_node) = start_node(vec![], Reply::RpcError("already known"));
+        let (accepting, accepting_node) = start_node(vec![], Reply::Result(json!("0xabcd")));
+        let (down, down_node) = start_node(vec![], Reply::ServerError);
+        let client = FailoverClient::new(vec![known, accepting, down]);
+
+        let hash: Uint256 = client
+            .request_method("eth_sendRawTransaction", vec!["0x00"])
+            .await
+            .unwrap();
+
+        assert_eq!(hash, 0xabcdu64.into());
+        for node in &[known_node, accepting_node, down_node] {
+            assert_eq!(node.methods(), vec!["eth_sendRawTransaction"]);
+        }
+        assert_eq!(client.healthy_endpoints().len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_broadcast_skips_unhealthy_endpoints() {
+        let (down, down_node) = start_node(vec![], Reply::ServerError);
+        let (up, up_node) = start_node(
+            vec![Reply::Result(json!("0x1"))],
+            Reply::Result(json!("0xabcd")),
+        );
+        let client = FailoverClient::new(vec![down, up]);
+
+        block_number(&client).await.unwrap();
+        let hash: Uint256 = client
+            .request_method("eth_sendRawTransaction", vec!["0x00"])
+            .await
+            .unwrap();
+
+        assert_eq!(hash, 0xabcdu64.into());
+        assert_eq!(down_node.methods(), vec!["eth_blockNumber"]);
+        assert_eq!(
+            up_node.methods(),
+            vec!["eth_blockNumber", "eth_sendRawTransaction"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_health_check_finds_lagging_endpoint() {
+        let (lagging, lagging_node) = start_node(
+            vec![Reply::Result(json!("0x1"))],
+            Reply::Result(json!("0x1")),
+        );
+        let (ahead, ahead_node) = start_node(
+            vec![Reply::Result(json!("0x10"))],
+            Reply::Result(json!("0x3b9aca00")),
+        );
+        let client = FailoverClient::new(vec![lagging, ahead]);
+
+        let healthy = client.check_health().await.unwrap();
+        let gas_price: Uint256 = client
+            .request_method("eth_gasPrice", Vec::<String>::new())
+            .await
+            .unwrap();
+
+        assert_eq!(healthy, 1);
+        assert_eq!(gas_price, 1_000_000_000u64.into());
+        assert_eq!(lagging_node.methods(), vec!["eth_blockNumber"]);
+        assert_eq!(
+            ahead_node.methods(),
+            vec!["eth_blockNumber", "eth_gasPrice"]
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_health_check_runs_when_due() {
+        let (flaky, flaky_node) = start_node(vec![Reply::ServerError], Reply::Result(json!("0x5")));
+        let (backup, backup_node) = start_node(vec![], Reply::Result(json!("0x5")));
+        let client = FailoverClient::new(vec![flaky, backup])
+            .with_health_check_interval(Duration::from_millis(0));
+
+        block_number(&client).await.unwrap();
+        // The first check found the flaky endpoint down
+        assert_eq!(client.healthy_endpoints().len(), 1);
+        block_number(&client).await.unwrap();
+
+        // The second check found it working again, so it got the second request
+        assert_eq!(
+            flaky_node.methods(),
+            vec!["eth_blockNumber", "eth_blockNumber", "eth_blockNumber"]
+        );
+        assert_eq!(
+            backup_node.methods(),
+            vec!["eth_blockNumber", "eth_blockNumber", "eth_blockNumber"]
+        );
+        assert_eq!(client.healthy_endpoints().len(), 2);
+    }
+
+    #[test]
+    fn test_strategy() {
+        assert_eq!(strategy("eth_call"), Strategy::Retry);
+        assert_eq!(strategy("eth_getLogs"), Strategy::Retry);
+        assert_eq!(strategy("eth_sendRawTransaction"), Strategy::Broadcast);
+        assert_eq!(strategy("eth_sendTransaction"), Strategy::Once);
+        assert_eq!(strategy("eth_getFilterChanges"), Strategy::Once);
+        assert_eq!(strategy("evm_snapshot"), Strategy::Once);
+    }
+}
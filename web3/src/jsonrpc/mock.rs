@@ -0,0 +1,188 @@
+//! A transport which never touches the network, for tests.
+//!
+//! Results are scripted per method and handed out in the order they were added. Every request
+//! is recorded and round trips are counted, so tests can check both what was asked and how many
+//! times the node was contacted.
+//!
+//! Subscriptions are only supported once one has been scripted with `expect_subscription`. Each
+//! scripted subscription delivers its notifications and then ends, as if the connection dropped.
+
+use crate::error::Web3Error;
+use crate::jsonrpc::client::{Client, Notifications};
+use crate::jsonrpc::request::Request;
+use crate::jsonrpc::response::{Error as ResponseError, Response, ResponseData};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// What a request gets back, scripted per method
+type Results = HashMap<String, VecDeque<Result<Value, ResponseError<Value>>>>;
+
+struct Inner {
+    results: Mutex<Results>,
+    requests: Mutex<Vec<(String, Value)>>,
+    /// Notifications of each subscription, `None` if the transport should not support them
+    subscriptions: Mutex<Option<VecDeque<Vec<Value>>>>,
+    round_trips: AtomicUsize,
+    id_counter: AtomicUsize,
+}
+
+#[derive(Clone)]
+pub struct MockClient {
+    inner: Arc<Inner>,
+}
+
+impl Default for MockClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClient {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                results: Mutex::new(HashMap::new()),
+                requests: Mutex::new(Vec::new()),
+                subscriptions: Mutex::new(None),
+                round_trips: AtomicUsize::new(0),
+                id_counter: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Queues a successful result for the next call of `method`
+    pub fn expect(&self, method: &str, result: Value) -> &Self {
+        self.push(method, Ok(result))
+    }
+
+    /// Queues a JSONRPC error for the next call of `method`
+    pub fn expect_error(&self, method: &str, code: i64, message: &str) -> &Self {
+        self.push(
+            method,
+            Err(ResponseError {
+                code,
+                message: message.to_string(),
+                data: None,
+            }),
+        )
+    }
+
+    /// Queues a subscription which delivers `notifications` and then ends
+    pub fn expect_subscription(&self, notifications: Vec<Value>) -> &Self {
+        self.inner
+            .subscriptions
+            .lock()
+            .expect("mock poisoned")
+            .get_or_insert_with(VecDeque::new)
+            .push_back(notifications);
+        self
+    }
+
+    fn push(&self, method: &str, result: Result<Value, ResponseError<Value>>) -> &Self {
+        self.inner
+            .results
+            .lock()
+            .expect("mock poisoned")
+            .entry(method.to_string())
+            .or_default()
+            .push_back(result);
+        self
+    }
+
+    /// Methods and params of every request made so far, in order
+    pub fn requests(&self) -> Vec<(String, Value)> {
+        self.inner.requests.lock().expect("mock poisoned").clone()
+    }
+
+    /// How many times the node would have been contacted. A batch counts as one.
+    pub fn round_trips(&self) -> usize {
+        self.inner.round_trips.load(Ordering::SeqCst)
+    }
+
+    fn respond(&self, request: Request<Value>) -> Response<Value> {
+        let (id, method, params) = request.into_parts();
+        self.inner
+            .requests
+            .lock()
+            .expect("mock poisoned")
+            .push((method.clone(), params));
+
+        let result = self
+            .inner
+            .results
+            .lock()
+            .expect("mock poisoned")
+            .get_mut(&method)
+            .and_then(|results| results.pop_front())
+            .unwrap_or_else(|| {
+                Err(ResponseError {
+                    code: -32601,
+                    message: format!("No mock result for {}", method),
+                    data: None,
+                })
+            });
+
+        Response {
+            id: id.into(),
+            jsonrpc: "2.0".to_string(),
+            data: match result {
+                Ok(result) => ResponseData::Success { result },
+                Err(error) => ResponseData::Error { error },
+            },
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Client for MockClient {
+    async fn send_request(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error> {
+        self.inner.round_trips.fetch_add(1, Ordering::SeqCst);
+        Ok(self.respond(request))
+    }
+
+    async fn send_batch(
+        &self,
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error> {
+        self.inner.round_trips.fetch_add(1, Ordering::SeqCst);
+        Ok(requests
+            .into_iter()
+            .map(|request| self.respond(request))
+            .collect())
+    }
+
+    fn next_id(&self) -> u64 {
+        self.inner.id_counter.fetch_add(1, Ordering::SeqCst) as u64 + 1
+    }
+
+    async fn subscribe(&self, params: Value) -> Result<Notifications, Web3Error> {
+        self.inner.round_trips.fetch_add(1, Ordering::SeqCst);
+        self.inner
+            .requests
+            .lock()
+            .expect("mock poisoned")
+            .push(("eth_subscribe".to_string(), params));
+
+        let notifications = self
+            .inner
+            .subscriptions
+            .lock()
+            .expect("mock poisoned")
+            .as_mut()
+            .and_then(|subscriptions| subscriptions.pop_front())
+            .ok_or(Web3Error::SubscriptionsUnsupported)?;
+        Ok(stream::iter(notifications.into_iter().map(Ok)).boxed_local())
+    }
+
+    fn supports_subscriptions(&self) -> bool {
+        self.inner
+            .subscriptions
+            .lock()
+            .expect("mock poisoned")
+            .is_some()
+    }
+}
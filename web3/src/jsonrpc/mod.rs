@@ -1,3 +1,6 @@
 pub mod client;
+pub mod failover;
+pub mod mock;
 pub mod request;
 pub mod response;
+pub mod websocket;
@@ -1,4 +1,4 @@
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Request<T> {
     id: u64,
     jsonrpc: String,
@@ -9,12 +9,25 @@ pub struct Request<T> {
 impl<T> Request<T> {
     pub fn new(id: u64, method: &str, params: T) -> Self {
         Self {
-            id: id,
+            id,
             jsonrpc: "2.0".to_string(),
             method: method.to_string(),
             params,
         }
     }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Splits the request into its id, method and params
+    pub fn into_parts(self) -> (u64, String, T) {
+        (self.id, self.method, self.params)
+    }
 }
 
 #[test]
@@ -0,0 +1,400 @@
+//! JSONRPC over a single WebSocket connection.
+//!
+//! The connection is opened lazily on the first request and shared by every request made
+//! through the client. Responses are matched to requests by their id, which is what allows
+//! several requests (and batches) to be in flight at once. If the connection drops, every
+//! request still waiting for a response fails and the next request opens a new connection.
+//!
+//! Subscriptions (`eth_subscribe`) are delivered as `eth_subscription` notifications which carry
+//! the subscription id instead of a request id. They are routed to the stream returned by
+//! `subscribe`, which ends when the connection drops since the node forgets subscriptions then.
+
+use crate::error::Web3Error;
+use crate::jsonrpc::client::{into_result, order_batch_responses, Client, Notifications};
+use crate::jsonrpc::request::Request;
+use crate::jsonrpc::response::Response;
+use async_trait::async_trait;
+use awc::ws::{Frame, Message};
+use futures::channel::{mpsc, oneshot};
+use futures::future;
+use futures::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+enum Connection {
+    Disconnected,
+    Connecting,
+    Connected(mpsc::UnboundedSender<Message>),
+}
+
+pub(crate) struct Shared {
+    connection: Connection,
+    /// Messages written before the connection was established
+    queue: Vec<Message>,
+    /// Requests which are waiting for a response, by id
+    pending: HashMap<u64, oneshot::Sender<Result<Response<Value>, Web3Error>>>,
+    /// Where to deliver notifications once the `eth_subscribe` request with this id succeeds
+    subscribing: HashMap<u64, mpsc::UnboundedSender<Value>>,
+    /// Active subscriptions, by subscription id
+    subscriptions: HashMap<String, mpsc::UnboundedSender<Value>>,
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            connection: Connection::Disconnected,
+            queue: Vec::new(),
+            pending: HashMap::new(),
+            subscribing: HashMap::new(),
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    /// Routes a message received from the node to whoever is waiting for it. Batch responses
+    /// arrive as a single array and are split into individual responses.
+    pub(crate) fn dispatch(&mut self, text: &str) {
+        let value: Value = match serde_json::from_str(text) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("Invalid JSON received over WebSocket {}: {:?}", e, text);
+                return;
+            }
+        };
+        match value {
+            Value::Array(values) => {
+                for value in values {
+                    self.dispatch_value(value)
+                }
+            }
+            value => self.dispatch_value(value),
+        }
+    }
+
+    fn dispatch_value(&mut self, value: Value) {
+        if value.get("method").and_then(Value::as_str) == Some("eth_subscription") {
+            return self.dispatch_notification(value);
+        }
+        let id = match value.get("id").and_then(Value::as_u64) {
+            Some(id) => id,
+            None => {
+                trace!("Ignoring WebSocket message without id {:?}", value);
+                return;
+            }
+        };
+        let sender = match self.pending.remove(&id) {
+            Some(sender) => sender,
+            None => {
+                warn!("Got a WebSocket response for unknown request {}", id);
+                return;
+            }
+        };
+        // Register the subscription before anyone gets to see the response, the first
+        // notification may already be in the next message
+        if let Some(notifications) = self.subscribing.remove(&id) {
+            if let Some(subscription_id) = value.get("result").and_then(Value::as_str) {
+                self.subscriptions
+                    .insert(subscription_id.to_string(), notifications);
+            }
+        }
+        let response = serde_json::from_value(value).map_err(Web3Error::from);
+        // The receiving end may have been dropped which just means nobody cares anymore
+        let _ = sender.send(response);
+    }
+
+    fn dispatch_notification(&mut self, mut value: Value) {
+        let params = value["params"].take();
+        let subscription_id = match params.get("subscription").and_then(Value::as_str) {
+            Some(subscription_id) => subscription_id.to_string(),
+            None => {
+                warn!("Got a subscription notification without id {:?}", params);
+                return;
+            }
+        };
+        let delivered = match self.subscriptions.get(&subscription_id) {
+            Some(sender) => sender.unbounded_send(params["result"].clone()).is_ok(),
+            None => {
+                trace!(
+                    "Got a notification for unknown subscription {}",
+                    subscription_id
+                );
+                return;
+            }
+        };
+        if !delivered {
+            trace!("Subscription {} was dropped", subscription_id);
+            self.subscriptions.remove(&subscription_id);
+        }
+    }
+
+    /// Fails every request which is still waiting for a response and ends every subscription
+    fn fail_pending(&mut self, reason: &str) {
+        for (_, sender) in self.pending.drain() {
+            let _ = sender.send(Err(Web3Error::Transport(reason.to_string())));
+        }
+        self.subscribing.clear();
+        self.subscriptions.clear();
+        self.queue.clear();
+    }
+}
+
+pub struct WebSocketClient {
+    url: String,
+    id_counter: AtomicUsize,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl WebSocketClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            id_counter: AtomicUsize::new(0),
+            shared: Arc::new(Mutex::new(Shared::new())),
+        }
+    }
+
+    /// Registers interest in the responses to `ids` and writes `text` to the connection,
+    /// connecting first if needed.
+    fn write(
+        &self,
+        ids: &[u64],
+        text: String,
+    ) -> Vec<oneshot::Receiver<Result<Response<Value>, Web3Error>>> {
+        let mut guard = self.shared.lock().expect("WebSocket state poisoned");
+        let shared = &mut *guard;
+        let receivers = ids
+            .iter()
+            .map(|id| {
+                let (sender, receiver) = oneshot::channel();
+                shared.pending.insert(*id, sender);
+                receiver
+            })
+            .collect();
+
+        let message = Message::Text(text.into());
+        let connect = match shared.connection {
+            Connection::Connected(ref sender) => match sender.unbounded_send(message) {
+                Ok(()) => false,
+                Err(e) => {
+                    // The writer is gone, reconnect and send it again
+                    shared.queue.push(e.into_inner());
+                    true
+                }
+            },
+            Connection::Connecting => {
+                shared.queue.push(message);
+                false
+            }
+            Connection::Disconnected => {
+                shared.queue.push(message);
+                true
+            }
+        };
+
+        if connect {
+            shared.connection = Connection::Connecting;
+            drop(guard);
+            self.connect();
+        }
+
+        receivers
+    }
+
+    fn connect(&self) {
+        let url = self.url.clone();
+        let shared = self.shared.clone();
+        trace!("Connecting to {}", url);
+
+        actix_rt::spawn(async move {
+            let connection = match awc::Client::new().ws(&url).connect().await {
+                Ok((_, connection)) => connection,
+                Err(e) => {
+                    let mut shared = shared.lock().expect("WebSocket state poisoned");
+                    shared.connection = Connection::Disconnected;
+                    shared.fail_pending(&format!("WebSocket connection failed: {}", e));
+                    return;
+                }
+            };
+            let (mut writer, mut reader) = connection.split::<Message>();
+
+            let (sender, mut receiver) = mpsc::unbounded::<Message>();
+            {
+                let mut shared = shared.lock().expect("WebSocket state poisoned");
+                for message in shared.queue.drain(..) {
+                    let _ = sender.unbounded_send(message);
+                }
+                shared.connection = Connection::Connected(sender.clone());
+            }
+
+            actix_rt::spawn(async move {
+                while let Some(message) = receiver.next().await {
+                    if let Err(e) = writer.send(message).await {
+                        warn!("WebSocket write failed {:?}", e);
+                        break;
+                    }
+                }
+            });
+
+            while let Some(frame) = reader.next().await {
+                match frame {
+                    Ok(Frame::Text(text)) => match str::from_utf8(&text) {
+                        Ok(text) => shared
+                            .lock()
+                            .expect("WebSocket state poisoned")
+                            .dispatch(text),
+                        Err(e) => warn!("Invalid UTF-8 received over WebSocket {}", e),
+                    },
+                    Ok(Frame::Ping(payload)) => {
+                        let _ = sender.unbounded_send(Message::Pong(payload));
+                    }
+                    Ok(Frame::Close(reason)) => {
+                        trace!("WebSocket closed {:?}", reason);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("WebSocket error {:?}", e);
+                        break;
+                    }
+                }
+            }
+
+            let mut shared = shared.lock().expect("WebSocket state poisoned");
+            shared.connection = Connection::Disconnected;
+            shared.fail_pending("WebSocket connection closed");
+        });
+    }
+}
+
+async fn wait_for(
+    receiver: oneshot::Receiver<Result<Response<Value>, Web3Error>>,
+) -> Result<Response<Value>, Web3Error> {
+    receiver
+        .await
+        .map_err(|_| Web3Error::Transport("WebSocket request canceled".to_string()))?
+}
+
+#[async_trait(?Send)]
+impl Client for WebSocketClient {
+    async fn send_request(&self, request: Request<Value>) -> Result<Response<Value>, Web3Error> {
+        let text = serde_json::to_string(&request)?;
+        let mut receivers = self.write(&[request.id()], text);
+        wait_for(receivers.remove(0)).await
+    }
+
+    async fn send_batch(
+        &self,
+        requests: Vec<Request<Value>>,
+    ) -> Result<Vec<Response<Value>>, Web3Error> {
+        let ids = requests.iter().map(|r| r.id()).collect::<Vec<u64>>();
+        let text = serde_json::to_string(&requests)?;
+        let receivers = self.write(&ids, text);
+        let responses = future::try_join_all(receivers.into_iter().map(wait_for)).await?;
+        order_batch_responses(&ids, responses)
+    }
+
+    fn next_id(&self) -> u64 {
+        self.id_counter.fetch_add(1, Ordering::SeqCst) as u64 + 1
+    }
+
+    async fn subscribe(&self, params: Value) -> Result<Notifications, Web3Error> {
+        let request = Request::new(self.next_id(), "eth_subscribe", params);
+        let text = serde_json::to_string(&request)?;
+        let (sender, receiver) = mpsc::unbounded();
+        self.shared
+            .lock()
+            .expect("WebSocket state poisoned")
+            .subscribing
+            .insert(request.id(), sender);
+
+        let mut receivers = self.write(&[request.id()], text);
+        let subscription_id = into_result(wait_for(receivers.remove(0)).await?)?;
+        trace!("Subscribed with id {}", subscription_id);
+        Ok(receiver.map(Ok).boxed_local())
+    }
+
+    fn supports_subscriptions(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_dispatch_batch() {
+        let mut shared = Shared::new();
+        let (sender_1, receiver_1) = oneshot::channel();
+        let (sender_2, receiver_2) = oneshot::channel();
+        shared.pending.insert(1, sender_1);
+        shared.pending.insert(2, sender_2);
+
+        shared.dispatch(
+            r#"[{"jsonrpc": "2.0", "result": "0x2", "id": 2},
+                {"jsonrpc": "2.0", "error": {"code": -32601, "message": "Method not found"}, "id": 1}]"#,
+        );
+
+        assert!(shared.pending.is_empty());
+        let response_2 = block_on(receiver_2).unwrap().unwrap();
+        assert_eq!(into_result(response_2).unwrap(), Value::from("0x2"));
+        let response_1 = block_on(receiver_1).unwrap().unwrap();
+        assert!(into_result(response_1).is_err());
+    }
+
+    #[test]
+    fn test_dispatch_subscription() {
+        let mut shared = Shared::new();
+        let (sender, receiver) = oneshot::channel();
+        let (notifications, mut stream) = mpsc::unbounded();
+        shared.pending.insert(1, sender);
+        shared.subscribing.insert(1, notifications);
+
+        // The first notification arrives in the same message as the subscription id
+        shared.dispatch(
+            r#"[{"jsonrpc": "2.0", "result": "0xcd0c", "id": 1},
+                {"jsonrpc": "2.0", "method": "eth_subscription",
+                 "params": {"subscription": "0xcd0c", "result": {"number": "0x1b4"}}}]"#,
+        );
+        shared.dispatch(
+            r#"{"jsonrpc": "2.0", "method": "eth_subscription",
+                "params": {"subscription": "0xffff", "result": {"number": "0x1"}}}"#,
+        );
+        shared.dispatch(
+            r#"{"jsonrpc": "2.0", "method": "eth_subscription",
+                "params": {"subscription": "0xcd0c", "result": {"number": "0x1b5"}}}"#,
+        );
+
+        let response = block_on(receiver).unwrap().unwrap();
+        assert_eq!(into_result(response).unwrap(), Value::from("0xcd0c"));
+        let first = block_on(stream.next()).unwrap();
+        assert_eq!(first["number"], "0x1b4");
+        let second = block_on(stream.next()).unwrap();
+        assert_eq!(second["number"], "0x1b5");
+    }
+
+    #[test]
+    fn test_fail_pending() {
+        let mut shared = Shared::new();
+        let (sender, receiver) = oneshot::channel();
+        let (notifications, stream) = mpsc::unbounded();
+        shared.pending.insert(1, sender);
+        shared
+            .subscriptions
+            .insert("0xcd0c".to_string(), notifications);
+        shared.queue.push(Message::Text("queued".into()));
+
+        shared.fail_pending("gone");
+
+        assert!(shared.queue.is_empty());
+        assert!(block_on(receiver).unwrap().is_err());
+        // The subscription stream ends
+        assert_eq!(
+            block_on(stream.collect::<Vec<Value>>()),
+            Vec::<Value>::new()
+        );
+    }
+}
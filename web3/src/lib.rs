@@ -1,18 +1,13 @@
-extern crate serde;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
-extern crate clarity;
-extern crate futures;
-extern crate num256;
-#[macro_use]
-extern crate failure;
-extern crate actix_web;
-extern crate futures_timer;
-extern crate tokio;
 #[macro_use]
 extern crate log;
 
 pub mod client;
+pub mod error;
 pub mod jsonrpc;
 pub mod types;
+
+pub use crate::error::Web3Error;
@@ -120,7 +120,7 @@ pub struct TransactionResponse {
     pub s: Uint256,
 }
 
-#[derive(Serialize, Default, Debug)]
+#[derive(Serialize, Default, Debug, Clone)]
 pub struct NewFilter {
     #[serde(rename = "fromBlock", skip_serializing_if = "Option::is_none")]
     pub from_block: Option<String>,
@@ -131,6 +131,22 @@ pub struct NewFilter {
     pub topics: Option<Vec<Option<Vec<Option<String>>>>>,
 }
 
+/// Block header as delivered by a `newHeads` subscription.
+///
+/// See more: https://github.com/ethereum/go-ethereum/wiki/RPC-PUB-SUB#newheads
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BlockHeader {
+    /// the block number. null when its pending block.
+    pub number: Option<Uint256>,
+    /// hash of the block. null when its pending block.
+    pub hash: Option<Data>,
+    /// hash of the parent block.
+    #[serde(rename = "parentHash")]
+    pub parent_hash: Data,
+    /// the unix timestamp for when the block was collated.
+    pub timestamp: Uint256,
+}
+
 #[derive(Serialize, Debug)]
 pub struct TransactionRequest {
     //The address the transaction is send from.
@@ -187,3 +203,29 @@ fn decode_transaction_response() {
     )
     .unwrap();
 }
+
+#[test]
+fn decode_block_header() {
+    let res: BlockHeader = serde_json::from_str(
+        r#"{
+    "difficulty":"0x15d9223a23aa",
+    "extraData":"0xd983010305844765746887676f312e342e328777696e646f7773",
+    "gasLimit":"0x47e7c4",
+    "gasUsed":"0x38658",
+    "hash":"0x61b2cbb4f9ef2b4a9ad6f7e6b0b2de9b7c6e15d6e5b1b4f3b6c8c3b3c3b1e5b5",
+    "logsBloom":"0x00",
+    "miner":"0xf8b483dba2c3b7176a3da549ad41a48bb3121069",
+    "nonce":"0x084149998194cc5f",
+    "number":"0x1348c9",
+    "parentHash":"0x7736fab79e05dc611604d22470dadad26f56fe494421b5b333de816ce1f25701",
+    "receiptRoot":"0x2fab35823ad00c7bb388595cb46652fe7886e00660a01e867824d3dceb1c8d36",
+    "sha3Uncles":"0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+    "stateRoot":"0xb3346685172db67de536d8765c43c31009d0eb3bd9c501c9be3229203f15f378",
+    "timestamp":"0x56ffeff8",
+    "transactionsRoot":"0x0167ffa60e3ebc0b080cdb95f7c0087dd6c0e61413140e39d94d3468d7c9689f"
+  }"#,
+    )
+    .unwrap();
+    assert_eq!(res.number, Some(0x1348c9u64.into()));
+    assert_eq!(res.timestamp, 0x56ffeff8u64.into());
+}